@@ -0,0 +1,411 @@
+//! Protocol conformance checks for signalling servers
+//!
+//! [`matchbox_socket`] only relies on a small part of what `matchbox_server`
+//! does to connect peers. This crate checks that part against any websocket
+//! endpoint, without a browser or WebRTC, so servers written in other
+//! languages can be validated against matchbox clients:
+//!
+//! ```sh
+//! cargo run -p matchbox_conformance -- ws://localhost:3536
+//! ```
+//!
+//! Every check uses rooms of its own, with random names, so the endpoint may
+//! be a server in use. The messages are the ones of
+//! [`matchbox_socket::protocol`], and peer ids are uuids. Events the checks
+//! don't look at, e.g. the extensions of `matchbox_server`, are ignored.
+
+use futures::{SinkExt, StreamExt};
+use matchbox_socket::protocol::{PeerEvent, PeerId, PeerRequest, PeerSignal, SignallingErrorCode};
+use std::{fmt, str::FromStr, time::Duration};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// How long to wait for events that should arrive, unless configured
+/// otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for events that shouldn't arrive
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+/// How often to try registering with an id that may not have been released
+/// yet
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A protocol conformance check, run with [`Check::run`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Peers register with ids of their own, and a peer registering with the
+    /// id of a connected one is rejected with
+    /// [`SignallingErrorCode::IdTaken`]
+    IdAssignment,
+    /// A peer joining a room is announced to the peers already in it, and
+    /// nobody else
+    PeerAnnouncement,
+    /// Signals are relayed to their receiver unchanged and in order, with
+    /// the id of their sender
+    SignalRelay,
+    /// A peer that disconnected leaves its room, so its id can be used again
+    /// and the peers joining later aren't paired with it
+    DisconnectPropagation,
+    /// Rooms with `next=N` in their url put peers into groups of N, which
+    /// only learn about each other
+    NextScoping,
+}
+
+impl Check {
+    /// Every check, in the order they are run by default
+    pub const ALL: [Check; 5] = [
+        Check::IdAssignment,
+        Check::PeerAnnouncement,
+        Check::SignalRelay,
+        Check::DisconnectPropagation,
+        Check::NextScoping,
+    ];
+
+    /// Name of the check, e.g. for selecting it on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::IdAssignment => "id_assignment",
+            Check::PeerAnnouncement => "peer_announcement",
+            Check::SignalRelay => "signal_relay",
+            Check::DisconnectPropagation => "disconnect_propagation",
+            Check::NextScoping => "next_scoping",
+        }
+    }
+
+    /// Runs the check against the server at `url`, e.g.
+    /// `"ws://localhost:3536"`, to which room names are appended
+    ///
+    /// Events that should arrive have to arrive within `wait`.
+    pub async fn run(self, url: &str, wait: Duration) -> Result<(), String> {
+        let room = format!(
+            "conformance_{}_{}",
+            self.name(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let server = Server { url, wait };
+        match self {
+            Check::IdAssignment => id_assignment(server, &room).await,
+            Check::PeerAnnouncement => peer_announcement(server, &room).await,
+            Check::SignalRelay => signal_relay(server, &room).await,
+            Check::DisconnectPropagation => disconnect_propagation(server, &room).await,
+            Check::NextScoping => next_scoping(server, &room).await,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Check::ALL
+            .iter()
+            .copied()
+            .find(|check| check.name() == name)
+            .ok_or_else(|| format!("no check named {name:?}"))
+    }
+}
+
+/// The outcome of a [`Check`]
+#[derive(Debug)]
+pub struct CheckResult {
+    /// The check that was run
+    pub check: Check,
+    /// Why the check failed, if it did
+    pub result: Result<(), String>,
+}
+
+/// Runs the given checks against the server at `url` one after the other,
+/// see [`Check::run`]
+pub async fn run_checks(url: &str, checks: &[Check], wait: Duration) -> Vec<CheckResult> {
+    let mut results = Vec::with_capacity(checks.len());
+    for &check in checks {
+        let result = check.run(url, wait).await;
+        results.push(CheckResult { check, result });
+    }
+    results
+}
+
+async fn id_assignment(server: Server<'_>, room: &str) -> Result<(), String> {
+    let mut a = server.join(room).await?;
+    let mut impostor = server.connect_as(room, a.id.clone()).await?;
+    match timeout(server.wait, impostor.next_event()).await {
+        Ok(Ok(Some(PeerEvent::Error(SignallingErrorCode::IdTaken)))) => {}
+        Ok(Ok(Some(event))) => {
+            return Err(format!(
+                "a peer reusing the id of a connected one got {event:?} instead of IdTaken"
+            ))
+        }
+        Ok(Ok(None)) => {
+            return Err(
+                "a peer reusing the id of a connected one was disconnected without IdTaken"
+                    .to_string(),
+            )
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err("a peer reusing the id of a connected one wasn't rejected".to_string())
+        }
+    }
+    a.expect_nothing().await?;
+
+    // the rejected peer didn't replace the one it impersonated
+    let b = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(b.id.clone())).await
+}
+
+async fn peer_announcement(server: Server<'_>, room: &str) -> Result<(), String> {
+    let mut a = server.join(room).await?;
+    let mut b = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(b.id.clone())).await?;
+
+    let _stranger = server.join(&format!("{room}_other")).await?;
+    a.expect_nothing().await?;
+    b.expect_nothing().await?;
+
+    let c = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(c.id.clone())).await?;
+    b.expect(PeerEvent::NewPeer(c.id.clone())).await
+}
+
+async fn signal_relay(server: Server<'_>, room: &str) -> Result<(), String> {
+    let mut a = server.join(room).await?;
+    let mut b = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(b.id.clone())).await?;
+
+    // sdp spans several lines, and custom signals may be anything
+    let offer = PeerSignal::Offer("v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\n".to_string());
+    relay(&mut a, &mut b, vec![offer]).await?;
+    let answer = PeerSignal::Answer("v=0\r\no=- 3 4 IN IP4 127.0.0.1\r\n".to_string());
+    relay(&mut b, &mut a, vec![answer]).await?;
+    let candidates = (0..3)
+        .map(|i| {
+            PeerSignal::IceCandidate(format!(
+                "candidate:{i} 1 udp 2130706431 127.0.0.1 {i} typ host"
+            ))
+        })
+        .collect();
+    relay(&mut a, &mut b, candidates).await?;
+    let custom = PeerSignal::Custom("{\"ünïcödé\": \"\\\"quoted\\\"\"}".to_string());
+    relay(&mut b, &mut a, vec![custom]).await
+}
+
+/// Sends the signals from one peer to the other, and checks that they
+/// arrive unchanged
+async fn relay(from: &mut Peer, to: &mut Peer, signals: Vec<PeerSignal>) -> Result<(), String> {
+    for data in &signals {
+        from.send(PeerRequest::Signal {
+            receiver: to.id.clone(),
+            data: data.clone(),
+        })
+        .await?;
+    }
+    for data in signals {
+        to.expect(PeerEvent::Signal {
+            sender: from.id.clone(),
+            data,
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn disconnect_propagation(server: Server<'_>, room: &str) -> Result<(), String> {
+    let mut a = server.join(room).await?;
+    let b = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(b.id.clone())).await?;
+    let b_id = b.id.clone();
+    b.close().await;
+
+    // joining doesn't tell the new peer about the one that left
+    let mut c = server.join(room).await?;
+    a.expect(PeerEvent::NewPeer(c.id.clone())).await?;
+
+    // the server may not have noticed the disconnect right away
+    let deadline = Instant::now() + server.wait;
+    loop {
+        let mut rejoined = server.connect_as(room, b_id.clone()).await?;
+        match timeout(QUIET_PERIOD, rejoined.next_event()).await {
+            Err(_) => break,
+            Ok(Ok(Some(PeerEvent::Error(SignallingErrorCode::IdTaken))))
+                if Instant::now() < deadline =>
+            {
+                sleep(RETRY_INTERVAL).await;
+            }
+            Ok(Ok(Some(event))) => {
+                return Err(format!(
+                    "a peer rejoining with the id of one that left got {event:?}"
+                ))
+            }
+            Ok(Ok(None)) => {
+                return Err(
+                    "a peer rejoining with the id of one that left was disconnected".to_string(),
+                )
+            }
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    a.expect(PeerEvent::NewPeer(b_id.clone())).await?;
+    c.expect(PeerEvent::NewPeer(b_id)).await
+}
+
+async fn next_scoping(server: Server<'_>, room: &str) -> Result<(), String> {
+    let room = format!("{room}?next=2");
+    let mut a = server.join(&room).await?;
+    let mut b = server.join(&room).await?;
+    a.expect(PeerEvent::NewPeer(b.id.clone())).await?;
+
+    // the first group is full, so the next peers start another one
+    let mut c = server.join(&room).await?;
+    let d = server.join(&room).await?;
+    c.expect(PeerEvent::NewPeer(d.id.clone())).await?;
+    a.expect_nothing().await?;
+    b.expect_nothing().await
+}
+
+/// The server under test
+#[derive(Clone, Copy)]
+struct Server<'a> {
+    url: &'a str,
+    wait: Duration,
+}
+
+impl Server<'_> {
+    /// Connects a peer with a new id to a room, and waits for the server to
+    /// register it
+    ///
+    /// Since peers are only announced to the ones already in the room, this
+    /// fails if the new peer is told about anyone.
+    async fn join(self, room: &str) -> Result<Peer, String> {
+        let mut peer = self
+            .connect_as(room, uuid::Uuid::new_v4().to_string())
+            .await?;
+        peer.expect_nothing().await?;
+        Ok(peer)
+    }
+
+    /// Connects a peer to a room and registers it with the given id, without
+    /// waiting for the server
+    async fn connect_as(self, room: &str, id: PeerId) -> Result<Peer, String> {
+        let room_url = format!("{}/{}", self.url.trim_end_matches('/'), room);
+        let (ws, _) = timeout(self.wait, connect_async(&room_url))
+            .await
+            .map_err(|_| format!("connecting to {room_url} timed out"))?
+            .map_err(|e| format!("couldn't connect to {room_url}: {e}"))?;
+        let mut peer = Peer {
+            id: id.clone(),
+            ws,
+            wait: self.wait,
+        };
+        peer.send(PeerRequest::Uuid(id)).await?;
+        Ok(peer)
+    }
+}
+
+/// A connection to the server, acting like a [`matchbox_socket`] peer
+struct Peer {
+    id: PeerId,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    wait: Duration,
+}
+
+impl Peer {
+    async fn send(&mut self, request: PeerRequest) -> Result<(), String> {
+        let text = serde_json::to_string(&request).expect("requests are serializable");
+        self.ws
+            .send(Message::Text(text))
+            .await
+            .map_err(|e| format!("{} couldn't send {request:?}: {e}", self.id))
+    }
+
+    /// Receives the next event the checks look at, or `None` once the server
+    /// closed the connection
+    async fn next_event(&mut self) -> Result<Option<PeerEvent>, String> {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let event = serde_json::from_str(&text);
+                    if let Ok(
+                        event @ (PeerEvent::NewPeer(_)
+                        | PeerEvent::Signal { .. }
+                        | PeerEvent::Error(_)),
+                    ) = event
+                    {
+                        return Ok(Some(event));
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(format!("the connection of {} failed: {e}", self.id)),
+            }
+        }
+    }
+
+    /// Fails unless the given event is the next one to arrive
+    async fn expect(&mut self, expected: PeerEvent) -> Result<(), String> {
+        match timeout(self.wait, self.next_event()).await {
+            Ok(Ok(Some(event))) if event == expected => Ok(()),
+            Ok(Ok(Some(event))) => Err(format!("{} expected {expected:?}, got {event:?}", self.id)),
+            Ok(Ok(None)) => Err(format!(
+                "{} was disconnected while expecting {expected:?}",
+                self.id
+            )),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(format!("{} never got {expected:?}", self.id)),
+        }
+    }
+
+    /// Fails if an event arrives, or the server disconnects us, for a while
+    async fn expect_nothing(&mut self) -> Result<(), String> {
+        match timeout(QUIET_PERIOD, self.next_event()).await {
+            Err(_) => Ok(()),
+            Ok(Ok(Some(event))) => Err(format!("{} unexpectedly got {event:?}", self.id)),
+            Ok(Ok(None)) => Err(format!("{} was unexpectedly disconnected", self.id)),
+            Ok(Err(e)) => Err(e),
+        }
+    }
+
+    async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matchbox_test::TestServer;
+
+    #[tokio::test]
+    async fn matchbox_server_conforms() {
+        let server = TestServer::start();
+        let url = format!("ws://{}", server.addr());
+        for CheckResult { check, result } in run_checks(&url, &Check::ALL, DEFAULT_TIMEOUT).await {
+            assert_eq!(result, Ok(()), "{}", check);
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_fails_every_check() {
+        let url = "ws://127.0.0.1:9";
+        for CheckResult { check, result } in run_checks(url, &Check::ALL, DEFAULT_TIMEOUT).await {
+            assert!(result.is_err(), "{}", check);
+        }
+    }
+
+    #[test]
+    fn checks_are_found_by_name() {
+        for check in Check::ALL {
+            assert_eq!(check.name().parse(), Ok(check));
+        }
+        assert!("no_such_check".parse::<Check>().is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use clap::Parser;
+use matchbox_conformance::{run_checks, Check, CheckResult};
+use std::{process, time::Duration};
+
+/// Checks that a signalling server speaks the protocol of matchbox_socket
+#[derive(Parser, Debug)]
+#[clap(name = "matchbox_conformance", rename_all = "kebab-case")]
+struct Args {
+    /// Websocket url of the server, e.g. `ws://localhost:3536`, room names
+    /// are appended to it
+    url: String,
+    /// Only run the check with this name, may be given several times
+    #[clap(long = "check")]
+    checks: Vec<Check>,
+    /// How long to wait for events that should arrive, in milliseconds
+    #[clap(long, default_value_t = 5000)]
+    timeout_ms: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let checks = if args.checks.is_empty() {
+        Check::ALL.to_vec()
+    } else {
+        args.checks
+    };
+    let wait = Duration::from_millis(args.timeout_ms);
+
+    let results = run_checks(&args.url, &checks, wait).await;
+    let mut failed = 0;
+    for CheckResult { check, result } in &results {
+        match result {
+            Ok(()) => println!("{check:<24} ok"),
+            Err(e) => {
+                failed += 1;
+                println!("{check:<24} FAILED: {e}");
+            }
+        }
+    }
+    println!("\n{} passed, {failed} failed", results.len() - failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
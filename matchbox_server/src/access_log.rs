@@ -0,0 +1,90 @@
+use log::info;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::signaling::{matchbox::PeerId, RoomId};
+
+/// Log target of the access log, so it can be filtered separately, e.g. with
+/// `RUST_LOG=matchbox_server::access=info`
+const TARGET: &str = "matchbox_server::access";
+
+/// Writes one log line per websocket connection, once it's closed
+#[derive(Debug, Default)]
+pub(crate) struct AccessLog {
+    anonymize_ips: bool,
+}
+
+/// A finished websocket connection
+pub(crate) struct AccessLogEntry<'a> {
+    /// The room the peer was in when it disconnected
+    pub room: &'a RoomId,
+    /// `None` if the peer never registered
+    pub peer: Option<&'a PeerId>,
+    pub origin: Option<&'a str>,
+    pub addr: Option<SocketAddr>,
+    pub duration: Duration,
+}
+
+impl AccessLog {
+    /// Creates an access log, truncating client ips if `anonymize_ips` is set,
+    /// see [`anonymize_ip`]
+    pub fn new(anonymize_ips: bool) -> Self {
+        Self { anonymize_ips }
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let ip = match entry.addr {
+            Some(addr) if self.anonymize_ips => anonymize_ip(addr.ip()).to_string(),
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        };
+        info!(
+            target: TARGET,
+            "room={:?} peer={:?} origin={:?} ip={} duration_ms={}",
+            entry.room.0,
+            entry.peer.map_or("-", String::as_str),
+            entry.origin.unwrap_or("-"),
+            ip,
+            entry.duration.as_millis()
+        );
+    }
+}
+
+/// Zeroes the host part of an ip, keeping the /24 of IPv4 and the /48 of IPv6
+/// addresses, so it can't be tied to a single person anymore
+pub(crate) fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::anonymize_ip;
+
+    #[test]
+    fn anonymizes_ipv4() {
+        let ip: IpAddr = "203.0.113.57".parse().unwrap();
+        assert_eq!(anonymize_ip(ip), "203.0.113.0".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn anonymizes_ipv6() {
+        let ip: IpAddr = "2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap();
+        assert_eq!(
+            anonymize_ip(ip),
+            "2001:db8:85a3::".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use warp::{http::StatusCode, reject::Reject, Filter, Rejection, Reply};
+
+use crate::{
+    signaling::{with_state, BanTarget, RoomId, State},
+    PeerId, PeerRole,
+};
+
+/// Rejection for requests without a valid admin token
+#[derive(Debug)]
+struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+#[derive(Debug, Serialize)]
+struct PeerInfo {
+    id: PeerId,
+    room: String,
+    next: Option<usize>,
+    addr: Option<SocketAddr>,
+    params: BTreeMap<String, String>,
+    role: PeerRole,
+}
+
+#[derive(Debug, Deserialize)]
+struct BanRequest {
+    #[serde(flatten)]
+    target: BanTarget,
+    duration_secs: u64,
+}
+
+/// Admin routes, authenticated with `Authorization: Bearer <token>`
+///
+/// - `GET /admin/peers` lists connected peers
+/// - `POST /admin/peers/<id>/kick` disconnects a peer
+/// - `POST /admin/rooms/<room>/close` ends the match in a room for everyone
+/// - `POST /admin/bans` with `{"ip": "1.2.3.4", "duration_secs": 600}` or
+///   `{"peer": "<id>", "duration_secs": 600}` bans an address or peer id
+///
+/// Peers pick their own ids, so a peer id ban only keeps that id out, e.g.
+/// a client with a fixed id. Ban the address to keep someone out.
+///
+/// If no token is configured, the admin api is disabled.
+#[allow(opaque_hidden_inferred_bound)]
+pub(crate) fn admin_filter(
+    state: Arc<Mutex<State>>,
+    token: Option<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let list_peers = warp::path!("peers")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(list_peers_handler);
+    let kick = warp::path!("peers" / PeerId / "kick")
+        .and(warp::post())
+        .and(with_state(state.clone()))
+        .and_then(kick_handler);
+    let close_room = warp::path!("rooms" / String / "close")
+        .and(warp::post())
+        .and(with_state(state.clone()))
+        .and_then(close_room_handler);
+    let ban = warp::path!("bans")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(ban_handler);
+
+    warp::path("admin")
+        .and(authorized(token))
+        .and(list_peers.or(kick).or(close_room).or(ban))
+        .recover(handle_rejection)
+}
+
+/// Rejects requests without `Authorization: Bearer <token>`, or all of them
+/// if no token is configured
+pub(crate) fn authorized(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Err(warp::reject::not_found());
+                };
+                let expected = format!("Bearer {token}");
+                // don't leak how much of the token a guess got right
+                let header = header.unwrap_or_default();
+                if bool::from(header.as_bytes().ct_eq(expected.as_bytes())) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(err)
+    }
+}
+
+async fn list_peers_handler(state: Arc<Mutex<State>>) -> Result<impl Reply, Infallible> {
+    let state = state.lock().await;
+    let peers: Vec<PeerInfo> = state
+        .peers()
+        .map(|peer| PeerInfo {
+            id: peer.uuid.clone(),
+            room: peer.room.id.0.clone(),
+            next: peer.room.next,
+            addr: peer.addr,
+            params: peer.params.clone(),
+            role: peer.role,
+        })
+        .collect();
+    Ok(warp::reply::json(&peers))
+}
+
+async fn kick_handler(peer_id: PeerId, state: Arc<Mutex<State>>) -> Result<impl Reply, Infallible> {
+    let mut state = state.lock().await;
+    Ok(if state.kick(&peer_id) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+async fn close_room_handler(
+    room: String,
+    state: Arc<Mutex<State>>,
+) -> Result<impl Reply, Infallible> {
+    let mut state = state.lock().await;
+    Ok(if state.close_room(&RoomId(room), None) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+async fn ban_handler(
+    request: BanRequest,
+    state: Arc<Mutex<State>>,
+) -> Result<impl Reply, Infallible> {
+    let mut state = state.lock().await;
+    Ok(
+        if state.ban(request.target, Duration::from_secs(request.duration_secs)) {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_REQUEST
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+
+    use super::admin_filter;
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let api = admin_filter(
+            Arc::new(Mutex::new(Default::default())),
+            Some("secret".into()),
+        );
+        for (header, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("Bearer secre"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer secret!"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer secret"), StatusCode::OK),
+        ] {
+            let mut request = warp::test::request().path("/admin/peers");
+            if let Some(header) = header {
+                request = request.header("authorization", header);
+            }
+            assert_eq!(request.reply(&api).await.status(), status, "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_endless_bans() {
+        let api = admin_filter(
+            Arc::new(Mutex::new(Default::default())),
+            Some("secret".into()),
+        );
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/bans")
+            .header("authorization", "Bearer secret")
+            .json(&serde_json::json!({ "ip": "10.0.0.1", "duration_secs": u64::MAX }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,5 +1,10 @@
-use clap::Parser;
-use std::net::SocketAddr;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::{
+    config::{Cluster, ConfigError, ConfigFile, Limits, Matchmaking, RoomRule, Turn},
+    hooks::JoinHook,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -10,4 +15,161 @@ use std::net::SocketAddr;
 pub struct Args {
     #[clap(default_value = "0.0.0.0:3536", env)]
     pub host: SocketAddr,
+    /// TOML file with further settings, flags and environment variables take
+    /// precedence over it
+    #[clap(long, env)]
+    pub config: Option<PathBuf>,
+    /// PEM encoded certificate chain, serves https and wss if set
+    #[clap(long, env, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key for the certificate
+    #[clap(long, env, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Url to post room lifecycle events to
+    #[clap(long, env)]
+    pub webhook_url: Option<String>,
+    /// Shared secret used to sign webhook requests with HMAC-SHA256
+    #[clap(long, env)]
+    pub webhook_secret: Option<String>,
+    /// Token required to use the admin api, which is disabled if not set
+    #[clap(long, env)]
+    pub admin_token: Option<String>,
+    /// Secret shared with the TURN servers, overrides the one in the `[turn]`
+    /// section of the config file
+    #[clap(long, env)]
+    pub turn_secret: Option<String>,
+    /// Zero the host part of client ips in the access log
+    #[clap(long, env)]
+    pub anonymize_ips: bool,
+    /// Accept peer ids that aren't uuids, e.g. for deterministic ids in tests
+    ///
+    /// Custom ids are easier to guess, so only enable this where peers are
+    /// trusted. Ids already in use are rejected either way.
+    #[clap(long, env)]
+    pub custom_peer_ids: bool,
+    /// OTLP/HTTP endpoint to export traces to, e.g.
+    /// `http://localhost:4318/v1/traces`, see [`crate::start_tracing`]
+    #[cfg(feature = "otlp")]
+    #[clap(long, env)]
+    pub otlp_endpoint: Option<String>,
+    /// Only configurable in the config file, reloaded on SIGHUP
+    #[clap(skip)]
+    pub limits: Limits,
+    /// Only configurable in the config file, reloaded on SIGHUP
+    #[clap(skip)]
+    pub rooms: Vec<RoomRule>,
+    /// Only configurable in the config file
+    #[clap(skip)]
+    pub matchmaking: Option<Matchmaking>,
+    /// Only configurable in the config file, except for the secret
+    #[clap(skip)]
+    pub turn: Option<Turn>,
+    /// Only configurable in the config file
+    #[clap(skip)]
+    pub cluster: Option<Cluster>,
+    /// Only configurable in code, for custom server builds, e.g. to check
+    /// game-specific query parameters of the room urls peers connect with
+    #[clap(skip)]
+    pub join_hook: Option<JoinHook>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            host: ([0, 0, 0, 0], 3536).into(),
+            config: None,
+            tls_cert: None,
+            tls_key: None,
+            webhook_url: None,
+            webhook_secret: None,
+            admin_token: None,
+            turn_secret: None,
+            anonymize_ips: false,
+            custom_peer_ids: false,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: None,
+            limits: Limits::default(),
+            rooms: vec![],
+            matchmaking: None,
+            turn: None,
+            cluster: None,
+            join_hook: None,
+        }
+    }
+}
+
+impl Args {
+    /// Parses the command line, and fills in settings that weren't given
+    /// there from the `--config` file
+    ///
+    /// Exits on invalid command lines, like [`Parser::parse`].
+    pub fn parse_with_config() -> Result<Self, ConfigError> {
+        let matches = Self::command().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        if let Some(path) = &args.config {
+            let host_is_default = matches.value_source("host") == Some(ValueSource::DefaultValue);
+            args.merge(ConfigFile::read(path)?, host_is_default);
+        }
+        Ok(args)
+    }
+
+    fn merge(&mut self, file: ConfigFile, host_is_default: bool) {
+        if let Some(host) = file.host.filter(|_| host_is_default) {
+            self.host = host;
+        }
+        if let (None, None, Some(tls)) = (&self.tls_cert, &self.tls_key, file.tls) {
+            self.tls_cert = Some(tls.cert_path);
+            self.tls_key = Some(tls.key_path);
+        }
+        self.webhook_url = self.webhook_url.take().or(file.webhook_url);
+        self.webhook_secret = self.webhook_secret.take().or(file.webhook_secret);
+        self.admin_token = self.admin_token.take().or(file.admin_token);
+        self.anonymize_ips |= file.anonymize_ips.unwrap_or_default();
+        self.custom_peer_ids |= file.custom_peer_ids.unwrap_or_default();
+        self.limits = file.limits;
+        self.rooms = file.rooms;
+        self.matchmaking = file.matchmaking;
+        self.turn = file.turn;
+        self.cluster = file.cluster;
+    }
+
+    /// The TURN settings, with the secret from the command line if given
+    pub fn turn(&self) -> Option<Turn> {
+        let mut turn = self.turn.clone()?;
+        if let Some(secret) = &self.turn_secret {
+            turn.secret = secret.clone();
+        }
+        Some(turn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use crate::config::ConfigFile;
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            host = "127.0.0.1:8080"
+            admin_token = "from-file"
+            webhook_url = "http://example.com/hook"
+            "#,
+        )
+        .unwrap();
+
+        let mut args = Args {
+            admin_token: Some("from-cli".to_string()),
+            ..Default::default()
+        };
+        args.merge(file.clone(), true);
+        assert_eq!(args.host, ([127, 0, 0, 1], 8080).into());
+        assert_eq!(args.admin_token.as_deref(), Some("from-cli"));
+        assert_eq!(args.webhook_url.as_deref(), Some("http://example.com/hook"));
+
+        let mut args = Args::default();
+        args.merge(file, false);
+        assert_eq!(args.host, Args::default().host);
+    }
 }
@@ -0,0 +1,233 @@
+use futures::lock::Mutex;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::{
+    admin::{authorized, handle_rejection},
+    config::Cluster,
+    signaling::{with_state, State},
+    PeerId,
+};
+
+/// How long a request to another instance may take before it's given up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of messages waiting to be posted to a single instance, further
+/// ones are dropped until it catches up
+const QUEUE_SIZE: usize = 1024;
+
+/// Messages instances of a cluster post to each other's `POST /cluster`
+///
+/// Every message names the url of the instance that sent it, so instances
+/// that weren't configured to know each other learn about each other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClusterMessage {
+    /// The instance started, and forgot about any peers it had before
+    Hello { instance: String },
+    /// A peer joined a room on the instance
+    Joined {
+        instance: String,
+        room: String,
+        peer: PeerId,
+    },
+    /// A peer on the instance left its room
+    Left { instance: String, peer: PeerId },
+    /// A signal from a peer on the instance to a peer on the receiving one
+    Signal {
+        instance: String,
+        sender: PeerId,
+        receiver: PeerId,
+        data: serde_json::Value,
+    },
+}
+
+impl ClusterMessage {
+    /// The url of the instance that sent the message
+    pub fn instance(&self) -> &str {
+        match self {
+            ClusterMessage::Hello { instance }
+            | ClusterMessage::Joined { instance, .. }
+            | ClusterMessage::Left { instance, .. }
+            | ClusterMessage::Signal { instance, .. } => instance,
+        }
+    }
+}
+
+/// The other instances of the cluster this instance is part of
+///
+/// Messages are posted to each instance one at a time, in order, by a
+/// background task per instance.
+pub(crate) struct ClusterLink {
+    url: String,
+    secret: String,
+    client: reqwest::Client,
+    instances: HashMap<String, mpsc::Sender<ClusterMessage>>,
+}
+
+impl ClusterLink {
+    /// Starts the tasks posting to the configured instances, and says hello
+    /// to them, must be called on a tokio runtime
+    pub fn new(config: Cluster) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("error building cluster client");
+        let mut link = Self {
+            url: config.url,
+            secret: config.secret,
+            client,
+            instances: HashMap::new(),
+        };
+        for instance in config.instances {
+            link.add_instance(&instance);
+        }
+        link.broadcast(ClusterMessage::Hello {
+            instance: link.url.clone(),
+        });
+        link
+    }
+
+    /// The url the other instances reach this one at
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Starts posting to an instance, returns whether it was new
+    pub fn add_instance(&mut self, url: &str) -> bool {
+        if url == self.url || self.instances.contains_key(url) {
+            return false;
+        }
+        info!("joined cluster instance {url}");
+        let (queue, messages) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(post_messages(
+            self.client.clone(),
+            format!("{}/cluster", url.trim_end_matches('/')),
+            self.secret.clone(),
+            messages,
+        ));
+        self.instances.insert(url.to_string(), queue);
+        true
+    }
+
+    /// Queues a message for every other instance
+    pub fn broadcast(&self, message: ClusterMessage) {
+        for instance in self.instances.keys() {
+            self.send(instance, message.clone());
+        }
+    }
+
+    /// Queues a message for the given instance
+    ///
+    /// Drops it if the instance is unknown or too far behind.
+    pub fn send(&self, instance: &str, message: ClusterMessage) {
+        let Some(queue) = self.instances.get(instance) else {
+            error!("unknown cluster instance {instance}, dropping {message:?}");
+            return;
+        };
+        match queue.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(message)) => {
+                warn!("queue of {instance} is full, dropping {message:?}");
+            }
+            Err(TrySendError::Closed(message)) => {
+                error!("task posting to {instance} stopped, dropping {message:?}")
+            }
+        }
+    }
+}
+
+async fn post_messages(
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    mut messages: mpsc::Receiver<ClusterMessage>,
+) {
+    while let Some(message) = messages.recv().await {
+        let body = serde_json::to_string(&message).expect("error serializing cluster message");
+        let request = client
+            .post(&url)
+            .bearer_auth(&secret)
+            .header("Content-Type", "application/json");
+        match request.body(body).send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => error!("{url} returned {} for {message:?}", response.status()),
+            Err(e) => warn!("failed to post {message:?} to {url}: {e:?}"),
+        }
+    }
+}
+
+/// The route other instances of the cluster post [`ClusterMessage`]s to,
+/// authenticated with `Authorization: Bearer <secret>`
+///
+/// If the instance isn't part of a cluster, the route is disabled.
+#[allow(opaque_hidden_inferred_bound)]
+pub(crate) fn cluster_filter(
+    state: Arc<Mutex<State>>,
+    secret: Option<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("cluster")
+        .and(warp::post())
+        .and(authorized(secret))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(cluster_handler)
+        .recover(handle_rejection)
+}
+
+async fn cluster_handler(
+    message: ClusterMessage,
+    state: Arc<Mutex<State>>,
+) -> Result<impl Reply, Infallible> {
+    state.lock().await.handle_cluster_message(message);
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+
+    use super::{cluster_filter, ClusterMessage};
+
+    #[tokio::test]
+    async fn requires_the_secret() {
+        let api = cluster_filter(
+            Arc::new(Mutex::new(Default::default())),
+            Some("secret".into()),
+        );
+        let hello = ClusterMessage::Hello {
+            instance: "http://10.0.0.2:3536".to_string(),
+        };
+        for (header, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("Bearer secre"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer secret"), StatusCode::OK),
+        ] {
+            let mut request = warp::test::request()
+                .method("POST")
+                .path("/cluster")
+                .json(&hello);
+            if let Some(header) = header {
+                request = request.header("authorization", header);
+            }
+            assert_eq!(request.reply(&api).await.status(), status, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn message_json() {
+        let message = ClusterMessage::Left {
+            instance: "http://10.0.0.2:3536".to_string(),
+            peer: "peer-1".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"left","instance":"http://10.0.0.2:3536","peer":"peer-1"}"#
+        );
+    }
+}
@@ -0,0 +1,568 @@
+use futures::lock::Mutex;
+use semver::VersionReq;
+use serde::Deserialize;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::signaling::{
+    matchbox::{MatchmakingRegion, RoomPolicy},
+    State,
+};
+
+/// Errors reading the file given with `--config`
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config file: {0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+/// The contents of a config file, e.g.
+///
+/// ```toml
+/// host = "0.0.0.0:443"
+/// admin_token = "secret"
+///
+/// [tls]
+/// cert_path = "cert.pem"
+/// key_path = "key.pem"
+///
+/// [limits]
+/// max_signals_per_peer = 512
+/// max_peers = 5000
+/// queue_when_busy = true
+///
+/// [[rooms]]
+/// pattern = "duel-*"
+/// next = 2
+///
+/// [[rooms]]
+/// pattern = "arena-*"
+/// max_peers = 16
+/// channels = 2
+/// event_log = 32
+/// strict_version = true
+/// listed = true
+/// client_versions = ">=1.2, <2"
+///
+/// [matchmaking]
+/// group_size = 4
+/// regions = [
+///     { name = "eu", stun_url = "stun:stun.eu.example.com:3478" },
+///     { name = "us", stun_url = "stun:stun.us.example.com:3478" },
+/// ]
+///
+/// [turn]
+/// secret = "shared with the TURN server"
+///
+/// [turn.relay]
+/// public_ip = "203.0.113.7"
+///
+/// [cluster]
+/// url = "http://10.0.0.1:3536"
+/// instances = ["http://10.0.0.2:3536"]
+/// secret = "shared by the instances"
+/// ```
+///
+/// Every setting is optional, command line flags and environment variables
+/// take precedence.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub host: Option<SocketAddr>,
+    pub tls: Option<TlsConfig>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub admin_token: Option<String>,
+    pub anonymize_ips: Option<bool>,
+    pub custom_peer_ids: Option<bool>,
+    /// Re-read when the server receives SIGHUP
+    pub limits: Limits,
+    /// Re-read when the server receives SIGHUP
+    pub rooms: Vec<RoomRule>,
+    /// Enables the matchmaking queue
+    pub matchmaking: Option<Matchmaking>,
+    /// Hands out TURN credentials, and optionally runs a TURN relay
+    pub turn: Option<Turn>,
+    /// Relays signals to peers connected to other instances of the server
+    pub cluster: Option<Cluster>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// Certificate and private key to serve https and wss with
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub cert_path: PathBuf,
+    /// PEM encoded private key
+    pub key_path: PathBuf,
+}
+
+/// Limits on what a single peer may do, and on how many peers the server
+/// takes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum size of a single websocket message from a peer, in bytes
+    ///
+    /// Larger messages close the connection.
+    pub max_message_size: usize,
+    /// Maximum number of signals (offers, answers and ice candidates) a peer
+    /// may send to a single other peer before it's disconnected
+    pub max_signals_per_peer: usize,
+    /// Maximum number of ICE candidates a peer may send to a single other
+    /// peer, or 0 for no limit
+    ///
+    /// Further candidates are dropped, and the peer is warned with
+    /// `SignalsThrottled`, instead of being disconnected.
+    pub max_candidates_per_peer: usize,
+    /// Maximum number of offers a peer may send to the peers of its room
+    /// per minute, or 0 for no limit
+    ///
+    /// Further offers are dropped until older ones are a minute old, and the
+    /// peer is warned with `SignalsThrottled`, so a misbehaving peer can't
+    /// flood a large room with renegotiations.
+    pub max_offers_per_minute: usize,
+    /// How long the slots a peer reserves in its `next` group are held for
+    /// the peers it reserved them for, in seconds
+    pub reservation_secs: u64,
+    /// How long a room may go without signalling traffic from its peers
+    /// before it's closed, in seconds, or 0 to never close idle rooms
+    ///
+    /// Keep-alives don't count, so peers that are still around have to send
+    /// something else, e.g. `KeepRoomAlive` once they're warned.
+    pub room_idle_secs: u64,
+    /// How long before an idle room is closed its peers are warned, in
+    /// seconds
+    pub room_idle_warning_secs: u64,
+    /// How many of the peers already in a room a new peer is introduced to
+    /// per second, or 0 to introduce it to all of them at once
+    ///
+    /// When dozens of peers join a room at once, pacing introductions keeps
+    /// each of them from being flooded with offers. The new peer is told how
+    /// many introductions are left with `IntroductionsPending`.
+    pub introductions_per_sec: u32,
+    /// Maximum number of rooms with peers in them, or 0 for no limit
+    ///
+    /// Peers joining a new room beyond it are turned away with `ServerBusy`,
+    /// or wait until a room closes, see [`Limits::queue_when_busy`].
+    pub max_rooms: usize,
+    /// Maximum number of peers in rooms at once, or 0 for no limit
+    ///
+    /// Peers joining beyond it are turned away with `ServerBusy`, or wait
+    /// until another peer leaves, see [`Limits::queue_when_busy`].
+    pub max_peers: usize,
+    /// Whether peers joining beyond [`Limits::max_rooms`] or
+    /// [`Limits::max_peers`] wait in a queue until there's space for them,
+    /// instead of being turned away
+    ///
+    /// Waiting peers are told their position in the queue with
+    /// `ServerQueuePosition` whenever it changes.
+    pub queue_when_busy: bool,
+    /// How long peers turned away with `ServerBusy` are told to wait before
+    /// trying again, in seconds
+    pub busy_retry_after_secs: u64,
+    /// Maximum number of messages queued for a peer that doesn't read them,
+    /// or 0 for no limit
+    ///
+    /// Further messages are dropped, and the peer is disconnected the next
+    /// time it sends something, so a stalled client can't make the server's
+    /// memory grow. Counted in the `queue_overflows` stat of its room. Only
+    /// applies to new connections.
+    pub max_queued_messages: usize,
+    /// Maximum number of peers sent to an observer in one message, or 0 to
+    /// always send the whole list at once
+    ///
+    /// Larger peer lists are sent in `RoomPeersPage`s, followed by
+    /// `PeerListComplete`, so rooms with hundreds of peers don't need a
+    /// single huge message.
+    pub peer_list_page_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // plenty for an sdp offer or answer
+            max_message_size: 64 * 1024,
+            max_signals_per_peer: 256,
+            max_candidates_per_peer: 64,
+            max_offers_per_minute: 120,
+            reservation_secs: 60,
+            room_idle_secs: 0,
+            room_idle_warning_secs: 60,
+            introductions_per_sec: 0,
+            max_rooms: 0,
+            max_peers: 0,
+            queue_when_busy: false,
+            busy_retry_after_secs: 10,
+            max_queued_messages: 1024,
+            peer_list_page_size: 100,
+        }
+    }
+}
+
+/// Settings of the matchmaking queue
+///
+/// Instead of joining a room, peers may join the queue and report their
+/// latency to each region. The server puts peers with similar latencies to
+/// the same closest region into a new room together, and tells them its id.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matchmaking {
+    /// Regions peers measure their latency to, with a STUN server in each
+    pub regions: Vec<MatchmakingRegion>,
+    /// Number of peers to put into each room
+    pub group_size: usize,
+    /// Peers are only grouped if their latencies to their closest region are
+    /// in the same bucket of this many milliseconds
+    pub bucket_ms: u64,
+}
+
+impl Default for Matchmaking {
+    fn default() -> Self {
+        Self {
+            regions: vec![],
+            group_size: 2,
+            bucket_ms: 50,
+        }
+    }
+}
+
+/// TURN servers to hand to peers, with credentials for each peer
+///
+/// Credentials are derived from a secret shared with the TURN servers, as in
+/// coturn's `use-auth-secret` mode, so they don't need to know about peers.
+/// Usernames are `<expiry>:<peer id>`, so the TURN server's logs show who
+/// relayed what.
+/// Peers use the TURN servers in addition to their own ICE server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Turn {
+    /// Urls of the TURN servers, e.g. `"turn:turn.example.com:3478"`
+    ///
+    /// Defaults to the url of [`Turn::relay`].
+    pub urls: Vec<String>,
+    /// The secret shared with the TURN servers, coturn's `static-auth-secret`
+    pub secret: String,
+    /// How long handed out credentials stay valid, in seconds
+    pub credential_ttl_secs: u64,
+    /// Runs a TURN relay in the signalling server, so a single host is
+    /// enough for NAT traversal
+    pub relay: Option<TurnRelay>,
+}
+
+impl Default for Turn {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: String::new(),
+            credential_ttl_secs: 24 * 60 * 60,
+            relay: None,
+        }
+    }
+}
+
+impl Turn {
+    /// The urls handed to peers
+    pub(crate) fn urls(&self) -> Vec<String> {
+        match &self.relay {
+            Some(relay) if self.urls.is_empty() => vec![relay.url()],
+            _ => self.urls.clone(),
+        }
+    }
+}
+
+/// Settings of a cluster of signalling servers behind a load balancer
+///
+/// Peers in the same room may connect to different instances. Instances
+/// tell each other who joins and leaves their rooms, and relay signals to
+/// peers connected to another instance, so those peers still meet.
+///
+/// Only rooms without a `next` group size span instances, `next` groups are
+/// formed by each instance on its own. So are room rules and limits, e.g.
+/// `max_peers` counts the peers of a single instance, and observers only see
+/// those.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cluster {
+    /// The url the other instances reach this one at, e.g.
+    /// `"http://10.0.0.1:3536"`
+    pub url: String,
+    /// Urls of the other instances
+    ///
+    /// Instances that aren't listed join the cluster by posting to one that
+    /// is, so it's enough to list a single instance that's always around.
+    #[serde(default)]
+    pub instances: Vec<String>,
+    /// The secret the instances authenticate each other with
+    pub secret: String,
+}
+
+/// Settings of the TURN relay embedded in the signalling server
+///
+/// Relayed traffic goes through UDP ports in `min_port..=max_port`, which
+/// need to be reachable, e.g. published when running in docker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TurnRelay {
+    /// The address peers reach this host at
+    pub public_ip: IpAddr,
+    /// UDP port to listen for TURN requests on
+    #[serde(default = "TurnRelay::default_port")]
+    pub port: u16,
+    /// Lowest UDP port to relay traffic through
+    #[serde(default = "TurnRelay::default_min_port")]
+    pub min_port: u16,
+    /// Highest UDP port to relay traffic through
+    #[serde(default = "TurnRelay::default_max_port")]
+    pub max_port: u16,
+}
+
+impl TurnRelay {
+    fn default_port() -> u16 {
+        3478
+    }
+
+    fn default_min_port() -> u16 {
+        49152
+    }
+
+    fn default_max_port() -> u16 {
+        65535
+    }
+
+    pub(crate) fn url(&self) -> String {
+        match self.public_ip {
+            IpAddr::V4(ip) => format!("turn:{ip}:{}", self.port),
+            IpAddr::V6(ip) => format!("turn:[{ip}]:{}", self.port),
+        }
+    }
+}
+
+/// Rules for all rooms with ids matching a pattern
+///
+/// The first matching rule applies. Peers learn the rules of their room when
+/// they join it, see [`RoomPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomRule {
+    /// A room id, a trailing `*` matches any suffix
+    pub pattern: String,
+    /// Pairs peers in groups of this size, overriding the `next` query
+    /// parameter
+    pub next: Option<usize>,
+    /// Peers joining a room with this many peers are rejected with
+    /// `RoomFull`, and so are migrations that would overfill it
+    pub max_peers: Option<usize>,
+    /// Number of data channels peers in the room are expected to open
+    ///
+    /// Only advertised to the peers, the server can't check it.
+    pub channels: Option<usize>,
+    /// Number of room messages to keep, and replay to peers joining later,
+    /// so they can catch up on e.g. lobby state without asking the host
+    ///
+    /// The log is dropped once the room is empty.
+    pub event_log: Option<usize>,
+    /// Rejects peers whose declared client version differs from the version
+    /// in the room's metadata with `VersionMismatch`
+    ///
+    /// The metadata defaults to the version of the peer that created the room.
+    #[serde(default)]
+    pub strict_version: bool,
+    /// Semver requirement the declared client versions of peers have to
+    /// meet, e.g. `">=1.2, <2"`, others are rejected with `VersionMismatch`
+    ///
+    /// Peers that don't declare a version, or not a semver one, are rejected
+    /// too. Migrating a room here is refused if any of its peers would be.
+    #[serde(default)]
+    pub client_versions: Option<VersionReq>,
+    /// Lists the rooms in `GET /rooms`, e.g. for a server browser
+    ///
+    /// Rooms are unlisted otherwise, since their ids often double as invite
+    /// codes.
+    #[serde(default)]
+    pub listed: bool,
+}
+
+impl RoomRule {
+    pub fn matches(&self, room_id: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => room_id.starts_with(prefix),
+            None => room_id == self.pattern,
+        }
+    }
+
+    pub fn policy(&self) -> RoomPolicy {
+        RoomPolicy {
+            next: self.next,
+            max_peers: self.max_peers,
+            channels: self.channels,
+        }
+    }
+}
+
+/// Re-reads the limits and room rules from the config file whenever the server receives
+/// SIGHUP
+///
+/// Other settings only take effect on restart.
+#[cfg(unix)]
+pub(crate) fn reload_on_sighup(path: PathBuf, state: Arc<Mutex<State>>) {
+    use log::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("can't listen for SIGHUP, config reloading is disabled: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match ConfigFile::read(&path) {
+                Ok(config) => {
+                    info!("reloaded {path:?}, limits are now {:?}", config.limits);
+                    let mut state = state.lock().await;
+                    state.set_limits(config.limits);
+                    state.set_room_rules(config.rooms);
+                }
+                Err(e) => error!("keeping the current config, {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub(crate) fn reload_on_sighup(_path: PathBuf, _state: Arc<Mutex<State>>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigFile, Limits, Matchmaking, RoomRule, Turn};
+
+    #[test]
+    fn parse_config_file() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            host = "127.0.0.1:8080"
+            anonymize_ips = true
+
+            [tls]
+            cert_path = "cert.pem"
+            key_path = "key.pem"
+
+            [limits]
+            max_signals_per_peer = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.host, Some(([127, 0, 0, 1], 8080).into()));
+        assert_eq!(config.anonymize_ips, Some(true));
+        assert_eq!(config.tls.unwrap().key_path.to_str(), Some("key.pem"));
+        assert_eq!(
+            config.limits,
+            Limits {
+                max_signals_per_peer: 10,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_matchmaking() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            [matchmaking]
+            group_size = 4
+            regions = [{ name = "eu", stun_url = "stun:stun.eu.example.com:3478" }]
+            "#,
+        )
+        .unwrap();
+        let matchmaking = config.matchmaking.unwrap();
+        assert_eq!(matchmaking.group_size, 4);
+        assert_eq!(matchmaking.bucket_ms, Matchmaking::default().bucket_ms);
+        assert_eq!(matchmaking.regions[0].name, "eu");
+    }
+
+    #[test]
+    fn parse_turn() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            [turn]
+            secret = "s3cret"
+
+            [turn.relay]
+            public_ip = "203.0.113.7"
+            min_port = 50000
+            max_port = 50100
+            "#,
+        )
+        .unwrap();
+        let turn = config.turn.unwrap();
+        assert_eq!(turn.secret, "s3cret");
+        assert_eq!(
+            turn.credential_ttl_secs,
+            Turn::default().credential_ttl_secs
+        );
+        let relay = turn.relay.unwrap();
+        assert_eq!(
+            (relay.port, relay.min_port, relay.max_port),
+            (3478, 50000, 50100)
+        );
+        assert_eq!(turn.urls(), ["turn:203.0.113.7:3478"]);
+    }
+
+    #[test]
+    fn room_rule_patterns() {
+        let rule = |pattern: &str| RoomRule {
+            pattern: pattern.to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: None,
+            strict_version: false,
+            client_versions: None,
+            listed: false,
+        };
+        assert!(rule("arena-*").matches("arena-1"));
+        assert!(rule("arena-*").matches("arena-"));
+        assert!(!rule("arena-*").matches("duel-1"));
+        assert!(rule("lobby").matches("lobby"));
+        assert!(!rule("lobby").matches("lobby-2"));
+        assert!(rule("*").matches("anything"));
+    }
+
+    #[test]
+    fn parse_client_versions() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            [[rooms]]
+            pattern = "arena-*"
+            client_versions = ">=1.2, <2"
+            "#,
+        )
+        .unwrap();
+        let versions = config.rooms[0].client_versions.as_ref().unwrap();
+        assert!(versions.matches(&"1.4.0".parse().unwrap()));
+        assert!(!versions.matches(&"2.0.0".parse().unwrap()));
+        assert!(toml::from_str::<ConfigFile>(
+            "[[rooms]]\npattern = \"a\"\nclient_versions = \"not a version\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reject_unknown_settings() {
+        assert!(toml::from_str::<ConfigFile>("hots = \"127.0.0.1:8080\"").is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use crate::signaling::matchbox::{PeerId, PeerRole, SignallingErrorCode};
+
+/// A peer asking to join a room
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    pub peer: PeerId,
+    pub room: String,
+    /// Group size the peer will be paired in, after room rules were applied
+    pub next: Option<usize>,
+    /// All query parameters of the room url the peer connected with,
+    /// including `next`, e.g. `region`, `mode` or `mmr` for a game that
+    /// uses those
+    pub params: BTreeMap<String, String>,
+}
+
+type CheckFn = dyn Fn(&JoinRequest) -> Result<Option<PeerRole>, SignallingErrorCode> + Send + Sync;
+
+/// Decides whether a peer may join a room, and which role it gets, e.g.
+/// based on game-specific query parameters of the room url or claims of a
+/// token passed in one
+///
+/// Runs after the server's own checks, so only for peers that would be let
+/// in otherwise. See [`crate::Args::join_hook`].
+#[derive(Clone)]
+pub struct JoinHook(Arc<CheckFn>);
+
+impl JoinHook {
+    /// Creates a hook from a function that returns the role to give a peer,
+    /// or `None` for the one it gets by join order, or the error to reject
+    /// it with if it may not join
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&JoinRequest) -> Result<Option<PeerRole>, SignallingErrorCode>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self(Arc::new(check))
+    }
+
+    pub(crate) fn check(
+        &self,
+        request: &JoinRequest,
+    ) -> Result<Option<PeerRole>, SignallingErrorCode> {
+        (self.0)(request)
+    }
+}
+
+impl fmt::Debug for JoinHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHook").finish_non_exhaustive()
+    }
+}
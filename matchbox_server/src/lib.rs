@@ -0,0 +1,116 @@
+use futures::lock::Mutex;
+use log::info;
+use std::sync::Arc;
+use warp::{http::StatusCode, hyper::Method, Filter, Rejection, Reply};
+
+pub use args::Args;
+pub use config::{
+    Cluster, ConfigError, ConfigFile, Limits, Matchmaking, RoomRule, TlsConfig, Turn, TurnRelay,
+};
+pub use hooks::{JoinHook, JoinRequest};
+pub use signaling::matchbox::{
+    IceServer, MatchmakingRegion, PeerId, PeerRole, RoomMetadata, RoomPolicy, SignallingErrorCode,
+};
+#[cfg(feature = "otlp")]
+pub use telemetry::{start_tracing, TracingHandle};
+pub use turn_relay::{start_turn_relay, TurnRelayHandle};
+
+mod access_log;
+mod admin;
+mod args;
+mod cluster;
+mod config;
+mod hooks;
+mod matchmaking;
+mod rooms;
+mod signaling;
+mod stats;
+mod telemetry;
+mod turn_relay;
+mod webhooks;
+
+/// All routes of the signalling server, configured by the given [`Args`]
+#[allow(opaque_hidden_inferred_bound)]
+pub fn routes(args: Args) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let health_route = warp::path("health").and_then(health_handler);
+
+    let log = warp::log("made_in_heaven");
+
+    // let cors = warp::cors()
+    //     .allow_methods(vec!["GET", "POST"])
+    //     .allow_header("content-type")
+    //     .allow_header("authorization")
+    //     .allow_any_origin()
+    //     .build();
+
+    let cors = warp::cors()
+        .allow_any_origin()
+        .allow_headers(vec![
+            "Access-Control-Allow-Headers",
+            "Access-Control-Request-Method",
+            "Access-Control-Request-Headers",
+            "Origin",
+            "Accept",
+            "X-Requested-With",
+            "Content-Type",
+        ])
+        .allow_methods(&[
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+            Method::HEAD,
+        ]);
+
+    // let cors = warp::cors()
+    //     .allow_any_origin()
+    //     .allow_methods(&[Method::GET]);
+
+    let turn = args.turn();
+    let mut state = signaling::State::default()
+        .with_access_log(access_log::AccessLog::new(args.anonymize_ips))
+        .with_limits(args.limits)
+        .with_room_rules(args.rooms);
+    if args.custom_peer_ids {
+        state = state.with_custom_peer_ids();
+    }
+    if let Some(matchmaking) = args.matchmaking {
+        state = state.with_matchmaking(matchmaking);
+    }
+    if let Some(hook) = args.join_hook {
+        state = state.with_join_hook(hook);
+    }
+    if let Some(turn) = turn {
+        state = state.with_turn(turn);
+    }
+    if let Some(url) = args.webhook_url {
+        info!("Sending room lifecycle events to {url}");
+        state = state.with_webhook(webhooks::Webhook::new(url, args.webhook_secret));
+    }
+    let cluster_secret = args.cluster.as_ref().map(|cluster| cluster.secret.clone());
+    if let Some(cluster) = args.cluster {
+        info!("Relaying signals through the cluster as {}", cluster.url);
+        state = state.with_cluster(cluster::ClusterLink::new(cluster));
+    }
+    let state = Arc::new(Mutex::new(state));
+    signaling::close_idle_rooms(&state);
+    signaling::pace_introductions(&state);
+    if let Some(path) = args.config {
+        config::reload_on_sighup(path, state.clone());
+    }
+
+    health_route
+        .or(stats::stats_filter(state.clone(), args.admin_token.clone()))
+        .or(rooms::rooms_filter(state.clone()))
+        .or(admin::admin_filter(state.clone(), args.admin_token))
+        .or(cluster::cluster_filter(state.clone(), cluster_secret))
+        .or(signaling::ws_filter(state))
+        .with(cors)
+        .with(log)
+}
+
+pub async fn health_handler() -> std::result::Result<impl Reply, Rejection> {
+    Ok(StatusCode::OK)
+}
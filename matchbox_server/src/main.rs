@@ -1,13 +1,6 @@
-use clap::Parser;
-use log::info;
-use std::env;
-use warp::{http::StatusCode, hyper::Method, Filter, Rejection, Reply};
-
-pub use args::Args;
-pub use signaling::matchbox::PeerId;
-
-mod args;
-mod signaling;
+use log::{error, info};
+use matchbox_server::Args;
+use std::{env, process};
 
 #[tokio::main]
 async fn main() {
@@ -15,56 +8,35 @@ async fn main() {
         env::set_var("RUST_LOG", "matchbox_server=info");
     }
     pretty_env_logger::init();
-    let args = Args::parse();
-
-    let health_route = warp::path("health").and_then(health_handler);
-
-    let log = warp::log("made_in_heaven");
-
-    // let cors = warp::cors()
-    //     .allow_methods(vec!["GET", "POST"])
-    //     .allow_header("content-type")
-    //     .allow_header("authorization")
-    //     .allow_any_origin()
-    //     .build();
-
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec![
-            "Access-Control-Allow-Headers",
-            "Access-Control-Request-Method",
-            "Access-Control-Request-Headers",
-            "Origin",
-            "Accept",
-            "X-Requested-With",
-            "Content-Type",
-        ])
-        .allow_methods(&[
-            Method::GET,
-            Method::POST,
-            Method::PUT,
-            Method::PATCH,
-            Method::DELETE,
-            Method::OPTIONS,
-            Method::HEAD,
-        ]);
-
-    // let cors = warp::cors()
-    //     .allow_any_origin()
-    //     .allow_methods(&[Method::GET]);
-
-    let routes = health_route
-        .or(signaling::ws_filter(Default::default()))
-        .with(cors)
-        .with(log);
-
-    info!(
-        "Starting matchbox signaling server at port {}",
-        args.host.port()
-    );
-    warp::serve(routes).run(args.host).await;
-}
-
-pub async fn health_handler() -> std::result::Result<impl Reply, Rejection> {
-    Ok(StatusCode::OK)
+    let args = Args::parse_with_config().unwrap_or_else(|e| {
+        error!("{e}");
+        process::exit(1);
+    });
+    // keeps relaying for as long as the server runs
+    let _turn_relay = match args.turn() {
+        Some(turn) => matchbox_server::start_turn_relay(&turn)
+            .await
+            .unwrap_or_else(|e| {
+                error!("failed to start the TURN relay: {e}");
+                process::exit(1);
+            }),
+        None => None,
+    };
+    // exports traces for as long as the server runs
+    #[cfg(feature = "otlp")]
+    let _tracing = args.otlp_endpoint.as_deref().map(|endpoint| {
+        matchbox_server::start_tracing(endpoint).unwrap_or_else(|e| {
+            error!("failed to start exporting traces: {e}");
+            process::exit(1);
+        })
+    });
+    let host = args.host;
+    let tls = args.tls_cert.clone().zip(args.tls_key.clone());
+
+    info!("Starting matchbox signaling server at port {}", host.port());
+    let server = warp::serve(matchbox_server::routes(args));
+    match tls {
+        Some((cert, key)) => server.tls().cert_path(cert).key_path(key).run(host).await,
+        None => server.run(host).await,
+    }
 }
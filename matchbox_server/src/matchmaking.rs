@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{config::Matchmaking, signaling::matchbox::MatchmakingRegion};
+
+/// A connection waiting in the matchmaking queue
+pub(crate) struct QueuedPeer<S> {
+    pub sender: S,
+    /// Latest reported latency to each region, in milliseconds
+    pub latencies: HashMap<String, u64>,
+}
+
+/// Peers waiting to be put into rooms together, see [`Matchmaking`]
+pub(crate) struct Queue<S> {
+    config: Matchmaking,
+    /// Ordered by id, so peers that queued first are matched first
+    peers: BTreeMap<usize, QueuedPeer<S>>,
+    next_id: usize,
+}
+
+impl<S> Queue<S> {
+    pub fn new(config: Matchmaking) -> Self {
+        Self {
+            config,
+            peers: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// The regions peers should measure their latency to
+    pub fn regions(&self) -> &[MatchmakingRegion] {
+        &self.config.regions
+    }
+
+    /// Adds a connection to the queue, returns an id to refer to it with
+    pub fn join(&mut self, sender: S) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let latencies = HashMap::new();
+        self.peers.insert(id, QueuedPeer { sender, latencies });
+        id
+    }
+
+    pub fn leave(&mut self, id: usize) {
+        self.peers.remove(&id);
+    }
+
+    /// Updates the latencies of a queued peer, ignoring unknown regions
+    pub fn report(&mut self, id: usize, latencies: HashMap<String, u64>) {
+        let regions = &self.config.regions;
+        if let Some(peer) = self.peers.get_mut(&id) {
+            let known = latencies
+                .into_iter()
+                .filter(|(region, _)| regions.iter().any(|r| &r.name == region));
+            peer.latencies.extend(known);
+        }
+    }
+
+    /// Takes groups of [`Matchmaking::group_size`] peers out of the queue
+    ///
+    /// Peers are grouped by their closest region, and their latency to it in
+    /// buckets of [`Matchmaking::bucket_ms`]. Peers that haven't reported
+    /// any latencies yet aren't matched.
+    pub fn match_peers(&mut self) -> Vec<Vec<QueuedPeer<S>>> {
+        let group_size = self.config.group_size.max(1);
+        let mut buckets: HashMap<(&str, u64), Vec<usize>> = HashMap::new();
+        let mut groups = vec![];
+        for (id, peer) in &self.peers {
+            let bucket = match self.bucket(peer) {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            let ids = buckets.entry(bucket).or_default();
+            ids.push(*id);
+            if ids.len() == group_size {
+                groups.push(std::mem::take(ids));
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| self.peers.remove(id).expect("matched peer is queued"))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns the closest region of a peer, and the bucket of its latency
+    /// to it
+    fn bucket<'a>(&self, peer: &'a QueuedPeer<S>) -> Option<(&'a str, u64)> {
+        let (region, latency) = peer
+            .latencies
+            .iter()
+            .min_by_key(|(region, latency)| (**latency, region.as_str()))?;
+        Some((region.as_str(), latency / self.config.bucket_ms.max(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Queue;
+    use crate::{config::Matchmaking, signaling::matchbox::MatchmakingRegion};
+
+    fn queue(group_size: usize) -> Queue<&'static str> {
+        let region = |name: &str| MatchmakingRegion {
+            name: name.to_string(),
+            stun_url: format!("stun:{name}.example.com:3478"),
+        };
+        Queue::new(Matchmaking {
+            regions: vec![region("eu"), region("us")],
+            group_size,
+            bucket_ms: 50,
+        })
+    }
+
+    fn latencies(reports: &[(&str, u64)]) -> HashMap<String, u64> {
+        reports
+            .iter()
+            .map(|(region, ms)| (region.to_string(), *ms))
+            .collect()
+    }
+
+    fn senders<S: Copy>(group: &[super::QueuedPeer<S>]) -> Vec<S> {
+        group.iter().map(|peer| peer.sender).collect()
+    }
+
+    #[test]
+    fn groups_peers_by_region_and_latency() {
+        let mut queue = queue(2);
+        let a = queue.join("a");
+        let b = queue.join("b");
+        let c = queue.join("c");
+        let d = queue.join("d");
+        queue.report(a, latencies(&[("eu", 20), ("us", 120)]));
+        queue.report(b, latencies(&[("eu", 140), ("us", 30)]));
+        queue.report(c, latencies(&[("eu", 80), ("us", 200)]));
+        assert!(queue.match_peers().is_empty());
+
+        queue.report(d, latencies(&[("eu", 10), ("us", 110)]));
+        let groups = queue.match_peers();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(senders(&groups[0]), ["a", "d"]);
+
+        // matched peers left the queue
+        queue.report(a, latencies(&[("us", 25)]));
+        assert!(queue.match_peers().is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_regions_and_silent_peers() {
+        let mut queue = queue(2);
+        let a = queue.join("a");
+        let b = queue.join("b");
+        let _c = queue.join("c");
+        queue.report(a, latencies(&[("mars", 5)]));
+        queue.report(b, latencies(&[("mars", 5)]));
+        assert!(queue.match_peers().is_empty());
+
+        queue.leave(b);
+        let d = queue.join("d");
+        queue.report(a, latencies(&[("us", 40)]));
+        queue.report(d, latencies(&[("us", 45)]));
+        assert_eq!(senders(&queue.match_peers()[0]), ["a", "d"]);
+    }
+}
@@ -0,0 +1,44 @@
+use futures::lock::Mutex;
+use serde::Serialize;
+use std::{convert::Infallible, sync::Arc};
+use warp::{Filter, Rejection, Reply};
+
+use crate::signaling::{matchbox::RoomMetadata, with_state, State};
+
+/// A room with peers in it, as listed by `GET /rooms`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct RoomListing {
+    pub id: String,
+    pub peers: usize,
+    /// Set by the peer that created the room, if it did
+    pub metadata: Option<RoomMetadata>,
+}
+
+/// `GET /rooms` lists the rooms that have peers in them, e.g. for a server
+/// browser
+///
+/// Only rooms matching a [`RoomRule::listed`](crate::RoomRule::listed) rule
+/// are included.
+#[allow(opaque_hidden_inferred_bound)]
+pub(crate) fn rooms_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("rooms")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(rooms_handler)
+}
+
+async fn rooms_handler(state: Arc<Mutex<State>>) -> Result<impl Reply, Infallible> {
+    let state = state.lock().await;
+    let rooms: Vec<RoomListing> = state
+        .room_listing()
+        .into_iter()
+        .map(|(id, peers, metadata)| RoomListing {
+            id: id.0,
+            peers,
+            metadata,
+        })
+        .collect();
+    Ok(warp::reply::json(&rooms))
+}
@@ -1,19 +1,41 @@
-use futures::{lock::Mutex, stream::SplitSink, StreamExt};
+use futures::{
+    lock::{Mutex, MutexGuard},
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
 use log::{error, info, warn};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     convert::Infallible,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::{
     ws::{Message, WebSocket},
     Error, Filter, Rejection, Reply,
 };
 
+use crate::{
+    access_log::{AccessLog, AccessLogEntry},
+    cluster::{ClusterLink, ClusterMessage},
+    config::{Limits, Matchmaking, RoomRule, Turn},
+    hooks::{JoinHook, JoinRequest},
+    matchmaking::Queue,
+    stats::{RoomStats, MAX_EMPTY_ROOM_STATS, STATS_RETENTION},
+    telemetry::Span,
+    turn_relay,
+    webhooks::{RoomEvent, Webhook},
+};
+
 pub mod matchbox {
     use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
 
     pub type PeerId = String;
 
@@ -21,15 +43,255 @@ pub mod matchbox {
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     pub enum PeerRequest<S> {
         Uuid(PeerId),
-        Signal { receiver: PeerId, data: S },
+        Signal {
+            receiver: PeerId,
+            data: S,
+        },
         KeepAlive,
+        /// Display name to register, must be sent before [`PeerRequest::Uuid`]
+        Name(String),
+        /// Move everyone in the sender's room to another room
+        MigrateRoom {
+            room: String,
+            next: Option<usize>,
+        },
+        /// Watch who is in the room without joining it, sent instead of
+        /// [`PeerRequest::Uuid`]
+        Observe,
+        /// Message for everyone else in the sender's room, e.g. lobby state,
+        /// kept in the room's event log if it has one
+        RoomMessage(String),
+        /// Version of the peer's client, checked against the room's rules,
+        /// must be sent before [`PeerRequest::Uuid`]
+        Version(String),
+        /// Describe the room, only accepted from the peer that created it
+        SetRoomMetadata(RoomMetadata),
+        /// Features the peer supports, e.g. `"voice"`, must be sent before
+        /// [`PeerRequest::Uuid`]
+        Capabilities(Vec<String>),
+        /// Hold free slots of the sender's `next` group for peers claiming
+        /// these tokens, instead of filling them with strangers
+        ReserveSlots(Vec<String>),
+        /// Token of a slot reserved for the peer, must be sent before
+        /// [`PeerRequest::Uuid`]
+        ClaimSlot(String),
+        /// Wait in the matchmaking queue for a room, sent instead of
+        /// [`PeerRequest::Uuid`]
+        JoinQueue,
+        /// Latency of a queued peer to matchmaking regions, in milliseconds
+        Latency(HashMap<String, u64>),
+        /// End the match for everyone in the room, only accepted from the
+        /// peer that created it
+        CloseRoom,
+        /// Keeps the room from being closed for being idle, see
+        /// [`crate::Limits::room_idle_secs`]
+        KeepRoomAlive,
+        /// Join another room besides the one the peer connected to, to see
+        /// who is in it and exchange room messages, once it sent
+        /// [`PeerRequest::Uuid`]
+        JoinExtraRoom(String),
+        /// Leave a room joined with [`PeerRequest::JoinExtraRoom`]
+        LeaveExtraRoom(String),
+        /// Message for everyone else in a room joined with
+        /// [`PeerRequest::JoinExtraRoom`]
+        ExtraRoomMessage {
+            room: String,
+            data: String,
+        },
     }
 
     /// Events go from signalling server to peer
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     pub enum PeerEvent<S> {
         NewPeer(PeerId),
-        Signal { sender: PeerId, data: S },
+        Signal {
+            sender: PeerId,
+            data: S,
+        },
+        /// The receiving peer is being disconnected, sent right before the
+        /// connection is closed
+        Error(SignallingErrorCode),
+        /// The display name of a peer, sent before it is announced
+        PeerName {
+            peer: PeerId,
+            name: String,
+        },
+        /// The features a peer supports, sent before it is announced
+        PeerCapabilities {
+            peer: PeerId,
+            capabilities: Vec<String>,
+        },
+        /// The role the server gave a peer, sent before it is announced, and
+        /// to the peer itself when it joins
+        PeerRole {
+            peer: PeerId,
+            role: PeerRole,
+        },
+        /// The receiving peer and everyone in its room were moved to another room
+        RoomMigrated {
+            room: String,
+            next: Option<usize>,
+        },
+        /// The receiving peer's [`PeerRequest::MigrateRoom`] was refused,
+        /// e.g. because it didn't create its room or the target room is full
+        MigrationRejected(SignallingErrorCode),
+        /// The rules of the receiving peer's room, sent when it joins or
+        /// migrates to a room that has any
+        RoomPolicy(RoomPolicy),
+        /// The peers in the observed room, sent to observers when they start
+        /// observing and whenever a peer joins or leaves
+        RoomPeers(Vec<PeerId>),
+        /// Part of the peers in the observed room, sent instead of
+        /// [`PeerEvent::RoomPeers`] when there are more than
+        /// [`crate::Limits::peer_list_page_size`], followed by the next page
+        /// or [`PeerEvent::PeerListComplete`]
+        RoomPeersPage(Vec<PeerId>),
+        /// All [`PeerEvent::RoomPeersPage`]s of the peer list were sent
+        PeerListComplete,
+        /// A message from a peer in the room, also replayed from the room's
+        /// event log when joining
+        RoomMessage {
+            sender: PeerId,
+            data: String,
+        },
+        /// The description of the receiving peer's room, sent when it joins a
+        /// room that has one and whenever it changes
+        RoomMetadata(RoomMetadata),
+        /// The regions a peer in the matchmaking queue should report its
+        /// latency to, sent when it joins the queue
+        MatchmakingRegions(Vec<MatchmakingRegion>),
+        /// The room the matchmaking queue put the receiving peer into, sent
+        /// right before the connection is closed
+        MatchFound {
+            room: String,
+        },
+        /// TURN servers with credentials for the receiving peer, sent when it
+        /// joins a room, see [`crate::Turn`]
+        IceServers(Vec<IceServer>),
+        /// The receiving peer's room was closed by the given host, or by an
+        /// admin if there is none, sent right before the connection is closed
+        RoomClosed {
+            host: Option<PeerId>,
+        },
+        /// The receiving peer's room will be closed for being idle in this
+        /// many seconds, unless a peer in it sends something, see
+        /// [`crate::Limits::room_idle_secs`]
+        RoomIdle {
+            closes_in_secs: u64,
+        },
+        /// Signals of the receiving peer to `receiver` are being dropped for
+        /// exceeding [`crate::Limits::max_candidates_per_peer`] or
+        /// [`crate::Limits::max_offers_per_minute`]
+        SignalsThrottled {
+            receiver: PeerId,
+        },
+        /// How many peers of its room the receiving peer hasn't been
+        /// introduced to yet, see [`crate::Limits::introductions_per_sec`]
+        IntroductionsPending(usize),
+        /// The position of the receiving peer in the queue of peers waiting
+        /// for the server to have space for them, starting at 1, or 0 once
+        /// it's let in, see [`crate::Limits::queue_when_busy`]
+        ServerQueuePosition(usize),
+        /// The peers in a room the receiving peer joined with
+        /// [`PeerRequest::JoinExtraRoom`], sent when it joins and whenever a
+        /// peer joins or leaves
+        ExtraRoomPeers {
+            room: String,
+            peers: Vec<PeerId>,
+        },
+        /// A message from a peer in a room the receiving peer joined with
+        /// [`PeerRequest::JoinExtraRoom`]
+        ExtraRoomMessage {
+            room: String,
+            sender: PeerId,
+            data: String,
+        },
+    }
+
+    /// What a peer is in its room, given to it by the server when it joins
+    ///
+    /// The peer that creates a room is its host, the others are players,
+    /// unless a [`crate::JoinHook`] says otherwise.
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    pub enum PeerRole {
+        /// The authority of the room, e.g. for an authoritative-host game
+        Host,
+        Player,
+        /// Watches the game without taking part in it
+        Spectator,
+        /// Oversees the game, e.g. in tournaments
+        Referee,
+    }
+
+    /// Rules the server enforces for a room, see [`crate::RoomRule`]
+    #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct RoomPolicy {
+        /// Group size the room pairs peers in, overriding the `next` query
+        /// parameter
+        pub next: Option<usize>,
+        /// Maximum number of peers in the room
+        pub max_peers: Option<usize>,
+        /// Number of data channels peers in the room are expected to open
+        pub channels: Option<usize>,
+    }
+
+    /// A region of the matchmaking queue, see [`crate::Matchmaking`]
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct MatchmakingRegion {
+        pub name: String,
+        /// STUN server to measure the latency to the region with, e.g.
+        /// `"stun:stun.eu.example.com:3478"`
+        pub stun_url: String,
+    }
+
+    /// An ICE server with the credentials to use it
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct IceServer {
+        pub urls: Vec<String>,
+        pub username: Option<String>,
+        pub credential: Option<String>,
+    }
+
+    /// Describes a room, set by the peer that created it
+    #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    #[serde(default)]
+    pub struct RoomMetadata {
+        pub game_mode: Option<String>,
+        pub map: Option<String>,
+        /// Version of the creator's client, see [`crate::RoomRule::strict_version`]
+        pub version: Option<String>,
+    }
+
+    /// Why the signalling server is closing the connection to a peer
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum SignallingErrorCode {
+        /// The room has no space for more peers
+        RoomFull,
+        /// The peer is missing valid credentials
+        Unauthorized,
+        /// The peer sent requests the server doesn't understand
+        ProtocolMismatch,
+        /// The peer sent too many requests
+        RateLimited,
+        /// The server is shutting down
+        ServerShutdown,
+        /// The peer was removed by an admin
+        Kicked,
+        /// The peer's id or address is banned
+        Banned,
+        /// The peer's client version differs from the room's, or isn't
+        /// accepted at all, see [`crate::RoomRule::strict_version`] and
+        /// [`crate::RoomRule::client_versions`]
+        VersionMismatch,
+        /// Another connected peer already uses the peer's id
+        IdTaken,
+        /// The peer's id is empty, too long, or not a uuid while the server
+        /// doesn't accept custom ids, see [`crate::Args::custom_peer_ids`]
+        InvalidId,
+        /// The server has reached [`crate::Limits::max_peers`] or
+        /// [`crate::Limits::max_rooms`], and the peer may try again after
+        /// this many seconds
+        ServerBusy { retry_after_secs: u64 },
     }
 }
 use matchbox::*;
@@ -37,13 +299,36 @@ use matchbox::*;
 type PeerRequest = matchbox::PeerRequest<serde_json::Value>;
 type PeerEvent = matchbox::PeerEvent<serde_json::Value>;
 
+/// Maximum number of characters in a peer's display name
+const MAX_NAME_LEN: usize = 32;
+
+/// Maximum number of bytes in a peer's id, a uuid takes 36
+const MAX_PEER_ID_LEN: usize = 64;
+
+/// Maximum number of capabilities a peer may advertise
+const MAX_CAPABILITIES: usize = 32;
+
+/// Maximum number of characters in a single capability
+const MAX_CAPABILITY_LEN: usize = 64;
+
+/// How often rooms are checked for being idle, see [`Limits::room_idle_secs`]
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often paced introductions are sent, see
+/// [`Limits::introductions_per_sec`]
+const INTRODUCTION_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Maximum number of rooms a peer may join besides its own, see
+/// [`PeerRequest::JoinExtraRoom`]
+const MAX_EXTRA_ROOMS: usize = 8;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub(crate) struct RoomId(String);
+pub(crate) struct RoomId(pub(crate) String);
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct RequestedRoom {
-    id: RoomId,
-    next: Option<usize>,
+    pub id: RoomId,
+    pub next: Option<usize>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -51,274 +336,3073 @@ pub(crate) struct QueryParam {
     next: Option<usize>,
 }
 
+type OutgoingMessage = std::result::Result<Message, warp::Error>;
+
+/// Queues messages for the websocket of a peer, see [`spawn_sender_task`]
+///
+/// Messages beyond [`Limits::max_queued_messages`] are dropped, since the
+/// peer isn't reading what it's sent, and it's disconnected the next time it
+/// sends something.
+#[derive(Clone)]
+pub(crate) struct PeerSender {
+    tx: mpsc::UnboundedSender<OutgoingMessage>,
+    /// Messages sent but not yet taken by the sender task
+    queued: Arc<AtomicUsize>,
+    overflowed: Arc<AtomicBool>,
+    max_queued: usize,
+}
+
+impl PeerSender {
+    fn new(tx: mpsc::UnboundedSender<OutgoingMessage>, max_queued: usize) -> Self {
+        Self {
+            tx,
+            queued: Default::default(),
+            overflowed: Default::default(),
+            max_queued,
+        }
+    }
+
+    pub fn send(
+        &self,
+        message: OutgoingMessage,
+    ) -> std::result::Result<(), mpsc::error::SendError<OutgoingMessage>> {
+        if self.max_queued > 0 && self.queued.load(Ordering::Relaxed) >= self.max_queued {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return Err(mpsc::error::SendError(message));
+        }
+        self.tx.send(message)?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether messages were dropped for exceeding
+    /// [`Limits::max_queued_messages`]
+    fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
 pub(crate) struct Peer {
     pub uuid: PeerId,
     pub room: RequestedRoom,
-    pub sender: tokio::sync::mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>,
+    pub sender: PeerSender,
+    pub joined_at: Instant,
+    pub signalled: bool,
+    pub addr: Option<SocketAddr>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub capabilities: Vec<String>,
+    /// Number of signals sent to each other peer
+    pub signals_sent: HashMap<PeerId, usize>,
+    /// Number of ICE candidates sent to each other peer
+    pub candidates_sent: HashMap<PeerId, usize>,
+    /// When the offers of the last minute were sent
+    pub offers_sent: VecDeque<Instant>,
+    /// All query parameters of the room url the peer connected with
+    pub params: BTreeMap<String, String>,
+    pub role: PeerRole,
+}
+
+/// A connection watching a room's peers, see [`PeerRequest::Observe`]
+pub(crate) struct Observer {
+    pub room: RoomId,
+    pub sender: PeerSender,
+}
+
+/// A peer waiting for the server to have space for it, see
+/// [`Limits::queue_when_busy`]
+pub(crate) struct WaitingPeer {
+    pub id: usize,
+    pub room: RoomId,
+    pub sender: PeerSender,
+    pub admit: oneshot::Sender<()>,
+}
+
+/// Something that can be banned from connecting to the server
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BanTarget {
+    Ip(IpAddr),
+    Peer(PeerId),
 }
 
 #[derive(Default)]
 pub(crate) struct State {
     clients: HashMap<PeerId, Peer>,
     rooms: HashMap<RequestedRoom, HashSet<PeerId>>,
+    stats: HashMap<RoomId, RoomStats>,
+    webhook: Option<Webhook>,
+    bans: HashMap<BanTarget, Instant>,
+    access_log: AccessLog,
+    limits: Limits,
+    room_rules: Vec<RoomRule>,
+    observers: HashMap<usize, Observer>,
+    next_observer_id: usize,
+    event_logs: HashMap<RoomId, VecDeque<(PeerId, String)>>,
+    room_creators: HashMap<RoomId, PeerId>,
+    room_metadata: HashMap<RoomId, RoomMetadata>,
+    /// Tokens of the reserved slots of each `next` group, and when they expire
+    reservations: HashMap<RequestedRoom, Vec<(String, Instant)>>,
+    /// Peers waiting for a slot that isn't reserved, oldest first
+    held_peers: HashMap<RequestedRoom, Vec<PeerId>>,
+    /// When the peers of each room last sent something other than a
+    /// keep-alive, and whether they were warned that it's idle
+    room_activity: HashMap<RoomId, (Instant, bool)>,
+    queue: Option<Queue<PeerSender>>,
+    turn: Option<Turn>,
+    /// Whether peers may register with ids that aren't uuids
+    custom_peer_ids: bool,
+    cluster: Option<ClusterLink>,
+    join_hook: Option<JoinHook>,
+    /// Rooms of the peers connected to other instances of the cluster, and
+    /// the urls of those instances, see [`crate::Cluster`]
+    remote_peers: HashMap<PeerId, (RoomId, String)>,
+    /// The peers each new peer still has to be introduced to, and when the
+    /// next one is due, see [`Limits::introductions_per_sec`]
+    introductions: HashMap<PeerId, (VecDeque<PeerId>, Instant)>,
+    /// Peers waiting for the server to have space for them, oldest first
+    waiting_peers: VecDeque<WaitingPeer>,
+    next_waiting_id: usize,
+    /// The peers in each room joined with [`PeerRequest::JoinExtraRoom`],
+    /// which is unrelated to who is in the room of the same id
+    extra_rooms: HashMap<RoomId, BTreeSet<PeerId>>,
 }
 
 impl State {
-    /// Returns peers already in room
-    fn add_peer(&mut self, peer: Peer) -> Vec<PeerId> {
-        let peer_id = peer.uuid.clone();
-        let room = peer.room.clone();
-        self.clients.insert(peer.uuid.clone(), peer);
-        let peers = self.rooms.entry(room.clone()).or_default();
+    /// Notify the given webhook about room lifecycle events
+    pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
 
-        let ret = peers.iter().cloned().collect();
-        match room.next {
-            None => {
-                peers.insert(peer_id);
-                ret
-            }
-            Some(num_players) => {
-                if peers.len() == num_players - 1 {
-                    peers.clear(); // the room is complete, we can forget about it now
-                } else {
-                    peers.insert(peer_id);
-                }
-                ret
-            }
-        }
+    /// Log closed connections to the given access log
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = access_log;
+        self
     }
 
-    fn remove_peer(&mut self, peer_id: &PeerId) {
-        let peer = self
-            .clients
-            .remove(peer_id)
-            .expect("Couldn't find uuid to remove");
+    /// Enforce the given limits on all peers
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
 
-        let room_peers = self.rooms.get_mut(&peer.room);
+    /// Changes the limits, e.g. after the config was reloaded
+    ///
+    /// The message size limit only applies to new connections.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
 
-        if let Some(room_peers) = room_peers {
-            room_peers.remove(peer_id);
-        }
+    /// Lets peers join the matchmaking queue
+    pub fn with_matchmaking(mut self, matchmaking: Matchmaking) -> Self {
+        self.queue = Some(Queue::new(matchmaking));
+        self
     }
 
-    fn try_send(&self, id: &PeerId, message: Message) {
-        let peer = self.clients.get(id);
-        let peer = match peer {
-            Some(peer) => peer,
-            None => {
-                error!("Unknown peer {:?}", id);
-                return;
-            }
-        };
-        if let Err(e) = peer.sender.send(Ok(message)) {
-            error!("Error sending message {:?}", e);
-        }
+    /// Accepts peer ids that aren't uuids
+    pub fn with_custom_peer_ids(mut self) -> Self {
+        self.custom_peer_ids = true;
+        self
     }
-}
 
-fn parse_room_id(id: String) -> RoomId {
-    RoomId(id)
-}
+    /// Relays signals to and from peers connected to other instances of the
+    /// given cluster
+    pub fn with_cluster(mut self, cluster: ClusterLink) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
 
-// I don't understand warp well enough to get rid of this warning
-// This is going to be a hard error in the future, though:
-// https://doc.rust-lang.org/nightly/nightly-rustc/rustc_lint/opaque_hidden_inferred_bound/static.OPAQUE_HIDDEN_INFERRED_BOUND.html
-// The compiler hint tells us to add an impl warp::generic::Tuple bound to the
-// Extract associated type. That type is private, though... I'm hoping that in
-// the meantime someone else will run into similar issues with warp and find
-// solutions for them.
-#[allow(opaque_hidden_inferred_bound)]
-pub(crate) fn ws_filter(
-    state: Arc<Mutex<State>>,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::ws()
-        .and(warp::any())
-        .and(warp::path::param().map(parse_room_id))
-        .and(warp::query::<QueryParam>().map(parse_room_next))
-        .and(with_state(state))
-        .and_then(ws_handler)
-}
+    /// Lets the hook reject peers joining rooms
+    pub fn with_join_hook(mut self, hook: JoinHook) -> Self {
+        self.join_hook = Some(hook);
+        self
+    }
 
-fn parse_room_next(p: QueryParam) -> Option<usize> {
-    p.next
-}
+    /// The role a joining peer gets, or why the join hook rejects it
+    fn check_join(&self, request: &JoinRequest) -> Result<PeerRole, SignallingErrorCode> {
+        let role = match &self.join_hook {
+            Some(hook) => hook.check(request)?,
+            None => None,
+        };
+        let room_id = RoomId(request.room.clone());
+        Ok(role.unwrap_or_else(|| {
+            if self.clients.values().any(|peer| peer.room.id == room_id) {
+                PeerRole::Player
+            } else {
+                PeerRole::Host
+            }
+        }))
+    }
 
-fn with_state(
-    state: Arc<Mutex<State>>,
-) -> impl Filter<Extract = (Arc<Mutex<State>>,), Error = Infallible> + Clone {
-    warp::any().map(move || state.clone())
-}
+    /// Why a peer can't register with the given id, if it can't
+    fn check_peer_id(&self, id: &PeerId) -> Option<SignallingErrorCode> {
+        let malformed =
+            id.is_empty() || id.len() > MAX_PEER_ID_LEN || id.chars().any(char::is_control);
+        if malformed || !self.custom_peer_ids && uuid::Uuid::parse_str(id).is_err() {
+            Some(SignallingErrorCode::InvalidId)
+        } else if self.clients.contains_key(id) || self.remote_peers.contains_key(id) {
+            Some(SignallingErrorCode::IdTaken)
+        } else {
+            None
+        }
+    }
 
-pub(crate) async fn ws_handler(
-    ws: warp::ws::Ws,
-    room_id: RoomId,
-    next: Option<usize>,
-    state: Arc<Mutex<State>>,
-) -> std::result::Result<impl Reply, Rejection> {
-    Ok(ws.on_upgrade(move |websocket| {
-        handle_ws(websocket, state, RequestedRoom { id: room_id, next })
-    }))
-}
+    /// Hands out credentials for the given TURN servers to each peer that
+    /// joins a room
+    pub fn with_turn(mut self, turn: Turn) -> Self {
+        self.turn = Some(turn);
+        self
+    }
 
-#[derive(Debug, thiserror::Error)]
-enum RequestError {
-    #[error("Warp error")]
-    Warp(#[from] warp::Error),
-    #[error("Not text error")]
-    NotText,
-    #[error("Message is close")]
-    Close,
-    #[error("Json error")]
-    Json(#[from] serde_json::Error),
-}
+    /// TURN servers with fresh credentials for `peer`, if any are configured
+    fn ice_servers(&self, peer: &PeerId) -> Option<Vec<IceServer>> {
+        let turn = self.turn.as_ref()?;
+        Some(vec![turn_relay::ice_server(turn, peer)])
+    }
 
-fn parse_request(request: Result<Message, Error>) -> Result<PeerRequest, RequestError> {
-    let request = request?;
+    /// Applies the first matching rule to each room
+    pub fn with_room_rules(mut self, rules: Vec<RoomRule>) -> Self {
+        self.room_rules = rules;
+        self
+    }
 
-    if request.is_close() {
-        return Err(RequestError::Close);
+    /// Changes the room rules, e.g. after the config was reloaded
+    ///
+    /// Peers already in a room aren't affected.
+    pub fn set_room_rules(&mut self, rules: Vec<RoomRule>) {
+        self.room_rules = rules;
     }
 
-    if !request.is_text() {
-        return Err(RequestError::NotText);
+    /// Returns the first rule matching the room, if any
+    fn room_rule(&self, room_id: &RoomId) -> Option<&RoomRule> {
+        self.room_rules.iter().find(|rule| rule.matches(&room_id.0))
     }
 
-    let request = request.to_str().map_err(|_| RequestError::NotText)?;
+    /// Returns the policy of the first rule matching the room, if any
+    fn room_policy(&self, room_id: &RoomId) -> Option<RoomPolicy> {
+        self.room_rule(room_id).map(RoomRule::policy)
+    }
 
-    let request: PeerRequest = serde_json::from_str(request)?;
+    /// Returns the description of the room, if its creator set one
+    pub fn room_metadata(&self, room_id: &RoomId) -> Option<&RoomMetadata> {
+        self.room_metadata.get(room_id)
+    }
 
-    Ok(request)
-}
+    /// Whether a peer with the given client version may join the room, see
+    /// [`RoomRule::strict_version`] and [`RoomRule::client_versions`]
+    fn is_version_compatible(&self, room_id: &RoomId, version: Option<&String>) -> bool {
+        let rule = self.room_rule(room_id);
+        if let Some(accepted) = rule.and_then(|rule| rule.client_versions.as_ref()) {
+            let version = version.and_then(|version| semver::Version::parse(version).ok());
+            if !version.is_some_and(|version| accepted.matches(&version)) {
+                return false;
+            }
+        }
+        let strict = rule.is_some_and(|rule| rule.strict_version);
+        let required = self
+            .room_metadata(room_id)
+            .and_then(|metadata| metadata.version.as_ref());
+        match required {
+            Some(required) if strict => version == Some(required),
+            _ => true,
+        }
+    }
 
-fn spawn_sender_task(
-    sender: SplitSink<WebSocket, Message>,
-) -> mpsc::UnboundedSender<std::result::Result<Message, warp::Error>> {
-    let (client_sender, receiver) = mpsc::unbounded_channel();
-    tokio::task::spawn(UnboundedReceiverStream::new(receiver).forward(sender));
-    client_sender
-}
+    /// Replaces the description of the peer's room and sends it to everyone
+    /// in the room, if the peer created the room
+    fn set_room_metadata(&mut self, peer_id: &PeerId, metadata: RoomMetadata) {
+        let room_id = match self.clients.get(peer_id) {
+            Some(peer) => peer.room.id.clone(),
+            None => return,
+        };
+        if self.room_creators.get(&room_id) != Some(peer_id) {
+            warn!("{peer_id:?} didn't create {room_id:?}, ignoring its metadata");
+            return;
+        }
+        let event = event_message(&PeerEvent::RoomMetadata(metadata.clone()));
+        let peers: Vec<PeerId> = self
+            .clients
+            .values()
+            .filter(|peer| peer.room.id == room_id)
+            .map(|peer| peer.uuid.clone())
+            .collect();
+        for id in &peers {
+            self.try_send(id, event.clone());
+        }
+        self.room_metadata.insert(room_id, metadata);
+    }
 
-async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_room: RequestedRoom) {
-    let (ws_sender, mut ws_receiver) = websocket.split();
-    let sender = spawn_sender_task(ws_sender);
-    let mut peer_uuid = None;
+    /// Sends the description of the peer's room to it, if there is one
+    fn send_room_metadata(&self, peer_id: &PeerId) {
+        let metadata = self
+            .clients
+            .get(peer_id)
+            .and_then(|peer| self.room_metadata(&peer.room.id));
+        if let Some(metadata) = metadata {
+            let event = event_message(&PeerEvent::RoomMetadata(metadata.clone()));
+            self.try_send(peer_id, event);
+        }
+    }
 
-    while let Some(request) = ws_receiver.next().await {
-        let request = match parse_request(request) {
-            Ok(request) => request,
-            Err(RequestError::Warp(e)) => {
-                error!("Warp error while receiving request: {:?}", e);
-                // Most likely a ConnectionReset or similar.
-                // just give up on this peer.
-                break;
+    /// Returns the [listed](RoomRule::listed) rooms that have peers in them,
+    /// with their number of peers and description, sorted by id
+    pub fn room_listing(&self) -> Vec<(RoomId, usize, Option<RoomMetadata>)> {
+        let mut peers: HashMap<&RoomId, usize> = HashMap::new();
+        for peer in self.clients.values() {
+            if self
+                .room_rule(&peer.room.id)
+                .is_some_and(|rule| rule.listed)
+            {
+                *peers.entry(&peer.room.id).or_default() += 1;
             }
-            Err(RequestError::Close) => {
-                info!("Received websocket close from {peer_uuid:?}");
+        }
+        let mut rooms: Vec<_> = peers
+            .into_iter()
+            .map(|(id, peers)| (id.clone(), peers, self.room_metadata(id).cloned()))
+            .collect();
+        rooms.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        rooms
+    }
+
+    /// Returns the number of slots reserved in the waiting group of a room,
+    /// forgetting expired reservations
+    fn reserved_slots(&mut self, room: &RequestedRoom) -> usize {
+        let now = Instant::now();
+        match self.reservations.get_mut(room) {
+            Some(reservations) => {
+                reservations.retain(|(_, expires)| *expires > now);
+                reservations.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Reserves free slots of the peer's waiting group for the given tokens,
+    /// see [`Limits::reservation_secs`]
+    ///
+    /// Only peers still waiting for their group to fill up may reserve slots,
+    /// and only as many as are free. Returns the peer's room and how long the
+    /// reservations last, if any were made.
+    fn reserve_slots(
+        &mut self,
+        peer_id: &PeerId,
+        tokens: Vec<String>,
+    ) -> Option<(RequestedRoom, Duration)> {
+        let room = self.clients.get(peer_id)?.room.clone();
+        let waiting = room.next.is_some()
+            && self
+                .rooms
+                .get(&room)
+                .is_some_and(|peers| peers.contains(peer_id));
+        if !waiting {
+            warn!("{peer_id:?} isn't waiting for a group, ignoring its reservations");
+            return None;
+        }
+        let free = self.free_slots(&room);
+        let duration = Duration::from_secs(self.limits.reservation_secs);
+        let expires = Instant::now() + duration;
+        let reservations = self.reservations.entry(room.clone()).or_default();
+        let mut reserved = 0;
+        for token in tokens {
+            if reserved == free {
+                warn!("no free slots left for {peer_id:?} to reserve");
                 break;
             }
-            Err(e) => {
-                error!("Error untangling request: {:?}", e);
+            if token.is_empty() || reservations.iter().any(|(t, _)| t == &token) {
                 continue;
             }
-        };
+            reservations.push((token, expires));
+            reserved += 1;
+        }
+        (reserved > 0).then_some((room, duration))
+    }
 
-        info!("{:?} <- {:?}", peer_uuid, request);
+    /// Slots of the room's waiting group that are neither taken nor reserved,
+    /// with the group size of the room's policy, if it has one
+    fn free_slots(&mut self, room: &RequestedRoom) -> usize {
+        let policy = self.room_policy(&room.id);
+        let room = RequestedRoom {
+            id: room.id.clone(),
+            next: policy.and_then(|p| p.next).or(room.next),
+        };
+        let Some(next) = room.next else {
+            return 0;
+        };
+        let waiting = self.rooms.get(&room).map_or(0, |peers| peers.len());
+        next.saturating_sub(waiting + self.reserved_slots(&room))
+    }
 
-        match request {
-            PeerRequest::Uuid(id) => {
-                if peer_uuid.is_some() {
-                    error!("client set uuid more than once");
-                    continue;
-                }
-                peer_uuid = Some(id.clone());
+    /// Gives up a reserved slot in the room's waiting group to the peer that
+    /// claims it, returns whether the token was reserved
+    fn claim_slot(&mut self, room: &RequestedRoom, token: &str) -> bool {
+        self.reserved_slots(room);
+        let reservations = match self.reservations.get_mut(room) {
+            Some(reservations) => reservations,
+            None => return false,
+        };
+        match reservations.iter().position(|(t, _)| t == token) {
+            Some(index) => {
+                reservations.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
 
-                let mut state = state.lock().await;
-                let peers = state.add_peer(Peer {
-                    uuid: id.clone(),
-                    sender: sender.clone(),
-                    room: requested_room.clone(),
+    /// Puts a peer into the group waiting in its room, unless the free slots
+    /// of the group are all reserved for others
+    ///
+    /// Returns the peers that were already waiting, or `None` if the peer has
+    /// to wait for a slot.
+    fn fill_slot(&mut self, peer_id: &PeerId, room: &RequestedRoom) -> Option<Vec<PeerId>> {
+        let reserved = self.reserved_slots(room);
+        let peers = self.rooms.entry(room.clone()).or_default();
+        let ret = peers.iter().cloned().collect();
+        match room.next {
+            Some(num_players) if peers.len() + reserved >= num_players => return None,
+            Some(num_players) if peers.len() == num_players - 1 => {
+                peers.clear(); // the room is complete, we can forget about it now
+                self.notify(RoomEvent::RoomFull {
+                    room: room.id.0.clone(),
+                    next: num_players,
                 });
-
-                let event = Message::text(
-                    serde_json::to_string(&PeerEvent::NewPeer(id.clone()))
-                        .expect("error serializing message"),
-                );
-
-                for peer_id in peers {
-                    // Tell everyone about this new peer
-                    info!("{:?} -> {:?}", peer_id, event.to_str().unwrap());
-                    state.try_send(&peer_id, event.clone());
-                }
             }
-            PeerRequest::Signal { receiver, data } => {
-                let sender = match peer_uuid.clone() {
-                    Some(sender) => sender,
-                    None => {
-                        error!("client is trying signal before sending uuid");
-                        continue;
-                    }
-                };
-                let event = Message::text(
-                    serde_json::to_string(&PeerEvent::Signal { sender, data })
-                        .expect("error serializing message"),
-                );
-                let state = state.lock().await;
-                if let Some(peer) = state.clients.get(&receiver) {
-                    if let Err(e) = peer.sender.send(Ok(event)) {
-                        error!("error sending: {:?}", e);
-                    }
-                } else {
-                    warn!("peer not found ({receiver}), ignoring signal");
-                }
+            _ => {
+                peers.insert(peer_id.clone());
             }
-            PeerRequest::KeepAlive => {}
         }
+        Some(ret)
     }
 
-    info!("Removing peer: {:?}", peer_uuid);
-    if let Some(uuid) = peer_uuid {
-        let mut state = state.lock().await;
-        state.remove_peer(&uuid);
+    /// Moves peers held back by reservations into the room's waiting group,
+    /// as long as there are slots for them, e.g. after reservations expired
+    fn release_held_peers(&mut self, room: &RequestedRoom) {
+        while let Some(peer_id) = self
+            .held_peers
+            .get(room)
+            .and_then(|held| held.first())
+            .cloned()
+        {
+            let peers = match self.fill_slot(&peer_id, room) {
+                Some(peers) => peers,
+                None => break,
+            };
+            if let Some(held) = self.held_peers.get_mut(room) {
+                held.remove(0);
+            }
+            self.announce_peer(&peer_id, &peers);
+        }
+        if self.held_peers.get(room).is_some_and(Vec::is_empty) {
+            self.held_peers.remove(room);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Adds a connection to the matchmaking queue and tells it which regions
+    /// to report its latency to, returns an id to refer to it with
+    ///
+    /// Returns `None` if matchmaking is disabled.
+    fn join_queue(&mut self, sender: PeerSender) -> Option<usize> {
+        let queue = self.queue.as_mut()?;
+        let event = event_message(&PeerEvent::MatchmakingRegions(queue.regions().to_vec()));
+        let _ = sender.send(Ok(event));
+        Some(queue.join(sender))
+    }
 
-    use std::time::Duration;
+    fn leave_queue(&mut self, id: usize) {
+        if let Some(queue) = &mut self.queue {
+            queue.leave(id);
+        }
+    }
 
-    use futures::pin_mut;
-    use tokio::{select, time};
-    use warp::{test::WsClient, ws::Message, Filter, Rejection, Reply};
+    /// Updates the latencies of a queued peer, and tells the peers that could
+    /// be matched now about their rooms
+    fn report_latency(&mut self, id: usize, latencies: HashMap<String, u64>) {
+        let queue = match &mut self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        queue.report(id, latencies);
+        for group in queue.match_peers() {
+            let room = format!("match-{}", uuid::Uuid::new_v4());
+            info!("matched {} peers into {room:?}", group.len());
+            let event = event_message(&PeerEvent::MatchFound { room });
+            for peer in group {
+                let _ = peer.sender.send(Ok(event.clone()));
+            }
+        }
+    }
 
-    use crate::signaling::{parse_room_id, parse_room_next, PeerEvent, QueryParam, RoomId};
+    /// Whether the room has reached its [`RoomPolicy::max_peers`]
+    fn is_room_full(&self, room_id: &RoomId, max_peers: Option<usize>) -> bool {
+        max_peers.is_some_and(|max_peers| {
+            let peers = self.clients.values().filter(|p| &p.room.id == room_id);
+            peers.count() >= max_peers
+        })
+    }
 
-    // warning: See comment for ws_filter
-    #[allow(opaque_hidden_inferred_bound)]
-    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        super::ws_filter(Default::default())
+    fn notify(&self, event: RoomEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event);
+        }
     }
 
-    #[tokio::test]
-    async fn ws_connect() {
-        let _ = pretty_env_logger::try_init();
-        let api = api();
+    /// Returns stats for all rooms that have been used
+    pub fn stats(&self) -> impl Iterator<Item = (&RoomId, &RoomStats)> {
+        self.stats.iter()
+    }
 
-        warp::test::ws()
-            .path("/room_a")
-            .handshake(api)
-            .await
-            .expect("handshake");
+    /// Returns stats for the given room, if it has been used
+    pub fn room_stats(&self, room_id: &RoomId) -> Option<&RoomStats> {
+        self.stats.get(room_id)
     }
 
-    #[tokio::test]
-    async fn new_peer() {
-        let _ = pretty_env_logger::try_init();
+    fn room_stats_mut(&mut self, room_id: &RoomId) -> &mut RoomStats {
+        let stats = self.stats.entry(room_id.clone()).or_default();
+        stats.touch();
+        stats
+    }
+
+    /// Drops the stats of empty rooms that weren't used in a while, so
+    /// peers can't grow them without bound by joining ever new rooms
+    fn prune_stats(&mut self) {
+        let occupied: HashSet<&RoomId> = self.clients.values().map(|peer| &peer.room.id).collect();
+        let mut empty: Vec<(Instant, RoomId)> = self
+            .stats
+            .iter()
+            .filter(|(id, _)| !occupied.contains(id))
+            .filter_map(|(id, stats)| Some((stats.last_active()?, id.clone())))
+            .collect();
+        empty.sort_by_key(|(last_active, _)| *last_active);
+        let excess = empty.len().saturating_sub(MAX_EMPTY_ROOM_STATS);
+        for (i, (last_active, id)) in empty.into_iter().enumerate() {
+            if i < excess || last_active.elapsed() > STATS_RETENTION {
+                self.stats.remove(&id);
+            }
+        }
+    }
+
+    fn record_peer_count(&mut self, room_id: &RoomId) {
+        let peers = self
+            .clients
+            .values()
+            .filter(|peer| &peer.room.id == room_id)
+            .count();
+        self.room_stats_mut(room_id).record_peer_count(peers);
+        self.update_observers(room_id);
+        if peers == 0 {
+            // nobody is left to care about what happened in the room
+            self.event_logs.remove(room_id);
+            self.room_creators.remove(room_id);
+            self.room_metadata.remove(room_id);
+            self.prune_stats();
+        }
+    }
+
+    /// Sends a message to everyone else in the sender's room, and adds it to
+    /// the room's event log, see [`RoomRule::event_log`]
+    fn relay_room_message(&mut self, sender: &PeerId, data: String) {
+        let room_id = match self.clients.get(sender) {
+            Some(peer) => peer.room.id.clone(),
+            None => return,
+        };
+        let event = event_message(&PeerEvent::RoomMessage {
+            sender: sender.clone(),
+            data: data.clone(),
+        });
+        for peer in self.room_peers(&room_id) {
+            if &peer != sender {
+                self.try_send(&peer, event.clone());
+            }
+        }
+        self.room_stats_mut(&room_id).record_relay();
+
+        let max_events = self
+            .room_rule(&room_id)
+            .and_then(|rule| rule.event_log)
+            .unwrap_or(0);
+        if max_events > 0 {
+            let log = self.event_logs.entry(room_id).or_default();
+            log.push_back((sender.clone(), data));
+            while log.len() > max_events {
+                log.pop_front();
+            }
+        }
+    }
+
+    /// Sends the messages in the event log of the peer's room to it, oldest
+    /// first
+    fn replay_event_log(&self, peer_id: &PeerId) {
+        let log = self
+            .clients
+            .get(peer_id)
+            .and_then(|peer| self.event_logs.get(&peer.room.id));
+        for (sender, data) in log.into_iter().flatten() {
+            let event = PeerEvent::RoomMessage {
+                sender: sender.clone(),
+                data: data.clone(),
+            };
+            self.try_send(peer_id, event_message(&event));
+        }
+    }
+
+    /// Starts sending the peers in the observer's room to it, returns an id
+    /// to remove it with
+    fn add_observer(&mut self, observer: Observer) -> usize {
+        let id = self.next_observer_id;
+        self.next_observer_id += 1;
+        for event in self.room_peer_list(&observer.room) {
+            let _ = observer.sender.send(Ok(event));
+        }
+        self.observers.insert(id, observer);
+        id
+    }
+
+    fn remove_observer(&mut self, id: usize) {
+        self.observers.remove(&id);
+    }
+
+    /// Whether another peer may join the room without exceeding
+    /// [`Limits::max_peers`] or [`Limits::max_rooms`]
+    fn has_capacity(&self, room_id: &RoomId) -> bool {
+        let limits = &self.limits;
+        if limits.max_peers > 0 && self.clients.len() >= limits.max_peers {
+            return false;
+        }
+        if limits.max_rooms == 0 {
+            return true;
+        }
+        let rooms: HashSet<&RoomId> = self.clients.values().map(|peer| &peer.room.id).collect();
+        rooms.contains(room_id) || rooms.len() < limits.max_rooms
+    }
+
+    /// Queues a peer until the server has space for it in the room, at the
+    /// front if it was let in before but lost its space to someone else
+    ///
+    /// Returns its id in the queue, and a receiver resolving once it's let in.
+    fn join_waiting_peers(
+        &mut self,
+        room: &RoomId,
+        sender: PeerSender,
+        front: bool,
+    ) -> (usize, oneshot::Receiver<()>) {
+        let id = self.next_waiting_id;
+        self.next_waiting_id += 1;
+        let (admit, admitted) = oneshot::channel();
+        let waiting = WaitingPeer {
+            id,
+            room: room.clone(),
+            sender,
+            admit,
+        };
+        // only it and the peers behind it have new positions
+        let position = if front {
+            self.waiting_peers.push_front(waiting);
+            0
+        } else {
+            self.waiting_peers.push_back(waiting);
+            self.waiting_peers.len() - 1
+        };
+        info!(
+            "the server is at capacity, {} peers are waiting",
+            self.waiting_peers.len()
+        );
+        self.send_queue_positions(position);
+        (id, admitted)
+    }
+
+    /// Removes a peer that disconnected while waiting
+    fn leave_waiting_peers(&mut self, id: usize) {
+        if let Some(i) = self.waiting_peers.iter().position(|w| w.id == id) {
+            self.waiting_peers.remove(i);
+            self.send_queue_positions(i);
+        }
+    }
+
+    /// Lets in the longest waiting peer the server has space for, if any
+    ///
+    /// Called whenever a peer leaves, and whenever a peer joins, since the
+    /// room it created may have space for more of the waiting peers.
+    fn admit_waiting_peer(&mut self) {
+        while let Some(i) = self
+            .waiting_peers
+            .iter()
+            .position(|waiting| self.has_capacity(&waiting.room))
+        {
+            let waiting = self.waiting_peers.remove(i).expect("position is in range");
+            let _ = waiting
+                .sender
+                .send(Ok(event_message(&PeerEvent::ServerQueuePosition(0))));
+            let admitted = waiting.admit.send(()).is_ok();
+            self.send_queue_positions(i);
+            if admitted {
+                return;
+            }
+            // it disconnected in the meantime
+        }
+    }
+
+    /// Tells the waiting peers from the given index on their new positions
+    fn send_queue_positions(&self, from: usize) {
+        for (i, waiting) in self.waiting_peers.iter().enumerate().skip(from) {
+            let event = event_message(&PeerEvent::ServerQueuePosition(i + 1));
+            let _ = waiting.sender.send(Ok(event));
+        }
+    }
+
+    /// Returns the ids of the peers in a room, regardless of their `next`
+    fn room_peers(&self, room_id: &RoomId) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self
+            .clients
+            .values()
+            .filter(|peer| &peer.room.id == room_id)
+            .map(|peer| peer.uuid.clone())
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Sends the current peers of the room to everyone observing it
+    fn update_observers(&self, room_id: &RoomId) {
+        let mut observers = self
+            .observers
+            .values()
+            .filter(|observer| &observer.room == room_id)
+            .peekable();
+        if observers.peek().is_none() {
+            return;
+        }
+        let events = self.room_peer_list(room_id);
+        for observer in observers {
+            for event in &events {
+                if let Err(e) = observer.sender.send(Ok(event.clone())) {
+                    error!("Error sending message {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// The messages telling an observer who is in the room, paged by
+    /// [`Limits::peer_list_page_size`]
+    fn room_peer_list(&self, room_id: &RoomId) -> Vec<Message> {
+        let peers = self.room_peers(room_id);
+        let page_size = self.limits.peer_list_page_size;
+        if page_size == 0 || peers.len() <= page_size {
+            return vec![event_message(&PeerEvent::RoomPeers(peers))];
+        }
+        peers
+            .chunks(page_size)
+            .map(|page| event_message(&PeerEvent::RoomPeersPage(page.to_vec())))
+            .chain(std::iter::once(event_message(&PeerEvent::PeerListComplete)))
+            .collect()
+    }
+
+    /// Logs a closed connection and adds it to the stats of its room
+    fn record_connection(&mut self, entry: &AccessLogEntry) {
+        self.access_log.log(entry);
+        self.room_stats_mut(entry.room)
+            .record_connection(entry.duration);
+    }
+
+    /// Records that a peer of the room was disconnected for exceeding
+    /// [`Limits::max_queued_messages`]
+    fn record_queue_overflow(&mut self, peer_id: &PeerId) {
+        if let Some(room_id) = self.clients.get(peer_id).map(|p| p.room.id.clone()) {
+            self.room_stats_mut(&room_id).record_queue_overflow();
+        }
+    }
+
+    /// Records an error attributed to the room the given peer is in
+    fn record_error(&mut self, peer_id: &PeerId) {
+        if let Some(room_id) = self.clients.get(peer_id).map(|p| p.room.id.clone()) {
+            self.room_stats_mut(&room_id).record_error();
+        }
+    }
+
+    /// Returns all connected peers
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.clients.values()
+    }
+
+    /// Counts a request of the peer as activity in its room, see
+    /// [`Limits::room_idle_secs`]
+    fn record_activity(&mut self, peer_id: &PeerId) {
+        if let Some(room) = self.peer_room(peer_id) {
+            self.room_activity
+                .insert(room.id.clone(), (Instant::now(), false));
+        }
+    }
+
+    /// Warns the peers of rooms that are about to be closed for being idle,
+    /// and closes the rooms that were idle for too long, as of `now`
+    fn close_idle_rooms(&mut self, now: Instant) {
+        if self.limits.room_idle_secs == 0 {
+            return;
+        }
+        let timeout = Duration::from_secs(self.limits.room_idle_secs);
+        let warning = Duration::from_secs(self.limits.room_idle_warning_secs);
+        let occupied: HashSet<RoomId> = self
+            .clients
+            .values()
+            .map(|peer| peer.room.id.clone())
+            .collect();
+        self.room_activity
+            .retain(|room_id, _| occupied.contains(room_id));
+
+        let mut idle = vec![];
+        let mut to_warn = vec![];
+        for (room_id, (last_active, warned)) in &mut self.room_activity {
+            let idle_for = now.saturating_duration_since(*last_active);
+            if idle_for >= timeout {
+                idle.push(room_id.clone());
+            } else if !*warned && idle_for + warning >= timeout {
+                *warned = true;
+                to_warn.push((room_id.clone(), timeout - idle_for));
+            }
+        }
+        for (room_id, closes_in) in to_warn {
+            info!("Warning the peers of {room_id:?}, it's idle");
+            let event = event_message(&PeerEvent::RoomIdle {
+                closes_in_secs: closes_in.as_secs_f64().ceil() as u64,
+            });
+            for peer_id in self.room_peers(&room_id) {
+                self.try_send(&peer_id, event.clone());
+            }
+        }
+        for room_id in idle {
+            info!("{room_id:?} was idle for too long");
+            self.room_activity.remove(&room_id);
+            self.close_room(&room_id, None);
+        }
+    }
+
+    /// Disconnects the peer with [`SignallingErrorCode::Kicked`]
+    ///
+    /// Returns false if the peer isn't connected.
+    pub fn kick(&mut self, peer_id: &PeerId) -> bool {
+        if !self.clients.contains_key(peer_id) {
+            return false;
+        }
+        info!("Kicking peer {peer_id:?}");
+        self.disconnect(peer_id, SignallingErrorCode::Kicked);
+        true
+    }
+
+    /// Ends the match in a room, telling everyone in it who closed it and
+    /// closing their connections
+    ///
+    /// With a `host`, only closes the room if the host created it. Returns
+    /// false if the room wasn't closed.
+    pub fn close_room(&mut self, room_id: &RoomId, host: Option<&PeerId>) -> bool {
+        if let Some(host) = host {
+            if self.room_creators.get(room_id) != Some(host) {
+                warn!("{host:?} didn't create {room_id:?}, not closing it");
+                return false;
+            }
+        }
+        let peers = self.room_peers(room_id);
+        if peers.is_empty() {
+            return false;
+        }
+        info!("Closing room {room_id:?}");
+        let event = event_message(&PeerEvent::RoomClosed {
+            host: host.cloned(),
+        });
+        for peer_id in &peers {
+            self.try_send(peer_id, event.clone());
+            self.try_send(peer_id, Message::close());
+        }
+        true
+    }
+
+    /// Tells the peer why it's being disconnected, and closes the connection
+    fn disconnect(&self, peer_id: &PeerId, code: SignallingErrorCode) {
+        for message in error_messages(code) {
+            self.try_send(peer_id, message);
+        }
+    }
+
+    /// Bans the target for the given duration, kicking any matching peers
+    ///
+    /// Returns false without banning if the duration is too long to
+    /// represent.
+    pub fn ban(&mut self, target: BanTarget, duration: Duration) -> bool {
+        let Some(expires) = Instant::now().checked_add(duration) else {
+            warn!("Not banning {target:?}, {duration:?} is too long");
+            return false;
+        };
+        info!("Banning {target:?} for {duration:?}");
+        let kicked: Vec<PeerId> = self
+            .clients
+            .values()
+            .filter(|peer| match &target {
+                BanTarget::Ip(ip) => peer.addr.map(|addr| addr.ip()) == Some(*ip),
+                BanTarget::Peer(id) => &peer.uuid == id,
+            })
+            .map(|peer| peer.uuid.clone())
+            .collect();
+        for peer_id in kicked {
+            self.kick(&peer_id);
+        }
+        self.bans.insert(target, expires);
+        true
+    }
+
+    /// Returns true if the target is currently banned
+    pub fn is_banned(&mut self, target: &BanTarget) -> bool {
+        let now = Instant::now();
+        self.bans.retain(|_, expires| *expires > now);
+        self.bans.contains_key(target)
+    }
+
+    /// Returns a sanitized version of the name that no other peer in the room uses
+    fn unique_name(&self, room: &RequestedRoom, name: &str) -> Option<String> {
+        let name = sanitize_name(name)?;
+        let taken: HashSet<&str> = self
+            .clients
+            .values()
+            .filter(|peer| &peer.room == room)
+            .filter_map(|peer| peer.name.as_deref())
+            .collect();
+        if !taken.contains(name.as_str()) {
+            return Some(name);
+        }
+        (2..)
+            .map(|i| format!("{name} ({i})"))
+            .find(|candidate| !taken.contains(candidate.as_str()))
+    }
+
+    /// Tells the given peers about a new peer, and the new peer about their names
+    ///
+    /// With [`Limits::introductions_per_sec`], only the first peer is told
+    /// right away, the others by [`State::pace_introductions`].
+    fn announce_peer(&mut self, id: &PeerId, peers: &[PeerId]) {
+        for peer_id in peers {
+            for details in self.peer_details(peer_id) {
+                self.try_send(id, details);
+            }
+        }
+
+        let paced = match peers.split_first() {
+            Some((first, rest)) if self.limits.introductions_per_sec > 0 && !rest.is_empty() => {
+                self.introduce(id, first);
+                rest
+            }
+            _ => {
+                for peer_id in peers {
+                    self.introduce(id, peer_id);
+                }
+                return;
+            }
+        };
+        let next_at = Instant::now() + Duration::from_secs(1) / self.limits.introductions_per_sec;
+        self.introductions
+            .insert(id.clone(), (paced.iter().cloned().collect(), next_at));
+        let event = event_message(&PeerEvent::IntroductionsPending(paced.len()));
+        self.try_send(id, event);
+    }
+
+    /// Tells a peer about a new peer
+    fn introduce(&self, id: &PeerId, peer_id: &PeerId) {
+        for details in self.peer_details(id) {
+            self.try_send(peer_id, details);
+        }
+        let event = event_message(&PeerEvent::NewPeer(id.clone()));
+        info!("{:?} -> {:?}", peer_id, event.to_str().unwrap());
+        self.try_send(peer_id, event);
+    }
+
+    /// Sends the introductions that are due, and tells the new peers how many
+    /// are left, see [`Limits::introductions_per_sec`]
+    ///
+    /// Sends all of them if pacing was turned off in the meantime.
+    fn pace_introductions(&mut self, now: Instant) {
+        let interval = Some(self.limits.introductions_per_sec)
+            .filter(|&rate| rate > 0)
+            .map(|rate| Duration::from_secs(1) / rate);
+        let mut introductions = std::mem::take(&mut self.introductions);
+        for (id, (peers, next_at)) in &mut introductions {
+            let pending = peers.len();
+            while interval.is_none() || *next_at <= now {
+                let Some(peer_id) = peers.pop_front() else {
+                    break;
+                };
+                // either peer may have left the room in the meantime
+                let room = |peer: &PeerId| self.clients.get(peer).map(|peer| &peer.room);
+                if room(id).is_some() && room(id) == room(&peer_id) {
+                    self.introduce(id, &peer_id);
+                }
+                *next_at += interval.unwrap_or_default();
+            }
+            if peers.len() != pending {
+                let event = event_message(&PeerEvent::IntroductionsPending(peers.len()));
+                self.try_send(id, event);
+            }
+        }
+        introductions.retain(|_, (peers, _)| !peers.is_empty());
+        self.introductions = introductions;
+    }
+
+    /// Returns the events describing a peer to others, i.e. its name and
+    /// capabilities if it has any
+    fn peer_details(&self, id: &PeerId) -> Vec<Message> {
+        let peer = &self.clients[id];
+        let mut events = vec![event_message(&PeerEvent::PeerRole {
+            peer: id.clone(),
+            role: peer.role,
+        })];
+        if let Some(name) = peer.name.clone() {
+            let peer = id.clone();
+            events.push(event_message(&PeerEvent::PeerName { peer, name }));
+        }
+        if !peer.capabilities.is_empty() {
+            events.push(event_message(&PeerEvent::PeerCapabilities {
+                peer: id.clone(),
+                capabilities: peer.capabilities.clone(),
+            }));
+        }
+        events
+    }
+
+    /// Moves every peer in the given peer's room to another room, if the peer
+    /// created its room
+    ///
+    /// The migrated peers stay connected to each other, and are introduced to
+    /// any peers already waiting in the target room. Each of them has to pass
+    /// the same checks as when joining the target room, otherwise nobody is
+    /// moved. Returns the migrated peers.
+    fn migrate_room(
+        &mut self,
+        peer_id: &PeerId,
+        to: RequestedRoom,
+    ) -> Result<Vec<PeerId>, SignallingErrorCode> {
+        let from = match self.clients.get(peer_id) {
+            Some(peer) => peer.room.clone(),
+            None => return Ok(vec![]),
+        };
+        if from == to {
+            return Ok(vec![]);
+        }
+        if self.room_creators.get(&from.id) != Some(peer_id) {
+            warn!("{peer_id:?} didn't create {:?}, not migrating it", from.id);
+            return Err(SignallingErrorCode::Unauthorized);
+        }
+
+        let group: Vec<PeerId> = self
+            .clients
+            .values()
+            .filter(|peer| peer.room == from)
+            .map(|peer| peer.uuid.clone())
+            .collect();
+        self.check_migration(&group, &to.id)?;
+        info!("Migrating room {from:?} to {to:?}");
+
+        if !self.clients.values().any(|peer| peer.room.id == to.id) {
+            self.room_creators.insert(to.id.clone(), peer_id.clone());
+        }
+        for id in &group {
+            self.clients.get_mut(id).expect("peer in group").room = to.clone();
+            self.leave_cluster(id, &from);
+            self.announce_to_cluster(id, &to);
+        }
+        self.room_activity
+            .insert(to.id.clone(), (Instant::now(), false));
+        if let Some(from_peers) = self.rooms.get_mut(&from) {
+            from_peers.retain(|id| !group.contains(id));
+            if from_peers.is_empty() {
+                self.reservations.remove(&from);
+            }
+        }
+        self.release_held_peers(&from);
+        self.record_peer_count(&from.id);
+        self.record_peer_count(&to.id);
+
+        let waiting: Vec<PeerId> = self
+            .rooms
+            .get(&to)
+            .map(|peers| peers.iter().cloned().collect())
+            .unwrap_or_default();
+        for id in &group {
+            self.announce_peer(id, &waiting);
+        }
+
+        let to_peers = self.rooms.entry(to.clone()).or_default();
+        match to.next {
+            Some(num_players) if waiting.len() + group.len() >= num_players => {
+                to_peers.clear(); // the room is complete
+                self.reservations.remove(&to);
+                self.notify(RoomEvent::RoomFull {
+                    room: to.id.0.clone(),
+                    next: num_players,
+                });
+            }
+            _ => to_peers.extend(group.iter().cloned()),
+        }
+
+        let event = event_message(&PeerEvent::RoomMigrated {
+            room: to.id.0.clone(),
+            next: to.next,
+        });
+        let policy = self.room_policy(&to.id);
+        for id in &group {
+            self.try_send(id, event.clone());
+            if let Some(policy) = policy {
+                self.try_send(id, event_message(&PeerEvent::RoomPolicy(policy)));
+            }
+            self.send_room_metadata(id);
+        }
+        Ok(group)
+    }
+
+    /// Runs the checks peers pass when joining a room on everyone in the
+    /// group, see [`State::migrate_room`]
+    fn check_migration(
+        &mut self,
+        group: &[PeerId],
+        room_id: &RoomId,
+    ) -> Result<(), SignallingErrorCode> {
+        let max_peers = self
+            .room_policy(room_id)
+            .and_then(|policy| policy.max_peers);
+        let joined = self
+            .clients
+            .values()
+            .filter(|peer| &peer.room.id == room_id)
+            .count();
+        if max_peers.is_some_and(|max_peers| joined + group.len() > max_peers) {
+            warn!("Not migrating {group:?}, {room_id:?} is full");
+            return Err(SignallingErrorCode::RoomFull);
+        }
+        for id in group {
+            let (addr, version) = match self.clients.get(id) {
+                Some(peer) => (peer.addr, peer.version.clone()),
+                None => continue,
+            };
+            let banned = self.is_banned(&BanTarget::Peer(id.clone()))
+                || addr.is_some_and(|addr| self.is_banned(&BanTarget::Ip(addr.ip())));
+            if banned {
+                warn!("Not migrating {group:?}, {id:?} is banned");
+                return Err(SignallingErrorCode::Banned);
+            }
+            if !self.is_version_compatible(room_id, version.as_ref()) {
+                warn!("Not migrating {group:?}, the version of {id:?} is {version:?}");
+                return Err(SignallingErrorCode::VersionMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the room the peer is in, which changes when the room is
+    /// migrated
+    fn peer_room(&self, peer_id: &PeerId) -> Option<&RequestedRoom> {
+        self.clients.get(peer_id).map(|peer| &peer.room)
+    }
+
+    /// Returns peers already in room
+    fn add_peer(&mut self, peer: Peer) -> Vec<PeerId> {
+        let peer_id = peer.uuid.clone();
+        let room = peer.room.clone();
+        if !self.clients.values().any(|peer| peer.room.id == room.id) {
+            self.room_creators.insert(room.id.clone(), peer_id.clone());
+        }
+        self.clients.insert(peer.uuid.clone(), peer);
+        self.record_peer_count(&room.id);
+        self.room_activity
+            .insert(room.id.clone(), (Instant::now(), false));
+        self.announce_to_cluster(&peer_id, &room);
+
+        let mut events = vec![];
+        if !self.rooms.contains_key(&room) {
+            events.push(RoomEvent::RoomCreated {
+                room: room.id.0.clone(),
+                next: room.next,
+                params: self.clients[&peer_id].params.clone(),
+            });
+        }
+        let peers = self.rooms.entry(room.clone()).or_default();
+        if peers.is_empty() {
+            events.push(RoomEvent::FirstPeerJoined {
+                room: room.id.0.clone(),
+                next: room.next,
+                peer: peer_id.clone(),
+                params: self.clients[&peer_id].params.clone(),
+            });
+        }
+        for event in events {
+            self.notify(event);
+        }
+
+        match self.fill_slot(&peer_id, &room) {
+            Some(peers) => {
+                // the held peers may make up the next group
+                self.release_held_peers(&room);
+                peers
+            }
+            None => {
+                info!("holding {peer_id:?} back, the free slots of {room:?} are reserved");
+                self.held_peers.entry(room).or_default().push(peer_id);
+                vec![]
+            }
+        }
+    }
+
+    fn remove_peer(&mut self, peer_id: &PeerId) {
+        let Some(peer) = self.clients.remove(peer_id) else {
+            warn!("{peer_id:?} was already removed");
+            return;
+        };
+        self.leave_cluster(peer_id, &peer.room);
+        self.introductions.remove(peer_id);
+        self.forget_relays_to(peer_id, &peer.room.id);
+
+        let room_peers = self.rooms.get_mut(&peer.room);
+
+        if let Some(room_peers) = room_peers {
+            if room_peers.remove(peer_id) && room_peers.is_empty() {
+                // nobody is left to wait for the reserved slots
+                self.reservations.remove(&peer.room);
+                self.notify(RoomEvent::RoomEmptied {
+                    room: peer.room.id.0.clone(),
+                    next: peer.room.next,
+                });
+            }
+        }
+        if let Some(held) = self.held_peers.get_mut(&peer.room) {
+            held.retain(|id| id != peer_id);
+        }
+        self.release_held_peers(&peer.room);
+
+        self.record_peer_count(&peer.room.id);
+        self.leave_extra_rooms(peer_id);
+        self.admit_waiting_peer();
+    }
+
+    /// Drops what the peers of the room remember about their signals to a
+    /// peer that left, so long-lived peers in busy rooms don't accumulate
+    /// counters for peers that are long gone
+    ///
+    /// A peer rejoining with the same id starts with fresh limits.
+    fn forget_relays_to(&mut self, peer_id: &PeerId, room_id: &RoomId) {
+        let mut pruned = 0;
+        for peer in self.clients.values_mut().filter(|p| &p.room.id == room_id) {
+            pruned += usize::from(peer.signals_sent.remove(peer_id).is_some());
+            peer.candidates_sent.remove(peer_id);
+        }
+        if pruned > 0 {
+            self.room_stats_mut(room_id).record_pruned_relays(pruned);
+        }
+    }
+
+    /// Adds a peer to a room besides its own, and tells everyone in it
+    fn join_extra_room(&mut self, peer_id: &PeerId, room: RoomId) {
+        let joined = self
+            .extra_rooms
+            .values()
+            .filter(|peers| peers.contains(peer_id))
+            .count();
+        if joined >= MAX_EXTRA_ROOMS {
+            warn!("{peer_id:?} is in too many extra rooms, not joining {room:?}");
+            return;
+        }
+        let peers = self.extra_rooms.entry(room.clone()).or_default();
+        if peers.insert(peer_id.clone()) {
+            self.send_extra_room_peers(&room);
+        }
+    }
+
+    /// Removes a peer from a room joined with [`PeerRequest::JoinExtraRoom`],
+    /// and tells everyone left in it
+    fn leave_extra_room(&mut self, peer_id: &PeerId, room: &RoomId) {
+        let Some(peers) = self.extra_rooms.get_mut(room) else {
+            return;
+        };
+        if !peers.remove(peer_id) {
+            return;
+        }
+        if peers.is_empty() {
+            self.extra_rooms.remove(room);
+        } else {
+            self.send_extra_room_peers(room);
+        }
+    }
+
+    fn leave_extra_rooms(&mut self, peer_id: &PeerId) {
+        let rooms: Vec<RoomId> = self
+            .extra_rooms
+            .iter()
+            .filter(|(_, peers)| peers.contains(peer_id))
+            .map(|(room, _)| room.clone())
+            .collect();
+        for room in rooms {
+            self.leave_extra_room(peer_id, &room);
+        }
+    }
+
+    fn send_extra_room_peers(&self, room: &RoomId) {
+        let peers = &self.extra_rooms[room];
+        let event = event_message(&PeerEvent::ExtraRoomPeers {
+            room: room.0.clone(),
+            peers: peers.iter().cloned().collect(),
+        });
+        for peer in peers {
+            self.try_send(peer, event.clone());
+        }
+    }
+
+    /// Sends a message to everyone else in a room the sender joined with
+    /// [`PeerRequest::JoinExtraRoom`]
+    fn relay_extra_room_message(&self, sender: &PeerId, room: RoomId, data: String) {
+        let Some(peers) = self.extra_rooms.get(&room).filter(|p| p.contains(sender)) else {
+            warn!("{sender:?} sent a message to {room:?} without joining it");
+            return;
+        };
+        let event = event_message(&PeerEvent::ExtraRoomMessage {
+            room: room.0,
+            sender: sender.clone(),
+            data,
+        });
+        for peer in peers.iter().filter(|peer| *peer != sender) {
+            self.try_send(peer, event.clone());
+        }
+    }
+
+    /// Relays a signal to the receiver, keeping track of room stats
+    ///
+    /// Signals to peers connected to other instances of the cluster are
+    /// forwarded to those.
+    fn relay_signal(&mut self, sender: &PeerId, receiver: &PeerId, data: serde_json::Value) {
+        if let Some(peer) = self.clients.get_mut(sender) {
+            let signals_sent = peer.signals_sent.entry(receiver.clone()).or_default();
+            *signals_sent += 1;
+            if *signals_sent > self.limits.max_signals_per_peer {
+                warn!("{sender:?} sent too many signals to {receiver:?}, disconnecting");
+                self.record_error(sender);
+                self.disconnect(sender, SignallingErrorCode::RateLimited);
+                return;
+            }
+        }
+        if self.throttle_signal(sender, receiver, &data) {
+            return;
+        }
+
+        let room_id = match self.clients.get_mut(receiver) {
+            Some(peer) => {
+                let room_id = peer.room.id.clone();
+                if !peer.signalled {
+                    peer.signalled = true;
+                    let elapsed = peer.joined_at.elapsed();
+                    self.room_stats_mut(&room_id).record_first_signal(elapsed);
+                }
+                room_id
+            }
+            None => {
+                match (&self.cluster, self.remote_peers.get(receiver)) {
+                    (Some(cluster), Some((_, instance))) => cluster.send(
+                        instance,
+                        ClusterMessage::Signal {
+                            instance: cluster.url().to_string(),
+                            sender: sender.clone(),
+                            receiver: receiver.clone(),
+                            data,
+                        },
+                    ),
+                    _ => {
+                        warn!("peer not found ({receiver}), ignoring signal");
+                        self.record_error(sender);
+                    }
+                }
+                return;
+            }
+        };
+
+        let message = event_message(&PeerEvent::Signal {
+            sender: sender.clone(),
+            data,
+        });
+        let peer = &self.clients[receiver];
+        if let Err(e) = peer.sender.send(Ok(message)) {
+            error!("error sending: {:?}", e);
+            self.room_stats_mut(&room_id).record_error();
+        } else {
+            self.room_stats_mut(&room_id).record_relay();
+        }
+    }
+
+    /// Returns whether to drop a signal for exceeding
+    /// [`Limits::max_candidates_per_peer`] or [`Limits::max_offers_per_minute`]
+    ///
+    /// The sender is warned with [`PeerEvent::SignalsThrottled`] when its
+    /// candidates start being dropped, and for every dropped offer.
+    fn throttle_signal(
+        &mut self,
+        sender: &PeerId,
+        receiver: &PeerId,
+        data: &serde_json::Value,
+    ) -> bool {
+        let Some(peer) = self.clients.get_mut(sender) else {
+            return false;
+        };
+        // the signal is e.g. `{"IceCandidate": "..."}` for matchbox_socket
+        let kind = data.as_object().and_then(|signal| signal.keys().next());
+        let warn = match kind.map(String::as_str) {
+            Some("IceCandidate") if self.limits.max_candidates_per_peer > 0 => {
+                let sent = peer.candidates_sent.entry(receiver.clone()).or_default();
+                *sent += 1;
+                if *sent <= self.limits.max_candidates_per_peer {
+                    return false;
+                }
+                *sent == self.limits.max_candidates_per_peer + 1
+            }
+            Some("Offer") if self.limits.max_offers_per_minute > 0 => {
+                let now = Instant::now();
+                while let Some(sent_at) = peer.offers_sent.front() {
+                    if now.duration_since(*sent_at) < Duration::from_secs(60) {
+                        break;
+                    }
+                    peer.offers_sent.pop_front();
+                }
+                if peer.offers_sent.len() < self.limits.max_offers_per_minute {
+                    peer.offers_sent.push_back(now);
+                    return false;
+                }
+                true
+            }
+            _ => return false,
+        };
+        if warn {
+            warn!("throttling signals of {sender:?} to {receiver:?}");
+            let event = event_message(&PeerEvent::SignalsThrottled {
+                receiver: receiver.clone(),
+            });
+            self.try_send(sender, event);
+        }
+        true
+    }
+
+    /// Tells the other instances of the cluster that a peer joined a room
+    /// spanning instances, and the peer about the peers it has to connect to
+    /// on other instances, see [`State::announce_remote_peer`]
+    fn announce_to_cluster(&self, peer_id: &PeerId, room: &RequestedRoom) {
+        let Some(cluster) = self.cluster.as_ref().filter(|_| room.next.is_none()) else {
+            return;
+        };
+        cluster.broadcast(ClusterMessage::Joined {
+            instance: cluster.url().to_string(),
+            room: room.id.0.clone(),
+            peer: peer_id.clone(),
+        });
+        for (remote, (remote_room, _)) in &self.remote_peers {
+            if remote_room == &room.id && peer_id < remote {
+                self.try_send(peer_id, event_message(&PeerEvent::NewPeer(remote.clone())));
+            }
+        }
+    }
+
+    /// Tells local peers in the room of a peer on another instance to connect
+    /// to it
+    ///
+    /// Either instance may hear about the other's peer last, so neither side
+    /// can tell which peer is the newer one. Instead, of two peers on
+    /// different instances, the one with the smaller id is told about the
+    /// other one and makes the offer.
+    fn announce_remote_peer(&self, remote: &PeerId, room: &RequestedRoom) {
+        let event = event_message(&PeerEvent::NewPeer(remote.clone()));
+        for peer_id in self.rooms.get(room).into_iter().flatten() {
+            if peer_id < remote {
+                self.try_send(peer_id, event.clone());
+            }
+        }
+    }
+
+    /// Tells the other instances of the cluster that a peer left a room
+    /// spanning instances
+    fn leave_cluster(&self, peer_id: &PeerId, room: &RequestedRoom) {
+        if let Some(cluster) = self.cluster.as_ref().filter(|_| room.next.is_none()) {
+            cluster.broadcast(ClusterMessage::Left {
+                instance: cluster.url().to_string(),
+                peer: peer_id.clone(),
+            });
+        }
+    }
+
+    /// Handles a message another instance of the cluster posted
+    pub fn handle_cluster_message(&mut self, message: ClusterMessage) {
+        let Some(cluster) = &mut self.cluster else {
+            warn!("not part of a cluster, ignoring {message:?}");
+            return;
+        };
+        let instance = message.instance().to_string();
+        let new_instance = cluster.add_instance(&instance);
+        if let ClusterMessage::Hello { .. } = &message {
+            // the instance restarted, so its peers are gone
+            self.remote_peers
+                .retain(|_, (_, remote)| *remote != instance);
+        }
+        if new_instance || matches!(message, ClusterMessage::Hello { .. }) {
+            self.sync_cluster_instance(&instance);
+        }
+
+        match message {
+            ClusterMessage::Hello { .. } => {}
+            ClusterMessage::Joined { room, peer, .. } => {
+                if self.clients.contains_key(&peer) {
+                    warn!("{peer:?} joined {instance} while connected here, ignoring it");
+                    return;
+                }
+                let room = RequestedRoom {
+                    id: parse_room_id(room),
+                    next: None,
+                };
+                self.announce_remote_peer(&peer, &room);
+                self.remote_peers.insert(peer, (room.id, instance));
+            }
+            ClusterMessage::Left { peer, .. } => {
+                if self
+                    .remote_peers
+                    .get(&peer)
+                    .is_some_and(|(_, remote)| *remote == instance)
+                {
+                    self.remote_peers.remove(&peer);
+                }
+            }
+            ClusterMessage::Signal {
+                sender,
+                receiver,
+                data,
+                ..
+            } => {
+                // only relayed once, so signals can't go around in circles
+                let Some(peer) = self.clients.get(&receiver) else {
+                    warn!("peer not found ({receiver}), ignoring signal from {instance}");
+                    return;
+                };
+                let room_id = peer.room.id.clone();
+                let message = event_message(&PeerEvent::Signal { sender, data });
+                if let Err(e) = peer.sender.send(Ok(message)) {
+                    error!("error sending: {:?}", e);
+                    self.room_stats_mut(&room_id).record_error();
+                } else {
+                    self.room_stats_mut(&room_id).record_relay();
+                }
+            }
+        }
+    }
+
+    /// Tells an instance that (re)joined the cluster about the peers of the
+    /// rooms spanning instances
+    fn sync_cluster_instance(&self, instance: &str) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        for peer in self
+            .clients
+            .values()
+            .filter(|peer| peer.room.next.is_none())
+        {
+            let message = ClusterMessage::Joined {
+                instance: cluster.url().to_string(),
+                room: peer.room.id.0.clone(),
+                peer: peer.uuid.clone(),
+            };
+            cluster.send(instance, message);
+        }
+    }
+
+    fn try_send(&self, id: &PeerId, message: Message) {
+        let peer = self.clients.get(id);
+        let peer = match peer {
+            Some(peer) => peer,
+            None => {
+                error!("Unknown peer {:?}", id);
+                return;
+            }
+        };
+        if let Err(e) = peer.sender.send(Ok(message)) {
+            error!("Error sending message {:?}", e);
+        }
+    }
+}
+
+fn sanitize_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Drops empty, oversized and duplicate capabilities, keeping at most
+/// [`MAX_CAPABILITIES`]
+fn sanitize_capabilities(capabilities: Vec<String>) -> Vec<String> {
+    let mut sanitized: Vec<String> = vec![];
+    for capability in capabilities {
+        let valid = !capability.is_empty()
+            && capability.chars().count() <= MAX_CAPABILITY_LEN
+            && !capability.chars().any(char::is_control);
+        if valid && !sanitized.contains(&capability) {
+            sanitized.push(capability);
+        }
+    }
+    sanitized.truncate(MAX_CAPABILITIES);
+    sanitized
+}
+
+fn event_message(event: &PeerEvent) -> Message {
+    Message::text(serde_json::to_string(event).expect("error serializing message"))
+}
+
+/// The final messages sent to a peer that is being disconnected
+fn error_messages(code: SignallingErrorCode) -> [Message; 2] {
+    [event_message(&PeerEvent::Error(code)), Message::close()]
+}
+
+fn parse_room_id(id: String) -> RoomId {
+    RoomId(id)
+}
+
+// I don't understand warp well enough to get rid of this warning
+// This is going to be a hard error in the future, though:
+// https://doc.rust-lang.org/nightly/nightly-rustc/rustc_lint/opaque_hidden_inferred_bound/static.OPAQUE_HIDDEN_INFERRED_BOUND.html
+// The compiler hint tells us to add an impl warp::generic::Tuple bound to the
+// Extract associated type. That type is private, though... I'm hoping that in
+// the meantime someone else will run into similar issues with warp and find
+// solutions for them.
+#[allow(opaque_hidden_inferred_bound)]
+pub(crate) fn ws_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::ws()
+        .and(warp::any())
+        .and(warp::path::param().map(parse_room_id))
+        .and(warp::query::<QueryParam>().map(parse_room_next))
+        .and(warp::query::<BTreeMap<String, String>>())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("origin"))
+        .and(with_state(state))
+        .and_then(ws_handler)
+}
+
+fn parse_room_next(p: QueryParam) -> Option<usize> {
+    p.next
+}
+
+pub(crate) fn with_state(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = (Arc<Mutex<State>>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+pub(crate) async fn ws_handler(
+    ws: warp::ws::Ws,
+    room_id: RoomId,
+    next: Option<usize>,
+    params: BTreeMap<String, String>,
+    addr: Option<SocketAddr>,
+    origin: Option<String>,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let mut locked_state = state.lock().await;
+    let ws = ws.max_message_size(locked_state.limits.max_message_size);
+    if let Some(addr) = addr {
+        if locked_state.is_banned(&BanTarget::Ip(addr.ip())) {
+            warn!("Rejecting banned address {addr}");
+            return Ok(Box::new(ws.on_upgrade(|websocket| {
+                reject_ws(websocket, SignallingErrorCode::Banned)
+            })));
+        }
+    }
+    drop(locked_state);
+    Ok(Box::new(ws.on_upgrade(move |websocket| {
+        let room = RequestedRoom { id: room_id, next };
+        handle_ws(websocket, state, room, params, addr, origin)
+    })))
+}
+
+#[derive(Debug, thiserror::Error)]
+enum RequestError {
+    #[error("Warp error")]
+    Warp(#[from] warp::Error),
+    #[error("Not text error")]
+    NotText,
+    #[error("Message is close")]
+    Close,
+    #[error("Json error")]
+    Json(#[from] serde_json::Error),
+}
+
+fn parse_request(request: Result<Message, Error>) -> Result<PeerRequest, RequestError> {
+    let request = request?;
+
+    if request.is_close() {
+        return Err(RequestError::Close);
+    }
+
+    if !request.is_text() {
+        return Err(RequestError::NotText);
+    }
+
+    let request = request.to_str().map_err(|_| RequestError::NotText)?;
+
+    let request: PeerRequest = serde_json::from_str(request)?;
+
+    Ok(request)
+}
+
+/// Lets peers held back by reservations into their room once the
+/// reservations expired
+fn release_held_peers_later(state: Arc<Mutex<State>>, room: RequestedRoom, after: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(after).await;
+        state.lock().await.release_held_peers(&room);
+    });
+}
+
+/// Closes idle rooms until the state is dropped, see
+/// [`Limits::room_idle_secs`]
+pub(crate) fn close_idle_rooms(state: &Arc<Mutex<State>>) {
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.upgrade() {
+                Some(state) => state.lock().await.close_idle_rooms(Instant::now()),
+                None => break,
+            }
+        }
+    });
+}
+
+/// Sends paced introductions as they are due, until the state is dropped, see
+/// [`Limits::introductions_per_sec`]
+pub(crate) fn pace_introductions(state: &Arc<Mutex<State>>) {
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INTRODUCTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.upgrade() {
+                Some(state) => state.lock().await.pace_introductions(Instant::now()),
+                None => break,
+            }
+        }
+    });
+}
+
+/// Tells the peer why it can't connect, then closes the connection
+async fn reject_ws(mut websocket: WebSocket, code: SignallingErrorCode) {
+    for message in error_messages(code) {
+        if let Err(e) = websocket.send(message).await {
+            warn!("failed to send {code:?} error: {e:?}");
+            return;
+        }
+    }
+}
+
+fn spawn_sender_task(sender: SplitSink<WebSocket, Message>, max_queued: usize) -> PeerSender {
+    let (client_sender, receiver) = mpsc::unbounded_channel();
+    let client_sender = PeerSender::new(client_sender, max_queued);
+    let queued = client_sender.queued.clone();
+    let messages = UnboundedReceiverStream::new(receiver).inspect(move |_| {
+        queued.fetch_sub(1, Ordering::Relaxed);
+    });
+    tokio::task::spawn(messages.forward(sender));
+    client_sender
+}
+
+/// Locks the state once the server has space for a peer joining the room
+///
+/// Beyond [`Limits::max_peers`] or [`Limits::max_rooms`], the peer is either
+/// turned away with [`SignallingErrorCode::ServerBusy`], or waits in a queue
+/// until there is space for it, see [`Limits::queue_when_busy`]. Returns
+/// `None` if it was turned away, or disconnected while waiting.
+async fn wait_for_capacity<'a>(
+    state: &'a Mutex<State>,
+    room: &RoomId,
+    sender: &PeerSender,
+    ws_receiver: &mut SplitStream<WebSocket>,
+) -> Option<MutexGuard<'a, State>> {
+    let mut admitted_before = false;
+    loop {
+        let mut locked = state.lock().await;
+        // peers that were let in don't wait behind those still waiting
+        let is_next = admitted_before || locked.waiting_peers.is_empty();
+        if is_next && locked.has_capacity(room) {
+            return Some(locked);
+        }
+        if !locked.limits.queue_when_busy {
+            let retry_after_secs = locked.limits.busy_retry_after_secs;
+            warn!("Turning a peer away from {room:?}, the server is at capacity");
+            for message in error_messages(SignallingErrorCode::ServerBusy { retry_after_secs }) {
+                let _ = sender.send(Ok(message));
+            }
+            return None;
+        }
+        let (id, mut admitted) = locked.join_waiting_peers(room, sender.clone(), admitted_before);
+        locked.admit_waiting_peer();
+        drop(locked);
+
+        loop {
+            tokio::select! {
+                _ = &mut admitted => break,
+                request = ws_receiver.next() => match request.map(parse_request) {
+                    None | Some(Err(RequestError::Close | RequestError::Warp(_))) => {
+                        info!("A waiting peer disconnected from {room:?}");
+                        state.lock().await.leave_waiting_peers(id);
+                        return None;
+                    }
+                    Some(Ok(PeerRequest::KeepAlive)) => {}
+                    Some(request) => warn!("Ignoring {request:?} while waiting to join"),
+                }
+            }
+        }
+        admitted_before = true;
+    }
+}
+
+async fn handle_ws(
+    websocket: WebSocket,
+    state: Arc<Mutex<State>>,
+    mut requested_room: RequestedRoom,
+    params: BTreeMap<String, String>,
+    addr: Option<SocketAddr>,
+    origin: Option<String>,
+) {
+    let connected_at = Instant::now();
+    let span = Span::root("connection").with("room", &requested_room.id.0);
+    if let Some(addr) = addr {
+        span.set("client.address", addr.ip());
+    }
+    let mut queue_span = None;
+    let (ws_sender, mut ws_receiver) = websocket.split();
+    let max_queued = state.lock().await.limits.max_queued_messages;
+    let sender = spawn_sender_task(ws_sender, max_queued);
+    let mut peer_uuid = None;
+    let mut observer_id = None;
+    let mut queue_id = None;
+    let mut requested_name = None;
+    let mut declared_version = None;
+    let mut requested_capabilities = vec![];
+    let mut requested_metadata = None;
+    let mut requested_reservations = vec![];
+    let mut requested_extra_rooms = vec![];
+    let mut claimed_slot: Option<String> = None;
+
+    while let Some(request) = ws_receiver.next().await {
+        let request = match parse_request(request) {
+            Ok(request) => request,
+            Err(RequestError::Warp(e)) => {
+                error!("Warp error while receiving request: {:?}", e);
+                // Most likely a ConnectionReset or similar.
+                // just give up on this peer.
+                break;
+            }
+            Err(RequestError::Close) => {
+                info!("Received websocket close from {peer_uuid:?}");
+                break;
+            }
+            Err(e) => {
+                error!("Error untangling request, disconnecting {peer_uuid:?}: {e:?}");
+                if let Some(uuid) = &peer_uuid {
+                    state.lock().await.record_error(uuid);
+                }
+                for message in error_messages(SignallingErrorCode::ProtocolMismatch) {
+                    let _ = sender.send(Ok(message));
+                }
+                break;
+            }
+        };
+
+        info!("{:?} <- {:?}", peer_uuid, request);
+        let keeps_room_alive = request != PeerRequest::KeepAlive;
+
+        match request {
+            PeerRequest::Uuid(id) => {
+                if peer_uuid.is_some() {
+                    error!("client set uuid more than once");
+                    continue;
+                }
+                if observer_id.is_some() {
+                    error!("observer is trying to join the room");
+                    continue;
+                }
+                if queue_id.is_some() {
+                    error!("queued client is trying to join a room");
+                    continue;
+                }
+                let join_span = span.child("join").with("peer.id", &id);
+                let Some(mut state) =
+                    wait_for_capacity(&state, &requested_room.id, &sender, &mut ws_receiver).await
+                else {
+                    join_span.fail("the server is at capacity");
+                    break;
+                };
+                if let Some(code) = state.check_peer_id(&id) {
+                    warn!("Rejecting {id:?}: {code:?}");
+                    join_span.fail(format!("{code:?}"));
+                    for message in error_messages(code) {
+                        let _ = sender.send(Ok(message));
+                    }
+                    break;
+                }
+                if state.is_banned(&BanTarget::Peer(id.clone())) {
+                    warn!("Kicking banned peer {id:?}");
+                    join_span.fail("banned");
+                    for message in error_messages(SignallingErrorCode::Banned) {
+                        let _ = sender.send(Ok(message));
+                    }
+                    break;
+                }
+
+                let policy = state.room_policy(&requested_room.id);
+                let max_peers = policy.and_then(|policy| policy.max_peers);
+                requested_room.next = policy.and_then(|p| p.next).or(requested_room.next);
+                if state.is_room_full(&requested_room.id, max_peers) {
+                    warn!("Rejecting {id:?}, {:?} is full", requested_room.id);
+                    join_span.fail("the room is full");
+                    for message in error_messages(SignallingErrorCode::RoomFull) {
+                        let _ = sender.send(Ok(message));
+                    }
+                    break;
+                }
+                if !state.is_version_compatible(&requested_room.id, declared_version.as_ref()) {
+                    warn!("Rejecting {id:?}, its version is {declared_version:?}");
+                    join_span.fail("version mismatch");
+                    for message in error_messages(SignallingErrorCode::VersionMismatch) {
+                        let _ = sender.send(Ok(message));
+                    }
+                    break;
+                }
+
+                let join = JoinRequest {
+                    peer: id.clone(),
+                    room: requested_room.id.0.clone(),
+                    next: requested_room.next,
+                    params: params.clone(),
+                };
+                let role = match state.check_join(&join) {
+                    Ok(role) => role,
+                    Err(code) => {
+                        warn!("Rejecting {id:?}, the join hook refused it: {code:?}");
+                        join_span.fail(format!("{code:?}"));
+                        for message in error_messages(code) {
+                            let _ = sender.send(Ok(message));
+                        }
+                        break;
+                    }
+                };
+
+                if let Some(token) = claimed_slot.take() {
+                    if !state.claim_slot(&requested_room, &token) {
+                        warn!("{id:?} claimed a slot that isn't reserved");
+                    }
+                }
+
+                peer_uuid = Some(id.clone());
+                span.set("peer.id", &id);
+                join_span.set("peer.role", format!("{role:?}"));
+                let name = requested_name
+                    .take()
+                    .and_then(|name: String| state.unique_name(&requested_room, &name));
+                let peers = state.add_peer(Peer {
+                    uuid: id.clone(),
+                    sender: sender.clone(),
+                    room: requested_room.clone(),
+                    joined_at: Instant::now(),
+                    signalled: false,
+                    addr,
+                    name: name.clone(),
+                    version: declared_version.clone(),
+                    capabilities: std::mem::take(&mut requested_capabilities),
+                    signals_sent: HashMap::new(),
+                    candidates_sent: HashMap::new(),
+                    offers_sent: VecDeque::new(),
+                    params: params.clone(),
+                    role,
+                });
+                // the room may have been created just now, with space for
+                // peers waiting to join it
+                state.admit_waiting_peer();
+
+                // Before any signals, so they're known by the time peers connect
+                let peer = id.clone();
+                state.try_send(&id, event_message(&PeerEvent::PeerRole { peer, role }));
+                if let Some(servers) = state.ice_servers(&id) {
+                    state.try_send(&id, event_message(&PeerEvent::IceServers(servers)));
+                }
+                if let Some(policy) = policy {
+                    state.try_send(&id, event_message(&PeerEvent::RoomPolicy(policy)));
+                }
+                // Tell the new peer its own name
+                if let Some(name) = name {
+                    let peer = id.clone();
+                    state.try_send(&id, event_message(&PeerEvent::PeerName { peer, name }));
+                }
+                match requested_metadata.take() {
+                    Some(metadata) => state.set_room_metadata(&id, metadata),
+                    None => state.send_room_metadata(&id),
+                }
+                state.announce_peer(&id, &peers);
+                state.replay_event_log(&id);
+            }
+            PeerRequest::MigrateRoom { room, next } => {
+                let id = match &peer_uuid {
+                    Some(id) => id,
+                    None => {
+                        error!("client is trying to migrate before sending uuid");
+                        continue;
+                    }
+                };
+                let migration = span.child("migrate_room").with("room", &room);
+                let mut state = state.lock().await;
+                let id_to = parse_room_id(room);
+                let to = RequestedRoom {
+                    next: state.room_policy(&id_to).and_then(|p| p.next).or(next),
+                    id: id_to,
+                };
+                if let Err(code) = state.migrate_room(id, to) {
+                    migration.fail(format!("{code:?}"));
+                    state.try_send(id, event_message(&PeerEvent::MigrationRejected(code)));
+                }
+            }
+            PeerRequest::Name(name) => {
+                if peer_uuid.is_some() {
+                    error!("client set name after joining the room");
+                    continue;
+                }
+                requested_name = Some(name);
+            }
+            PeerRequest::Version(version) => {
+                if peer_uuid.is_some() {
+                    error!("client declared its version after joining the room");
+                    continue;
+                }
+                declared_version = Some(version);
+            }
+            PeerRequest::Capabilities(capabilities) => {
+                if peer_uuid.is_some() {
+                    error!("client advertised capabilities after joining the room");
+                    continue;
+                }
+                requested_capabilities = sanitize_capabilities(capabilities);
+            }
+            PeerRequest::ReserveSlots(mut tokens) => {
+                if peer_uuid.is_none() {
+                    // held until we join, one of the free slots is ours then
+                    let free = state.lock().await.free_slots(&requested_room);
+                    let left = free.saturating_sub(1 + requested_reservations.len());
+                    if tokens.len() > left {
+                        warn!("client is reserving more slots than are free, dropping the rest");
+                        tokens.truncate(left);
+                    }
+                }
+                requested_reservations.extend(tokens);
+            }
+            PeerRequest::ClaimSlot(token) => {
+                if peer_uuid.is_some() {
+                    error!("client claimed a slot after joining the room");
+                    continue;
+                }
+                claimed_slot = Some(token);
+            }
+            PeerRequest::CloseRoom => {
+                let id = match &peer_uuid {
+                    Some(id) => id,
+                    None => {
+                        error!("client is trying to close the room before sending uuid");
+                        continue;
+                    }
+                };
+                let _close = span.child("close_room");
+                let mut state = state.lock().await;
+                if let Some(room) = state.peer_room(id).cloned() {
+                    state.close_room(&room.id, Some(id));
+                }
+            }
+            PeerRequest::SetRoomMetadata(mut metadata) => {
+                // the room requires the creator's version unless it says otherwise
+                metadata.version = metadata.version.or_else(|| declared_version.clone());
+                match &peer_uuid {
+                    Some(id) => state.lock().await.set_room_metadata(id, metadata),
+                    None => requested_metadata = Some(metadata),
+                }
+            }
+            PeerRequest::Signal { receiver, data } => {
+                let sender = match peer_uuid.clone() {
+                    Some(sender) => sender,
+                    None => {
+                        error!("client is trying signal before sending uuid");
+                        continue;
+                    }
+                };
+                let _relay = span.child("relay_signal").with("peer.receiver", &receiver);
+                let mut state = state.lock().await;
+                state.relay_signal(&sender, &receiver, data);
+            }
+            PeerRequest::Observe => {
+                if peer_uuid.is_some() || observer_id.is_some() {
+                    error!("client is trying to observe after joining the room");
+                    continue;
+                }
+                let mut state = state.lock().await;
+                observer_id = Some(state.add_observer(Observer {
+                    room: requested_room.id.clone(),
+                    sender: sender.clone(),
+                }));
+            }
+            PeerRequest::JoinQueue => {
+                if peer_uuid.is_some() || observer_id.is_some() || queue_id.is_some() {
+                    error!("client is trying to queue after joining a room or the queue");
+                    continue;
+                }
+                // lasts until the peer leaves the queue for its room
+                let matchmaking = span.child("matchmaking");
+                queue_id = state.lock().await.join_queue(sender.clone());
+                if queue_id.is_none() {
+                    warn!("matchmaking is disabled, rejecting queued client");
+                    matchmaking.fail("matchmaking is disabled");
+                    for message in error_messages(SignallingErrorCode::ProtocolMismatch) {
+                        let _ = sender.send(Ok(message));
+                    }
+                    break;
+                }
+                queue_span = Some(matchmaking);
+            }
+            PeerRequest::Latency(latencies) => match queue_id {
+                Some(id) => {
+                    let _report = queue_span.as_ref().map(|span| span.child("report_latency"));
+                    state.lock().await.report_latency(id, latencies);
+                }
+                None => error!("client is reporting latencies without queueing"),
+            },
+            PeerRequest::RoomMessage(data) => {
+                let sender = match &peer_uuid {
+                    Some(sender) => sender,
+                    None => {
+                        error!("client is sending a room message before sending uuid");
+                        continue;
+                    }
+                };
+                let _relay = span.child("relay_room_message");
+                state.lock().await.relay_room_message(sender, data);
+            }
+            PeerRequest::JoinExtraRoom(room) => {
+                // held until we join, we couldn't be in more rooms than this
+                if requested_extra_rooms.len() >= MAX_EXTRA_ROOMS {
+                    warn!("client is joining too many extra rooms, ignoring {room:?}");
+                } else if !requested_extra_rooms.contains(&room) {
+                    requested_extra_rooms.push(room);
+                }
+            }
+            PeerRequest::LeaveExtraRoom(room) => {
+                requested_extra_rooms.retain(|requested| requested != &room);
+                if let Some(id) = &peer_uuid {
+                    state.lock().await.leave_extra_room(id, &RoomId(room));
+                }
+            }
+            PeerRequest::ExtraRoomMessage { room, data } => match &peer_uuid {
+                Some(id) => {
+                    let _relay = span.child("relay_room_message").with("room", &room);
+                    let state = state.lock().await;
+                    state.relay_extra_room_message(id, RoomId(room), data);
+                }
+                None => error!("client is sending a room message before sending uuid"),
+            },
+            PeerRequest::KeepAlive | PeerRequest::KeepRoomAlive => {}
+        }
+
+        if let Some(id) = peer_uuid.as_ref().filter(|_| keeps_room_alive) {
+            state.lock().await.record_activity(id);
+        }
+
+        if sender.overflowed() {
+            warn!("{peer_uuid:?} isn't reading its messages, disconnecting");
+            span.fail("too many queued messages");
+            if let Some(id) = &peer_uuid {
+                state.lock().await.record_queue_overflow(id);
+            }
+            break;
+        }
+
+        // slots can only be reserved once the peer is waiting in its room
+        if let Some(id) = peer_uuid
+            .as_ref()
+            .filter(|_| !requested_reservations.is_empty())
+        {
+            let tokens = std::mem::take(&mut requested_reservations);
+            let mut locked_state = state.lock().await;
+            if let Some((room, expires_in)) = locked_state.reserve_slots(id, tokens) {
+                release_held_peers_later(state.clone(), room, expires_in);
+            }
+        }
+
+        // likewise, extra rooms can only be joined once the peer has an id
+        if let Some(id) = peer_uuid
+            .as_ref()
+            .filter(|_| !requested_extra_rooms.is_empty())
+        {
+            let mut state = state.lock().await;
+            for room in std::mem::take(&mut requested_extra_rooms) {
+                state.join_extra_room(id, RoomId(room));
+            }
+        }
+    }
+
+    info!("Removing peer: {:?}", peer_uuid);
+    let mut state = state.lock().await;
+    if let Some(uuid) = &peer_uuid {
+        // the room may have been migrated since we joined
+        if let Some(room) = state.peer_room(uuid) {
+            requested_room = room.clone();
+        }
+        state.remove_peer(uuid);
+    }
+    if let Some(id) = observer_id {
+        state.remove_observer(id);
+    }
+    if let Some(id) = queue_id {
+        state.leave_queue(id);
+    }
+    // we may have been let in, and left without joining
+    state.admit_waiting_peer();
+    state.record_connection(&AccessLogEntry {
+        room: &requested_room.id,
+        peer: peer_uuid.as_ref(),
+        origin: origin.as_deref(),
+        addr,
+        duration: connected_at.elapsed(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures::{lock::Mutex, pin_mut};
+    use tokio::{select, time};
+    use warp::{test::WsClient, ws::Message, Filter, Rejection, Reply};
+
+    use crate::{
+        config::RoomRule,
+        hooks::JoinHook,
+        signaling::{
+            parse_room_id, parse_room_next, BanTarget, MatchmakingRegion, Peer, PeerEvent,
+            PeerRole, PeerSender, QueryParam, RequestedRoom, RoomId, RoomMetadata, RoomPolicy,
+            SignallingErrorCode, State,
+        },
+        stats::MAX_EMPTY_ROOM_STATS,
+    };
+
+    // warning: See comment for ws_filter
+    #[allow(opaque_hidden_inferred_bound)]
+    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        super::ws_filter(Arc::new(Mutex::new(test_state())))
+    }
+
+    /// The tests use readable ids instead of uuids
+    fn test_state() -> State {
+        State::default().with_custom_peer_ids()
+    }
+
+    #[tokio::test]
+    async fn ws_connect() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+    }
+
+    #[tokio::test]
+    async fn new_peer() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        let new_peer_event = recv_peer_event(&mut client_a).await;
+
+        assert_eq!(new_peer_event, PeerEvent::NewPeer("uuid-b".to_string()));
+    }
+
+    #[tokio::test]
+    async fn signal() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        let new_peer_event = recv_peer_event(&mut client_a).await;
+
+        let peer_uuid = match new_peer_event {
+            PeerEvent::NewPeer(peer) => peer,
+            _ => panic!("unexpected event"),
+        };
+
+        client_a
+            .send(Message::text(format!(
+                "{{\"Signal\": {{\"receiver\": \"{}\", \"data\": \"123\" }}}}",
+                peer_uuid
+            )))
+            .await;
+
+        let signal_event = recv_peer_event(&mut client_b).await;
+
+        assert_eq!(
+            signal_event,
+            PeerEvent::Signal {
+                data: serde_json::Value::String("123".to_string()),
+                sender: "uuid-a".to_string(),
+            }
+        );
+    }
+
+    /// Receives the next event, skipping the roles every peer gets, see
+    /// `peer_roles`
+    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
+        loop {
+            match recv_any_peer_event(client).await {
+                PeerEvent::PeerRole { .. } => {}
+                event => return event,
+            }
+        }
+    }
+
+    async fn recv_any_peer_event(client: &mut WsClient) -> PeerEvent {
+        let message = client.recv().await;
+        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn peer_roles() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_any_peer_event(&mut client_a).await,
+            PeerEvent::PeerRole {
+                peer: "uuid-a".to_string(),
+                role: PeerRole::Host
+            }
+        );
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+        let role_b = PeerEvent::PeerRole {
+            peer: "uuid-b".to_string(),
+            role: PeerRole::Player,
+        };
+        assert_eq!(recv_any_peer_event(&mut client_b).await, role_b);
+        // the role of the new peer is sent before it's announced
+        assert_eq!(recv_any_peer_event(&mut client_a).await, role_b);
+        assert_eq!(
+            recv_any_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+        // and the new peer learns the roles of the peers already there
+        assert_eq!(
+            recv_any_peer_event(&mut client_b).await,
+            PeerEvent::PeerRole {
+                peer: "uuid-a".to_string(),
+                role: PeerRole::Host
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn match_pairs() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        let mut client_c = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_c
+            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+            .await;
+
+        let mut client_d = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_d
+            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
+            .await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
+        let new_peer_b = recv_peer_event(&mut client_a).await;
+        let new_peer_d = recv_peer_event(&mut client_c).await;
+
+        assert_eq!(new_peer_b, PeerEvent::NewPeer("uuid-b".to_string()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut client_a) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_b) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_c) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_d) => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+    #[tokio::test]
+    async fn match_pair_and_other_alone_room_without_next() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_name")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        let mut client_c = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_c
+            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+            .await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
+        let new_peer_c = recv_peer_event(&mut client_a).await;
+
+        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut client_a) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_b) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_c) => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn match_different_id_same_next() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/scope_1?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_b = warp::test::ws()
+            .path("/scope_2?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_c = warp::test::ws()
+            .path("/scope_1?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_d = warp::test::ws()
+            .path("/scope_2?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        client_c
+            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+            .await;
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        client_d
+            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
+            .await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + c and b + d
+        let new_peer_c = recv_peer_event(&mut client_a).await;
+        let new_peer_d = recv_peer_event(&mut client_b).await;
+
+        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut client_a) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_b) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_c) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_d) => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+    #[tokio::test]
+    async fn match_same_id_different_next() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/scope_1?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_b = warp::test::ws()
+            .path("/scope_1?next=3")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_c = warp::test::ws()
+            .path("/scope_1?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_d = warp::test::ws()
+            .path("/scope_1?next=3")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let mut client_e = warp::test::ws()
+            .path("/scope_1?next=3")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        client_c
+            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+            .await;
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        client_d
+            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
+            .await;
+
+        client_e
+            .send(Message::text(r#"{"Uuid": "uuid-e"}"#.to_string()))
+            .await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + c and (b + d ; b + e ; d + e)
+        let new_peer_c = recv_peer_event(&mut client_a).await;
+        let new_peer_d = recv_peer_event(&mut client_b).await;
+        let new_peer_e = recv_peer_event(&mut client_b).await;
+        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
+        let new_peer_e = recv_peer_event(&mut client_d).await;
+
+        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut client_a) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_b) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_c) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_d) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_e) => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn kick() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        // make sure the server has processed the uuid
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(state.lock().await.kick(&"uuid-a".to_string()));
+        assert!(!state.lock().await.kick(&"uuid-unknown".to_string()));
+
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Error(SignallingErrorCode::Kicked)
+        );
+        client_a.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn host_closes_room_for_everyone() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut host = join(&api, "/lobby", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut guest = join(&api, "/lobby", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut host).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+
+        // only the host may close the room
+        guest.send(Message::text(r#""CloseRoom""#)).await;
+        time::sleep(Duration::from_millis(50)).await;
+        host.send(Message::text(r#""CloseRoom""#)).await;
+
+        let closed = PeerEvent::RoomClosed {
+            host: Some("uuid-a".to_string()),
+        };
+        assert_eq!(recv_peer_event(&mut guest).await, closed);
+        guest.recv_closed().await.expect("closed");
+        assert_eq!(recv_peer_event(&mut host).await, closed);
+        host.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn admin_closes_room() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut client = join(&api, "/arena", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        // make sure the server has processed the uuid
+        time::sleep(Duration::from_millis(50)).await;
+        let admin = crate::admin::admin_filter(state, Some("secret".to_string()));
+        let close = |room: &str| {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/admin/rooms/{room}/close"))
+                .header("authorization", "Bearer secret")
+                .reply(&admin)
+        };
+        assert_eq!(close("empty").await.status(), 404);
+        assert_eq!(close("arena").await.status(), 200);
+
+        assert_eq!(
+            recv_peer_event(&mut client).await,
+            PeerEvent::RoomClosed { host: None }
+        );
+        client.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_counted() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        client_a.send(Message::close()).await;
+
+        // make sure the server has processed the close
+        time::sleep(Duration::from_millis(50)).await;
+        let state = state.lock().await;
+        let stats = state
+            .room_stats(&RoomId("room_a".to_string()))
+            .expect("no stats");
+        assert_eq!(stats.connections, 1);
+        assert!(stats.avg_connection_duration_ms.is_some());
+    }
+
+    #[test]
+    fn stats_of_empty_rooms_are_bounded() {
+        let mut state = test_state();
+        for i in 0..MAX_EMPTY_ROOM_STATS + 10 {
+            state
+                .room_stats_mut(&RoomId(format!("room_{i}")))
+                .record_error();
+        }
+        state.prune_stats();
+        assert_eq!(state.stats().count(), MAX_EMPTY_ROOM_STATS);
+    }
+
+    #[tokio::test]
+    async fn observer_sees_peers_come_and_go() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut observer = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        observer
+            .send(Message::text(r#""Observe""#.to_string()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::RoomPeers(vec![])
+        );
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?next=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::RoomPeers(vec!["uuid-a".to_string()])
+        );
+
+        client_a.send(Message::close()).await;
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::RoomPeers(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn observer_gets_large_peer_lists_in_pages() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_limits(crate::Limits {
+            peer_list_page_size: 2,
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
+
+        let mut client_a = join(&api, "/room_a", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let _client_b = join(&api, "/room_a", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        let _client_c = join(&api, "/room_a", &[r#"{"Uuid": "uuid-c"}"#]).await;
+        // everyone is in the room once the first peer heard of the last
+        while recv_peer_event(&mut client_a).await != PeerEvent::NewPeer("uuid-c".to_string()) {}
+        let mut observer = join(&api, "/room_a", &[r#""Observe""#]).await;
+        let peers = |peers: &[&str]| peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::RoomPeersPage(peers(&["uuid-a", "uuid-b"]))
+        );
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::RoomPeersPage(peers(&["uuid-c"]))
+        );
+        assert_eq!(
+            recv_peer_event(&mut observer).await,
+            PeerEvent::PeerListComplete
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_of_different_rooms_meet_in_an_extra_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+        let chat = r#"{"JoinExtraRoom": "chat"}"#;
+        let peers = |peers: &[&str]| PeerEvent::ExtraRoomPeers {
+            room: "chat".to_string(),
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+        };
+
+        let mut client_a = warp::test::ws()
+            .path("/match_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        client_a.send(Message::text(chat.to_string())).await;
+        assert_eq!(recv_peer_event(&mut client_a).await, peers(&["uuid-a"]));
+
+        let mut client_b = warp::test::ws()
+            .path("/match_b")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+        client_b.send(Message::text(chat.to_string())).await;
+        let both = peers(&["uuid-a", "uuid-b"]);
+        assert_eq!(recv_peer_event(&mut client_a).await, both);
+        assert_eq!(recv_peer_event(&mut client_b).await, both);
+
+        client_b
+            .send(Message::text(
+                r#"{"ExtraRoomMessage": {"room": "chat", "data": "gg"}}"#.to_string(),
+            ))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::ExtraRoomMessage {
+                room: "chat".to_string(),
+                sender: "uuid-b".to_string(),
+                data: "gg".to_string(),
+            }
+        );
+
+        client_a
+            .send(Message::text(r#"{"LeaveExtraRoom": "chat"}"#.to_string()))
+            .await;
+        assert_eq!(recv_peer_event(&mut client_b).await, peers(&["uuid-b"]));
+        // peers leave their extra rooms when they disconnect
+        client_a.send(Message::text(chat.to_string())).await;
+        assert_eq!(recv_peer_event(&mut client_b).await, both);
+        client_a.send(Message::close()).await;
+        assert_eq!(recv_peer_event(&mut client_b).await, peers(&["uuid-b"]));
+    }
+
+    #[tokio::test]
+    async fn malformed_request_disconnects() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        client_a
+            .send(Message::text(r#"{"Signal": ["#.to_string()))
+            .await;
+
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Error(SignallingErrorCode::ProtocolMismatch)
+        );
+        client_a.recv_closed().await.expect("closed");
+        assert_eq!(state.lock().await.peers().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn too_many_signals_disconnects() {
+        let _ = pretty_env_logger::try_init();
         let api = api();
 
         let mut client_a = warp::test::ws()
             .path("/room_a")
-            .handshake(api.clone())
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+
+        let signal = r#"{"Signal": {"receiver": "uuid-b", "data": "candidate"}}"#;
+        for _ in 0..=crate::Limits::default().max_signals_per_peer {
+            client_a.send(Message::text(signal.to_string())).await;
+        }
+
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Error(SignallingErrorCode::RateLimited)
+        );
+        client_a.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn excess_candidates_and_offers_are_throttled() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_limits(crate::Limits {
+            max_candidates_per_peer: 2,
+            max_offers_per_minute: 1,
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
+
+        let mut client_a = join(&api, "/room_a", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut client_b = join(&api, "/room_a", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+
+        let candidate =
+            r#"{"Signal": {"receiver": "uuid-b", "data": {"IceCandidate": "candidate"}}}"#;
+        for _ in 0..4 {
+            client_a.send(Message::text(candidate)).await;
+        }
+        let offer = r#"{"Signal": {"receiver": "uuid-b", "data": {"Offer": "sdp"}}}"#;
+        for _ in 0..2 {
+            client_a.send(Message::text(offer)).await;
+        }
+
+        // one warning when candidates start being dropped, one per dropped offer
+        let throttled = PeerEvent::SignalsThrottled {
+            receiver: "uuid-b".to_string(),
+        };
+        assert_eq!(recv_peer_event(&mut client_a).await, throttled);
+        assert_eq!(recv_peer_event(&mut client_a).await, throttled);
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            match recv_peer_event(&mut client_b).await {
+                PeerEvent::Signal { data, .. } => received.push(data),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                serde_json::json!({"IceCandidate": "candidate"}),
+                serde_json::json!({"IceCandidate": "candidate"}),
+                serde_json::json!({"Offer": "sdp"}),
+            ]
+        );
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            event = recv_peer_event(&mut client_b) => panic!("throttled signal arrived: {:?}", event),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn banned_peer_is_kicked() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        state.lock().await.ban(
+            BanTarget::Peer("uuid-a".to_string()),
+            Duration::from_secs(60),
+        );
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
             .await
             .expect("handshake");
 
@@ -326,329 +3410,1195 @@ mod tests {
             .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
             .await;
 
-        let mut client_b = warp::test::ws()
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Error(SignallingErrorCode::Banned)
+        );
+        assert_eq!(state.lock().await.peers().count(), 0);
+    }
+
+    #[test]
+    fn endless_ban_is_refused() {
+        let mut state = test_state();
+        let target = BanTarget::Peer("uuid-a".to_string());
+        assert!(!state.ban(target.clone(), Duration::MAX));
+        assert!(!state.is_banned(&target));
+    }
+
+    #[tokio::test]
+    async fn taken_id_is_rejected() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
             .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        let mut impostor = warp::test::ws()
+            .path("/room_b")
             .handshake(api)
             .await
             .expect("handshake");
+        impostor
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
 
-        client_b
+        assert_eq!(
+            recv_peer_event(&mut impostor).await,
+            PeerEvent::Error(SignallingErrorCode::IdTaken)
+        );
+        // the impostor closing doesn't remove the original peer
+        impostor.recv_closed().await.expect("closed");
+        time::sleep(Duration::from_millis(50)).await;
+        let state = state.lock().await;
+        let peers: Vec<_> = state.peers().map(|peer| peer.uuid.clone()).collect();
+        assert_eq!(peers, vec!["uuid-a".to_string()]);
+        assert_eq!(
+            state.room_peers(&RoomId("room_a".to_string())),
+            vec!["uuid-a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn join_hook_sees_all_query_params() {
+        let _ = pretty_env_logger::try_init();
+        let hook = JoinHook::new(|join| match join.params.get("region").map(String::as_str) {
+            Some("eu") => Ok(Some(PeerRole::Spectator)),
+            _ => Err(SignallingErrorCode::Unauthorized),
+        });
+        let state = Arc::new(Mutex::new(test_state().with_join_hook(hook)));
+        let api = super::ws_filter(state.clone());
+
+        let mut rejected = warp::test::ws()
+            .path("/room_a?region=us&mode=duel")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        rejected
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut rejected).await,
+            PeerEvent::Error(SignallingErrorCode::Unauthorized)
+        );
+
+        let mut client = warp::test::ws()
+            .path("/room_a?next=2&region=eu&mode=duel")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client
             .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
             .await;
+        time::sleep(Duration::from_millis(50)).await;
+        let state = state.lock().await;
+        let peer = state.peers().next().expect("peer joined");
+        assert_eq!(peer.uuid, "uuid-b");
+        assert_eq!(peer.room.next, Some(2));
+        assert_eq!(peer.role, PeerRole::Spectator);
+        let params: Vec<_> = peer
+            .params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(params, [("mode", "duel"), ("next", "2"), ("region", "eu")]);
+    }
+
+    #[tokio::test]
+    async fn custom_ids_are_rejected_by_default() {
+        let _ = pretty_env_logger::try_init();
+        let state: Arc<Mutex<State>> = Default::default();
+        let api = super::ws_filter(state.clone());
 
-        let a_msg = client_a.recv().await;
-        let new_peer_event: PeerEvent =
-            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
+        let mut client = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client).await,
+            PeerEvent::Error(SignallingErrorCode::InvalidId)
+        );
 
-        assert_eq!(new_peer_event, PeerEvent::NewPeer("uuid-b".to_string()));
+        let mut client = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id = uuid::Uuid::new_v4().to_string();
+        assert_eq!(state.lock().await.check_peer_id(&id), None);
+        assert_eq!(
+            test_state().check_peer_id(&"a".repeat(65)),
+            Some(SignallingErrorCode::InvalidId)
+        );
+        client
+            .send(Message::text(format!(r#"{{"Uuid": "{id}"}}"#)))
+            .await;
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.lock().await.peers().count(), 1);
     }
 
     #[tokio::test]
-    async fn signal() {
+    async fn room_rules() {
         let _ = pretty_env_logger::try_init();
-        let api = api();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "arena-*".to_string(),
+            next: None,
+            max_peers: Some(1),
+            channels: Some(2),
+            event_log: None,
+            strict_version: false,
+            client_versions: None,
+            listed: false,
+        }]);
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
 
         let mut client_a = warp::test::ws()
-            .path("/room_a")
+            .path("/arena-1")
             .handshake(api.clone())
             .await
             .expect("handshake");
-
         client_a
             .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
             .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomPolicy(RoomPolicy {
+                next: None,
+                max_peers: Some(1),
+                channels: Some(2),
+            })
+        );
 
         let mut client_b = warp::test::ws()
-            .path("/room_a")
+            .path("/arena-1")
             .handshake(api)
             .await
             .expect("handshake");
-
         client_b
             .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
             .await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::Error(SignallingErrorCode::RoomFull)
+        );
+        client_b.recv_closed().await.expect("closed");
+    }
 
-        let a_msg = client_a.recv().await;
-        let new_peer_event: PeerEvent =
-            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
-
-        let peer_uuid = match new_peer_event {
-            PeerEvent::NewPeer(peer) => peer,
-            _ => panic!("unexpected event"),
-        };
+    #[tokio::test]
+    async fn event_log_is_replayed_to_late_joiners() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "lobby".to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: Some(2),
+            strict_version: false,
+            client_versions: None,
+            listed: false,
+        }]);
+        let state = Arc::new(Mutex::new(state));
+        let api = super::ws_filter(state.clone());
 
+        let mut client_a = warp::test::ws()
+            .path("/lobby")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
         client_a
-            .send(Message::text(format!(
-                "{{\"Signal\": {{\"receiver\": \"{}\", \"data\": \"123\" }}}}",
-                peer_uuid
-            )))
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
             .await;
+        for data in ["one", "two", "three"] {
+            client_a
+                .send(Message::text(format!(r#"{{"RoomMessage": "{data}"}}"#)))
+                .await;
+        }
+        // wait for the server to log the last one
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                let logged = state
+                    .lock()
+                    .await
+                    .event_logs
+                    .get(&RoomId("lobby".to_string()))
+                    .and_then(|log| log.back().cloned());
+                if logged.map(|(_, data)| data).as_deref() == Some("three") {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("messages weren't logged");
 
-        let b_msg = client_b.recv().await;
-        let signal_event: PeerEvent =
-            serde_json::from_str(b_msg.unwrap().to_str().unwrap()).unwrap();
+        let mut client_b = warp::test::ws()
+            .path("/lobby")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+        assert!(matches!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::RoomPolicy(_)
+        ));
+        for data in ["two", "three"] {
+            assert_eq!(
+                recv_peer_event(&mut client_b).await,
+                PeerEvent::RoomMessage {
+                    sender: "uuid-a".to_string(),
+                    data: data.to_string(),
+                }
+            );
+        }
 
+        // live messages are relayed to everyone else in the room
+        client_b
+            .send(Message::text(r#"{"RoomMessage": "four"}"#.to_string()))
+            .await;
+        assert!(matches!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomPolicy(_)
+        ));
         assert_eq!(
-            signal_event,
-            PeerEvent::Signal {
-                data: serde_json::Value::String("123".to_string()),
-                sender: "uuid-a".to_string(),
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomMessage {
+                sender: "uuid-b".to_string(),
+                data: "four".to_string(),
             }
         );
+
+        // the log is gone once everyone left
+        client_a.send(Message::close()).await;
+        client_b.send(Message::close()).await;
+        client_a.recv_closed().await.expect("closed");
+        client_b.recv_closed().await.expect("closed");
+        time::timeout(Duration::from_secs(5), async {
+            while !state.lock().await.event_logs.is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("event log wasn't dropped");
     }
 
-    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
-        let message = client.recv().await;
-        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
+    #[tokio::test]
+    async fn room_metadata_and_strict_versions() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "arena".to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: None,
+            strict_version: true,
+            client_versions: None,
+            listed: true,
+        }]);
+        let state = Arc::new(Mutex::new(state));
+        let api = super::ws_filter(state.clone());
+        let join = |version: &'static str, uuid: &'static str| {
+            let api = api.clone();
+            async move {
+                let mut client = warp::test::ws()
+                    .path("/arena")
+                    .handshake(api)
+                    .await
+                    .expect("handshake");
+                let version = format!(r#"{{"Version": "{version}"}}"#);
+                client.send(Message::text(version)).await;
+                if uuid == "uuid-a" {
+                    let metadata = r#"{"SetRoomMetadata": {"game_mode": "ctf"}}"#;
+                    client.send(Message::text(metadata)).await;
+                }
+                client
+                    .send(Message::text(format!(r#"{{"Uuid": "{uuid}"}}"#)))
+                    .await;
+                client
+            }
+        };
+        let metadata = RoomMetadata {
+            game_mode: Some("ctf".to_string()),
+            map: None,
+            version: Some("1.0".to_string()),
+        };
+
+        // the creator's version is required unless the metadata says otherwise
+        let mut client_a = join("1.0", "uuid-a").await;
+        assert!(matches!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomPolicy(_)
+        ));
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomMetadata(metadata.clone())
+        );
+
+        let mut client_b = join("1.1", "uuid-b").await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::Error(SignallingErrorCode::VersionMismatch)
+        );
+        client_b.recv_closed().await.expect("closed");
+
+        let mut client_c = join("1.0", "uuid-c").await;
+        assert!(matches!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::RoomPolicy(_)
+        ));
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::RoomMetadata(metadata.clone())
+        );
+
+        let rooms = warp::test::request()
+            .path("/rooms")
+            .reply(&crate::rooms::rooms_filter(state))
+            .await;
+        let rooms: serde_json::Value = serde_json::from_slice(rooms.body()).unwrap();
+        assert_eq!(
+            rooms,
+            serde_json::json!([{
+                "id": "arena",
+                "peers": 2,
+                "metadata": metadata,
+            }])
+        );
+    }
+
+    #[test]
+    fn messages_to_a_stalled_peer_are_bounded() {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let sender = PeerSender::new(tx, 2);
+        assert!(sender.send(Ok(Message::text("1"))).is_ok());
+        assert!(sender.send(Ok(Message::text("2"))).is_ok());
+        assert!(!sender.overflowed());
+        assert!(sender.send(Ok(Message::text("3"))).is_err());
+        assert!(sender.overflowed());
+    }
+
+    #[test]
+    fn only_listed_rooms_are_listed() {
+        let mut state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "public-*".to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: None,
+            strict_version: false,
+            client_versions: None,
+            listed: true,
+        }]);
+        for (id, room) in [("uuid-a", "public-1"), ("uuid-b", "invite-1")] {
+            state.add_peer(Peer {
+                uuid: id.to_string(),
+                room: RequestedRoom {
+                    id: RoomId(room.to_string()),
+                    next: None,
+                },
+                sender: PeerSender::new(tokio::sync::mpsc::unbounded_channel().0, 0),
+                joined_at: Instant::now(),
+                signalled: false,
+                addr: None,
+                name: None,
+                version: None,
+                capabilities: vec![],
+                signals_sent: Default::default(),
+                candidates_sent: Default::default(),
+                offers_sent: Default::default(),
+                params: Default::default(),
+                role: PeerRole::Player,
+            });
+        }
+        assert_eq!(
+            state.room_listing(),
+            vec![(RoomId("public-1".to_string()), 1, None)]
+        );
     }
 
     #[tokio::test]
-    async fn match_pairs() {
+    async fn peer_names() {
         let _ = pretty_env_logger::try_init();
         let api = api();
 
         let mut client_a = warp::test::ws()
-            .path("/room_name?next=2")
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
 
+        client_a
+            .send(Message::text(r#"{"Name": " alice\n"}"#.to_string()))
+            .await;
         client_a
             .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
             .await;
 
+        let own_name = recv_peer_event(&mut client_a).await;
+        assert_eq!(
+            own_name,
+            PeerEvent::PeerName {
+                peer: "uuid-a".to_string(),
+                name: "alice".to_string()
+            }
+        );
+
         let mut client_b = warp::test::ws()
-            .path("/room_name?next=2")
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        client_b
+            .send(Message::text(r#"{"Name": "alice"}"#.to_string()))
+            .await;
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+
+        // b is told its own (deduplicated) name, and the names of existing peers
+        let b_name = PeerEvent::PeerName {
+            peer: "uuid-b".to_string(),
+            name: "alice (2)".to_string(),
+        };
+        assert_eq!(recv_peer_event(&mut client_b).await, b_name);
+        assert_eq!(recv_peer_event(&mut client_b).await, own_name);
+
+        // a is told b's name before it is announced
+        assert_eq!(recv_peer_event(&mut client_a).await, b_name);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_capabilities() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        client_a
+            .send(Message::text(
+                r#"{"Capabilities": ["voice", "", "voice", "zstd"]}"#.to_string(),
+            ))
+            .await;
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+        // make sure a joined first
+        time::sleep(Duration::from_millis(50)).await;
 
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Capabilities": ["zstd"]}"#.to_string()))
+            .await;
         client_b
             .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
             .await;
 
-        let mut client_c = warp::test::ws()
-            .path("/room_name?next=2")
+        // b learns the capabilities of existing peers, a those of b before it
+        // is announced
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::PeerCapabilities {
+                peer: "uuid-a".to_string(),
+                capabilities: vec!["voice".to_string(), "zstd".to_string()],
+            }
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerCapabilities {
+                peer: "uuid-b".to_string(),
+                capabilities: vec!["zstd".to_string()],
+            }
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+    }
+
+    async fn join(
+        api: &(impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static),
+        path: &str,
+        requests: &[&str],
+    ) -> WsClient {
+        let mut client = warp::test::ws()
+            .path(path)
             .handshake(api.clone())
             .await
             .expect("handshake");
+        for request in requests {
+            client.send(Message::text(request.to_string())).await;
+        }
+        client
+    }
+
+    #[tokio::test]
+    async fn reserved_slots_are_held_for_claiming_peers() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut host = join(
+            &api,
+            "/game?next=3",
+            &[r#"{"Uuid": "host"}"#, r#"{"ReserveSlots": ["friend"]}"#],
+        )
+        .await;
+        time::sleep(Duration::from_millis(50)).await;
+
+        // one slot is left for strangers
+        let mut client_a = join(&api, "/game?next=3", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut host).await,
+            PeerEvent::NewPeer("uuid-a".to_string())
+        );
+        let mut client_b = join(&api, "/game?next=3", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut friend = join(
+            &api,
+            "/game?next=3",
+            &[r#"{"ClaimSlot": "friend"}"#, r#"{"Uuid": "friend"}"#],
+        )
+        .await;
+        assert_eq!(
+            recv_peer_event(&mut host).await,
+            PeerEvent::NewPeer("friend".to_string())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("friend".to_string())
+        );
+
+        // b waits for the next group
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut host) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_a) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut client_b) => panic!("unexpected message"),
+            _ = recv_peer_event(&mut friend) => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_reservations_let_held_peers_in() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_limits(crate::Limits {
+            reservation_secs: 1,
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
+
+        let mut host = join(
+            &api,
+            "/game?next=2",
+            &[r#"{"ReserveSlots": ["friend"]}"#, r#"{"Uuid": "host"}"#],
+        )
+        .await;
+        time::sleep(Duration::from_millis(50)).await;
+
+        let _client_a = join(&api, "/game?next=2", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let timeout = time::sleep(Duration::from_millis(500));
+        pin_mut!(timeout);
+        select! {
+            _ = recv_peer_event(&mut host) => panic!("stranger took the reserved slot"),
+            _ = &mut timeout => {}
+        }
+
+        assert_eq!(
+            recv_peer_event(&mut host).await,
+            PeerEvent::NewPeer("uuid-a".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_held_before_joining_are_capped() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state()));
+        let api = super::ws_filter(state.clone());
 
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
+        let reservations = r#"{"ReserveSlots": ["a", "b", "c", "d", "e"]}"#.to_string();
+        let extra_rooms: Vec<_> = (0..super::MAX_EXTRA_ROOMS + 4)
+            .map(|i| format!(r#"{{"JoinExtraRoom": "extra-{i}"}}"#))
+            .collect();
+        let mut requests = vec![reservations.as_str()];
+        requests.extend(extra_rooms.iter().map(String::as_str));
+        requests.push(r#"{"Uuid": "host"}"#);
+        let _host = join(&api, "/game?next=3", &requests).await;
+        time::sleep(Duration::from_millis(50)).await;
 
-        let mut client_d = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        let state = state.lock().await;
+        let room = RequestedRoom {
+            id: RoomId("game".to_string()),
+            next: Some(3),
+        };
+        // the host takes one of the three slots
+        let reserved: Vec<_> = state.reservations[&room]
+            .iter()
+            .map(|(token, _)| token.as_str())
+            .collect();
+        assert_eq!(reserved, ["a", "b"]);
+        let joined = state
+            .extra_rooms
+            .values()
+            .filter(|peers| peers.contains("host"))
+            .count();
+        assert_eq!(joined, super::MAX_EXTRA_ROOMS);
+    }
 
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
-            .await;
+    #[tokio::test]
+    async fn introductions_are_paced() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state().with_limits(crate::Limits {
+            introductions_per_sec: 10,
+            ..Default::default()
+        })));
+        let api = super::ws_filter(state.clone());
 
-        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
-        let new_peer_b = recv_peer_event(&mut client_a).await;
-        let new_peer_d = recv_peer_event(&mut client_c).await;
+        let mut client_a = join(&api, "/paced", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut client_b = join(&api, "/paced", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+        let mut client_c = join(&api, "/paced", &[r#"{"Uuid": "uuid-c"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::IntroductionsPending(1)
+        );
+        let start = Instant::now();
 
-        assert_eq!(new_peer_b, PeerEvent::NewPeer("uuid-b".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        // one of the peers is told right away, the other one a bit later
+        let (mut first, mut second) = select! {
+            event = recv_peer_event(&mut client_a) => {
+                assert_eq!(event, PeerEvent::NewPeer("uuid-c".to_string()));
+                (client_a, client_b)
+            }
+            event = recv_peer_event(&mut client_b) => {
+                assert_eq!(event, PeerEvent::NewPeer("uuid-c".to_string()));
+                (client_b, client_a)
+            }
+        };
+        state.lock().await.pace_introductions(start);
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            event = recv_peer_event(&mut second) => panic!("introduced too early: {:?}", event),
+            _ = &mut timeout => {}
+        }
+
+        state
+            .lock()
+            .await
+            .pace_introductions(start + Duration::from_millis(100));
+        assert_eq!(
+            recv_peer_event(&mut second).await,
+            PeerEvent::NewPeer("uuid-c".to_string())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::IntroductionsPending(0)
+        );
+        assert!(state.lock().await.introductions.is_empty());
 
+        // nobody is introduced twice
         let timeout = time::sleep(Duration::from_millis(100));
         pin_mut!(timeout);
         select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = client_d.recv() => panic!("unexpected message"),
+            event = recv_peer_event(&mut first) => panic!("unexpected event {:?}", event),
             _ = &mut timeout => {}
         }
     }
+
     #[tokio::test]
-    async fn match_pair_and_other_alone_room_without_next() {
+    async fn idle_rooms_are_closed_after_a_warning() {
         let _ = pretty_env_logger::try_init();
-        let api = api();
+        let state = Arc::new(Mutex::new(test_state().with_limits(crate::Limits {
+            room_idle_secs: 60,
+            room_idle_warning_secs: 10,
+            ..Default::default()
+        })));
+        let api = super::ws_filter(state.clone());
 
-        let mut client_a = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
+        let mut client_a = join(&api, "/idle", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut client_b = join(&api, "/idle", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        recv_peer_event(&mut client_a).await;
+        let start = Instant::now();
+
+        state
+            .lock()
             .await
-            .expect("handshake");
+            .close_idle_rooms(start + Duration::from_secs(45));
+        state
+            .lock()
+            .await
+            .close_idle_rooms(start + Duration::from_secs(55));
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::RoomIdle { closes_in_secs: 5 }
+            );
+        }
 
+        // keep-alives don't count as activity
         client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .send(Message::text(r#""KeepAlive""#.to_string()))
             .await;
+        time::sleep(Duration::from_millis(50)).await;
+        state
+            .lock()
+            .await
+            .close_idle_rooms(start + Duration::from_secs(61));
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::RoomClosed { host: None }
+            );
+        }
+    }
 
-        let mut client_b = warp::test::ws()
-            .path("/room_name")
-            .handshake(api.clone())
+    #[tokio::test]
+    async fn keeping_a_room_alive_resets_its_idle_time() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state().with_limits(crate::Limits {
+            room_idle_secs: 60,
+            room_idle_warning_secs: 10,
+            ..Default::default()
+        })));
+        let api = super::ws_filter(state.clone());
+
+        let mut client_a = join(&api, "/idle", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        state
+            .lock()
             .await
-            .expect("handshake");
+            .close_idle_rooms(start + Duration::from_secs(55));
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomIdle { closes_in_secs: 5 }
+        );
 
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+        client_a
+            .send(Message::text(r#""KeepRoomAlive""#.to_string()))
             .await;
-
-        let mut client_c = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
+        time::sleep(Duration::from_millis(50)).await;
+        let kept_alive = Instant::now();
+        // warned again, as it's a different idle period
+        state
+            .lock()
             .await
-            .expect("handshake");
+            .close_idle_rooms(kept_alive + Duration::from_secs(55));
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomIdle { closes_in_secs: 5 }
+        );
+    }
 
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+    #[tokio::test]
+    async fn matchmaking_queue_groups_peers_into_rooms() {
+        let _ = pretty_env_logger::try_init();
+        let region = MatchmakingRegion {
+            name: "eu".to_string(),
+            stun_url: "stun:stun.eu.example.com:3478".to_string(),
+        };
+        let state = test_state().with_matchmaking(crate::Matchmaking {
+            regions: vec![region.clone()],
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
+
+        let mut client_a = join(&api, "/queue", &[r#""JoinQueue""#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::MatchmakingRegions(vec![region])
+        );
+        client_a
+            .send(Message::text(r#"{"Latency": {"eu": 20}}"#.to_string()))
             .await;
 
-        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
-        let new_peer_c = recv_peer_event(&mut client_a).await;
+        let mut client_b = join(
+            &api,
+            "/queue",
+            &[r#""JoinQueue""#, r#"{"Latency": {"eu": 30}}"#],
+        )
+        .await;
+        recv_peer_event(&mut client_b).await;
 
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
+        let room = match recv_peer_event(&mut client_a).await {
+            PeerEvent::MatchFound { room } => room,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert!(room.starts_with("match-"));
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::MatchFound { room }
+        );
+    }
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = &mut timeout => {}
+    #[tokio::test]
+    async fn turn_credentials_are_sent_on_join() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_turn(crate::Turn {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
+
+        let mut client = join(&api, "/room", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        match recv_peer_event(&mut client).await {
+            PeerEvent::IceServers(servers) => {
+                assert_eq!(servers.len(), 1);
+                assert_eq!(servers[0].urls, ["turn:turn.example.com:3478"]);
+                let username = servers[0].username.as_ref().unwrap();
+                assert!(username.ends_with(":uuid-a"));
+                assert!(servers[0].credential.is_some());
+            }
+            event => panic!("unexpected event {:?}", event),
         }
     }
 
     #[tokio::test]
-    async fn match_different_id_same_next() {
+    async fn queueing_without_matchmaking_disconnects() {
+        let _ = pretty_env_logger::try_init();
+        let mut client = join(&api(), "/queue", &[r#""JoinQueue""#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client).await,
+            PeerEvent::Error(SignallingErrorCode::ProtocolMismatch)
+        );
+        client.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn migrate_room() {
         let _ = pretty_env_logger::try_init();
         let api = api();
 
         let mut client_a = warp::test::ws()
-            .path("/scope_1?next=2")
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
 
         let mut client_b = warp::test::ws()
-            .path("/scope_2?next=2")
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
 
-        let mut client_c = warp::test::ws()
-            .path("/scope_1?next=2")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
 
-        let mut client_d = warp::test::ws()
-            .path("/scope_2?next=2")
-            .handshake(api.clone())
+        let mut client_c = warp::test::ws()
+            .path("/game?next=3")
+            .handshake(api)
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
         client_c
             .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
             .await;
+
+        // make sure the server has processed c's uuid
+        time::sleep(Duration::from_millis(50)).await;
+
+        // only the peer that created the room may migrate it
         client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .send(Message::text(
+                r#"{"MigrateRoom": {"room": "elsewhere", "next": null}}"#.to_string(),
+            ))
             .await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::MigrationRejected(SignallingErrorCode::Unauthorized)
+        );
 
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
+        client_a
+            .send(Message::text(
+                r#"{"MigrateRoom": {"room": "game", "next": 3}}"#.to_string(),
+            ))
             .await;
 
-        // Clients should be matched in pairs as they arrive, i.e. a + c and b + d
-        let new_peer_c = recv_peer_event(&mut client_a).await;
-        let new_peer_d = recv_peer_event(&mut client_b).await;
-
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        // c is introduced to both migrated peers, which stay connected to each other
+        let mut new_peers = vec![
+            recv_peer_event(&mut client_c).await,
+            recv_peer_event(&mut client_c).await,
+        ];
+        new_peers.sort_by_key(|event| format!("{event:?}"));
+        assert_eq!(
+            new_peers,
+            vec![
+                PeerEvent::NewPeer("uuid-a".to_string()),
+                PeerEvent::NewPeer("uuid-b".to_string())
+            ]
+        );
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = client_d.recv() => panic!("unexpected message"),
-            _ = &mut timeout => {}
-        }
+        let migrated = PeerEvent::RoomMigrated {
+            room: "game".to_string(),
+            next: Some(3),
+        };
+        assert_eq!(recv_peer_event(&mut client_a).await, migrated);
+        assert_eq!(recv_peer_event(&mut client_b).await, migrated);
     }
+
     #[tokio::test]
-    async fn match_same_id_different_next() {
+    async fn migrating_into_a_full_room_is_rejected() {
         let _ = pretty_env_logger::try_init();
-        let api = api();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "arena".to_string(),
+            next: None,
+            max_peers: Some(2),
+            channels: None,
+            event_log: None,
+            strict_version: false,
+            client_versions: None,
+            listed: false,
+        }]);
+        let state = Arc::new(Mutex::new(state));
+        let api = super::ws_filter(state.clone());
+        let join = |room: &'static str, uuid: &'static str| {
+            let api = api.clone();
+            async move {
+                let mut client = warp::test::ws()
+                    .path(room)
+                    .handshake(api)
+                    .await
+                    .expect("handshake");
+                client
+                    .send(Message::text(format!(r#"{{"Uuid": "{uuid}"}}"#)))
+                    .await;
+                // make sure the server has processed the uuid
+                time::sleep(Duration::from_millis(50)).await;
+                client
+            }
+        };
+        let _client_a = join("/arena", "uuid-a").await;
+        let mut client_b = join("/lobby", "uuid-b").await;
+        let _client_c = join("/lobby", "uuid-c").await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::NewPeer("uuid-c".to_string())
+        );
 
-        let mut client_a = warp::test::ws()
-            .path("/scope_1?next=2")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        client_b
+            .send(Message::text(
+                r#"{"MigrateRoom": {"room": "arena", "next": null}}"#.to_string(),
+            ))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::MigrationRejected(SignallingErrorCode::RoomFull)
+        );
 
-        let mut client_b = warp::test::ws()
-            .path("/scope_1?next=3")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        // nobody moved
+        let state = state.lock().await;
+        assert_eq!(
+            state.room_peers(&RoomId("lobby".to_string())),
+            vec!["uuid-b", "uuid-c"]
+        );
+    }
 
-        let mut client_c = warp::test::ws()
-            .path("/scope_1?next=2")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+    #[tokio::test]
+    async fn client_versions_outside_the_accepted_range_are_rejected() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "arena".to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: None,
+            strict_version: false,
+            client_versions: Some(">=1.2, <2".parse().unwrap()),
+            listed: false,
+        }]);
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
 
-        let mut client_d = warp::test::ws()
-            .path("/scope_1?next=3")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        for (version, accepted) in [(Some("1.3.0"), true), (Some("2.0.0"), false), (None, false)] {
+            let mut client = warp::test::ws()
+                .path("/arena")
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            if let Some(version) = version {
+                let version = format!(r#"{{"Version": "{version}"}}"#);
+                client.send(Message::text(version)).await;
+            }
+            client
+                .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+                .await;
+            let event = recv_peer_event(&mut client).await;
+            if accepted {
+                assert!(matches!(event, PeerEvent::RoomPolicy(_)), "{:?}", version);
+                client.send(Message::close()).await;
+            } else {
+                assert_eq!(
+                    event,
+                    PeerEvent::Error(SignallingErrorCode::VersionMismatch),
+                    "{version:?}"
+                );
+            }
+            client.recv_closed().await.expect("closed");
+        }
+    }
 
-        let mut client_e = warp::test::ws()
-            .path("/scope_1?next=3")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+    #[tokio::test]
+    async fn migrating_peers_outside_the_accepted_range_is_rejected() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_room_rules(vec![RoomRule {
+            pattern: "arena".to_string(),
+            next: None,
+            max_peers: None,
+            channels: None,
+            event_log: None,
+            strict_version: false,
+            client_versions: Some(">=1.2, <2".parse().unwrap()),
+            listed: false,
+        }]);
+        let state = Arc::new(Mutex::new(state));
+        let api = super::ws_filter(state.clone());
+        let join = |version: &'static str, uuid: &'static str| {
+            let api = api.clone();
+            async move {
+                let mut client = warp::test::ws()
+                    .path("/lobby")
+                    .handshake(api)
+                    .await
+                    .expect("handshake");
+                client
+                    .send(Message::text(format!(r#"{{"Version": "{version}"}}"#)))
+                    .await;
+                client
+                    .send(Message::text(format!(r#"{{"Uuid": "{uuid}"}}"#)))
+                    .await;
+                // make sure the server has processed the uuid
+                time::sleep(Duration::from_millis(50)).await;
+                client
+            }
+        };
+        let mut client_a = join("1.3.0", "uuid-a").await;
+        let _client_b = join("2.0.0", "uuid-b").await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
 
         client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .send(Message::text(
+                r#"{"MigrateRoom": {"room": "arena", "next": null}}"#.to_string(),
+            ))
             .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::MigrationRejected(SignallingErrorCode::VersionMismatch)
+        );
+        let state = state.lock().await;
+        assert!(state.room_peers(&RoomId("arena".to_string())).is_empty());
+    }
 
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
-            .await;
+    #[tokio::test]
+    async fn busy_server_turns_peers_away() {
+        let _ = pretty_env_logger::try_init();
+        let state = test_state().with_limits(crate::Limits {
+            max_peers: 1,
+            busy_retry_after_secs: 30,
+            ..Default::default()
+        });
+        let api = super::ws_filter(Arc::new(Mutex::new(state)));
 
-        client_e
-            .send(Message::text(r#"{"Uuid": "uuid-e"}"#.to_string()))
-            .await;
+        let _client_a = join(&api, "/room", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut client_b = join(&api, "/room", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::Error(SignallingErrorCode::ServerBusy {
+                retry_after_secs: 30
+            })
+        );
+        client_b.recv_closed().await.expect("closed");
+    }
 
-        // Clients should be matched in pairs as they arrive, i.e. a + c and (b + d ; b + e ; d + e)
-        let new_peer_c = recv_peer_event(&mut client_a).await;
-        let new_peer_d = recv_peer_event(&mut client_b).await;
-        let new_peer_e = recv_peer_event(&mut client_b).await;
-        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
-        let new_peer_e = recv_peer_event(&mut client_d).await;
+    #[tokio::test]
+    async fn waiting_peers_are_let_in_as_rooms_close() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(test_state().with_limits(crate::Limits {
+            max_rooms: 1,
+            queue_when_busy: true,
+            ..Default::default()
+        })));
+        let api = super::ws_filter(state.clone());
 
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
-        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
+        let mut client_a = join(&api, "/first", &[r#"{"Uuid": "uuid-a"}"#]).await;
+        let mut client_b = join(&api, "/second", &[r#"{"Uuid": "uuid-b"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::ServerQueuePosition(1)
+        );
+        let mut client_c = join(&api, "/second", &[r#"{"Uuid": "uuid-c"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::ServerQueuePosition(2)
+        );
+        // joining a room that is open doesn't need to wait
+        let mut client_d = join(&api, "/first", &[r#"{"Uuid": "uuid-d"}"#]).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-d".to_string())
+        );
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = client_d.recv() => panic!("unexpected message"),
-            _ = client_e.recv() => panic!("unexpected message"),
-            _ = &mut timeout => {}
-        }
+        client_a.send(Message::close()).await;
+        client_d.send(Message::close()).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::ServerQueuePosition(0)
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::ServerQueuePosition(1)
+        );
+        // once b opened the room, c fits in too
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::ServerQueuePosition(0)
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::NewPeer("uuid-c".to_string())
+        );
+        assert!(state.lock().await.waiting_peers.is_empty());
     }
 
     #[test]
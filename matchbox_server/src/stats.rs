@@ -0,0 +1,219 @@
+use futures::lock::Mutex;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use warp::{Filter, Rejection, Reply};
+
+use crate::{
+    admin::{authorized, handle_rejection},
+    signaling::{with_state, RoomId, State},
+};
+
+/// Maximum number of peer count samples kept per room
+const PEER_COUNT_HISTORY_LEN: usize = 100;
+
+/// How long stats are kept after a room was last used
+pub(crate) const STATS_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of empty rooms stats are kept for, the ones used longest
+/// ago are dropped first
+pub(crate) const MAX_EMPTY_ROOM_STATS: usize = 1000;
+
+/// The number of peers in a room at a given point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PeerCountSample {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub peers: usize,
+}
+
+/// Statistics collected for a single room id
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct RoomStats {
+    /// The most recent changes in peer count, oldest first
+    pub peer_count_history: VecDeque<PeerCountSample>,
+    /// Number of signals relayed between peers in the room
+    pub messages_relayed: u64,
+    /// Number of malformed requests, unknown receivers and failed sends
+    pub errors: u64,
+    /// Average time from a peer joining until it is first signalled by
+    /// another peer, i.e. until connection negotiation starts
+    pub avg_time_to_first_signal_ms: Option<u64>,
+    /// Number of websocket connections to the room that have closed
+    pub connections: u64,
+    /// Average duration of those connections
+    pub avg_connection_duration_ms: Option<u64>,
+    /// Number of peers disconnected for not reading their messages, see
+    /// [`Limits::max_queued_messages`](crate::Limits::max_queued_messages)
+    pub queue_overflows: u64,
+    /// Number of per-peer signal counters dropped because the peer they
+    /// counted signals to left the room
+    pub relays_pruned: u64,
+    #[serde(skip)]
+    first_signal_total: Duration,
+    #[serde(skip)]
+    first_signal_count: u32,
+    #[serde(skip)]
+    connection_duration_total: Duration,
+    #[serde(skip)]
+    last_active: Option<Instant>,
+}
+
+impl RoomStats {
+    /// When anything was last recorded
+    pub fn last_active(&self) -> Option<Instant> {
+        self.last_active
+    }
+
+    pub fn touch(&mut self) {
+        self.last_active = Some(Instant::now());
+    }
+
+    pub fn record_peer_count(&mut self, peers: usize) {
+        if self.peer_count_history.len() == PEER_COUNT_HISTORY_LEN {
+            self.peer_count_history.pop_front();
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.peer_count_history
+            .push_back(PeerCountSample { timestamp, peers });
+    }
+
+    pub fn record_relay(&mut self) {
+        self.messages_relayed += 1;
+    }
+
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub fn record_queue_overflow(&mut self) {
+        self.queue_overflows += 1;
+    }
+
+    pub fn record_pruned_relays(&mut self, pruned: usize) {
+        self.relays_pruned += pruned as u64;
+    }
+
+    pub fn record_first_signal(&mut self, elapsed: Duration) {
+        self.first_signal_total += elapsed;
+        self.first_signal_count += 1;
+        let avg = self.first_signal_total / self.first_signal_count;
+        self.avg_time_to_first_signal_ms = Some(avg.as_millis() as u64);
+    }
+
+    pub fn record_connection(&mut self, duration: Duration) {
+        self.connection_duration_total += duration;
+        self.connections += 1;
+        let avg = self.connection_duration_total.as_millis() / u128::from(self.connections);
+        self.avg_connection_duration_ms = Some(avg as u64);
+    }
+}
+
+/// `GET /stats` lists stats for all rooms, `GET /stats/<room>` for a single one
+///
+/// Authenticated with the admin token like the admin api, since the stats
+/// reveal every room id. Disabled if no token is configured.
+#[allow(opaque_hidden_inferred_bound)]
+pub(crate) fn stats_filter(
+    state: Arc<Mutex<State>>,
+    token: Option<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let all = warp::path::end()
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(all_stats_handler);
+    let room = warp::path!(String)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(room_stats_handler);
+    warp::path("stats")
+        .and(authorized(token))
+        .and(all.or(room))
+        .recover(handle_rejection)
+}
+
+async fn all_stats_handler(state: Arc<Mutex<State>>) -> Result<impl Reply, Infallible> {
+    let state = state.lock().await;
+    let stats = state
+        .stats()
+        .map(|(id, stats)| (id.0.clone(), stats.clone()))
+        .collect::<std::collections::HashMap<_, _>>();
+    Ok(warp::reply::json(&stats))
+}
+
+async fn room_stats_handler(
+    room: String,
+    state: Arc<Mutex<State>>,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let state = state.lock().await;
+    Ok(match state.room_stats(&RoomId(room)) {
+        Some(stats) => Box::new(warp::reply::json(stats)),
+        None => Box::new(warp::http::StatusCode::NOT_FOUND),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::{sync::Arc, time::Duration};
+    use warp::http::StatusCode;
+
+    use super::{stats_filter, RoomStats, PEER_COUNT_HISTORY_LEN};
+
+    #[tokio::test]
+    async fn stats_require_the_admin_token() {
+        let state = Arc::new(Mutex::new(Default::default()));
+        let api = stats_filter(state.clone(), Some("secret".into()));
+        let response = warp::test::request().path("/stats").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = warp::test::request()
+            .path("/stats")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let api = stats_filter(state, None);
+        let response = warp::test::request()
+            .path("/stats/room_a")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn peer_count_history_is_bounded() {
+        let mut stats = RoomStats::default();
+        for peers in 0..PEER_COUNT_HISTORY_LEN + 10 {
+            stats.record_peer_count(peers);
+        }
+        assert_eq!(stats.peer_count_history.len(), PEER_COUNT_HISTORY_LEN);
+        assert_eq!(stats.peer_count_history.front().unwrap().peers, 10);
+    }
+
+    #[test]
+    fn average_time_to_first_signal() {
+        let mut stats = RoomStats::default();
+        assert_eq!(stats.avg_time_to_first_signal_ms, None);
+        stats.record_first_signal(Duration::from_millis(100));
+        stats.record_first_signal(Duration::from_millis(300));
+        assert_eq!(stats.avg_time_to_first_signal_ms, Some(200));
+    }
+
+    #[test]
+    fn average_connection_duration() {
+        let mut stats = RoomStats::default();
+        assert_eq!(stats.avg_connection_duration_ms, None);
+        stats.record_connection(Duration::from_secs(1));
+        stats.record_connection(Duration::from_secs(2));
+        assert_eq!(stats.connections, 2);
+        assert_eq!(stats.avg_connection_duration_ms, Some(1500));
+    }
+}
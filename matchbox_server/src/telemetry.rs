@@ -0,0 +1,131 @@
+use std::fmt::Display;
+
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    global,
+    trace::{Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+/// Name of the tracer, and the service traces are exported as
+#[cfg(feature = "otlp")]
+const SERVICE_NAME: &str = "matchbox_server";
+
+/// Exports traces to an OTLP/HTTP endpoint for as long as it's kept around,
+/// see [`start_tracing`]
+#[cfg(feature = "otlp")]
+pub struct TracingHandle {
+    provider: SdkTracerProvider,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for TracingHandle {
+    fn drop(&mut self) {
+        // flushes the spans that weren't exported yet
+        if let Err(e) = self.provider.shutdown() {
+            log::warn!("failed to export the last traces: {e}");
+        }
+    }
+}
+
+/// Starts exporting spans of the server's work to an OpenTelemetry
+/// collector, e.g. `http://localhost:4318/v1/traces`
+///
+/// Each connection is traced from the websocket upgrade until it closes,
+/// with child spans for joining a room (including the wait for space, see
+/// [`crate::Limits::queue_when_busy`]), matchmaking, relayed signals and
+/// room operations, so a slow session can be followed in Jaeger or Tempo.
+/// Spans are exported in batches, until the returned handle is dropped.
+#[cfg(feature = "otlp")]
+pub fn start_tracing(endpoint: &str) -> Result<TracingHandle, ExporterBuildError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    log::info!("exporting traces to {endpoint}");
+    Ok(TracingHandle { provider })
+}
+
+/// A span of the server's work, exported if tracing was started, see
+/// [`start_tracing`], and doing nothing without the `otlp` feature
+///
+/// Ends when dropped.
+pub(crate) struct Span {
+    #[cfg(feature = "otlp")]
+    cx: Context,
+}
+
+#[cfg(feature = "otlp")]
+impl Span {
+    /// Starts a span that isn't part of another one, e.g. for a connection
+    pub fn root(name: &'static str) -> Self {
+        Self {
+            cx: start(name, &Context::new()),
+        }
+    }
+
+    /// Starts a span for part of the work of this one
+    pub fn child(&self, name: &'static str) -> Self {
+        Self {
+            cx: start(name, &self.cx),
+        }
+    }
+
+    /// Adds an attribute, e.g. the id of the peer the work is for
+    pub fn with(self, key: &'static str, value: impl Display) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Adds an attribute to a span that was already started
+    pub fn set(&self, key: &'static str, value: impl Display) {
+        let attribute = KeyValue::new(key, value.to_string());
+        self.cx.span().set_attribute(attribute);
+    }
+
+    /// Marks the work as failed, e.g. a join that was rejected
+    pub fn fail(&self, reason: impl Display) {
+        self.cx.span().set_status(Status::error(reason.to_string()));
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.cx.span().end();
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn start(name: &'static str, parent: &Context) -> Context {
+    let span = global::tracer(SERVICE_NAME).start_with_context(name, parent);
+    parent.with_span(span)
+}
+
+#[cfg(not(feature = "otlp"))]
+impl Span {
+    pub fn root(_name: &'static str) -> Self {
+        Self {}
+    }
+
+    pub fn child(&self, _name: &'static str) -> Self {
+        Self {}
+    }
+
+    pub fn with(self, _key: &'static str, _value: impl Display) -> Self {
+        self
+    }
+
+    pub fn set(&self, _key: &'static str, _value: impl Display) {}
+
+    pub fn fail(&self, _reason: impl Display) {}
+}
@@ -0,0 +1,193 @@
+use hmac::{Hmac, Mac};
+use log::info;
+use sha1::Sha1;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_range::RelayAddressGeneratorRanges,
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+};
+use webrtc_util::vnet::net::Net;
+
+use crate::{
+    config::{Turn, TurnRelay},
+    signaling::matchbox::{IceServer, PeerId},
+};
+
+/// Realm of the embedded relay, clients learn it from the relay itself
+const REALM: &str = "matchbox";
+
+/// Creates credentials for `peer` for the TURN servers of `turn`, valid for
+/// [`Turn::credential_ttl_secs`]
+///
+/// This is coturn's REST API scheme: the username is the expiry time as a
+/// unix timestamp followed by `:` and the peer's id, and the credential is
+/// its HMAC-SHA1 with the shared secret.
+pub(crate) fn ice_server(turn: &Turn, peer: &PeerId) -> IceServer {
+    let expiry = unix_time() + turn.credential_ttl_secs;
+    let username = format!("{expiry}:{peer}");
+    IceServer {
+        urls: turn.urls(),
+        credential: Some(credential(&turn.secret, &username)),
+        username: Some(username),
+    }
+}
+
+fn credential(secret: &str, username: &str) -> String {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(username.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_secs()
+}
+
+/// Accepts the credentials minted by [`ice_server`] until they expire
+struct RestAuthHandler {
+    secret: String,
+}
+
+impl AuthHandler for RestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, turn::Error> {
+        let expiry = username.split(':').next().unwrap_or_default();
+        let expiry: u64 = expiry
+            .parse()
+            .map_err(|_| turn::Error::Other(format!("invalid username {username:?}")))?;
+        if expiry < unix_time() {
+            return Err(turn::Error::Other(format!("expired username {username:?}")));
+        }
+        let password = credential(&self.secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+/// A running TURN relay, see [`start_turn_relay`]
+pub struct TurnRelayHandle {
+    server: Server,
+}
+
+impl TurnRelayHandle {
+    /// Stops relaying, and frees the ports
+    pub async fn close(self) -> Result<(), turn::Error> {
+        self.server.close().await
+    }
+}
+
+/// Starts the TURN relay configured in [`Turn::relay`], if any
+///
+/// It accepts the credentials the signalling server hands out, and keeps
+/// running until the returned handle is closed.
+pub async fn start_turn_relay(turn: &Turn) -> Result<Option<TurnRelayHandle>, turn::Error> {
+    let relay = match turn.relay {
+        Some(relay) => relay,
+        None => return Ok(None),
+    };
+    let TurnRelay {
+        public_ip,
+        port,
+        min_port,
+        max_port,
+    } = relay;
+
+    let any: IpAddr = match public_ip {
+        IpAddr::V4(_) => [0, 0, 0, 0].into(),
+        IpAddr::V6(_) => [0u16; 8].into(),
+    };
+    let conn = Arc::new(UdpSocket::bind((any, port)).await?);
+    let server = Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorRanges {
+                relay_address: public_ip,
+                min_port,
+                max_port,
+                max_retries: 10,
+                address: any.to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: REALM.to_string(),
+        auth_handler: Arc::new(RestAuthHandler {
+            secret: turn.secret.clone(),
+        }),
+        // the default
+        channel_bind_timeout: Duration::ZERO,
+    })
+    .await?;
+    info!("Relaying TURN traffic at {}", relay.url());
+    Ok(Some(TurnRelayHandle { server }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use turn::auth::{generate_auth_key, AuthHandler};
+
+    use super::{ice_server, RestAuthHandler, REALM};
+    use crate::config::Turn;
+
+    #[test]
+    fn credentials_are_accepted_by_the_relay() {
+        let turn = Turn {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        };
+        let server = ice_server(&turn, &"peer-a".to_string());
+        assert_eq!(server.urls, turn.urls);
+        let username = server.username.unwrap();
+        let credential = server.credential.unwrap();
+        assert!(username.ends_with(":peer-a"));
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 1234).into();
+        let expected = generate_auth_key(&username, REALM, &credential);
+        let relay = RestAuthHandler {
+            secret: turn.secret.clone(),
+        };
+        assert_eq!(relay.auth_handle(&username, REALM, addr).unwrap(), expected);
+        let other_relay = RestAuthHandler {
+            secret: "other".to_string(),
+        };
+        assert_ne!(
+            other_relay.auth_handle(&username, REALM, addr).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn expired_credentials_are_rejected() {
+        let relay = RestAuthHandler {
+            secret: "s3cret".to_string(),
+        };
+        let addr: SocketAddr = ([127, 0, 0, 1], 1234).into();
+        assert!(relay.auth_handle("1000:peer-a", REALM, addr).is_err());
+        assert!(relay.auth_handle("peer-a", REALM, addr).is_err());
+    }
+
+    #[test]
+    fn credentials_match_coturn() {
+        // echo -n "1700000000:peer-a" | openssl dgst -sha1 -hmac s3cret -binary | base64
+        assert_eq!(
+            super::credential("s3cret", "1700000000:peer-a"),
+            "lcUDOT/JZasA8/Hgu20bIC/ZiRQ="
+        );
+    }
+}
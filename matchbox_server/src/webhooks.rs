@@ -0,0 +1,166 @@
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Header containing the hex encoded HMAC-SHA256 signature of the request body
+pub(crate) const SIGNATURE_HEADER: &str = "X-Matchbox-Signature";
+
+/// How long a webhook request may take before it's given up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of events waiting to be posted, further ones are dropped until the
+/// webhook catches up
+const QUEUE_SIZE: usize = 256;
+
+/// Room lifecycle events posted to the configured webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum RoomEvent {
+    /// A room was used for the first time
+    RoomCreated {
+        room: String,
+        next: Option<usize>,
+        /// Query parameters of the room url the first peer connected with
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        params: BTreeMap<String, String>,
+    },
+    /// A peer joined a room that was empty
+    FirstPeerJoined {
+        room: String,
+        next: Option<usize>,
+        peer: String,
+        /// Query parameters of the room url the peer connected with
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        params: BTreeMap<String, String>,
+    },
+    /// A `next=N` room reached N peers
+    RoomFull { room: String, next: usize },
+    /// The last peer left a room
+    RoomEmptied { room: String, next: Option<usize> },
+}
+
+/// An outbound webhook, optionally signing its requests with a shared secret
+///
+/// Events are posted one at a time, in order, by a background task.
+pub(crate) struct Webhook {
+    queue: mpsc::Sender<RoomEvent>,
+}
+
+impl Webhook {
+    /// Starts the task posting events to `url`, must be called on a tokio
+    /// runtime
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("error building webhook client");
+        let (queue, events) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(post_events(client, url, secret, events));
+        Self { queue }
+    }
+
+    /// Queues the event to be posted to the webhook
+    ///
+    /// Drops it if the webhook is too far behind.
+    pub fn notify(&self, event: RoomEvent) {
+        match self.queue.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                warn!("webhook queue is full, dropping {event:?}");
+            }
+            Err(TrySendError::Closed(event)) => error!("webhook task stopped, dropping {event:?}"),
+        }
+    }
+}
+
+async fn post_events(
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    mut events: mpsc::Receiver<RoomEvent>,
+) {
+    while let Some(event) = events.recv().await {
+        let body = serde_json::to_string(&event).expect("error serializing room event");
+        let mut request = client.post(&url).header("Content-Type", "application/json");
+        if let Some(secret) = &secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+        }
+        match request.body(body).send().await {
+            Ok(response) if response.status().is_success() => {
+                info!("webhook notified about {event:?}");
+            }
+            Ok(response) => {
+                error!("webhook returned {} for {event:?}", response.status());
+            }
+            Err(e) => error!("failed to notify webhook about {event:?}: {e:?}"),
+        }
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, RoomEvent, Webhook, SIGNATURE_HEADER};
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn event_json() {
+        let event = RoomEvent::RoomFull {
+            room: "room_a".to_string(),
+            next: 2,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"room_full","room":"room_a","next":2}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_posted_in_order() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let receiver = warp::post()
+            .and(warp::header::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: String, body: warp::hyper::body::Bytes| {
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                tx.send((signature, body)).unwrap();
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(receiver).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let webhook = Webhook::new(format!("http://{addr}/hook"), Some("key".to_string()));
+        for room in ["room_a", "room_b"] {
+            webhook.notify(RoomEvent::RoomEmptied {
+                room: room.to_string(),
+                next: None,
+            });
+        }
+        for room in ["room_a", "room_b"] {
+            let (signature, body) = rx.recv().await.unwrap();
+            assert_eq!(
+                body,
+                format!(r#"{{"event":"room_emptied","room":"{room}","next":null}}"#)
+            );
+            assert_eq!(signature, sign("key", &body));
+        }
+    }
+}
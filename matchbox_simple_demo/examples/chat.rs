@@ -0,0 +1,86 @@
+//! Chat with everyone in a room from the command line
+//!
+//! Native only. Run a signalling server on localhost, then in a few terminals:
+//!
+//! ```text
+//! cargo run --example chat -- alice
+//! cargo run --example chat -- bob
+//! ```
+//!
+//! Lines typed into one terminal show up in all the others.
+
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use log::{info, warn};
+use matchbox_socket::{ChannelConfig, PeerState, WebRtcSocket, WebRtcSocketConfig};
+use std::{io::BufRead, sync::mpsc, time::Duration};
+
+#[tokio::main]
+async fn main() {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "chat=info,matchbox_socket=warn");
+    }
+    pretty_env_logger::init();
+
+    let name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "anonymous".into());
+    let (mut socket, loop_fut) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+        room_url: "ws://localhost:3536/chat".into(),
+        // chat messages must neither get lost nor overtake each other
+        channels: vec![ChannelConfig::reliable()],
+        // so the others know who's talking
+        display_name: Some(name.clone()),
+        ..Default::default()
+    });
+    let loop_fut = loop_fut.fuse();
+    futures::pin_mut!(loop_fut);
+
+    // reading stdin blocks, so leave that to a thread
+    let (lines_tx, lines_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    info!("joined the chat as {name:?}, type away");
+    let mut chatting = vec![];
+    let timeout = Delay::new(Duration::from_millis(50));
+    futures::pin_mut!(timeout);
+    loop {
+        for peer in socket.accept_new_connections() {
+            info!("{} joined", display_name(&socket, &peer));
+        }
+        for peer in &chatting {
+            if socket.peer_state(peer) == Some(PeerState::Disconnected) {
+                warn!("{} left", display_name(&socket, peer));
+            }
+        }
+        chatting = socket.connected_peers();
+
+        for (peer, packet) in socket.receive() {
+            let text = String::from_utf8_lossy(&packet);
+            println!("{}: {text}", display_name(&socket, &peer));
+        }
+
+        while let Ok(line) = lines_rx.try_recv() {
+            for peer in socket.connected_peers() {
+                socket.send(line.as_bytes().into(), peer);
+            }
+        }
+
+        select! {
+            _ = (&mut timeout).fuse() => timeout.reset(Duration::from_millis(50)),
+            _ = &mut loop_fut => break,
+        }
+    }
+}
+
+fn display_name(socket: &WebRtcSocket, peer: &String) -> String {
+    socket
+        .peer_name(peer)
+        .map_or_else(|| peer.clone(), str::to_string)
+}
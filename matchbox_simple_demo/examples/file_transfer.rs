@@ -0,0 +1,212 @@
+//! Send a file to another peer, in chunks, with progress
+//!
+//! Native only. Run a signalling server on localhost, then:
+//!
+//! ```text
+//! cargo run --example file_transfer -- receive
+//! cargo run --example file_transfer -- send path/to/file
+//! ```
+//!
+//! The receiver saves the file in the current directory, prefixed with
+//! `received_`.
+
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use log::{error, info};
+use matchbox_socket::{ChannelConfig, WebRtcSocket, WebRtcSocketConfig};
+use std::{fs, path::Path, time::Duration};
+
+/// Packets start with one of these
+const HEADER: u8 = 0;
+const CHUNK: u8 = 1;
+const DONE: u8 = 2;
+
+/// Largest chunk we send, even if the peer accepts larger messages, since
+/// browsers have trouble with larger ones
+const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunks queued per tick, so we don't queue the whole file at once
+const CHUNKS_PER_TICK: usize = 16;
+
+#[tokio::main]
+async fn main() {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "file_transfer=info,matchbox_socket=warn");
+    }
+    pretty_env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let file = match args.as_slice() {
+        [mode] if mode == "receive" => None,
+        [mode, path] if mode == "send" => Some(path.clone()),
+        _ => {
+            eprintln!("usage: file_transfer receive | file_transfer send <path>");
+            return;
+        }
+    };
+
+    let (mut socket, loop_fut) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+        room_url: "ws://localhost:3536/file_transfer?next=2".into(),
+        channels: vec![ChannelConfig::reliable()],
+        // let the last chunks go out before we hang up
+        flush_timeout_ms: 5000,
+        ..Default::default()
+    });
+    let loop_fut = loop_fut.fuse();
+    futures::pin_mut!(loop_fut);
+
+    info!("waiting for a peer");
+    let peer = select! {
+        peers = socket.wait_for_peers(1).fuse() => match peers {
+            Ok(peers) => peers[0].clone(),
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        },
+        _ = &mut loop_fut => return,
+    };
+    info!("connected to {peer:?}");
+
+    let mut transfer = match file {
+        Some(path) => Transfer::Send(Sender::new(&mut socket, &peer, &path)),
+        None => Transfer::Receive(Receiver::default()),
+    };
+
+    let timeout = Delay::new(Duration::from_millis(50));
+    futures::pin_mut!(timeout);
+    loop {
+        socket.accept_new_connections();
+        let done = match &mut transfer {
+            Transfer::Send(sender) => sender.update(&mut socket, &peer),
+            Transfer::Receive(receiver) => receiver.update(&mut socket, &peer),
+        };
+        if done {
+            break;
+        }
+
+        select! {
+            _ = (&mut timeout).fuse() => timeout.reset(Duration::from_millis(50)),
+            _ = &mut loop_fut => {
+                info!("lost the connection");
+                return;
+            }
+        }
+    }
+
+    // dropping the socket sends what's still queued, as long as the message
+    // loop keeps running
+    drop(socket);
+    let _ = loop_fut.await;
+    info!("done");
+}
+
+enum Transfer {
+    Send(Sender),
+    Receive(Receiver),
+}
+
+struct Sender {
+    data: Vec<u8>,
+    chunk_size: usize,
+    next_chunk: usize,
+}
+
+impl Sender {
+    fn new(socket: &mut WebRtcSocket, peer: &String, path: &str) -> Self {
+        let data = fs::read(path).expect("failed to read the file");
+        let name = Path::new(path)
+            .file_name()
+            .expect("not a file")
+            .to_string_lossy();
+
+        // leave room for the packet type
+        let chunk_size = socket
+            .connection_info(peer)
+            .and_then(|info| info.max_message_size)
+            .unwrap_or(MAX_CHUNK_SIZE)
+            .min(MAX_CHUNK_SIZE)
+            - 1;
+
+        let mut header = vec![HEADER];
+        header.extend_from_slice(format!("{}\n{name}", data.len()).as_bytes());
+        socket.send(header.into(), peer.clone());
+        info!("sending {name:?}, {} bytes", data.len());
+
+        Self {
+            data,
+            chunk_size,
+            next_chunk: 0,
+        }
+    }
+
+    /// Returns whether the receiver has got the whole file
+    fn update(&mut self, socket: &mut WebRtcSocket, peer: &str) -> bool {
+        let queued_before = self.next_chunk;
+        let chunks = self.data.chunks(self.chunk_size);
+        for chunk in chunks.skip(self.next_chunk).take(CHUNKS_PER_TICK) {
+            let mut packet = Vec::with_capacity(chunk.len() + 1);
+            packet.push(CHUNK);
+            packet.extend_from_slice(chunk);
+            socket.send(packet.into(), peer);
+            self.next_chunk += 1;
+        }
+        // every 64 chunks
+        if self.next_chunk / 64 > queued_before / 64 {
+            let stats = socket.channel_stats(0);
+            info!("queued {} of {} bytes", stats.bytes_sent, self.data.len());
+        }
+
+        socket
+            .receive()
+            .iter()
+            .any(|(_, packet)| packet.first() == Some(&DONE))
+    }
+}
+
+#[derive(Default)]
+struct Receiver {
+    name: String,
+    size: usize,
+    data: Vec<u8>,
+    last_percent: usize,
+}
+
+impl Receiver {
+    /// Returns whether we've got the whole file
+    fn update(&mut self, socket: &mut WebRtcSocket, peer: &str) -> bool {
+        for (_, packet) in socket.receive() {
+            match packet.split_first() {
+                Some((&HEADER, header)) => {
+                    let header = String::from_utf8_lossy(header);
+                    let (size, name) = header.split_once('\n').expect("invalid header");
+                    self.size = size.parse().expect("invalid size");
+                    // don't let the sender pick where we write to
+                    let name = Path::new(name).file_name().expect("invalid name");
+                    self.name = format!("received_{}", name.to_string_lossy());
+                    info!("receiving {:?}, {size} bytes", self.name);
+                }
+                Some((&CHUNK, chunk)) => self.data.extend_from_slice(chunk),
+                _ => {}
+            }
+        }
+        if self.name.is_empty() {
+            return false;
+        }
+
+        let percent = self.data.len() * 100 / self.size.max(1);
+        if percent / 10 > self.last_percent / 10 {
+            let stats = socket.channel_stats(0);
+            info!("{percent}%, {} packets received", stats.messages_received);
+            self.last_percent = percent;
+        }
+        if self.data.len() < self.size {
+            return false;
+        }
+
+        fs::write(&self.name, &self.data).expect("failed to write the file");
+        info!("saved {:?}", self.name);
+        socket.send(vec![DONE].into(), peer);
+        true
+    }
+}
@@ -0,0 +1,105 @@
+//! Show the round trip time to each peer, and the traffic of the channel
+//!
+//! Native only. Run a signalling server on localhost, then run this in a few
+//! terminals:
+//!
+//! ```text
+//! cargo run --example latency
+//! ```
+
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use log::info;
+use matchbox_socket::{ChannelConfig, WebRtcSocket, WebRtcSocketConfig};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Packets start with one of these, followed by the time the ping was sent,
+/// in microseconds since we started
+const PING: u8 = 0;
+const PONG: u8 = 1;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+async fn main() {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "latency=info,matchbox_socket=warn");
+    }
+    pretty_env_logger::init();
+
+    let (mut socket, loop_fut) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+        room_url: "ws://localhost:3536/latency".into(),
+        // a late ping is no use, so don't bother retransmitting it
+        channels: vec![ChannelConfig::unreliable()],
+        ..Default::default()
+    });
+    let loop_fut = loop_fut.fuse();
+    futures::pin_mut!(loop_fut);
+
+    let start = Instant::now();
+    let mut last_ping = start;
+    let mut round_trips: HashMap<String, Duration> = HashMap::new();
+
+    let timeout = Delay::new(Duration::from_millis(10));
+    futures::pin_mut!(timeout);
+    loop {
+        for state in socket.signalling_state_changes() {
+            info!("signalling server: {state:?}");
+        }
+        for peer in socket.accept_new_connections() {
+            let info = socket.connection_info(&peer);
+            info!("{peer:?} connected: {info:?}");
+        }
+        round_trips.retain(|peer, _| socket.is_connected(peer));
+
+        for (peer, packet) in socket.receive() {
+            match packet.split_first() {
+                Some((&PING, _)) => {
+                    let mut pong = packet.to_vec();
+                    pong[0] = PONG;
+                    socket.send(pong.into(), peer);
+                }
+                Some((&PONG, sent_at)) => {
+                    let sent_at = u64::from_le_bytes(sent_at.try_into().expect("invalid pong"));
+                    let now = start.elapsed().as_micros() as u64;
+                    round_trips.insert(peer, Duration::from_micros(now - sent_at));
+                }
+                _ => {}
+            }
+        }
+
+        if last_ping.elapsed() >= PING_INTERVAL {
+            last_ping = Instant::now();
+            report(&socket, &round_trips);
+
+            let mut ping = vec![PING];
+            ping.extend_from_slice(&(start.elapsed().as_micros() as u64).to_le_bytes());
+            for peer in socket.connected_peers() {
+                socket.send(ping.clone().into(), peer);
+            }
+        }
+
+        select! {
+            _ = (&mut timeout).fuse() => timeout.reset(Duration::from_millis(10)),
+            _ = &mut loop_fut => break,
+        }
+    }
+}
+
+fn report(socket: &WebRtcSocket, round_trips: &HashMap<String, Duration>) {
+    for (peer, rtt) in round_trips {
+        info!("{peer}: {rtt:?}");
+    }
+    let stats = socket.channel_stats(0);
+    info!(
+        "sent {} packets ({} bytes), received {} ({} bytes), dropped {}",
+        stats.messages_sent,
+        stats.bytes_sent,
+        stats.messages_received,
+        stats.bytes_received,
+        stats.messages_dropped + stats.send_failures,
+    );
+}
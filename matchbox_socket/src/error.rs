@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use crate::webrtc_socket::SignallingErrorCode;
+
+/// Errors that can end a [`WebRtcSocket`](crate::WebRtcSocket)'s message loop
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The signalling server closed the connection
+    #[error("disconnected by the signalling server: {0}")]
+    Signalling(#[from] SignallingError),
+    /// The signalling server sent a message that is too large or isn't json,
+    /// so we stopped listening to it
+    #[error("invalid message from the signalling server: {0}")]
+    InvalidMessage(String),
+    /// [`WebRtcSocketConfig::certificate_pem`](crate::WebRtcSocketConfig::certificate_pem)
+    /// couldn't be parsed, or a new certificate couldn't be generated
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(String),
+    /// A room url, e.g.
+    /// [`WebRtcSocketConfig::room_url`](crate::WebRtcSocketConfig::room_url),
+    /// isn't a valid websocket url
+    #[error("invalid room url {url:?}: {reason}")]
+    InvalidUrl {
+        /// The url as given
+        url: String,
+        /// What's wrong with it
+        reason: String,
+    },
+    /// A `ws://` room url was given to a socket on a page served over https,
+    /// where browsers block insecure websockets as mixed content
+    ///
+    /// Use a `wss://` url, or set
+    /// [`WebRtcSocketConfig::upgrade_insecure_signalling`](crate::WebRtcSocketConfig::upgrade_insecure_signalling).
+    #[error("{0:?} is blocked as mixed content on a page served over https, use wss:// instead")]
+    InsecureSignallingUrl(String),
+    /// The message loop panicked, with the given panic message
+    ///
+    /// Only on native, panics abort on wasm.
+    #[error("the message loop panicked: {0}")]
+    MessageLoopPanicked(String),
+    /// The message loop isn't running anymore, so packets and requests to
+    /// the signalling server can't be sent
+    #[error("the message loop has stopped")]
+    MessageLoopStopped,
+    /// A packet was sent on a channel that isn't opened with its peer, see
+    /// [`ChannelConfig::required_capability`](crate::ChannelConfig::required_capability)
+    ///
+    /// Only returned by sends, the message loop keeps running.
+    #[error("channel {channel} isn't opened with peer {peer}")]
+    ChannelNotOpen {
+        /// The peer the packet was meant for
+        peer: String,
+        /// The index of the channel in
+        /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+        channel: usize,
+    },
+    /// The connection to the signalling server couldn't be established, or
+    /// was lost
+    ///
+    /// Retried according to
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`](crate::WebRtcSocketConfig::signalling_reconnect_attempts).
+    #[error("connection to the signalling server failed: {0}")]
+    SignallingConnection(String),
+    /// Connecting to the signalling server took longer than
+    /// [`WebRtcSocketConfig::signalling_timeout_ms`](crate::WebRtcSocketConfig::signalling_timeout_ms)
+    #[error("timed out connecting to the signalling server")]
+    SignallingTimeout,
+    /// The first handshake with the given peer took longer than
+    /// [`WebRtcSocketConfig::peer_connect_timeout_ms`](crate::WebRtcSocketConfig::peer_connect_timeout_ms),
+    /// before we connected to anyone in the room
+    #[error("timed out connecting to peer {0}")]
+    PeerConnectTimeout(String),
+    /// A request wasn't answered within
+    /// [`WebRtcSocketConfig::request_timeout_ms`](crate::WebRtcSocketConfig::request_timeout_ms)
+    ///
+    /// Only returned by [`ChannelSender::request`](crate::ChannelSender::request),
+    /// the message loop keeps running.
+    #[error("request to peer {peer} on channel {channel} timed out")]
+    RequestTimeout {
+        /// The peer the request was sent to
+        peer: String,
+        /// The index of the channel in
+        /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+        channel: usize,
+    },
+    /// A request can't be answered, because the peer disconnected or the
+    /// channel doesn't have
+    /// [`ChannelConfig::requests`](crate::ChannelConfig::requests) enabled
+    ///
+    /// Only returned by [`ChannelSender::request`](crate::ChannelSender::request),
+    /// the message loop keeps running.
+    #[error("request to peer {peer} on channel {channel} failed")]
+    RequestFailed {
+        /// The peer the request was sent to
+        peer: String,
+        /// The index of the channel in
+        /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+        channel: usize,
+    },
+    /// The host answered [`Room::await_initial_state`](crate::Room::await_initial_state)
+    /// with a message that couldn't be deserialized
+    ///
+    /// Only returned by it, the message loop keeps running.
+    #[error("invalid room state from host {host}: {reason}")]
+    InvalidState {
+        /// The host that sent the state
+        host: String,
+        /// Why it couldn't be deserialized
+        reason: String,
+    },
+}
+
+impl Error {
+    /// Whether connecting to the signalling server again may succeed, e.g.
+    /// after a network error, see [`SignallingError::is_retryable`]
+    ///
+    /// These errors are retried according to
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`](crate::WebRtcSocketConfig::signalling_reconnect_attempts),
+    /// all others end the message loop right away.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Signalling(e) => e.is_retryable(),
+            Error::SignallingConnection(_) | Error::SignallingTimeout => true,
+            Error::InvalidMessage(_)
+            | Error::InvalidCertificate(_)
+            | Error::InvalidUrl { .. }
+            | Error::InsecureSignallingUrl(_)
+            | Error::MessageLoopPanicked(_)
+            | Error::MessageLoopStopped
+            | Error::ChannelNotOpen { .. }
+            | Error::PeerConnectTimeout(_)
+            | Error::RequestTimeout { .. }
+            | Error::RequestFailed { .. }
+            | Error::InvalidState { .. } => false,
+        }
+    }
+}
+
+/// Reasons for the signalling server to close the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SignallingError {
+    /// The room has no space for more peers
+    #[error("the room is full")]
+    RoomFull,
+    /// The server requires credentials we don't have
+    #[error("unauthorized")]
+    Unauthorized,
+    /// The server doesn't understand our requests, it may be running an
+    /// incompatible version
+    #[error("protocol mismatch")]
+    ProtocolMismatch,
+    /// We sent too many requests
+    #[error("rate limited")]
+    RateLimited,
+    /// The server is shutting down
+    #[error("the server is shutting down")]
+    ServerShutdown,
+    /// We were removed from the room by an admin
+    #[error("kicked from the room")]
+    Kicked,
+    /// Our id or address is banned
+    #[error("banned")]
+    Banned,
+    /// Our [`WebRtcSocketConfig::client_version`](crate::WebRtcSocketConfig::client_version)
+    /// differs from the version the room requires, or the server doesn't
+    /// accept it at all
+    #[error("incompatible client version")]
+    VersionMismatch,
+    /// Another peer connected to the server already uses our
+    /// [`WebRtcSocketConfig::peer_id`](crate::WebRtcSocketConfig::peer_id)
+    ///
+    /// Also happens when we reconnect before the server noticed our old
+    /// connection closed, so it's retried.
+    #[error("the peer id is taken")]
+    IdTaken,
+    /// Our [`WebRtcSocketConfig::peer_id`](crate::WebRtcSocketConfig::peer_id)
+    /// is empty or too long, or isn't a uuid and the server doesn't accept
+    /// custom ids
+    #[error("invalid peer id")]
+    InvalidId,
+    /// The server has as many peers or rooms as it takes, and asks us to try
+    /// again after this many seconds
+    ///
+    /// It's retried, waiting at least that long, see
+    /// [`SignallingError::retry_after`].
+    #[error("the server is busy, retry after {retry_after_secs} seconds")]
+    ServerBusy {
+        /// Seconds to wait before trying again
+        retry_after_secs: u64,
+    },
+}
+
+impl SignallingError {
+    /// Whether the server may accept us when we try again later
+    ///
+    /// Rejections like [`SignallingError::RoomFull`] or
+    /// [`SignallingError::Unauthorized`] won't go away by reconnecting, so
+    /// they aren't retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SignallingError::RateLimited
+            | SignallingError::ServerShutdown
+            | SignallingError::IdTaken
+            | SignallingError::ServerBusy { .. } => true,
+            SignallingError::RoomFull
+            | SignallingError::Unauthorized
+            | SignallingError::ProtocolMismatch
+            | SignallingError::Kicked
+            | SignallingError::Banned
+            | SignallingError::VersionMismatch
+            | SignallingError::InvalidId => false,
+        }
+    }
+
+    /// How long the server asked us to wait before trying again, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SignallingError::ServerBusy { retry_after_secs } => {
+                Some(Duration::from_secs(*retry_after_secs))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<SignallingErrorCode> for SignallingError {
+    fn from(code: SignallingErrorCode) -> Self {
+        match code {
+            SignallingErrorCode::RoomFull => SignallingError::RoomFull,
+            SignallingErrorCode::Unauthorized => SignallingError::Unauthorized,
+            SignallingErrorCode::ProtocolMismatch => SignallingError::ProtocolMismatch,
+            SignallingErrorCode::RateLimited => SignallingError::RateLimited,
+            SignallingErrorCode::ServerShutdown => SignallingError::ServerShutdown,
+            SignallingErrorCode::Kicked => SignallingError::Kicked,
+            SignallingErrorCode::Banned => SignallingError::Banned,
+            SignallingErrorCode::VersionMismatch => SignallingError::VersionMismatch,
+            SignallingErrorCode::IdTaken => SignallingError::IdTaken,
+            SignallingErrorCode::InvalidId => SignallingError::InvalidId,
+            SignallingErrorCode::ServerBusy { retry_after_secs } => {
+                SignallingError::ServerBusy { retry_after_secs }
+            }
+        }
+    }
+}
@@ -1,8 +1,29 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 
+mod error;
 #[cfg(feature = "ggrs-socket")]
 mod ggrs_socket;
+pub mod protocol;
+mod room;
+mod socket_set;
+pub mod time;
 mod webrtc_socket;
 
-pub use webrtc_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketConfig};
+pub use error::{Error, SignallingError};
+pub use room::Room;
+pub use socket_set::SocketSet;
+pub use webrtc_socket::{
+    peer_id_from_u128, peer_id_to_u128, probe_endpoint, select_best_endpoint, short_peer_id,
+    AppUserId, ApprovalFuture, BackoffPolicy, BinaryType, CandidatePreference, ChannelConfig,
+    ChannelInfo, ChannelLiveness, ChannelMut, ChannelPriority, ChannelSender, ChannelStats,
+    ChannelsMut, Congestion, CongestionLevel, ConnectFuture, ConnectionInfo, Endpoint,
+    EndpointLatency, ExtraRoomEvent, FingerprintVerifier, HandshakeValidator, IdentityMove,
+    IncomingPackets, IncomingPeer, IncomingPeerApprover, IncomingRequest, LobbyState,
+    MatchmakingRegion, MaybeSend, MaybeSendSync, Messenger, MessengerConnection, MessengerError,
+    MessengerPeer, NativeSocketConfig, PacketAction, PacketDirection, PacketHook, PacketPool,
+    PeerApproval, PeerHandshake, PeerRole, PeerState, PlatformRelay, PooledPacket, RecordedPacket,
+    Recorder, RelayMessenger, RelayPacket, Replay, ResumeEvent, RoomClosedBy, RoomInfo,
+    RoomMetadata, RtcIceServerConfig, Signaller, SignallingState, SocketDiagnostics, SocketSession,
+    WebRtcReceiver, WebRtcSender, WebRtcSocket, WebRtcSocketConfig,
+};
@@ -0,0 +1,108 @@
+//! The messages [`WebRtcSocket`](crate::WebRtcSocket) exchanges with the
+//! signalling server, for implementing other signalling servers
+//!
+//! Messages are sent as json in text websocket messages. Peers send
+//! [`PeerRequest`]s, the server sends [`PeerEvent`]s, and relays the
+//! [`PeerSignal`]s peers send each other through them. Enums are externally
+//! tagged, e.g. `{"Uuid": "8c2dd1a4-..."}` or `"KeepAlive"`.
+//!
+//! A minimal server registers a peer on [`PeerRequest::Uuid`], sends
+//! [`PeerEvent::NewPeer`] to the peers already in its room, and relays
+//! [`PeerRequest::Signal`] to the receiver as [`PeerEvent::Signal`]:
+//!
+//! ```
+//! use matchbox_socket::protocol::{PeerEvent, PeerRequest};
+//!
+//! let request: PeerRequest =
+//!     serde_json::from_str(r#"{"Signal": {"receiver": "b", "data": {"Offer": "sdp"}}}"#)
+//!         .unwrap();
+//! let event = match request {
+//!     PeerRequest::Signal { data, .. } => PeerEvent::Signal {
+//!         sender: "a".to_string(),
+//!         data,
+//!     },
+//!     _ => unimplemented!(),
+//! };
+//! assert_eq!(
+//!     serde_json::to_string(&event).unwrap(),
+//!     r#"{"Signal":{"sender":"a","data":{"Offer":"sdp"}}}"#
+//! );
+//! ```
+//!
+//! # Stability
+//!
+//! The wire format of existing messages only changes in breaking releases.
+//! New variants may be added to the enums in any release, which is why they
+//! are `#[non_exhaustive]`, so servers should ignore requests they don't
+//! understand rather than disconnecting the peer. Sockets ignore events
+//! they don't understand in the same way. The format described here is
+//! [`PROTOCOL_VERSION`].
+//!
+//! # Peers without matchbox
+//!
+//! Browser clients written in plain JavaScript can join a room and connect
+//! to [`WebRtcSocket`](crate::WebRtcSocket)s, e.g. to a Rust host. They
+//! register like a socket, and answer the offers of the peers already in the
+//! room, which send them as soon as they hear of the new peer:
+//!
+//! - Send `{"Uuid": id}` with an id of their own, after any
+//!   `{"Capabilities": [...]}`, and `"KeepAlive"` every 10 seconds.
+//! - Answer a [`PeerSignal::Offer`] with a [`PeerSignal::Answer`], both
+//!   plain SDP, and relay ICE candidates as [`PeerSignal::IceCandidate`]s
+//!   holding the json of an `RTCIceCandidateInit`.
+//! - Create the data channels negotiated, with the index in
+//!   [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+//!   as their id and the same `ordered` and `maxRetransmits`.
+//! - Advertise the capability [`CHANNELS_CAPABILITY_PREFIX`] followed by the
+//!   indices of the channels they create, if they don't create all of them.
+//!
+//! Packets arrive as binary messages, unless the channel is configured with
+//! [`ChannelConfig::text`](crate::ChannelConfig::text). Sockets talking to
+//! them can't verify [handshakes](crate::WebRtcSocketConfig::handshake_data)
+//! and shouldn't [coalesce](crate::ChannelConfig::coalesce) packets or use
+//! [requests](crate::ChannelConfig::requests). A client only using the
+//! first, reliable channel:
+//!
+//! ```js
+//! const ws = new WebSocket("wss://match.example.com/lobby");
+//! const send = (message) => ws.send(JSON.stringify(message));
+//! ws.onopen = () => {
+//!   send({ Capabilities: ["channels:0"] });
+//!   send({ Uuid: crypto.randomUUID() });
+//!   setInterval(() => send("KeepAlive"), 10000);
+//! };
+//! const connections = {};
+//! ws.onmessage = async ({ data }) => {
+//!   const signal = JSON.parse(data).Signal;
+//!   if (!signal) return;
+//!   const { sender, data: { Offer, IceCandidate } } = signal;
+//!   if (Offer) {
+//!     const pc = new RTCPeerConnection({ iceServers: [{ urls: "stun:stun.l.google.com:19302" }] });
+//!     connections[sender] = pc;
+//!     pc.onicecandidate = ({ candidate }) => candidate && send({
+//!       Signal: { receiver: sender, data: { IceCandidate: JSON.stringify(candidate) } },
+//!     });
+//!     const channel = pc.createDataChannel("game", { negotiated: true, id: 0, ordered: true });
+//!     channel.binaryType = "arraybuffer";
+//!     channel.onmessage = ({ data }) => console.log(sender, data);
+//!     await pc.setRemoteDescription({ type: "offer", sdp: Offer });
+//!     const answer = await pc.createAnswer();
+//!     await pc.setLocalDescription(answer);
+//!     send({ Signal: { receiver: sender, data: { Answer: answer.sdp } } });
+//!   } else if (IceCandidate) {
+//!     await connections[sender].addIceCandidate(JSON.parse(IceCandidate));
+//!   }
+//! };
+//! ```
+
+pub use crate::webrtc_socket::{
+    MatchmakingRegion, PeerEvent, PeerId, PeerRequest, PeerRole, PeerSignal, RoomInfo,
+    RoomMetadata, RtcIceServerConfig, SignallingErrorCode, CHANNELS_CAPABILITY_PREFIX,
+};
+
+/// Version of the messages described here, bumped whenever the wire format
+/// of an existing message changes
+///
+/// Adding messages doesn't change it, see the [stability](self#stability)
+/// guarantees.
+pub const PROTOCOL_VERSION: u32 = 1;
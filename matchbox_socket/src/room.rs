@@ -0,0 +1,254 @@
+use std::marker::PhantomData;
+
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Error, WebRtcSocket};
+
+/// Requests peers exchange on the room's channel, next to the game's messages
+#[derive(Debug, Serialize, Deserialize)]
+enum Control {
+    /// A peer joining a running room asks the host for its state
+    NeedsState,
+}
+
+/// A [`WebRtcSocket`] for games where one of the peers acts as the host,
+/// exchanging messages of type `M`
+///
+/// Messages are serialized as json and sent on a single channel, the first
+/// one unless configured with [`Room::with_channel`]. The host is the peer
+/// with the lowest id, including our own, so every peer agrees on it without
+/// exchanging any messages. When the host disconnects, the peer with the next
+/// lowest id takes over.
+///
+/// ```no_run
+/// use matchbox_socket::{Room, WebRtcSocket};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum Message {
+///     Ready,
+///     Start { seed: u64 },
+/// }
+///
+/// # async fn example() {
+/// let (socket, message_loop) = WebRtcSocket::new("ws://localhost:3536/my_game?next=4");
+/// // spawn the message loop on your runtime of choice
+/// # drop(message_loop);
+/// let mut room = Room::<Message>::new(socket);
+/// room.wait_for_peers(3).await.expect("message loop stopped");
+/// if room.is_host() {
+///     room.send_to_all(&Message::Start { seed: 42 });
+/// } else {
+///     room.send_to_host(&Message::Ready);
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Room<M> {
+    socket: WebRtcSocket,
+    channel: usize,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M: Serialize + DeserializeOwned> Room<M> {
+    /// Wraps a socket, sending messages on its first channel
+    pub fn new(socket: WebRtcSocket) -> Self {
+        Self {
+            socket,
+            channel: 0,
+            _message: PhantomData,
+        }
+    }
+
+    /// Sends and receives messages on the channel with the given index
+    /// instead, see [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    pub fn with_channel(mut self, index: usize) -> Self {
+        self.channel = index;
+        self
+    }
+
+    /// Returns a future that resolves when the given number of peers have
+    /// connected, see [`WebRtcSocket::wait_for_peers`]
+    pub async fn wait_for_peers(&mut self, peers: usize) -> Result<Vec<String>, Error> {
+        self.socket.wait_for_peers(peers).await
+    }
+
+    /// Check if new peers have connected and if so add them as peers
+    pub fn accept_new_connections(&mut self) -> Vec<String> {
+        self.socket.accept_new_connections()
+    }
+
+    /// Returns the ids of the connected peers, not including our own
+    pub fn peers(&self) -> Vec<String> {
+        self.socket.connected_peers()
+    }
+
+    /// Returns the id of the host, which may be our own
+    ///
+    /// Updated by [`Room::accept_new_connections`].
+    pub fn host(&self) -> String {
+        self.peers()
+            .into_iter()
+            .chain(Some(self.socket.id().clone()))
+            .min()
+            .expect("our own id is always included")
+    }
+
+    /// Whether we are the host
+    pub fn is_host(&self) -> bool {
+        &self.host() == self.socket.id()
+    }
+
+    /// Sends a message to the given peer
+    ///
+    /// # Panics
+    ///
+    /// If the message can't be serialized as json, e.g. a map with non-string
+    /// keys.
+    pub fn send_to<T: Into<String>>(&mut self, message: &M, peer: T) {
+        let packet = serialize(message);
+        self.socket.send_on_channel(packet, peer, self.channel);
+    }
+
+    /// Sends a message to every connected peer
+    ///
+    /// # Panics
+    ///
+    /// See [`Room::send_to`].
+    pub fn send_to_all(&mut self, message: &M) {
+        let packet = serialize(message);
+        for peer in self.peers() {
+            self.socket
+                .send_on_channel(packet.clone(), peer, self.channel);
+        }
+    }
+
+    /// Sends a message to the host, does nothing if we are the host
+    ///
+    /// # Panics
+    ///
+    /// See [`Room::send_to`].
+    pub fn send_to_host(&mut self, message: &M) {
+        if !self.is_host() {
+            let host = self.host();
+            self.send_to(message, host);
+        }
+    }
+
+    /// Returns the messages received since the last call, with the ids of
+    /// their senders
+    ///
+    /// Packets that aren't valid messages are logged and dropped.
+    pub fn receive(&mut self) -> Vec<(String, M)> {
+        self.socket
+            .receive_on_channel(self.channel)
+            .into_iter()
+            .filter_map(|(peer, packet)| match serde_json::from_slice(&packet) {
+                Ok(message) => Some((peer, message)),
+                Err(e) => {
+                    warn!("dropping invalid message from {peer:?}: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Asks the host for the current state of the room, and waits for its
+    /// answer
+    ///
+    /// Meant for peers joining a room that's already running, once they've
+    /// connected to the others, see [`Room::wait_for_peers`]. The host answers
+    /// with [`Room::answer_state_requests`]. Returns `None` if we are the host
+    /// ourselves, since there's nobody to ask. If the host disconnects before
+    /// answering, the next host is asked instead.
+    ///
+    /// The room's channel needs
+    /// [`ChannelConfig::requests`](crate::ChannelConfig::requests) enabled on
+    /// every peer, otherwise this fails with [`Error::RequestFailed`]. Fails
+    /// with [`Error::RequestTimeout`] if the host doesn't answer in time, and
+    /// with [`Error::InvalidState`] if its answer isn't a valid message.
+    pub async fn await_initial_state(&mut self) -> Result<Option<M>, Error> {
+        loop {
+            self.accept_new_connections();
+            if self.is_host() {
+                return Ok(None);
+            }
+            let host = self.host();
+            let request = self
+                .socket
+                .channel_sender(self.channel)
+                .request(serialize(&Control::NeedsState), host.clone());
+            match request.await {
+                Ok(packet) => {
+                    return serde_json::from_slice(&packet).map(Some).map_err(|e| {
+                        Error::InvalidState {
+                            host,
+                            reason: e.to_string(),
+                        }
+                    })
+                }
+                Err(e @ Error::RequestFailed { .. }) => {
+                    self.accept_new_connections();
+                    if self.peers().contains(&host) {
+                        return Err(e);
+                    }
+                    warn!("host {host:?} left before sending the room's state, asking again");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answers the [`Room::await_initial_state`] calls of joining peers with
+    /// the given state, returns how many peers were answered
+    ///
+    /// Call it wherever the host keeps the room's state, e.g. once per frame.
+    /// The state is only serialized if somebody asked for it. Other requests
+    /// on the room's channel are logged and dropped.
+    ///
+    /// # Panics
+    ///
+    /// See [`Room::send_to`].
+    pub fn answer_state_requests(&mut self, state: &M) -> usize {
+        let mut packet = None;
+        let mut answered = 0;
+        for request in self.socket.receive_requests_on_channel(self.channel) {
+            match serde_json::from_slice(&request.data) {
+                Ok(Control::NeedsState) => {}
+                Err(e) => {
+                    warn!("dropping invalid request from {:?}: {e}", request.peer);
+                    continue;
+                }
+            }
+            let packet = packet.get_or_insert_with(|| serialize(state)).clone();
+            match self.socket.respond(&request, packet) {
+                Ok(()) => answered += 1,
+                Err(e) => warn!("couldn't send the room's state to {:?}: {e}", request.peer),
+            }
+        }
+        answered
+    }
+
+    /// Returns the underlying socket, e.g. to check on the connection to a
+    /// peer
+    pub fn socket(&self) -> &WebRtcSocket {
+        &self.socket
+    }
+
+    /// Returns the underlying socket mutably, e.g. to use other channels
+    pub fn socket_mut(&mut self) -> &mut WebRtcSocket {
+        &mut self.socket
+    }
+
+    /// Returns the underlying socket, consuming the room
+    pub fn into_socket(self) -> WebRtcSocket {
+        self.socket
+    }
+}
+
+fn serialize<M: Serialize>(message: &M) -> Box<[u8]> {
+    serde_json::to_vec(message)
+        .expect("message can't be serialized")
+        .into_boxed_slice()
+}
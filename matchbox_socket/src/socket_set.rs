@@ -0,0 +1,103 @@
+use std::{collections::HashMap, hash::Hash};
+
+use crate::WebRtcSocket;
+
+/// Several sockets, e.g. one per match hosted by the same process, polled
+/// together
+///
+/// Each socket is identified by a key of type `K`, which is attached to
+/// everything it returns. The sockets are independent, each one needs its
+/// message loop spawned, but they can all run on the same runtime.
+#[derive(Debug)]
+pub struct SocketSet<K> {
+    sockets: HashMap<K, WebRtcSocket>,
+}
+
+impl<K> Default for SocketSet<K> {
+    fn default() -> Self {
+        Self {
+            sockets: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> SocketSet<K> {
+    /// Creates an empty set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a socket, returning the one previously added with the same key
+    pub fn insert(&mut self, key: K, socket: WebRtcSocket) -> Option<WebRtcSocket> {
+        self.sockets.insert(key, socket)
+    }
+
+    /// Removes the socket with the given key, e.g. when its match is over
+    pub fn remove(&mut self, key: &K) -> Option<WebRtcSocket> {
+        self.sockets.remove(key)
+    }
+
+    /// Returns the socket with the given key
+    pub fn get(&self, key: &K) -> Option<&WebRtcSocket> {
+        self.sockets.get(key)
+    }
+
+    /// Returns the socket with the given key mutably, e.g. to send on it
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut WebRtcSocket> {
+        self.sockets.get_mut(key)
+    }
+
+    /// Returns the number of sockets
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// Whether there are no sockets
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Iterates over the sockets and their keys, in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &WebRtcSocket)> {
+        self.sockets.iter()
+    }
+
+    /// Iterates mutably over the sockets and their keys, in arbitrary order
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut WebRtcSocket)> {
+        self.sockets.iter_mut()
+    }
+
+    /// Checks every socket for new connections, see
+    /// [`WebRtcSocket::accept_new_connections`]
+    pub fn accept_new_connections(&mut self) -> Vec<(K, String)> {
+        self.sockets
+            .iter_mut()
+            .flat_map(|(key, socket)| {
+                socket
+                    .accept_new_connections()
+                    .into_iter()
+                    .map(move |peer| (key.clone(), peer))
+            })
+            .collect()
+    }
+
+    /// Receives messages on the default channel of every socket, see
+    /// [`WebRtcSocket::receive`]
+    pub fn receive(&mut self) -> Vec<(K, String, Box<[u8]>)> {
+        self.receive_on_channel(0)
+    }
+
+    /// Receives messages on the given channel of every socket, see
+    /// [`WebRtcSocket::receive_on_channel`]
+    pub fn receive_on_channel(&mut self, index: usize) -> Vec<(K, String, Box<[u8]>)> {
+        self.sockets
+            .iter_mut()
+            .flat_map(|(key, socket)| {
+                socket
+                    .receive_on_channel(index)
+                    .into_iter()
+                    .map(move |(peer, packet)| (key.clone(), peer, packet))
+            })
+            .collect()
+    }
+}
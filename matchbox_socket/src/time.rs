@@ -0,0 +1,158 @@
+//! Timers that work the same natively and on wasm, for netcode written once
+//! for both targets, e.g. resend timers and heartbeats
+//!
+//! These are the timers the socket's message loop runs on. Natively, they're
+//! driven by a timer thread, so they work with any executor, and on wasm by
+//! `setTimeout`.
+//!
+//! ```
+//! use futures::StreamExt;
+//! use matchbox_socket::time::{timeout, Interval};
+//! use std::time::Duration;
+//!
+//! # futures::executor::block_on(async {
+//! let mut heartbeat = Interval::new(Duration::from_millis(10));
+//! for _ in 0..3 {
+//!     heartbeat.next().await;
+//!     // send a heartbeat to every peer
+//! }
+//! let never = futures::future::pending::<()>();
+//! assert!(timeout(Duration::from_millis(10), never).await.is_err());
+//! # });
+//! ```
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::Either, Stream};
+
+/// A future that resolves once the given duration has passed, and can be
+/// [reset](Delay::reset) to wait again
+pub use futures_timer::Delay;
+
+/// The wall clock time, in milliseconds since the unix epoch
+///
+/// From `Date.now()` on wasm, where [`std::time::SystemTime`] isn't
+/// available.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now_ms() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0., |since_epoch| since_epoch.as_secs_f64() * 1000.)
+}
+
+/// The wall clock time, in milliseconds since the unix epoch
+///
+/// From `Date.now()` on wasm, where [`std::time::SystemTime`] isn't
+/// available.
+#[cfg(target_arch = "wasm32")]
+pub fn now_ms() -> f64 {
+    js_sys::Date::now()
+}
+
+/// Waits for the given duration
+pub async fn sleep(duration: Duration) {
+    Delay::new(duration).await
+}
+
+/// Returned by [`timeout`] if the future didn't resolve in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Waits for the future, but at most for the given duration
+///
+/// The future is dropped if it doesn't resolve in time.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    futures::pin_mut!(future);
+    match futures::future::select(future, Delay::new(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+/// A stream that ticks once per period, starting one period from now
+///
+/// A tick that's late doesn't make the next one come sooner, so a stalled
+/// frame doesn't cause a burst of heartbeats.
+#[derive(Debug)]
+pub struct Interval {
+    delay: Delay,
+    period: Duration,
+}
+
+impl Interval {
+    /// Ticks every `period`
+    pub fn new(period: Duration) -> Self {
+        Self {
+            delay: Delay::new(period),
+            period,
+        }
+    }
+
+    /// Starts waiting for a whole period again, e.g. after sending something
+    /// that makes a heartbeat unnecessary
+    pub fn reset(&mut self) {
+        self.delay.reset(self.period);
+    }
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => {
+                self.reset();
+                Poll::Ready(Some(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::{executor::block_on, StreamExt};
+
+    use super::{sleep, timeout, Elapsed, Interval};
+
+    #[test]
+    fn timers_wait_and_give_up() {
+        block_on(async {
+            let started = Instant::now();
+            sleep(Duration::from_millis(20)).await;
+            assert!(started.elapsed() >= Duration::from_millis(20));
+
+            let ready = timeout(Duration::from_secs(10), async { 1 }).await;
+            assert_eq!(ready, Ok(1));
+            let never = futures::future::pending::<()>();
+            assert_eq!(
+                timeout(Duration::from_millis(10), never).await,
+                Err(Elapsed)
+            );
+
+            let started = Instant::now();
+            let mut interval = Interval::new(Duration::from_millis(10));
+            for _ in 0..3 {
+                interval.next().await;
+            }
+            assert!(started.elapsed() >= Duration::from_millis(30));
+        });
+    }
+}
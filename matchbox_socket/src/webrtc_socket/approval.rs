@@ -0,0 +1,249 @@
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+use futures::{
+    future::{pending, ready},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use log::{debug, warn};
+
+use crate::webrtc_socket::{
+    messages::{PeerId, PeerRole, PeerSignal},
+    MaybeSend, WebRtcSocketConfig,
+};
+
+/// Whether to connect to a peer, see [`WebRtcSocketConfig::on_incoming_peer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerApproval {
+    /// Connect to the peer
+    Accept,
+    /// Ignore the peer, it's never connected to and never shows up in the
+    /// socket's peer states
+    Reject,
+}
+
+/// What the signalling server told us about a peer before introducing it
+///
+/// See [`WebRtcSocketConfig::on_incoming_peer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingPeer {
+    /// The id of the peer
+    pub id: PeerId,
+    /// Its [`WebRtcSocketConfig::display_name`], if it registered one
+    pub name: Option<String>,
+    /// The role the server gave it, if the server told us
+    pub role: Option<PeerRole>,
+    /// Its [`WebRtcSocketConfig::capabilities`]
+    pub capabilities: Vec<String>,
+}
+
+impl IncomingPeer {
+    fn new(id: PeerId) -> Self {
+        Self {
+            id,
+            name: None,
+            role: None,
+            capabilities: vec![],
+        }
+    }
+}
+
+/// Resolves with the decision about a peer, see [`IncomingPeerApprover`]
+#[cfg(not(target_arch = "wasm32"))]
+pub type ApprovalFuture = Pin<Box<dyn Future<Output = PeerApproval> + Send>>;
+/// Resolves with the decision about a peer, see [`IncomingPeerApprover`]
+#[cfg(target_arch = "wasm32")]
+pub type ApprovalFuture = Pin<Box<dyn Future<Output = PeerApproval>>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Decision = Pin<Box<dyn Future<Output = (PeerId, PeerApproval)> + Send>>;
+#[cfg(target_arch = "wasm32")]
+type Decision = Pin<Box<dyn Future<Output = (PeerId, PeerApproval)>>>;
+
+type ApproveFn = dyn Fn(IncomingPeer) -> ApprovalFuture + Send + Sync;
+
+/// Decides whether to connect to a peer before the socket does
+///
+/// See [`WebRtcSocketConfig::on_incoming_peer`].
+#[derive(Clone)]
+pub struct IncomingPeerApprover(Arc<ApproveFn>);
+
+impl IncomingPeerApprover {
+    /// Creates an approver from an async function that gets what is known
+    /// about a peer, and resolves with whether to connect to it
+    ///
+    /// ```
+    /// use matchbox_socket::{IncomingPeerApprover, PeerApproval};
+    ///
+    /// let banned = vec!["cheater".to_string()];
+    /// let approver = IncomingPeerApprover::new(move |peer| {
+    ///     let approval = match peer.name {
+    ///         Some(name) if banned.contains(&name) => PeerApproval::Reject,
+    ///         _ => PeerApproval::Accept,
+    ///     };
+    ///     async move { approval }
+    /// });
+    /// ```
+    pub fn new<F, Fut>(approve: F) -> Self
+    where
+        F: Fn(IncomingPeer) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = PeerApproval> + MaybeSend + 'static,
+    {
+        Self(Arc::new(move |peer| -> ApprovalFuture {
+            Box::pin(approve(peer))
+        }))
+    }
+}
+
+impl fmt::Debug for IncomingPeerApprover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IncomingPeerApprover")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A peer the message loop may connect to
+pub(crate) enum Approved {
+    /// A peer that joined after us, for us to offer to
+    NewPeer(PeerId),
+    /// A peer that offered to connect, with the signals it sent so far,
+    /// starting with its offer
+    Offer(PeerId, Vec<PeerSignal>),
+}
+
+/// Asks the [`IncomingPeerApprover`] about new peers before the message loop
+/// connects to them, holding back their signals in the meantime
+pub(crate) struct Approvals {
+    approver: Option<IncomingPeerApprover>,
+    /// What the signalling server told us about the peers of the room
+    peers: HashMap<PeerId, IncomingPeer>,
+    /// Peers waiting for a decision, with the signals they sent, or `None`
+    /// if we are to offer
+    waiting: HashMap<PeerId, Option<Vec<PeerSignal>>>,
+    decisions: FuturesUnordered<Decision>,
+}
+
+impl Approvals {
+    pub fn new(config: &WebRtcSocketConfig) -> Self {
+        Self {
+            approver: config.on_incoming_peer.clone(),
+            peers: HashMap::new(),
+            waiting: HashMap::new(),
+            decisions: FuturesUnordered::new(),
+        }
+    }
+
+    pub fn named(&mut self, peer: PeerId, name: String) {
+        self.peer(peer).name = Some(name);
+    }
+
+    pub fn role(&mut self, peer: PeerId, role: PeerRole) {
+        self.peer(peer).role = Some(role);
+    }
+
+    pub fn capabilities(&mut self, peer: PeerId, capabilities: Vec<String>) {
+        self.peer(peer).capabilities = capabilities;
+    }
+
+    /// Asks about a peer that joined after us
+    pub fn new_peer(&mut self, peer: PeerId) {
+        self.ask(peer, None);
+    }
+
+    /// Asks about a peer that sent its first signal
+    pub fn offered(&mut self, peer: PeerId, signal: PeerSignal) {
+        self.ask(peer, Some(vec![signal]));
+    }
+
+    /// Holds back a signal of a peer that offered and is waiting for a
+    /// decision, otherwise returns it
+    pub fn hold(&mut self, peer: &PeerId, signal: PeerSignal) -> Option<PeerSignal> {
+        match self.waiting.get_mut(peer) {
+            Some(Some(signals)) => {
+                signals.push(signal);
+                None
+            }
+            _ => Some(signal),
+        }
+    }
+
+    /// Resolves with the next peer that was accepted
+    pub async fn next_approved(&mut self) -> Approved {
+        loop {
+            let Some((peer, approval)) = self.decisions.next().await else {
+                return pending().await;
+            };
+            let Some(signals) = self.waiting.remove(&peer) else {
+                continue;
+            };
+            match (approval, signals) {
+                (PeerApproval::Accept, None) => return Approved::NewPeer(peer),
+                (PeerApproval::Accept, Some(signals)) => return Approved::Offer(peer, signals),
+                (PeerApproval::Reject, _) => warn!("rejected {peer:?}, not connecting"),
+            }
+        }
+    }
+
+    fn peer(&mut self, peer: PeerId) -> &mut IncomingPeer {
+        self.peers
+            .entry(peer.clone())
+            .or_insert_with(|| IncomingPeer::new(peer))
+    }
+
+    fn ask(&mut self, peer: PeerId, signals: Option<Vec<PeerSignal>>) {
+        let approval = match &self.approver {
+            Some(approver) => {
+                debug!("asking whether to connect to {peer:?}");
+                let known = self.peers.get(&peer).cloned();
+                (approver.0)(known.unwrap_or_else(|| IncomingPeer::new(peer.clone())))
+            }
+            None => Box::pin(ready(PeerApproval::Accept)),
+        };
+        self.waiting.insert(peer.clone(), signals);
+        self.decisions
+            .push(Box::pin(approval.map(move |approval| (peer, approval))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, FutureExt};
+
+    use super::{Approvals, Approved, IncomingPeerApprover, PeerApproval};
+    use crate::webrtc_socket::{messages::PeerSignal, WebRtcSocketConfig};
+
+    #[test]
+    fn signals_are_held_until_the_offer_is_approved() {
+        let config = WebRtcSocketConfig {
+            on_incoming_peer: Some(IncomingPeerApprover::new(|peer| {
+                let approval = if peer.name.as_deref() == Some("banned") {
+                    PeerApproval::Reject
+                } else {
+                    PeerApproval::Accept
+                };
+                async move { approval }
+            })),
+            ..Default::default()
+        };
+        let mut approvals = Approvals::new(&config);
+        approvals.named("a".to_string(), "banned".to_string());
+        approvals.offered("a".to_string(), PeerSignal::Offer("a".to_string()));
+        approvals.offered("b".to_string(), PeerSignal::Offer("b".to_string()));
+        let candidate = PeerSignal::IceCandidate("candidate".to_string());
+        assert_eq!(approvals.hold(&"b".to_string(), candidate.clone()), None);
+        assert_eq!(
+            approvals.hold(&"c".to_string(), candidate.clone()),
+            Some(candidate.clone())
+        );
+
+        match block_on(approvals.next_approved()) {
+            Approved::Offer(peer, signals) => {
+                assert_eq!(peer, "b");
+                assert_eq!(signals, vec![PeerSignal::Offer("b".to_string()), candidate]);
+            }
+            Approved::NewPeer(peer) => panic!("{} was never announced", peer),
+        }
+        // "a" was rejected
+        assert!(approvals.next_approved().now_or_never().is_none());
+    }
+}
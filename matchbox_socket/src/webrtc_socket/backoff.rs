@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// How long to wait before each attempt to reconnect, see
+/// [`WebRtcSocketConfig::signalling_backoff`](crate::WebRtcSocketConfig::signalling_backoff)
+/// and [`WebRtcSocketConfig::reconnect_backoff`](crate::WebRtcSocketConfig::reconnect_backoff)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackoffPolicy {
+    /// Delay before the first attempt, in milliseconds
+    pub initial_delay_ms: u64,
+    /// Factor the delay grows by with every following attempt
+    pub multiplier: f64,
+    /// Upper bound for the delay, in milliseconds
+    pub max_delay_ms: u64,
+    /// Fraction of the delay, between 0 and 1, to randomly add or take away
+    ///
+    /// Keeps peers that lost the server at the same time from all coming
+    /// back at once.
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            multiplier: 2.,
+            max_delay_ms: 30_000,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Returns the delay before the given attempt, the first one being `1`
+    pub fn delay(&self, attempt: u16) -> Duration {
+        let exponent = i32::from(attempt.saturating_sub(1));
+        let delay_ms = (self.initial_delay_ms as f64 * self.multiplier.powi(exponent))
+            .min(self.max_delay_ms as f64);
+        let jitter = self.jitter.clamp(0., 1.) * (2. * fastrand::f64() - 1.);
+        Duration::from_secs_f64((delay_ms * (1. + jitter)).max(0.) / 1000.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackoffPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn delay_grows_up_to_the_cap() {
+        let policy = BackoffPolicy {
+            initial_delay_ms: 100,
+            multiplier: 3.,
+            max_delay_ms: 1000,
+            jitter: 0.,
+        };
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.delay(attempt)).collect();
+        let expected = [100, 300, 900, 1000, 1000].map(Duration::from_millis);
+        assert_eq!(delays, expected);
+        // attempt 0 is treated like the first one
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        // no overflow on huge attempt counts
+        assert_eq!(policy.delay(u16::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = BackoffPolicy {
+            initial_delay_ms: 1000,
+            multiplier: 1.,
+            max_delay_ms: 1000,
+            jitter: 0.5,
+        };
+        let delays: Vec<_> = (0..100).map(|_| policy.delay(1)).collect();
+        for delay in &delays {
+            assert!(
+                (Duration::from_millis(500)..=Duration::from_millis(1500)).contains(delay),
+                "{:?}",
+                delay
+            );
+        }
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        // jitter is clamped to 1, so delays never go negative
+        let policy = BackoffPolicy {
+            jitter: 5.,
+            ..policy
+        };
+        for _ in 0..100 {
+            assert!(policy.delay(1) <= Duration::from_millis(2000));
+        }
+    }
+}
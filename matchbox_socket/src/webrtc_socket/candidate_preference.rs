@@ -0,0 +1,150 @@
+use std::net::IpAddr;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Which ICE candidates to try first, see
+/// [`WebRtcSocketConfig::candidate_preference`](crate::WebRtcSocketConfig::candidate_preference)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidatePreference {
+    /// Leave the order to ICE
+    #[default]
+    Standard,
+    /// Prefer IPv4 addresses over IPv6 addresses of the same candidate type
+    PreferIpv4,
+    /// Prefer IPv6 addresses over IPv4 addresses of the same candidate type
+    PreferIpv6,
+    /// Try relayed candidates after all others
+    RelayLast,
+}
+
+/// The highest local preference of RFC 8445, section 5.1.2.1
+const MAX_LOCAL_PREFERENCE: u32 = 65535;
+
+impl CandidatePreference {
+    /// Rewrites the priority of a `candidate:` attribute, as found in the
+    /// `candidate` field of an `RTCIceCandidateInit`
+    ///
+    /// Candidates that can't be parsed, or whose address family is unknown,
+    /// e.g. mDNS hostnames, are left as they are.
+    pub(crate) fn apply(self, candidate: &str) -> String {
+        if self == CandidatePreference::Standard {
+            return candidate.to_string();
+        }
+        let (prefix, attribute) = match candidate.strip_prefix("candidate:") {
+            Some(attribute) => ("candidate:", attribute),
+            None => ("", candidate),
+        };
+        let mut fields: Vec<&str> = attribute.split(' ').collect();
+        let priority = fields
+            .get(3)
+            .and_then(|priority| priority.parse::<u32>().ok());
+        let (Some(priority), Some(address), Some(kind)) = (priority, fields.get(4), fields.get(7))
+        else {
+            return candidate.to_string();
+        };
+        let type_preference = priority >> 24;
+        let component = priority & 0xff;
+        let (type_preference, local_preference) = match (self, address.parse::<IpAddr>()) {
+            (CandidatePreference::RelayLast, _) if *kind == "relay" => (0, 0),
+            (CandidatePreference::PreferIpv4, Ok(ip)) => (
+                type_preference,
+                if ip.is_ipv4() {
+                    MAX_LOCAL_PREFERENCE
+                } else {
+                    0
+                },
+            ),
+            (CandidatePreference::PreferIpv6, Ok(ip)) => (
+                type_preference,
+                if ip.is_ipv6() {
+                    MAX_LOCAL_PREFERENCE
+                } else {
+                    0
+                },
+            ),
+            _ => return candidate.to_string(),
+        };
+        let priority = (type_preference << 24 | local_preference << 8 | component).to_string();
+        fields[3] = &priority;
+        format!("{prefix}{}", fields.join(" "))
+    }
+
+    /// Like [`CandidatePreference::apply`], for a whole `RTCIceCandidateInit`
+    /// serialized as json, as sent in [`PeerSignal::IceCandidate`](crate::protocol::PeerSignal::IceCandidate)
+    #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+    pub(crate) fn apply_to_json(self, candidate_json: &str) -> String {
+        if self == CandidatePreference::Standard {
+            return candidate_json.to_string();
+        }
+        let mut init: serde_json::Value = match serde_json::from_str(candidate_json) {
+            Ok(init) => init,
+            Err(e) => {
+                warn!("not reordering invalid candidate {candidate_json:?}: {e}");
+                return candidate_json.to_string();
+            }
+        };
+        if let Some(candidate) = init.get_mut("candidate") {
+            if let Some(attribute) = candidate.as_str() {
+                *candidate = self.apply(attribute).into();
+            }
+        }
+        init.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CandidatePreference;
+
+    const HOST_V4: &str = "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host";
+    const HOST_V6: &str = "candidate:2 1 udp 2130706431 2001:db8::2 50001 typ host";
+    const RELAY: &str =
+        "candidate:3 1 udp 16777215 203.0.113.7 3478 typ relay raddr 0.0.0.0 rport 0";
+
+    fn priority(candidate: &str) -> u32 {
+        candidate.split(' ').nth(3).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn preferred_family_goes_first() {
+        let v4 = CandidatePreference::PreferIpv4;
+        assert!(priority(&v4.apply(HOST_V4)) > priority(&v4.apply(HOST_V6)));
+        let v6 = CandidatePreference::PreferIpv6;
+        assert!(priority(&v6.apply(HOST_V6)) > priority(&v6.apply(HOST_V4)));
+        // the type still counts most
+        assert!(priority(&v4.apply(HOST_V6)) > priority(&v4.apply(RELAY)));
+        assert_eq!(
+            v4.apply(HOST_V4),
+            "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host"
+        );
+    }
+
+    #[test]
+    fn relays_go_last() {
+        let relay_last = CandidatePreference::RelayLast;
+        assert_eq!(priority(&relay_last.apply(RELAY)), 255);
+        assert_eq!(relay_last.apply(HOST_V4), HOST_V4);
+    }
+
+    #[test]
+    fn unknown_candidates_are_left_alone() {
+        let mdns = "candidate:4 1 udp 2130706431 0a1b2c.local 50002 typ host";
+        for candidate in [mdns, "garbage", ""].iter().copied() {
+            assert_eq!(CandidatePreference::PreferIpv4.apply(candidate), candidate);
+        }
+        let json = r#"{"candidate":"candidate:2 1 udp 2130706431 2001:db8::2 50001 typ host","sdpMid":"0"}"#;
+        let reordered: serde_json::Value =
+            serde_json::from_str(&CandidatePreference::PreferIpv4.apply_to_json(json)).unwrap();
+        assert_eq!(reordered["sdpMid"], "0");
+        assert_eq!(
+            priority(reordered["candidate"].as_str().unwrap()),
+            2113929471
+        );
+        assert_eq!(
+            CandidatePreference::PreferIpv4.apply_to_json("null"),
+            "null"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::webrtc_socket::{ChannelConfig, ChannelPriority, WebRtcSocketConfig};
+
+/// Channel layouts for common kinds of games
+///
+/// Each preset replaces [`WebRtcSocketConfig::channels`], and its channels
+/// can be found at the indices of the matching constants, so every project
+/// doesn't have to keep its own list of indices in sync with its config.
+/// Both sides need the same preset.
+impl WebRtcSocketConfig {
+    /// Index of the unreliable input channel of
+    /// [`WebRtcSocketConfig::with_ggrs_channels`]
+    ///
+    /// The `ggrs` socket sends on this one.
+    pub const GGRS_INPUT_CHANNEL: usize = 0;
+    /// Index of the reliable channel of
+    /// [`WebRtcSocketConfig::with_ggrs_channels`]
+    pub const GGRS_SYNC_CHANNEL: usize = 1;
+    /// Index of the unreliable snapshot channel of
+    /// [`WebRtcSocketConfig::with_state_sync_channels`]
+    pub const STATE_SNAPSHOT_CHANNEL: usize = 0;
+    /// Index of the reliable event channel of
+    /// [`WebRtcSocketConfig::with_state_sync_channels`]
+    pub const STATE_EVENT_CHANNEL: usize = 1;
+
+    /// Channels for a rollback session
+    ///
+    /// [`WebRtcSocketConfig::GGRS_INPUT_CHANNEL`] is unreliable with a high
+    /// priority, for inputs, which rollback copes with losing.
+    /// [`WebRtcSocketConfig::GGRS_SYNC_CHANNEL`] is reliable, for whatever
+    /// can't be lost, e.g. the settings of a match or checksums to detect
+    /// desyncs.
+    ///
+    /// ```
+    /// use matchbox_socket::WebRtcSocketConfig;
+    ///
+    /// let config = WebRtcSocketConfig::default().with_ggrs_channels();
+    /// let sync = &config.channels[WebRtcSocketConfig::GGRS_SYNC_CHANNEL];
+    /// assert!(sync.ordered);
+    /// ```
+    pub fn with_ggrs_channels(mut self) -> Self {
+        self.channels = vec![
+            ChannelConfig {
+                priority: ChannelPriority::High,
+                ..ChannelConfig::unreliable()
+            },
+            ChannelConfig::reliable(),
+        ];
+        self
+    }
+
+    /// Channels for a game that replicates its state
+    ///
+    /// [`WebRtcSocketConfig::STATE_SNAPSHOT_CHANNEL`] is unreliable and
+    /// coalescing, for frequent snapshots where only the latest one matters.
+    /// [`WebRtcSocketConfig::STATE_EVENT_CHANNEL`] is reliable, for events
+    /// that have to arrive in order, e.g. a player joining or scoring.
+    pub fn with_state_sync_channels(mut self) -> Self {
+        self.channels = vec![
+            ChannelConfig {
+                coalesce: true,
+                ..ChannelConfig::unreliable()
+            },
+            ChannelConfig {
+                priority: ChannelPriority::Medium,
+                ..ChannelConfig::reliable()
+            },
+        ];
+        self
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for a single data channel, summed over all peers since the socket
+/// was created
+///
+/// See [`ChannelSender::stats`](crate::ChannelSender::stats), e.g. to find
+/// out which traffic is worth moving to another channel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Packets queued for sending, including the ones dropped later
+    pub messages_sent: u64,
+    /// Total size of [`ChannelStats::messages_sent`], in bytes
+    pub bytes_sent: u64,
+    /// Packets that arrived from peers
+    pub messages_received: u64,
+    /// Total size of [`ChannelStats::messages_received`], in bytes
+    pub bytes_received: u64,
+    /// Outgoing messages dropped because their peer was disconnected or
+    /// reconnecting
+    ///
+    /// A batch of a coalescing channel counts once.
+    pub messages_dropped: u64,
+    /// Outgoing messages the data channel failed to send
+    pub send_failures: u64,
+    /// Packets queued for sending that the message loop hasn't picked up
+    /// yet, e.g. because of [`WebRtcSocketConfig::send_batch_delay_ms`](crate::WebRtcSocketConfig::send_batch_delay_ms)
+    pub queued: u64,
+}
+
+/// The counters behind [`ChannelStats`], shared by the socket and its message
+/// loop
+#[derive(Debug, Default)]
+pub(crate) struct ChannelCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_dropped: AtomicU64,
+    send_failures: AtomicU64,
+    messages_taken: AtomicU64,
+}
+
+impl ChannelCounters {
+    pub fn record_sent(&self, len: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_send_failure(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an outgoing packet the message loop took off the queue
+    pub fn record_taken(&self) {
+        self.messages_taken.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ChannelStats {
+        let messages_sent = self.messages_sent.load(Ordering::Relaxed);
+        // a packet may be taken before it's counted as sent
+        let queued = messages_sent.saturating_sub(self.messages_taken.load(Ordering::Relaxed));
+        ChannelStats {
+            messages_sent,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            queued,
+        }
+    }
+}
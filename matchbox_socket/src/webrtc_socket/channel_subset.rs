@@ -0,0 +1,95 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use crate::webrtc_socket::{messages::PeerId, IncomingSender, WebRtcSocketConfig};
+
+/// Prefix of the capability of a peer that only opens some of the data
+/// channels, followed by their comma separated indices, e.g. `"channels:0,2"`
+///
+/// Lets peers that don't use matchbox, e.g. hand-written JavaScript clients,
+/// skip the channels they don't need, see [`crate::protocol`]. Sending on
+/// the others fails with [`Error::ChannelNotOpen`](crate::Error::ChannelNotOpen).
+pub const CHANNELS_CAPABILITY_PREFIX: &str = "channels:";
+
+/// The channels listed by a [`CHANNELS_CAPABILITY_PREFIX`] capability, or
+/// `None` if there is none, so all channels are opened
+fn listed_channels(capabilities: &[String]) -> Option<Vec<usize>> {
+    let list = capabilities
+        .iter()
+        .find_map(|capability| capability.strip_prefix(CHANNELS_CAPABILITY_PREFIX))?;
+    Some(
+        list.split(',')
+            .filter_map(|index| index.trim().parse().ok())
+            .collect(),
+    )
+}
+
+/// Which of our channels are opened with a peer with the given capabilities,
+/// in the order of [`WebRtcSocketConfig::channels`]
+///
+/// Both ends of a connection decide this on their own, so it only depends on
+/// what both of them advertise, see
+/// [`ChannelConfig::required_capability`](crate::ChannelConfig::required_capability)
+/// and [`CHANNELS_CAPABILITY_PREFIX`].
+fn open_channels(config: &WebRtcSocketConfig, theirs: &[String]) -> Vec<bool> {
+    let listed = [
+        listed_channels(&config.capabilities),
+        listed_channels(theirs),
+    ];
+    config
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(index, channel)| {
+            let capable = match &channel.required_capability {
+                Some(capability) => {
+                    config.capabilities.contains(capability) && theirs.contains(capability)
+                }
+                None => true,
+            };
+            capable
+                && listed
+                    .iter()
+                    .flatten()
+                    .all(|channels| channels.contains(&index))
+        })
+        .collect()
+}
+
+/// The peers a single data channel isn't opened with, shared by the socket
+/// and its message loop
+#[derive(Debug, Default)]
+pub(crate) struct UnopenedPeers(Mutex<HashSet<PeerId>>);
+
+impl UnopenedPeers {
+    pub fn set_open(&self, peer: &PeerId, open: bool) {
+        let mut peers = self.peers();
+        if open {
+            peers.remove(peer);
+        } else {
+            peers.insert(peer.clone());
+        }
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers().contains(peer)
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashSet<PeerId>> {
+        self.0.lock().expect("unopened peers lock poisoned")
+    }
+}
+
+/// Picks the channels to open with `peer`, and tells the socket which ones
+/// it can't send on
+pub(crate) fn open_channels_with(
+    peer: &PeerId,
+    config: &WebRtcSocketConfig,
+    capabilities: Option<&Vec<String>>,
+    channels: &[IncomingSender],
+) -> Vec<bool> {
+    let open = open_channels(config, capabilities.map_or(&[], Vec::as_slice));
+    for (channel, open) in channels.iter().zip(&open) {
+        channel.set_open(peer, *open);
+    }
+    open
+}
@@ -0,0 +1,127 @@
+use std::ops::{Deref, DerefMut};
+
+use futures_channel::mpsc::UnboundedReceiver;
+
+use crate::{
+    webrtc_socket::{
+        drain_packets, drain_requests, messages::PeerId, ChannelSender, IncomingRequest, Packet,
+        PooledPacket, Recorder,
+    },
+    Error,
+};
+
+/// One channel of a socket, borrowed together with the others, see
+/// [`WebRtcSocket::channels_mut`](crate::WebRtcSocket::channels_mut)
+///
+/// Receives what arrived on the channel and sends on it, while the other
+/// channels are used in the same scope.
+#[derive(Debug)]
+pub struct ChannelMut<'a> {
+    index: usize,
+    messages: &'a mut UnboundedReceiver<(PeerId, PooledPacket)>,
+    requests: &'a mut UnboundedReceiver<IncomingRequest>,
+    recorder: Option<&'a Recorder>,
+    sender: ChannelSender,
+}
+
+impl<'a> ChannelMut<'a> {
+    pub(crate) fn new(
+        index: usize,
+        messages: &'a mut UnboundedReceiver<(PeerId, PooledPacket)>,
+        requests: &'a mut UnboundedReceiver<IncomingRequest>,
+        recorder: Option<&'a Recorder>,
+        sender: ChannelSender,
+    ) -> Self {
+        Self {
+            index,
+            messages,
+            requests,
+            recorder,
+            sender,
+        }
+    }
+
+    /// The index of the channel in [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// See [`WebRtcReceiver::receive_on_channel`](crate::WebRtcReceiver::receive_on_channel)
+    pub fn receive(&mut self) -> Vec<(PeerId, Packet)> {
+        self.receive_pooled()
+            .into_iter()
+            .map(|(peer, packet)| (peer, packet.into_boxed_slice()))
+            .collect()
+    }
+
+    /// See [`WebRtcReceiver::receive_pooled_on_channel`](crate::WebRtcReceiver::receive_pooled_on_channel)
+    pub fn receive_pooled(&mut self) -> Vec<(PeerId, PooledPacket)> {
+        drain_packets(self.messages, self.index, self.recorder)
+    }
+
+    /// See [`WebRtcReceiver::receive_requests_on_channel`](crate::WebRtcReceiver::receive_requests_on_channel)
+    pub fn receive_requests(&mut self) -> Vec<IncomingRequest> {
+        drain_requests(self.requests)
+    }
+
+    /// See [`ChannelSender::send`]
+    pub fn send<T: Into<PeerId>>(&self, packet: Packet, id: T) {
+        self.sender.send(packet, id)
+    }
+
+    /// See [`ChannelSender::try_send`]
+    pub fn try_send<T: Into<PeerId>>(&self, packet: Packet, id: T) -> Result<(), Error> {
+        self.sender.try_send(packet, id)
+    }
+
+    /// The sending side of the channel, which can be cloned and kept after
+    /// the channels are given back
+    pub fn sender(&self) -> &ChannelSender {
+        &self.sender
+    }
+}
+
+/// All channels of a socket, borrowed at once, see
+/// [`WebRtcSocket::channels_mut`](crate::WebRtcSocket::channels_mut)
+///
+/// Derefs to a slice of [`ChannelMut`] in the order of
+/// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels), so
+/// slice patterns and [`split_at_mut`](slice::split_at_mut) work as well as
+/// [`ChannelsMut::get_many_mut`].
+#[derive(Debug)]
+pub struct ChannelsMut<'a>(pub(crate) Vec<ChannelMut<'a>>);
+
+impl<'a> ChannelsMut<'a> {
+    /// Returns the channels with the given indices, or `None` if one of them
+    /// doesn't exist or an index is given twice
+    pub fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [usize; N],
+    ) -> Option<[&mut ChannelMut<'a>; N]> {
+        let mut found: [Option<&mut ChannelMut<'a>>; N] = [(); N].map(|_| None);
+        for (index, channel) in self.0.iter_mut().enumerate() {
+            // a repeated index only gets the first slot, leaving the other empty
+            if let Some(slot) = indices.iter().position(|&i| i == index) {
+                found[slot] = Some(channel);
+            }
+        }
+        if found.iter().any(Option::is_none) {
+            return None;
+        }
+        Some(found.map(Option::unwrap))
+    }
+}
+
+impl<'a> Deref for ChannelsMut<'a> {
+    type Target = [ChannelMut<'a>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a> DerefMut for ChannelsMut<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{future::Fuse, FutureExt};
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_timer::Delay;
+use log::error;
+
+use crate::webrtc_socket::{
+    channel_stats::ChannelCounters, messages::PeerId, metrics, recording::now_ms, IncomingSender,
+    PacketHook, PooledPacket, WebRtcSocketConfig,
+};
+
+/// Size of the length prefix in front of every packet in a batch
+const LEN_PREFIX_SIZE: usize = 4;
+
+/// Batches are sent when they would grow beyond this size, in bytes
+///
+/// Well below the message size limits of all the browsers.
+const MAX_BATCH_SIZE: usize = 16 * 1024;
+
+/// Maximum number of queued packets to take in one go, so a socket that
+/// keeps sending can't starve the rest of the message loop
+const MAX_PACKETS_PER_FLUSH: usize = 1024;
+
+/// Combines outgoing packets on coalescing channels into batches
+///
+/// Every packet in a batch is prefixed with its length as a big-endian
+/// `u32`, like the ids of [`Exchanges`](super::exchange::Exchanges). The receiving side splits them up again with [`split_batch`].
+/// See [`ChannelConfig::coalesce`](crate::ChannelConfig::coalesce).
+///
+/// Also holds back outgoing packets for
+/// [`WebRtcSocketConfig::send_batch_delay_ms`], or until the next
+/// [`WebRtcSocketConfig::send_tick_ms`], so they are sent in bursts. The
+/// message loop keeps running in the meantime, and sends them once the timer
+/// from [`Coalescer::flush_timer`] fires.
+pub(crate) struct Coalescer {
+    coalesce: Vec<bool>,
+    /// Counts the packets taken off the queues, see [`ChannelStats::queued`](crate::ChannelStats::queued)
+    stats: Vec<Arc<ChannelCounters>>,
+    batches: HashMap<(PeerId, usize), Vec<u8>>,
+    batch_delay: Option<Duration>,
+    tick_ms: Option<u64>,
+    /// The packet that started the burst we're waiting to send
+    held: Option<(usize, PeerId, PooledPacket)>,
+    on_outgoing: Option<PacketHook>,
+}
+
+impl Coalescer {
+    pub fn new(config: &WebRtcSocketConfig, channels: &[IncomingSender]) -> Self {
+        Self {
+            coalesce: config.channels.iter().map(|c| c.coalesce).collect(),
+            stats: channels.iter().map(|c| c.stats()).collect(),
+            batches: HashMap::new(),
+            batch_delay: match config.send_batch_delay_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            tick_ms: Some(config.send_tick_ms).filter(|&ms| ms > 0),
+            held: None,
+            on_outgoing: config.on_outgoing.clone(),
+        }
+    }
+
+    /// Takes an outgoing packet, along with all other packets queued up right
+    /// now, and returns the messages to send as `(channel, peer, message)`
+    ///
+    /// With a batch delay or tick, holds on to the packet instead, so more
+    /// packets can be queued, and returns nothing. They're sent by
+    /// [`Coalescer::flush`] once [`Coalescer::flush_timer`] fires. Packets go
+    /// through [`WebRtcSocketConfig::on_outgoing`] before they are batched.
+    pub fn collect(
+        &mut self,
+        first: (usize, PeerId, PooledPacket),
+        peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+        channel_order: &[usize],
+    ) -> Vec<(usize, PeerId, PooledPacket)> {
+        if self.tick_ms.is_some() || self.batch_delay.is_some() {
+            self.held = Some(first);
+            return vec![];
+        }
+        if !self.coalesce.contains(&true) {
+            self.stats[first.0].record_taken();
+            return self.hook(first).into_iter().collect();
+        }
+        self.coalesce(first, peer_messages_out_rx, channel_order)
+    }
+
+    /// Whether we're holding back packets until the flush timer fires
+    ///
+    /// The message loop leaves further packets queued in the meantime, so
+    /// they're taken in priority order when flushing.
+    pub fn is_holding(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// Returns a timer for sending the held back packets, or one that never
+    /// fires if we aren't holding any
+    pub fn flush_timer(&self) -> Fuse<Delay> {
+        if self.held.is_none() {
+            return Fuse::terminated();
+        }
+        match (self.tick_ms, self.batch_delay) {
+            (Some(tick_ms), _) => Delay::new(until_next_tick(now_ms(), tick_ms)).fuse(),
+            (None, Some(delay)) => Delay::new(delay).fuse(),
+            (None, None) => Fuse::terminated(),
+        }
+    }
+
+    /// Takes the held back packet, along with all other packets queued up
+    /// right now, and returns the messages to send, see
+    /// [`Coalescer::collect`]
+    pub fn flush(
+        &mut self,
+        peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+        channel_order: &[usize],
+    ) -> Vec<(usize, PeerId, PooledPacket)> {
+        match self.held.take() {
+            Some(first) => self.coalesce(first, peer_messages_out_rx, channel_order),
+            None => vec![],
+        }
+    }
+
+    /// Takes the held back packet and everything that's queued, without
+    /// waiting for the flush timer, e.g. when the socket is closing
+    pub fn flush_all(
+        &mut self,
+        peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+        channel_order: &[usize],
+    ) -> Vec<(usize, PeerId, PooledPacket)> {
+        let mut messages = self.flush(peer_messages_out_rx, channel_order);
+        while let Some(first) = try_next_peer_message_out(peer_messages_out_rx, channel_order) {
+            messages.extend(self.coalesce(first, peer_messages_out_rx, channel_order));
+        }
+        messages
+    }
+
+    fn coalesce(
+        &mut self,
+        first: (usize, PeerId, PooledPacket),
+        peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+        channel_order: &[usize],
+    ) -> Vec<(usize, PeerId, PooledPacket)> {
+        self.stats[first.0].record_taken();
+        let mut messages = vec![];
+        self.push(first, &mut messages);
+        let mut flushed = 1;
+        while flushed < MAX_PACKETS_PER_FLUSH {
+            match try_next_peer_message_out(peer_messages_out_rx, channel_order) {
+                Some(packet) => {
+                    self.stats[packet.0].record_taken();
+                    self.push(packet, &mut messages);
+                }
+                None => break,
+            }
+            flushed += 1;
+        }
+        metrics::send_burst(flushed);
+        for ((peer, channel), batch) in self.batches.drain() {
+            messages.push((channel, peer, batch.into()));
+        }
+        messages
+    }
+
+    /// Runs an outgoing packet through [`WebRtcSocketConfig::on_outgoing`]
+    fn hook(
+        &self,
+        (channel, peer, packet): (usize, PeerId, PooledPacket),
+    ) -> Option<(usize, PeerId, PooledPacket)> {
+        let hook = match &self.on_outgoing {
+            Some(hook) => hook,
+            None => return Some((channel, peer, packet)),
+        };
+        let packet = hook.apply(&peer, packet.into_boxed_slice())?;
+        Some((channel, peer, packet.into()))
+    }
+
+    fn push(
+        &mut self,
+        packet: (usize, PeerId, PooledPacket),
+        messages: &mut Vec<(usize, PeerId, PooledPacket)>,
+    ) {
+        let (channel, peer, packet) = match self.hook(packet) {
+            Some(packet) => packet,
+            None => return,
+        };
+        if !self.coalesce[channel] {
+            messages.push((channel, peer, packet));
+            return;
+        }
+        let batch = self.batches.entry((peer.clone(), channel)).or_default();
+        if !batch.is_empty() && batch.len() + LEN_PREFIX_SIZE + packet.len() > MAX_BATCH_SIZE {
+            messages.push((channel, peer, std::mem::take(batch).into()));
+        }
+        let len = u32::try_from(packet.len()).expect("packet too large to coalesce");
+        batch.extend_from_slice(&len.to_be_bytes());
+        batch.extend_from_slice(&packet);
+    }
+}
+
+/// Returns how long it is from `now_ms` until the next multiple of `tick_ms`
+fn until_next_tick(now_ms: f64, tick_ms: u64) -> Duration {
+    let tick_ms = tick_ms as f64;
+    Duration::from_secs_f64((tick_ms - now_ms.rem_euclid(tick_ms)) / 1000.)
+}
+
+/// Takes the next queued outgoing packet without waiting, highest priority
+/// channels first
+pub(crate) fn try_next_peer_message_out(
+    peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+    channel_order: &[usize],
+) -> Option<(usize, PeerId, PooledPacket)> {
+    channel_order.iter().find_map(|&index| {
+        match peer_messages_out_rx[index].try_next() {
+            Ok(Some((peer, packet))) => Some((index, peer, packet)),
+            // closed or empty, closed channels are handled by the message loop
+            Ok(None) | Err(_) => None,
+        }
+    })
+}
+
+/// Splits a batch from a coalescing channel into the original packets
+///
+/// A malformed batch is logged, and whatever could be read from it is returned.
+pub(crate) fn split_batch(mut batch: &[u8]) -> Vec<&[u8]> {
+    let mut packets = vec![];
+    while !batch.is_empty() {
+        if batch.len() < LEN_PREFIX_SIZE {
+            error!("truncated length prefix in coalesced batch, dropping the rest");
+            break;
+        }
+        let (len, rest) = batch.split_at(LEN_PREFIX_SIZE);
+        let len = u32::from_be_bytes(len.try_into().expect("prefix is 4 bytes")) as usize;
+        if rest.len() < len {
+            error!("truncated packet in coalesced batch, dropping the rest");
+            break;
+        }
+        let (packet, rest) = rest.split_at(len);
+        packets.push(packet);
+        batch = rest;
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_batch, try_next_peer_message_out, until_next_tick};
+    use crate::{
+        webrtc_socket::{channels_by_priority, next_peer_message_out, PacketPool},
+        ChannelConfig, ChannelPriority, WebRtcSocketConfig,
+    };
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    #[test]
+    fn batches_are_split_into_packets() {
+        let batch = [0, 0, 0, 2, 1, 2, 0, 0, 0, 0, 0, 0, 0, 1, 3];
+        assert_eq!(split_batch(&batch), vec![&[1, 2][..], &[], &[3]]);
+        assert!(split_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn truncated_batches_keep_the_complete_packets() {
+        // the second packet claims 2 bytes, but only has 1
+        let batch = [0, 0, 0, 1, 1, 0, 0, 0, 2, 2];
+        assert_eq!(split_batch(&batch), vec![&[1][..]]);
+        // the second length prefix is cut off
+        let batch = [0, 0, 0, 1, 1, 0, 0];
+        assert_eq!(split_batch(&batch), vec![&[1][..]]);
+    }
+
+    #[test]
+    fn ticks_are_aligned_to_the_clock() {
+        assert_eq!(until_next_tick(1000., 50), Duration::from_millis(50));
+        assert_eq!(until_next_tick(1030., 50), Duration::from_millis(20));
+        assert_eq!(until_next_tick(1049.5, 50), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn high_priority_packets_are_taken_first() {
+        let config = WebRtcSocketConfig {
+            channels: vec![
+                ChannelConfig::reliable(),
+                ChannelConfig {
+                    priority: ChannelPriority::High,
+                    ..ChannelConfig::unreliable()
+                },
+            ],
+            ..Default::default()
+        };
+        let order = channels_by_priority(&config);
+        assert_eq!(order, vec![1, 0]);
+
+        let pool = PacketPool::new(0);
+        let (senders, mut receivers): (Vec<_>, Vec<_>) = config
+            .channels
+            .iter()
+            .map(|_| futures_channel::mpsc::unbounded())
+            .unzip();
+        let queue_both = || {
+            // the low priority packet is queued first
+            for (index, tx) in senders.iter().enumerate() {
+                let packet = pool.packet_from(&[index as u8]);
+                tx.unbounded_send(("peer".to_string(), packet)).unwrap();
+            }
+        };
+
+        queue_both();
+        let (index, message) = block_on(next_peer_message_out(&mut receivers, &order));
+        assert_eq!(index, 1);
+        assert_eq!(&message.unwrap().1[..], &[1]);
+        let (index, message) = block_on(next_peer_message_out(&mut receivers, &order));
+        assert_eq!(index, 0);
+        assert_eq!(&message.unwrap().1[..], &[0]);
+
+        queue_both();
+        let (index, _, packet) = try_next_peer_message_out(&mut receivers, &order).unwrap();
+        assert_eq!((index, &packet[..]), (1, &[1][..]));
+        let (index, _, packet) = try_next_peer_message_out(&mut receivers, &order).unwrap();
+        assert_eq!((index, &packet[..]), (0, &[0][..]));
+        assert!(try_next_peer_message_out(&mut receivers, &order).is_none());
+    }
+}
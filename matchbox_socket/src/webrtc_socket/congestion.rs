@@ -0,0 +1,113 @@
+use std::{collections::HashMap, convert::TryFrom, sync::Mutex};
+
+use crate::webrtc_socket::messages::PeerId;
+
+/// How often the message loop samples how much data is buffered for each
+/// peer, in milliseconds
+pub(crate) const CONGESTION_SAMPLE_INTERVAL_MS: u64 = 250;
+
+/// Buffered data from which on a link counts as moderately congested, as
+/// long as it isn't draining
+const MODERATE_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// Buffered data from which on a link counts as severely congested
+const SEVERE_BUFFERED_BYTES: usize = 1024 * 1024;
+
+/// How badly the link to a peer is congested, see [`Congestion`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CongestionLevel {
+    /// The link keeps up with what is sent
+    #[default]
+    Light,
+    /// Data piles up in the data channels, sending less would help
+    Moderate,
+    /// So much data is waiting that it arrives with seconds of delay,
+    /// only send what's essential
+    Severe,
+}
+
+/// Congestion of the link to a peer, derived from how much data its data
+/// channels have yet to send, and how that amount changes
+///
+/// See [`WebRtcSocket::congestion`](crate::WebRtcSocket::congestion).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Congestion {
+    /// How much the sending side should back off
+    pub level: CongestionLevel,
+    /// Bytes the socket sent to the peer that its data channels haven't put
+    /// on the wire yet, summed over all channels
+    pub buffered_bytes: usize,
+    /// How fast [`Congestion::buffered_bytes`] changed since the previous
+    /// sample, in bytes per second, negative while the buffers drain
+    pub buffered_growth: i64,
+}
+
+impl Congestion {
+    /// Classifies a new sample of the buffered data, taken
+    /// [`CONGESTION_SAMPLE_INTERVAL_MS`] after the previous one
+    fn sample(previous: &Congestion, buffered_bytes: usize) -> Self {
+        let growth = i64::try_from(buffered_bytes).unwrap_or(i64::MAX)
+            - i64::try_from(previous.buffered_bytes).unwrap_or(i64::MAX);
+        let buffered_growth = growth * 1000 / CONGESTION_SAMPLE_INTERVAL_MS as i64;
+        let level = if buffered_bytes >= SEVERE_BUFFERED_BYTES {
+            CongestionLevel::Severe
+        } else if buffered_bytes >= MODERATE_BUFFERED_BYTES && buffered_growth >= 0 {
+            CongestionLevel::Moderate
+        } else {
+            CongestionLevel::Light
+        };
+        Self {
+            level,
+            buffered_bytes,
+            buffered_growth,
+        }
+    }
+}
+
+/// The latest [`Congestion`] sample of every peer, shared by the socket and
+/// its message loop
+#[derive(Debug, Default)]
+pub(crate) struct PeerCongestion(Mutex<HashMap<PeerId, Congestion>>);
+
+impl PeerCongestion {
+    /// Records how many bytes the data channels to `peer` have buffered
+    pub fn sample(&self, peer: &PeerId, buffered_bytes: usize) {
+        let mut peers = self.peers();
+        let previous = peers.get(peer).copied().unwrap_or_default();
+        peers.insert(peer.clone(), Congestion::sample(&previous, buffered_bytes));
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Congestion {
+        self.peers().get(peer).copied().unwrap_or_default()
+    }
+
+    pub fn remove(&self, peer: &PeerId) {
+        self.peers().remove(peer);
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, Congestion>> {
+        self.0.lock().expect("peer congestion lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Congestion, CongestionLevel};
+
+    #[test]
+    fn level_follows_buffered_data() {
+        let idle = Congestion::default();
+        let growing = Congestion::sample(&idle, 100 * 1024);
+        assert_eq!(growing.level, CongestionLevel::Moderate);
+        assert_eq!(growing.buffered_growth, 400 * 1024);
+
+        let draining = Congestion::sample(&growing, 80 * 1024);
+        assert_eq!(draining.level, CongestionLevel::Light);
+        assert_eq!(draining.buffered_growth, -80 * 1024);
+
+        let backlog = Congestion::sample(&draining, 2 * 1024 * 1024);
+        assert_eq!(backlog.level, CongestionLevel::Severe);
+        let still_backlogged = Congestion::sample(&backlog, 1024 * 1024);
+        assert_eq!(still_backlogged.level, CongestionLevel::Severe);
+    }
+}
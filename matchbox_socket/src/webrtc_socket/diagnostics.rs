@@ -0,0 +1,60 @@
+use crate::webrtc_socket::{ChannelStats, PeerState, SignallingState};
+
+/// A snapshot of how a socket's connections are doing, see
+/// [`WebRtcSocket::diagnostics`](crate::WebRtcSocket::diagnostics)
+///
+/// Meant for debug overlays and inspectors, cheap enough to take every frame.
+///
+/// Round trip times to peers aren't part of it. Natively, the WebRTC stack
+/// doesn't measure them (its candidate pair stats always report 0), and the
+/// socket doesn't ping peers by itself, as peers that aren't matchbox
+/// sockets wouldn't expect the extra packets. To show them anyway, time a
+/// [`ChannelSender::request`](crate::ChannelSender::request) the peer
+/// answers right away, or send your own pings. For how congested the link
+/// to a peer is, see [`WebRtcSocket::congestion`](crate::WebRtcSocket::congestion).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketDiagnostics {
+    /// The state of the connection to the signalling server
+    pub signalling: SignallingState,
+    /// Peers we're doing the first handshake with
+    pub connecting_peers: usize,
+    /// Peers whose data channels are open
+    pub connected_peers: usize,
+    /// Peers whose connection failed, and that we're trying to get back
+    pub reconnecting_peers: usize,
+    /// The counters of each channel, in the order of
+    /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    ///
+    /// [`ChannelStats::queued`] is the depth of its send queue.
+    pub channels: Vec<ChannelStats>,
+}
+
+impl SocketDiagnostics {
+    pub(crate) fn new<'a>(
+        signalling: SignallingState,
+        peer_states: impl Iterator<Item = &'a PeerState>,
+        channels: Vec<ChannelStats>,
+    ) -> Self {
+        let mut diagnostics = Self {
+            signalling,
+            connecting_peers: 0,
+            connected_peers: 0,
+            reconnecting_peers: 0,
+            channels,
+        };
+        for state in peer_states {
+            match state {
+                PeerState::Connecting => diagnostics.connecting_peers += 1,
+                PeerState::Connected => diagnostics.connected_peers += 1,
+                PeerState::Reconnecting => diagnostics.reconnecting_peers += 1,
+                PeerState::Disconnected => {}
+            }
+        }
+        diagnostics
+    }
+
+    /// Total number of packets waiting in the send queues of all channels
+    pub fn queued(&self) -> u64 {
+        self.channels.iter().map(|channel| channel.queued).sum()
+    }
+}
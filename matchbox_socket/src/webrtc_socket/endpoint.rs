@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use log::debug;
+
+use crate::{
+    webrtc_socket::{parse_room_url, signalling_connect, RtcIceServerConfig, WebRtcSocketConfig},
+    Error,
+};
+
+/// How long to wait for an endpoint's signalling server to accept a
+/// connection, in milliseconds
+const PROBE_TIMEOUT_MS: u64 = 2_000;
+
+/// A signalling server and the ICE server to use with it, e.g. one region of
+/// a multi-region deployment
+///
+/// See [`select_best_endpoint`].
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// The url of the room to join on this endpoint's signalling server, see
+    /// [`WebRtcSocketConfig::room_url`]
+    pub room_url: String,
+    /// The STUN/TURN server that goes with the signalling server, see
+    /// [`WebRtcSocketConfig::ice_server`]
+    pub ice_server: RtcIceServerConfig,
+}
+
+impl Endpoint {
+    /// Points `config` at this endpoint, keeping the rest of it
+    pub fn configure(self, config: WebRtcSocketConfig) -> WebRtcSocketConfig {
+        WebRtcSocketConfig {
+            room_url: self.room_url,
+            ice_server: self.ice_server,
+            ..config
+        }
+    }
+}
+
+/// Latencies measured by [`probe_endpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointLatency {
+    /// How long the signalling server took to accept a websocket connection
+    pub signalling: Duration,
+    /// Round trip time of a STUN binding request to the closest of the ICE
+    /// server's urls, if any of them answered
+    ///
+    /// Always `None` on wasm, where browsers don't let us send STUN requests.
+    /// `turn:` urls are probed with a STUN request as well, `turns:` urls
+    /// aren't.
+    pub ice: Option<Duration>,
+}
+
+impl EndpointLatency {
+    /// The latency endpoints are compared by, the sum of both measurements
+    pub fn total(&self) -> Duration {
+        self.signalling + self.ice.unwrap_or_default()
+    }
+}
+
+/// Measures the latency of an endpoint's signalling and ICE servers
+///
+/// Fails if the room url is invalid or blocked as mixed content, or the
+/// signalling server can't be reached. The connection to it is closed right
+/// away, without joining the room.
+pub async fn probe_endpoint(endpoint: &Endpoint) -> Result<EndpointLatency, Error> {
+    let room_url = parse_room_url(&endpoint.room_url, false)?;
+    let elapsed = stopwatch();
+    let connection = signalling_connect(&room_url, PROBE_TIMEOUT_MS).await?;
+    let signalling = elapsed();
+    drop(connection);
+
+    let ice = join_all(endpoint.ice_server.urls.iter().map(|url| ice_latency(url)))
+        .await
+        .into_iter()
+        .flatten()
+        .min();
+    Ok(EndpointLatency { signalling, ice })
+}
+
+/// Probes all endpoints at once and returns the one with the lowest
+/// [total latency](EndpointLatency::total)
+///
+/// Endpoints whose signalling server can't be reached are skipped, so this
+/// only returns `None` if none of them can. Use [`Endpoint::configure`] to
+/// create a socket with the result.
+pub async fn select_best_endpoint(
+    endpoints: impl IntoIterator<Item = Endpoint>,
+) -> Option<Endpoint> {
+    let endpoints: Vec<_> = endpoints.into_iter().collect();
+    let latencies = join_all(endpoints.iter().map(probe_endpoint)).await;
+    endpoints
+        .into_iter()
+        .zip(latencies)
+        .filter_map(|(endpoint, latency)| match latency {
+            Ok(latency) => {
+                debug!("{:?}: {latency:?}", endpoint.room_url);
+                Some((latency.total(), endpoint))
+            }
+            Err(e) => {
+                debug!("{:?} is unreachable: {e}", endpoint.room_url);
+                None
+            }
+        })
+        .min_by_key(|(total, _)| *total)
+        .map(|(_, endpoint)| endpoint)
+}
+
+/// Measures the STUN round trip time to an ICE server url, if it can be
+/// probed
+#[cfg(not(target_arch = "wasm32"))]
+async fn ice_latency(url: &str) -> Option<Duration> {
+    // TURN servers answer binding requests too
+    let stun_url = match url.strip_prefix("turn:") {
+        Some(rest) => format!("stun:{}", rest.split('?').next().unwrap_or(rest)),
+        None if url.starts_with("stun:") => url.to_string(),
+        None => return None,
+    };
+    match crate::webrtc_socket::stun_latency(&stun_url).await {
+        Ok(latency) => Some(latency),
+        Err(e) => {
+            debug!("failed to probe {url:?}: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn ice_latency(_url: &str) -> Option<Duration> {
+    None
+}
+
+/// Returns a function that returns the time since it was created
+#[cfg(not(target_arch = "wasm32"))]
+fn stopwatch() -> impl Fn() -> Duration {
+    let start = std::time::Instant::now();
+    move || start.elapsed()
+}
+
+/// `std::time::Instant` isn't available in browsers, so use the clock of
+/// javascript instead
+#[cfg(target_arch = "wasm32")]
+fn stopwatch() -> impl Fn() -> Duration {
+    let start = js_sys::Date::now();
+    move || Duration::from_secs_f64((js_sys::Date::now() - start).max(0.0) / 1000.0)
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io::IoSlice,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use futures_channel::{
+    mpsc::UnboundedSender,
+    oneshot::{self, Receiver, Sender},
+};
+use log::warn;
+
+use crate::{
+    webrtc_socket::{messages::PeerId, with_timeout, Packet, PacketPool, PooledPacket},
+    Error,
+};
+
+/// A packet that isn't part of a request/response exchange
+const PLAIN: u8 = 0;
+/// A request, followed by its id
+const REQUEST: u8 = 1;
+/// A response, followed by the id of the request it answers
+const RESPONSE: u8 = 2;
+
+/// Size of the header in front of requests and responses
+const HEADER_SIZE: usize = 5;
+
+/// A request from a peer, see [`ChannelSender::request`](crate::ChannelSender::request)
+///
+/// Answer it with [`WebRtcSender::respond`](crate::WebRtcSender::respond).
+#[derive(Debug)]
+pub struct IncomingRequest {
+    /// The peer that sent the request
+    pub peer: PeerId,
+    /// The packet it sent
+    pub data: Packet,
+    channel: usize,
+    id: u32,
+}
+
+impl IncomingRequest {
+    /// The index of the channel the request was sent on
+    pub fn channel(&self) -> usize {
+        self.channel
+    }
+}
+
+/// The request/response state of a channel with
+/// [`ChannelConfig::requests`](crate::ChannelConfig::requests), shared by its
+/// senders and the message loop
+///
+/// Every packet on such a channel starts with a kind byte, requests and
+/// responses are followed by a big-endian `u32` id matching them up.
+#[derive(Debug)]
+pub(crate) struct Exchanges {
+    index: usize,
+    timeout_ms: u64,
+    next_id: AtomicU32,
+    waiting: Mutex<HashMap<(PeerId, u32), Sender<Packet>>>,
+    incoming: UnboundedSender<IncomingRequest>,
+}
+
+impl Exchanges {
+    pub fn new(index: usize, timeout_ms: u64, incoming: UnboundedSender<IncomingRequest>) -> Self {
+        Self {
+            index,
+            timeout_ms,
+            next_id: AtomicU32::new(0),
+            waiting: Mutex::default(),
+            incoming,
+        }
+    }
+
+    /// Frames a packet that isn't part of an exchange
+    pub fn plain(pool: &PacketPool, packet: &[u8]) -> PooledPacket {
+        pool.packet_from_slices(&[IoSlice::new(&[PLAIN]), IoSlice::new(packet)])
+    }
+
+    /// Frames a request to `peer`, and returns it along with the receiving
+    /// end of its response
+    pub fn request(
+        &self,
+        pool: &PacketPool,
+        peer: &PeerId,
+        packet: &[u8],
+    ) -> (u32, PooledPacket, Receiver<Packet>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.waiting().insert((peer.clone(), id), tx);
+        (id, frame(pool, REQUEST, id, packet), rx)
+    }
+
+    /// Frames the response to a request
+    pub fn response(pool: &PacketPool, request: &IncomingRequest, packet: &[u8]) -> PooledPacket {
+        frame(pool, RESPONSE, request.id, packet)
+    }
+
+    /// Waits for the response to a request made with [`Exchanges::request`]
+    pub async fn response_to(
+        &self,
+        peer: PeerId,
+        id: u32,
+        response: Receiver<Packet>,
+    ) -> Result<Packet, Error> {
+        let timeout = Error::RequestTimeout {
+            peer: peer.clone(),
+            channel: self.index,
+        };
+        match with_timeout(response, self.timeout_ms, timeout).await {
+            Ok(Ok(packet)) => Ok(packet),
+            // the sender is dropped when the peer disconnects
+            Ok(Err(_)) => Err(Error::RequestFailed {
+                peer,
+                channel: self.index,
+            }),
+            Err(e) => {
+                self.cancel(&peer, id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Forgets a request, e.g. because it couldn't be sent
+    pub fn cancel(&self, peer: &PeerId, id: u32) {
+        self.waiting().remove(&(peer.clone(), id));
+    }
+
+    /// Handles a message from `peer`, and returns its payload if it isn't
+    /// part of an exchange
+    pub fn receive<'a>(&self, peer: &PeerId, message: &'a [u8]) -> Option<&'a [u8]> {
+        let (kind, id, payload) = match parse(message) {
+            Some(parsed) => parsed,
+            None => {
+                warn!(
+                    "dropping malformed packet from {peer:?} on channel {}",
+                    self.index
+                );
+                return None;
+            }
+        };
+        match kind {
+            REQUEST => {
+                let request = IncomingRequest {
+                    peer: peer.clone(),
+                    data: payload.into(),
+                    channel: self.index,
+                    id,
+                };
+                // the socket may have been dropped, that's fine
+                let _ = self.incoming.unbounded_send(request);
+                None
+            }
+            RESPONSE => {
+                match self.waiting().remove(&(peer.clone(), id)) {
+                    // the request may have been given up on, that's fine
+                    Some(tx) => drop(tx.send(payload.into())),
+                    None => warn!("dropping late or unexpected response {id} from {peer:?}"),
+                }
+                None
+            }
+            _ => Some(payload),
+        }
+    }
+
+    /// Fails all requests waiting for a response from `peer`
+    pub fn disconnected(&self, peer: &PeerId) {
+        self.waiting()
+            .retain(|(waiting_for, _), _| waiting_for != peer);
+    }
+
+    fn waiting(&self) -> MutexGuard<'_, HashMap<(PeerId, u32), Sender<Packet>>> {
+        self.waiting.lock().expect("exchanges lock poisoned")
+    }
+}
+
+/// Strips the header off a packet framed by [`Exchanges`]
+pub(crate) fn payload(message: &[u8]) -> &[u8] {
+    parse(message).map_or(message, |(_, _, payload)| payload)
+}
+
+fn frame(pool: &PacketPool, kind: u8, id: u32, packet: &[u8]) -> PooledPacket {
+    let id = id.to_be_bytes();
+    pool.packet_from_slices(&[
+        IoSlice::new(&[kind]),
+        IoSlice::new(&id),
+        IoSlice::new(packet),
+    ])
+}
+
+fn parse(message: &[u8]) -> Option<(u8, u32, &[u8])> {
+    match *message.first()? {
+        PLAIN => Some((PLAIN, 0, &message[1..])),
+        kind @ (REQUEST | RESPONSE) if message.len() >= HEADER_SIZE => {
+            let id = u32::from_be_bytes(message[1..HEADER_SIZE].try_into().ok()?);
+            Some((kind, id, &message[HEADER_SIZE..]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, parse, payload, PLAIN, REQUEST, RESPONSE};
+    use crate::webrtc_socket::PacketPool;
+
+    #[test]
+    fn framed_packets_parse_back() {
+        let pool = PacketPool::new(0);
+        let request = frame(&pool, REQUEST, 0x0102_0304, b"ping");
+        assert_eq!(&request[..5], &[REQUEST, 1, 2, 3, 4]);
+        assert_eq!(parse(&request), Some((REQUEST, 0x0102_0304, &b"ping"[..])));
+        let response = frame(&pool, RESPONSE, 7, b"");
+        assert_eq!(parse(&response), Some((RESPONSE, 7, &b""[..])));
+        assert_eq!(parse(&[PLAIN, 9]), Some((PLAIN, 0, &[9][..])));
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected() {
+        assert_eq!(parse(&[]), None);
+        // too short for an id
+        assert_eq!(parse(&[REQUEST, 0, 0]), None);
+        assert_eq!(parse(&[42, 0, 0, 0, 0]), None);
+        // payload() passes them through untouched
+        assert_eq!(payload(&[REQUEST, 0, 0]), &[REQUEST, 0, 0]);
+    }
+}
@@ -0,0 +1,63 @@
+use std::{fmt, sync::Arc};
+
+use log::warn;
+
+use crate::webrtc_socket::{messages::PeerId, WebRtcSocketConfig};
+
+type VerifyFn = dyn Fn(&PeerId, &str) -> bool + Send + Sync;
+
+/// Decides whether to connect to a peer, given the fingerprint of its DTLS
+/// certificate
+///
+/// See [`WebRtcSocketConfig::fingerprint_verifier`].
+#[derive(Clone)]
+pub struct FingerprintVerifier(Arc<VerifyFn>);
+
+impl FingerprintVerifier {
+    /// Creates a verifier from a function that gets the id of the peer and
+    /// its fingerprint, and returns whether to go ahead with the connection
+    ///
+    /// The fingerprint is formatted like
+    /// [`WebRtcSocket::local_fingerprint`](crate::WebRtcSocket::local_fingerprint),
+    /// e.g. `"sha-256 0A:1B:..."`.
+    pub fn new<F: Fn(&PeerId, &str) -> bool + Send + Sync + 'static>(verify: F) -> Self {
+        Self(Arc::new(verify))
+    }
+}
+
+impl fmt::Debug for FingerprintVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FingerprintVerifier")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Checks the fingerprint in the remote SDP with the configured verifier
+///
+/// Fails if the verifier rejects it, or if there is a verifier but the SDP
+/// has no fingerprint.
+pub(crate) fn verify_remote_fingerprint(
+    config: &WebRtcSocketConfig,
+    peer: &PeerId,
+    sdp: &str,
+) -> Result<(), String> {
+    let verifier = match &config.fingerprint_verifier {
+        Some(verifier) => verifier,
+        None => return Ok(()),
+    };
+    let fingerprint = remote_fingerprint(sdp)
+        .ok_or_else(|| format!("no fingerprint in the description from {peer:?}"))?;
+    if (verifier.0)(peer, fingerprint) {
+        Ok(())
+    } else {
+        warn!("rejected fingerprint {fingerprint:?} of {peer:?}");
+        Err(format!("fingerprint of {peer:?} was rejected"))
+    }
+}
+
+/// Returns the value of the first `a=fingerprint` attribute of an SDP
+fn remote_fingerprint(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(str::trim)
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use futures_channel::mpsc::UnboundedSender;
+use log::{debug, warn};
+
+use crate::webrtc_socket::{
+    messages::{PeerId, PeerRequest, PeerSignal, RoomUpdate},
+    PeerState, WebRtcSocketConfig,
+};
+
+type ValidateFn = dyn Fn(&PeerId, &[u8]) -> bool + Send + Sync;
+
+/// Decides whether to stay connected to a peer, given the data it sent in
+/// the application handshake
+///
+/// See [`WebRtcSocketConfig::handshake_validator`].
+#[derive(Clone)]
+pub struct HandshakeValidator(Arc<ValidateFn>);
+
+impl HandshakeValidator {
+    /// Creates a validator from a function that gets the id of the peer and
+    /// its [`WebRtcSocketConfig::handshake_data`], and returns whether to
+    /// stay connected to it
+    pub fn new<F: Fn(&PeerId, &[u8]) -> bool + Send + Sync + 'static>(validate: F) -> Self {
+        Self(Arc::new(validate))
+    }
+}
+
+impl fmt::Debug for HandshakeValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeValidator").finish_non_exhaustive()
+    }
+}
+
+/// The data a peer sent in the application handshake
+///
+/// See [`WebRtcSocket::peer_handshakes`](crate::WebRtcSocket::peer_handshakes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerHandshake {
+    /// The peer that sent it
+    pub peer: PeerId,
+    /// The peer's [`WebRtcSocketConfig::handshake_data`], byte for byte
+    pub data: Vec<u8>,
+}
+
+/// Exchanges [`WebRtcSocketConfig::handshake_data`] with the peers of a room
+/// once they're connected, and checks theirs
+pub(crate) struct Handshakes {
+    data: Option<Vec<u8>>,
+    validator: Option<HandshakeValidator>,
+    room_tx: UnboundedSender<RoomUpdate>,
+    /// Peers that are connected, so their data can be checked
+    connected: HashSet<PeerId>,
+    /// Data of peers we don't consider connected yet, it may arrive before
+    /// our side of the connection is open
+    pending: HashMap<PeerId, Vec<u8>>,
+}
+
+impl Handshakes {
+    pub fn new(config: &WebRtcSocketConfig, room_tx: UnboundedSender<RoomUpdate>) -> Self {
+        Self {
+            data: config.handshake_data.clone(),
+            validator: config.handshake_validator.clone(),
+            room_tx,
+            connected: HashSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Sends our data to a peer that just connected, and checks the data
+    /// it sent us so far
+    ///
+    /// Returns the peer if it has to be disconnected.
+    pub fn state_changed(
+        &mut self,
+        peer: &PeerId,
+        state: PeerState,
+        requests_sender: &UnboundedSender<PeerRequest>,
+    ) -> Option<PeerId> {
+        match state {
+            PeerState::Connecting => None,
+            PeerState::Connected => {
+                if let Some(data) = &self.data {
+                    // relayed by the signalling server, like the WebRTC handshake
+                    let _ = requests_sender.unbounded_send(PeerRequest::Signal {
+                        receiver: peer.clone(),
+                        data: PeerSignal::Handshake(data.clone()),
+                    });
+                }
+                self.connected.insert(peer.clone());
+                let data = self.pending.remove(peer)?;
+                self.check(peer.clone(), data)
+            }
+            PeerState::Reconnecting => {
+                // both sides send their data again once they're reconnected
+                self.connected.remove(peer);
+                None
+            }
+            PeerState::Disconnected => {
+                self.connected.remove(peer);
+                self.pending.remove(peer);
+                None
+            }
+        }
+    }
+
+    /// Checks the data a peer sent, or keeps it until the peer is connected
+    ///
+    /// Returns the peer if it has to be disconnected.
+    pub fn received(&mut self, peer: PeerId, data: Vec<u8>) -> Option<PeerId> {
+        if self.connected.contains(&peer) {
+            self.check(peer, data)
+        } else {
+            debug!("keeping the handshake of {peer:?} until it's connected");
+            self.pending.insert(peer, data);
+            None
+        }
+    }
+
+    fn check(&self, peer: PeerId, data: Vec<u8>) -> Option<PeerId> {
+        if let Some(validator) = &self.validator {
+            if !(validator.0)(&peer, &data) {
+                warn!("rejected the handshake of {peer:?}, disconnecting");
+                return Some(peer);
+            }
+        }
+        // the socket may have been dropped, that's fine
+        let _ = self
+            .room_tx
+            .unbounded_send(RoomUpdate::PeerHandshake(PeerHandshake { peer, data }));
+        None
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::webrtc_socket::messages::PeerId;
+
+/// The id a game knows a player by, e.g. an account id, as opposed to the
+/// [`PeerId`] of the socket the player happens to be connected with
+///
+/// See [`WebRtcReceiver::associate`](crate::WebRtcReceiver::associate).
+pub type AppUserId = String;
+
+/// A player that showed up with a new [`PeerId`], e.g. after restarting the
+/// game, see [`WebRtcReceiver::identity_moves`](crate::WebRtcReceiver::identity_moves)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdentityMove {
+    /// The player
+    pub user: AppUserId,
+    /// The peer the player was associated with before
+    pub from: PeerId,
+    /// The peer the player is associated with now
+    pub to: PeerId,
+}
+
+/// Which player each peer is, kept in both directions
+///
+/// Associations outlive the connections to peers, so a player coming back
+/// with a new id is noticed as a move.
+#[derive(Debug, Default)]
+pub(crate) struct Identities {
+    users: HashMap<PeerId, AppUserId>,
+    peers: HashMap<AppUserId, PeerId>,
+    moves: Vec<IdentityMove>,
+}
+
+impl Identities {
+    pub fn new(associations: &[(PeerId, AppUserId)]) -> Self {
+        let mut identities = Self::default();
+        for (peer, user) in associations {
+            identities.associate(peer.clone(), user.clone());
+        }
+        identities.moves.clear();
+        identities
+    }
+
+    /// Associates a peer with a player, replacing what either was associated
+    /// with before
+    pub fn associate(&mut self, peer: PeerId, user: AppUserId) {
+        if self.users.get(&peer) == Some(&user) {
+            return;
+        }
+        self.dissociate(&peer);
+        if let Some(from) = self.peers.insert(user.clone(), peer.clone()) {
+            self.users.remove(&from);
+            self.moves.push(IdentityMove {
+                user: user.clone(),
+                from,
+                to: peer.clone(),
+            });
+        }
+        self.users.insert(peer, user);
+    }
+
+    pub fn dissociate(&mut self, peer: &PeerId) -> Option<AppUserId> {
+        let user = self.users.remove(peer)?;
+        self.peers.remove(&user);
+        Some(user)
+    }
+
+    pub fn user(&self, peer: &PeerId) -> Option<&AppUserId> {
+        self.users.get(peer)
+    }
+
+    pub fn peer(&self, user: &str) -> Option<&PeerId> {
+        self.peers.get(user)
+    }
+
+    pub fn take_moves(&mut self) -> Vec<IdentityMove> {
+        std::mem::take(&mut self.moves)
+    }
+
+    /// All associations, sorted by peer
+    pub fn associations(&self) -> Vec<(PeerId, AppUserId)> {
+        let mut associations: Vec<_> = self
+            .users
+            .iter()
+            .map(|(peer, user)| (peer.clone(), user.clone()))
+            .collect();
+        associations.sort();
+        associations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identities, IdentityMove};
+
+    #[test]
+    fn players_moving_to_new_peers_are_reported() {
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        let mut identities = Identities::new(&[("a".to_string(), alice.clone())]);
+        assert!(identities.take_moves().is_empty());
+        identities.associate("b".to_string(), bob.clone());
+        identities.associate("b".to_string(), bob.clone());
+        assert!(identities.take_moves().is_empty());
+
+        // alice rejoined as "c"
+        identities.associate("c".to_string(), alice.clone());
+        assert_eq!(
+            identities.take_moves(),
+            vec![IdentityMove {
+                user: alice.clone(),
+                from: "a".to_string(),
+                to: "c".to_string(),
+            }]
+        );
+        assert_eq!(identities.user(&"a".to_string()), None);
+        assert_eq!(identities.peer(&alice), Some(&"c".to_string()));
+
+        // "b" turns out to be someone else, which isn't a move of bob
+        identities.associate("b".to_string(), "carol".to_string());
+        assert!(identities.take_moves().is_empty());
+        assert_eq!(identities.peer(&bob), None);
+        assert_eq!(
+            identities.associations(),
+            vec![
+                ("b".to_string(), "carol".to_string()),
+                ("c".to_string(), alice),
+            ]
+        );
+        assert_eq!(
+            identities.dissociate(&"b".to_string()).as_deref(),
+            Some("carol")
+        );
+        assert_eq!(identities.peer("carol"), None);
+    }
+}
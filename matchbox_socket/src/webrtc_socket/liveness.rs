@@ -0,0 +1,235 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::{future::Fuse, FutureExt};
+use futures_timer::Delay;
+
+use crate::webrtc_socket::{messages::PeerId, IncomingSender, WebRtcSocketConfig};
+
+/// How often the message loop sends keep-alives and looks for silent
+/// channels, in milliseconds
+const LIVENESS_CHECK_INTERVAL_MS: u64 = 100;
+
+/// The message sent as a keep-alive
+///
+/// Not empty, webrtc-rs takes an empty message for the data channel closing.
+pub(crate) const KEEP_ALIVE: &[u8] = b"\0matchbox:keep-alive";
+
+/// A change in whether packets arrive from a peer on a channel with
+/// [`ChannelConfig::silence_timeout_ms`](crate::ChannelConfig::silence_timeout_ms)
+///
+/// See [`WebRtcSocket::channel_liveness_changes`](crate::WebRtcSocket::channel_liveness_changes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelLiveness {
+    /// Nothing arrived from the peer on the channel for longer than the
+    /// channel's silence timeout, while the peer is still connected
+    Stalled {
+        /// The silent peer
+        peer: PeerId,
+        /// The index of the channel in
+        /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+        channel: usize,
+    },
+    /// Packets arrive again from a peer that stalled on the channel
+    Resumed {
+        /// The peer that was silent
+        peer: PeerId,
+        /// The index of the channel in
+        /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+        channel: usize,
+    },
+}
+
+/// When each peer was last heard from on a channel, in milliseconds since
+/// the epoch, shared by the channel's [`IncomingSender`] and the message loop
+#[derive(Debug, Default)]
+pub(crate) struct LastReceived(Mutex<HashMap<PeerId, u64>>);
+
+impl LastReceived {
+    pub fn record(&self, peer: &PeerId, now_ms: u64) {
+        self.peers().insert(peer.clone(), now_ms);
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<u64> {
+        self.peers().get(peer).copied()
+    }
+
+    pub fn remove(&self, peer: &PeerId) {
+        self.peers().remove(peer);
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, u64>> {
+        self.0.lock().expect("last received lock poisoned")
+    }
+}
+
+/// Sends the keep-alives of channels with
+/// [`ChannelConfig::keep_alive_interval_ms`](crate::ChannelConfig::keep_alive_interval_ms),
+/// and notices peers going silent on channels with
+/// [`ChannelConfig::silence_timeout_ms`](crate::ChannelConfig::silence_timeout_ms)
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    /// The keep-alive interval and silence timeout of every channel
+    channels: Vec<(Option<u64>, Option<u64>)>,
+    /// When each connected peer connected, silence is counted from then on
+    connected: HashMap<PeerId, u64>,
+    keep_alives_sent: HashMap<(PeerId, usize), u64>,
+    stalled: HashSet<(PeerId, usize)>,
+}
+
+impl Liveness {
+    pub fn new(config: &WebRtcSocketConfig) -> Self {
+        Self {
+            channels: config
+                .channels
+                .iter()
+                .map(|channel| (channel.keep_alive_interval_ms, channel.silence_timeout_ms))
+                .collect(),
+            connected: HashMap::new(),
+            keep_alives_sent: HashMap::new(),
+            stalled: HashSet::new(),
+        }
+    }
+
+    /// Returns a timer for the next check, or one that never fires if no
+    /// channel needs checking
+    pub fn next_check(&self) -> Fuse<Delay> {
+        let enabled = self
+            .channels
+            .iter()
+            .any(|(keep_alive, silence)| keep_alive.is_some() || silence.is_some());
+        if enabled {
+            Delay::new(Duration::from_millis(LIVENESS_CHECK_INTERVAL_MS)).fuse()
+        } else {
+            Fuse::terminated()
+        }
+    }
+
+    pub fn connected(&mut self, peer: &PeerId, now_ms: u64) {
+        self.connected.insert(peer.clone(), now_ms);
+    }
+
+    /// Forgets a peer that disconnected or is reconnecting, without
+    /// reporting it as resumed
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+        self.keep_alives_sent
+            .retain(|(sent_to, _), _| sent_to != peer);
+        self.stalled.retain(|(stalled, _)| stalled != peer);
+    }
+
+    /// Returns the channels and peers due for a keep-alive, and which peers
+    /// stalled or resumed on which channels since the last check
+    pub fn check(
+        &mut self,
+        now_ms: u64,
+        channels: &[IncomingSender],
+    ) -> (Vec<(usize, PeerId)>, Vec<ChannelLiveness>) {
+        let mut keep_alives = vec![];
+        let mut changes = vec![];
+        for (peer, connected_at) in &self.connected {
+            for (index, (keep_alive, silence)) in self.channels.iter().enumerate() {
+                let channel = &channels[index];
+                if !channel.is_open(peer) {
+                    continue;
+                }
+                if let Some(interval) = keep_alive {
+                    let sent = self
+                        .keep_alives_sent
+                        .entry((peer.clone(), index))
+                        .or_insert(*connected_at);
+                    if now_ms >= *sent + interval {
+                        *sent = now_ms;
+                        keep_alives.push((index, peer.clone()));
+                    }
+                }
+                if let Some(timeout) = silence {
+                    let heard = channel.last_received(peer).unwrap_or(0).max(*connected_at);
+                    let silent = now_ms.saturating_sub(heard) > *timeout;
+                    let key = (peer.clone(), index);
+                    if silent && self.stalled.insert(key.clone()) {
+                        changes.push(ChannelLiveness::Stalled {
+                            peer: peer.clone(),
+                            channel: index,
+                        });
+                    } else if !silent && self.stalled.remove(&key) {
+                        changes.push(ChannelLiveness::Resumed {
+                            peer: peer.clone(),
+                            channel: index,
+                        });
+                    }
+                }
+            }
+        }
+        (keep_alives, changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ChannelLiveness, Liveness, KEEP_ALIVE};
+    use crate::{
+        webrtc_socket::{recording::now_ms, IncomingSender, PacketPool},
+        ChannelConfig, WebRtcSocketConfig,
+    };
+
+    #[test]
+    fn silent_channels_stall_and_resume() {
+        let config = WebRtcSocketConfig {
+            channels: vec![
+                ChannelConfig {
+                    keep_alive_interval_ms: Some(100),
+                    silence_timeout_ms: Some(500),
+                    ..ChannelConfig::reliable()
+                },
+                ChannelConfig::unreliable(),
+            ],
+            ..Default::default()
+        };
+        let channels: Vec<_> = config
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(index, channel)| {
+                let (tx, _rx) = futures_channel::mpsc::unbounded();
+                IncomingSender::new(tx, PacketPool::new(0), Arc::default(), index, false)
+                    .with_liveness(channel.silence_timeout_ms.is_some())
+            })
+            .collect();
+        let peer = "peer".to_string();
+        let mut liveness = Liveness::new(&config);
+        liveness.connected(&peer, 1000);
+
+        assert_eq!(liveness.check(1050, &channels), (vec![], vec![]));
+        let (keep_alives, changes) = liveness.check(1100, &channels);
+        assert_eq!(keep_alives, vec![(0, peer.clone())]);
+        assert!(changes.is_empty());
+
+        let (_, changes) = liveness.check(1600, &channels);
+        let stalled = ChannelLiveness::Stalled {
+            peer: peer.clone(),
+            channel: 0,
+        };
+        assert_eq!(changes, vec![stalled]);
+        assert!(liveness.check(1700, &channels).1.is_empty());
+
+        // a keep-alive counts as being heard from
+        channels[0].send(&peer, KEEP_ALIVE, false);
+        let (_, changes) = liveness.check(now_ms() as u64, &channels);
+        assert_eq!(
+            changes,
+            vec![ChannelLiveness::Resumed {
+                peer: peer.clone(),
+                channel: 0
+            }]
+        );
+
+        liveness.disconnected(&peer);
+        assert_eq!(liveness.check(u64::MAX, &channels), (vec![], vec![]));
+    }
+}
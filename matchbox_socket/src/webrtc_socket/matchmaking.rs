@@ -0,0 +1,102 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use futures_util::select;
+use log::{debug, warn};
+
+use crate::{
+    webrtc_socket::{
+        messages::{MatchmakingRegion, PeerEvent, PeerRequest},
+        room_url_on_same_server, KEEP_ALIVE_INTERVAL,
+    },
+    Error,
+};
+
+/// How often to measure and report our latency to the matchmaking regions,
+/// in milliseconds
+const LATENCY_REPORT_INTERVAL: u64 = 5_000;
+
+/// Replaces the message loop while a socket waits in the matchmaking queue,
+/// see [`WebRtcSocketConfig::matchmaking`](crate::WebRtcSocketConfig::matchmaking)
+///
+/// Periodically reports our latency to the regions the server asks for, and
+/// resolves with the url of the room the server matched us into, on the same
+/// server as `room_url`.
+pub(crate) async fn queue_loop(
+    room_url: String,
+    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
+    mut leave_rx: futures_channel::oneshot::Receiver<()>,
+) -> Result<Option<String>, Error> {
+    debug!("Joining the matchmaking queue");
+    requests_sender
+        .unbounded_send(PeerRequest::JoinQueue)
+        .expect("failed to send queue request");
+
+    let mut regions = vec![];
+    let keep_alive = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+    let report = Delay::new(Duration::from_millis(LATENCY_REPORT_INTERVAL));
+    futures::pin_mut!(keep_alive, report);
+
+    loop {
+        select! {
+            _ = (&mut keep_alive).fuse() => {
+                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
+                keep_alive.reset(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+            }
+
+            _ = (&mut report).fuse() => {
+                let latencies = measure_latencies(&regions).await;
+                if !latencies.is_empty() {
+                    requests_sender.unbounded_send(PeerRequest::Latency(latencies)).expect("send failed");
+                }
+                report.reset(Duration::from_millis(LATENCY_REPORT_INTERVAL));
+            }
+
+            _ = leave_rx => {
+                debug!("Leaving the matchmaking queue");
+                break;
+            }
+
+            event = events_receiver.next() => match event {
+                Some(PeerEvent::MatchmakingRegions(new_regions)) => {
+                    regions = new_regions;
+                    // no need to wait for the first report
+                    report.reset(Duration::ZERO);
+                }
+                Some(PeerEvent::MatchFound { room }) => {
+                    debug!("matched into {room:?}");
+                    return Ok(Some(room_url_on_same_server(&room_url, &room, None)));
+                }
+                Some(event) => debug!("ignoring {event:?} while queued"),
+                // Disconnected from signalling server
+                None => break,
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Measures our latency to each region, in milliseconds, leaving out the
+/// ones that can't be reached
+#[cfg(not(target_arch = "wasm32"))]
+async fn measure_latencies(regions: &[MatchmakingRegion]) -> HashMap<String, u64> {
+    let mut latencies = HashMap::new();
+    for region in regions {
+        match crate::webrtc_socket::stun_latency(&region.stun_url).await {
+            Ok(latency) => {
+                latencies.insert(region.name.clone(), latency.as_millis() as u64);
+            }
+            Err(e) => warn!("failed to measure latency to {:?}: {e}", region.name),
+        }
+    }
+    latencies
+}
+
+/// Browsers can't send STUN requests themselves, so the application has to
+/// report latencies with [`WebRtcSender::report_latency`](crate::WebRtcSender::report_latency)
+#[cfg(target_arch = "wasm32")]
+async fn measure_latencies(_regions: &[MatchmakingRegion]) -> HashMap<String, u64> {
+    HashMap::new()
+}
@@ -1,26 +1,600 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
 
-pub(crate) type PeerId = String;
+use crate::webrtc_socket::{
+    ChannelLiveness, PeerHandshake, ResumeEvent, RtcIceServerConfig, SignallingState,
+};
+
+/// Id of a peer, a uuid for peers of [`WebRtcSocket`](crate::WebRtcSocket)
+///
+/// Being a `String`, it parses with [`FromStr`](std::str::FromStr) and
+/// (de)serializes with serde like any other string. Use [`peer_id_to_u128`]
+/// to pack it into 16 bytes instead, e.g. in game packets.
+pub type PeerId = String;
+
+/// Returns the first 8 characters of a peer id, e.g. for showing peers in a
+/// game's UI or in logs
+///
+/// ```
+/// use matchbox_socket::short_peer_id;
+///
+/// assert_eq!(short_peer_id("8c2dd1a4-31c5-4a6b-9f57-0e3c8de5b7a2"), "8c2dd1a4");
+/// assert_eq!(short_peer_id("host"), "host");
+/// ```
+pub fn short_peer_id(id: &str) -> &str {
+    match id.char_indices().nth(8) {
+        Some((end, _)) => &id[..end],
+        None => id,
+    }
+}
+
+/// Packs a uuid peer id into a `u128`, e.g. to send it in 16 bytes, or
+/// returns `None` if the id isn't a uuid
+///
+/// ```
+/// use matchbox_socket::{peer_id_from_u128, peer_id_to_u128};
+///
+/// let id = "8c2dd1a4-31c5-4a6b-9f57-0e3c8de5b7a2";
+/// let packed = peer_id_to_u128(id).unwrap();
+/// assert_eq!(peer_id_from_u128(packed), id);
+/// assert_eq!(peer_id_to_u128("host"), None);
+/// ```
+pub fn peer_id_to_u128(id: &str) -> Option<u128> {
+    uuid::Uuid::parse_str(id).ok().map(|uuid| uuid.as_u128())
+}
+
+/// Turns an id packed with [`peer_id_to_u128`] back into a peer id
+///
+/// The id is a lowercase, hyphenated uuid, like the ids the socket creates.
+pub fn peer_id_from_u128(id: u128) -> PeerId {
+    uuid::Uuid::from_u128(id).to_string()
+}
 
 /// Events go from signalling server to peer
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+///
+/// See [`crate::protocol`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum PeerEvent {
+    /// A peer joined our room, and we should connect to it
     NewPeer(PeerId),
-    Signal { sender: PeerId, data: PeerSignal },
+    /// A signal a peer in our room sent us
+    Signal {
+        /// The peer that sent the signal
+        sender: PeerId,
+        /// The signal
+        data: PeerSignal,
+    },
+    /// The signalling server is disconnecting us on purpose
+    Error(SignallingErrorCode),
+    /// The display name of a peer, sent before it is announced
+    PeerName {
+        /// The named peer
+        peer: PeerId,
+        /// Its display name
+        name: String,
+    },
+    /// The features a peer supports, sent before it is announced
+    PeerCapabilities {
+        /// The peer supporting the features
+        peer: PeerId,
+        /// Names of the features
+        capabilities: Vec<String>,
+    },
+    /// The role the server gave a peer, sent before it is announced, and to
+    /// us when we join
+    PeerRole {
+        /// The peer given the role
+        peer: PeerId,
+        /// Its role
+        role: PeerRole,
+    },
+    /// We and everyone in our room were moved to another room
+    RoomMigrated {
+        /// Id of the room we were moved to
+        room: String,
+        /// Group size of the room we were moved to
+        next: Option<usize>,
+    },
+    /// Our request to migrate the room was refused, e.g. because we didn't
+    /// create it or the target room is full
+    MigrationRejected(SignallingErrorCode),
+    /// The configuration of our room, sent when we join or migrate to a room
+    /// that has any
+    RoomPolicy(RoomInfo),
+    /// The peers in the room we observe, see
+    /// [`WebRtcSocketConfig::signalling_only`](crate::WebRtcSocketConfig::signalling_only)
+    RoomPeers(Vec<PeerId>),
+    /// Part of the peers in the room we observe, sent instead of
+    /// [`PeerEvent::RoomPeers`] for large rooms, followed by the next page or
+    /// [`PeerEvent::PeerListComplete`]
+    RoomPeersPage(Vec<PeerId>),
+    /// All [`PeerEvent::RoomPeersPage`]s of the peer list were sent
+    PeerListComplete,
+    /// A message from a peer in our room, or replayed from the room's event
+    /// log when we join
+    RoomMessage {
+        /// The peer that sent the message
+        sender: PeerId,
+        /// The message
+        data: String,
+    },
+    /// The description of our room, sent when we join a room that has one
+    /// and whenever it changes
+    RoomMetadata(RoomMetadata),
+    /// The regions to report our latency to, sent when we join the
+    /// matchmaking queue
+    MatchmakingRegions(Vec<MatchmakingRegion>),
+    /// The room the matchmaking queue put us into
+    MatchFound {
+        /// Id of the room to join
+        room: String,
+    },
+    /// TURN servers of the signalling server, with credentials for us
+    IceServers(Vec<RtcIceServerConfig>),
+    /// Our room was closed by its host, or by the server if there is none
+    RoomClosed {
+        /// The host that closed the room, or `None` if the server did
+        host: Option<PeerId>,
+    },
+    /// Our room will be closed for being idle in this many seconds, unless
+    /// someone in it sends something
+    RoomIdle {
+        /// Seconds until the room is closed
+        closes_in_secs: u64,
+    },
+    /// Our signals to `receiver` are being dropped for sending too many ICE
+    /// candidates or offers
+    SignalsThrottled {
+        /// The peer our signals were meant for
+        receiver: PeerId,
+    },
+    /// How many peers of our room haven't been told about us yet, when the
+    /// server paces introductions in large rooms
+    IntroductionsPending(usize),
+    /// Our position in the queue of peers waiting for the server to have
+    /// space for them, starting at 1, or 0 once we're let in
+    ServerQueuePosition(usize),
+    /// The peers in a room we joined with [`PeerRequest::JoinExtraRoom`],
+    /// sent when we join and whenever a peer joins or leaves
+    ExtraRoomPeers {
+        /// Id of the room
+        room: String,
+        /// The peers in it, including us
+        peers: Vec<PeerId>,
+    },
+    /// A message from a peer in a room we joined with
+    /// [`PeerRequest::JoinExtraRoom`]
+    ExtraRoomMessage {
+        /// Id of the room
+        room: String,
+        /// The peer that sent the message
+        sender: PeerId,
+        /// The message
+        data: String,
+    },
+}
+
+/// What a peer is in its room, given to it by the signalling server when it
+/// joins
+///
+/// The peer that creates a room is its host, the others are players, unless
+/// the server is configured otherwise.
+///
+/// See [`WebRtcSocket::peer_role`](crate::WebRtcSocket::peer_role).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum PeerRole {
+    /// The authority of the room, e.g. for an authoritative-host game
+    Host,
+    /// Takes part in the game
+    Player,
+    /// Watches the game without taking part in it
+    Spectator,
+    /// Oversees the game, e.g. in tournaments
+    Referee,
+}
+
+/// Who ended the match in our room, see
+/// [`WebRtcSocket::room_closed`](crate::WebRtcSocket::room_closed)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomClosedBy {
+    /// The peer that created the room, see
+    /// [`WebRtcSender::close_room`](crate::WebRtcSender::close_room)
+    Host(PeerId),
+    /// The signalling server, e.g. through its admin api
+    Server,
+}
+
+impl From<Option<PeerId>> for RoomClosedBy {
+    fn from(host: Option<PeerId>) -> Self {
+        host.map_or(RoomClosedBy::Server, RoomClosedBy::Host)
+    }
+}
+
+/// Configuration the signalling server advertises for a room
+///
+/// See [`WebRtcSocket::room_info`](crate::WebRtcSocket::room_info).
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoomInfo {
+    /// Group size the room pairs peers in, overriding the `next` parameter
+    /// of the room url
+    pub next: Option<usize>,
+    /// Maximum number of peers in the room, more are rejected with
+    /// [`SignallingError::RoomFull`](crate::SignallingError::RoomFull)
+    pub max_peers: Option<usize>,
+    /// Number of channels every peer in the room is expected to open, see
+    /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    pub channels: Option<usize>,
+}
+
+/// Describes a room, set by the peer that created it
+///
+/// See [`WebRtcSocket::room_metadata`](crate::WebRtcSocket::room_metadata).
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RoomMetadata {
+    /// Game mode played in the room
+    pub game_mode: Option<String>,
+    /// Map played in the room
+    pub map: Option<String>,
+    /// Client version required to join the room, if the server matches
+    /// versions strictly
+    ///
+    /// Defaults to the creator's
+    /// [`WebRtcSocketConfig::client_version`](crate::WebRtcSocketConfig::client_version).
+    pub version: Option<String>,
+}
+
+/// A region the signalling server's matchmaking queue groups peers by
+///
+/// See [`WebRtcSocket::matchmaking_regions`](crate::WebRtcSocket::matchmaking_regions).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MatchmakingRegion {
+    /// Name of the region, e.g. `"eu"`
+    pub name: String,
+    /// STUN server in the region to measure our latency to, e.g.
+    /// `"stun:stun.eu.example.com:3478"`
+    pub stun_url: String,
+}
+
+/// Information about our room, passed from the signalling loop to the socket
+#[derive(Debug)]
+pub(crate) enum RoomUpdate {
+    /// The display name of a peer
+    PeerName { peer: PeerId, name: String },
+    /// The features a peer supports
+    PeerCapabilities {
+        peer: PeerId,
+        capabilities: Vec<String>,
+    },
+    /// The role the server gave a peer
+    PeerRole { peer: PeerId, role: PeerRole },
+    /// The configuration of the room, if it has any
+    Info(Option<RoomInfo>),
+    /// The peers in the room we observe
+    Peers(Vec<PeerId>),
+    /// Part of the peers in the room we observe, see
+    /// [`PeerEvent::RoomPeersPage`]
+    PeersPage(Vec<PeerId>),
+    /// The pages of the peer list were all sent
+    PeerListComplete,
+    /// The state of the connection to the signalling server
+    Signalling(SignallingState),
+    /// The url of the room on the signalling server we connected to
+    SignallingUrl(String),
+    /// A message from a peer in the room
+    Message { sender: PeerId, data: String },
+    /// The description of the room, if it has one
+    Metadata(Option<RoomMetadata>),
+    /// The regions of the matchmaking queue we're in
+    MatchmakingRegions(Vec<MatchmakingRegion>),
+    /// What happened when the socket was resumed
+    Resume(ResumeEvent),
+    /// Who closed the room, if it was closed
+    Closed(Option<RoomClosedBy>),
+    /// The group size of the room we joined or were migrated to, and whether
+    /// we're waiting in the matchmaking queue instead
+    Group { next: Option<usize>, queued: bool },
+    /// The handshake data a peer sent, after it was accepted
+    PeerHandshake(PeerHandshake),
+    /// How long until the room is closed for being idle
+    IdleWarning(Duration),
+    /// How many peers of the room haven't been told about us yet
+    IntroductionsPending(usize),
+    /// Our position in the server's queue, 0 once we're let in
+    ServerQueuePosition(usize),
+    /// The peers in a room we joined besides our own
+    ExtraRoomPeers { room: String, peers: Vec<PeerId> },
+    /// A message from a peer in a room we joined besides our own
+    ExtraRoomMessage {
+        room: String,
+        sender: PeerId,
+        data: String,
+    },
+    /// We left a room we joined besides our own, or all of them when
+    /// joining another room
+    ExtraRoomLeft(Option<String>),
+    /// A peer stalled or resumed on a channel
+    ChannelLiveness(ChannelLiveness),
 }
 
-// TODO: move back into lib
 /// Requests go from peer to signalling server
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+///
+/// See [`crate::protocol`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum PeerRequest {
+    /// Join the room of the url we connected to with this id, after any
+    /// other registration requests
     Uuid(PeerId),
-    Signal { receiver: PeerId, data: PeerSignal },
+    /// Relay a signal to a peer in our room
+    Signal {
+        /// The peer to relay the signal to
+        receiver: PeerId,
+        /// The signal
+        data: PeerSignal,
+    },
+    /// Keep the connection to the server open, sent while nothing else is
     KeepAlive,
+    /// Display name to register, must be sent before [`PeerRequest::Uuid`]
+    Name(String),
+    /// Move everyone in our room to another room
+    MigrateRoom {
+        /// Id of the room to move to
+        room: String,
+        /// Group size of the room to move to
+        next: Option<usize>,
+    },
+    /// Watch who is in the room without joining it, sent instead of
+    /// [`PeerRequest::Uuid`]
+    Observe,
+    /// Message for everyone else in our room, kept in the room's event log
+    /// if it has one
+    RoomMessage(String),
+    /// Version of our client, must be sent before [`PeerRequest::Uuid`]
+    Version(String),
+    /// Describe our room, only accepted if we created it
+    SetRoomMetadata(RoomMetadata),
+    /// Features we support, must be sent before [`PeerRequest::Uuid`]
+    Capabilities(Vec<String>),
+    /// Hold free slots of our `next` group for peers claiming these tokens
+    ReserveSlots(Vec<String>),
+    /// Token of a slot reserved for us, must be sent before
+    /// [`PeerRequest::Uuid`]
+    ClaimSlot(String),
+    /// Wait in the matchmaking queue for a room, sent instead of
+    /// [`PeerRequest::Uuid`]
+    JoinQueue,
+    /// Our latency to matchmaking regions, in milliseconds
+    Latency(HashMap<String, u64>),
+    /// End the match for everyone in our room, only accepted if we created
+    /// it
+    CloseRoom,
+    /// Keep our room from being closed for being idle
+    KeepRoomAlive,
+    /// Join another room besides ours, to see who is in it and exchange room
+    /// messages with them, once we sent [`PeerRequest::Uuid`]
+    JoinExtraRoom(String),
+    /// Leave a room joined with [`PeerRequest::JoinExtraRoom`]
+    LeaveExtraRoom(String),
+    /// Message for everyone else in a room joined with
+    /// [`PeerRequest::JoinExtraRoom`]
+    ExtraRoomMessage {
+        /// Id of the room
+        room: String,
+        /// The message
+        data: String,
+    },
+}
+
+impl PeerRequest {
+    /// Whether the request tells the server who we are, so it has to be
+    /// repeated when reconnecting to it
+    pub fn is_registration(&self) -> bool {
+        matches!(
+            self,
+            Self::Uuid(_)
+                | Self::Name(_)
+                | Self::Version(_)
+                | Self::Capabilities(_)
+                | Self::ClaimSlot(_)
+                | Self::Observe
+                | Self::JoinQueue
+                | Self::JoinExtraRoom(_)
+        )
+    }
+}
+
+/// Why the signalling server is closing the connection
+///
+/// Each code maps to the [`SignallingError`](crate::SignallingError) of the
+/// same name.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SignallingErrorCode {
+    /// The room has no space left for us
+    RoomFull,
+    /// We aren't allowed to join the room
+    Unauthorized,
+    /// We sent something the server didn't understand
+    ProtocolMismatch,
+    /// We sent too many requests
+    RateLimited,
+    /// The server is shutting down
+    ServerShutdown,
+    /// An admin removed us from the room
+    Kicked,
+    /// We are banned from the server
+    Banned,
+    /// Our client version doesn't match the room's
+    VersionMismatch,
+    /// Another peer already uses our id
+    IdTaken,
+    /// Our id isn't one the server accepts
+    InvalidId,
+    /// The server has no space for more peers or rooms right now, try again
+    /// after this many seconds
+    ServerBusy {
+        /// Seconds to wait before trying again
+        retry_after_secs: u64,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Data peers exchange through the signalling server to connect to each
+/// other, relayed as is
+///
+/// See [`crate::protocol`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum PeerSignal {
+    /// An ICE candidate of the sender, in the format of the browser's
+    /// `RTCIceCandidate.candidate`
     IceCandidate(String),
+    /// An SDP offer
     Offer(String),
+    /// An SDP answer to our offer
     Answer(String),
+    /// Sent by a custom [`Messenger`](crate::Messenger)
+    Custom(String),
+    /// Our [`WebRtcSocketConfig::handshake_data`](crate::WebRtcSocketConfig::handshake_data),
+    /// sent once we're connected
+    Handshake(Vec<u8>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
+
+    /// Checks that `message` is sent as `json`, and parsed back unchanged
+    fn assert_wire_format<T>(message: T, json: &str)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+        assert_eq!(serde_json::from_str::<T>(json).unwrap(), message);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        assert_wire_format(PeerRequest::Uuid("a".to_string()), r#"{"Uuid":"a"}"#);
+        assert_wire_format(PeerRequest::KeepAlive, r#""KeepAlive""#);
+        assert_wire_format(
+            PeerRequest::Signal {
+                receiver: "b".to_string(),
+                data: PeerSignal::IceCandidate("candidate".to_string()),
+            },
+            r#"{"Signal":{"receiver":"b","data":{"IceCandidate":"candidate"}}}"#,
+        );
+        assert_wire_format(
+            PeerRequest::MigrateRoom {
+                room: "next".to_string(),
+                next: Some(2),
+            },
+            r#"{"MigrateRoom":{"room":"next","next":2}}"#,
+        );
+        assert_wire_format(
+            PeerRequest::SetRoomMetadata(RoomMetadata {
+                map: Some("dust".to_string()),
+                ..Default::default()
+            }),
+            r#"{"SetRoomMetadata":{"game_mode":null,"map":"dust","version":null}}"#,
+        );
+        assert_wire_format(
+            PeerRequest::Latency(HashMap::from([("eu".to_string(), 20)])),
+            r#"{"Latency":{"eu":20}}"#,
+        );
+        assert_wire_format(
+            PeerRequest::ExtraRoomMessage {
+                room: "chat".to_string(),
+                data: "gg".to_string(),
+            },
+            r#"{"ExtraRoomMessage":{"room":"chat","data":"gg"}}"#,
+        );
+    }
+
+    #[test]
+    fn events_round_trip() {
+        assert_wire_format(PeerEvent::NewPeer("a".to_string()), r#"{"NewPeer":"a"}"#);
+        assert_wire_format(
+            PeerEvent::Signal {
+                sender: "a".to_string(),
+                data: PeerSignal::Handshake(vec![1, 2]),
+            },
+            r#"{"Signal":{"sender":"a","data":{"Handshake":[1,2]}}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::Error(SignallingErrorCode::RoomFull),
+            r#"{"Error":"RoomFull"}"#,
+        );
+        assert_wire_format(
+            PeerEvent::Error(SignallingErrorCode::ServerBusy {
+                retry_after_secs: 10,
+            }),
+            r#"{"Error":{"ServerBusy":{"retry_after_secs":10}}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::PeerRole {
+                peer: "a".to_string(),
+                role: PeerRole::Spectator,
+            },
+            r#"{"PeerRole":{"peer":"a","role":"Spectator"}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::RoomPolicy(RoomInfo {
+                next: Some(2),
+                max_peers: None,
+                channels: Some(1),
+            }),
+            r#"{"RoomPolicy":{"next":2,"max_peers":null,"channels":1}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::IceServers(vec![RtcIceServerConfig {
+                urls: vec!["turn:turn.example.com".to_string()],
+                username: Some("user".to_string()),
+                credential: None,
+            }]),
+            r#"{"IceServers":[{"urls":["turn:turn.example.com"],"username":"user","credential":null}]}"#,
+        );
+        assert_wire_format(
+            PeerEvent::RoomClosed { host: None },
+            r#"{"RoomClosed":{"host":null}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::ExtraRoomPeers {
+                room: "chat".to_string(),
+                peers: vec!["a".to_string()],
+            },
+            r#"{"ExtraRoomPeers":{"room":"chat","peers":["a"]}}"#,
+        );
+        assert_wire_format(
+            PeerEvent::RoomPeersPage(vec!["a".to_string()]),
+            r#"{"RoomPeersPage":["a"]}"#,
+        );
+        assert_wire_format(PeerEvent::PeerListComplete, r#""PeerListComplete""#);
+    }
+
+    #[test]
+    fn unknown_events_are_ignored() {
+        let event = crate::webrtc_socket::parse_event(r#"{"FromTheFuture":{"x":1}}"#);
+        assert!(matches!(event, Ok(None)));
+        let event = crate::webrtc_socket::parse_event("not json");
+        assert!(matches!(event, Err(crate::Error::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn uuid_peer_ids_pack_into_u128() {
+        let id = uuid::Uuid::new_v4().to_string();
+        let packed = peer_id_to_u128(&id).expect("not a uuid");
+        assert_eq!(packed, uuid::Uuid::parse_str(&id).unwrap().as_u128());
+        assert_eq!(peer_id_from_u128(packed), id);
+
+        assert_eq!(
+            peer_id_to_u128("8C2DD1A4-31C5-4A6B-9F57-0E3C8DE5B7A2").map(peer_id_from_u128),
+            Some("8c2dd1a4-31c5-4a6b-9f57-0e3c8de5b7a2".to_string())
+        );
+        assert_eq!(peer_id_to_u128("host"), None);
+        assert_eq!(peer_id_to_u128(""), None);
+    }
 }
@@ -0,0 +1,540 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use futures::{
+    future,
+    stream::{self, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_timer::Delay;
+use futures_util::select;
+use log::{debug, warn};
+
+use crate::{
+    webrtc_socket::{
+        channels_by_priority,
+        coalesce::Coalescer,
+        forward_to_peer,
+        messages::{PeerEvent, PeerId, PeerRequest, PeerSignal},
+        new_senders_and_receivers, next_peer_message_out, open_channels_with,
+        reconnect::{AttemptEvent, AttemptReporter, Reconnector},
+        signal_peer::SignalPeer,
+        Approvals, Approved, ChannelInfo, ConnectionInfo, Handshakes, IncomingSender,
+        MessageLoopChannels, PeerState, PooledPacket, WebRtcSocketConfig, KEEP_ALIVE_INTERVAL,
+    },
+    Error,
+};
+
+/// `Send` on native, where the message loop may move between threads, and
+/// nothing on wasm
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` on native, where the message loop may move between threads, and
+/// nothing on wasm
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Send + Sync` on native, and nothing on wasm, see [`MaybeSend`]
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+/// `Send + Sync` on native, and nothing on wasm, see [`MaybeSend`]
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSendSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSendSync for T {}
+
+/// Why a [`Messenger`] failed to connect to or send to a peer
+pub type MessengerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Resolves with the connection to a peer, see [`Messenger::connect`]
+#[cfg(not(target_arch = "wasm32"))]
+pub type ConnectFuture =
+    Pin<Box<dyn Future<Output = Result<Box<dyn MessengerConnection>, MessengerError>> + Send>>;
+/// Resolves with the connection to a peer, see [`Messenger::connect`]
+#[cfg(target_arch = "wasm32")]
+pub type ConnectFuture =
+    Pin<Box<dyn Future<Output = Result<Box<dyn MessengerConnection>, MessengerError>>>>;
+
+/// Carries packets between peers in place of WebRTC data channels, e.g.
+/// memory channels in tests, QUIC, or a platform relay
+///
+/// Matchbox still finds the peers through the signalling server, and keeps
+/// track of them just like with WebRTC: peer states, reconnects, channel
+/// priorities, coalescing, stats and dead letters all work the same. The
+/// messenger only has to connect to a peer, and send packets to it once it
+/// has. See [`WebRtcSocket::new_with_messenger`](crate::WebRtcSocket::new_with_messenger).
+///
+/// # Example
+///
+/// A messenger that just hands the peer's packets back:
+///
+/// ```
+/// use matchbox_socket::{ConnectFuture, Messenger, MessengerConnection, MessengerError, MessengerPeer};
+///
+/// struct Echo;
+///
+/// impl Messenger for Echo {
+///     fn connect(&self, peer: MessengerPeer) -> ConnectFuture {
+///         let connection: Box<dyn MessengerConnection> = Box::new(EchoConnection(peer));
+///         Box::pin(async move { Ok(connection) })
+///     }
+/// }
+///
+/// struct EchoConnection(MessengerPeer);
+///
+/// impl MessengerConnection for EchoConnection {
+///     fn send(&mut self, channel: usize, packet: &[u8]) -> Result<(), MessengerError> {
+///         self.0.incoming.deliver(channel, packet);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait Messenger: MaybeSendSync {
+    /// Connects to a peer, resolving once packets can be sent to it
+    ///
+    /// Called for every connection attempt, including reconnects. Exchange
+    /// whatever the transport needs to connect, e.g. addresses or keys, with
+    /// the peer's messenger through [`MessengerPeer::signaller`]. The
+    /// [initiating](MessengerPeer::initiator) side has to send the first
+    /// signal, the other side's `connect` is only called once it arrives.
+    fn connect(&self, peer: MessengerPeer) -> ConnectFuture;
+}
+
+/// A connection to a single peer, made by a [`Messenger`]
+///
+/// Dropped when the peer disconnects, or before reconnecting to it.
+pub trait MessengerConnection: MaybeSend {
+    /// Sends a packet on a channel, by its index in
+    /// [`WebRtcSocketConfig::channels`]
+    ///
+    /// Only called for the peer's [`MessengerPeer::open_channels`]. Shouldn't
+    /// block, queue the packet instead if the transport isn't ready. An error
+    /// counts as a failed send, and reconnects to the peer.
+    fn send(&mut self, channel: usize, packet: &[u8]) -> Result<(), MessengerError>;
+
+    /// The parameters of the connection, see
+    /// [`WebRtcSocket::connection_info`](crate::WebRtcSocket::connection_info)
+    ///
+    /// Defaults to the configured channels, without a message size limit.
+    fn connection_info(&self) -> Option<ConnectionInfo> {
+        None
+    }
+}
+
+/// A peer for a [`Messenger`] to connect to
+#[derive(Debug)]
+pub struct MessengerPeer {
+    /// The peer's id
+    pub id: PeerId,
+    /// Whether we start the connection, and send the first signal
+    ///
+    /// Exactly one of both sides is the initiator.
+    pub initiator: bool,
+    /// Which channels are opened with the peer, in the order of
+    /// [`WebRtcSocketConfig::channels`], see
+    /// [`ChannelConfig::required_capability`](crate::ChannelConfig::required_capability)
+    pub open_channels: Vec<bool>,
+    /// Exchanges messages with the peer's messenger through the signalling
+    /// server
+    pub signaller: Signaller,
+    /// Hands the packets the peer sends us to the socket
+    pub incoming: IncomingPackets,
+}
+
+/// Exchanges messages with a peer's [`Messenger`] through the signalling
+/// server, see [`MessengerPeer::signaller`]
+#[derive(Debug)]
+pub struct Signaller {
+    signal_peer: SignalPeer,
+    receiver: UnboundedReceiver<PeerSignal>,
+}
+
+impl Signaller {
+    /// Sends a message to the peer's messenger
+    pub fn send(&self, data: String) {
+        self.signal_peer.send(PeerSignal::Custom(data));
+    }
+
+    /// Waits for the next message from the peer's messenger
+    ///
+    /// Resolves with `None` once the connection attempt is given up on, or
+    /// the connection to the signalling server is lost.
+    pub async fn receive(&mut self) -> Option<String> {
+        while let Some(signal) = self.receiver.next().await {
+            match signal {
+                PeerSignal::Custom(data) => return Some(data),
+                signal => warn!("ignoring WebRTC signal {signal:?}"),
+            }
+        }
+        None
+    }
+}
+
+/// Hands the packets a peer sends to the socket, see
+/// [`MessengerPeer::incoming`]
+///
+/// Cheap to clone, e.g. into a task that receives from the peer.
+#[derive(Debug, Clone)]
+pub struct IncomingPackets {
+    peer: PeerId,
+    channels: Vec<IncomingSender>,
+    coalesce: Vec<bool>,
+    attempt: AttemptReporter,
+}
+
+impl IncomingPackets {
+    /// Delivers a packet the peer sent on the given channel
+    pub fn deliver(&self, channel: usize, packet: &[u8]) {
+        match self.channels.get(channel) {
+            Some(sender) => sender.send(&self.peer, packet, self.coalesce[channel]),
+            None => warn!(
+                "dropping packet from {:?} on unknown channel {channel}",
+                self.peer
+            ),
+        }
+    }
+
+    /// Reports that the connection to the peer was lost
+    ///
+    /// The socket reconnects according to
+    /// [`WebRtcSocketConfig::reconnect_attempts`], calling
+    /// [`Messenger::connect`] again.
+    pub fn disconnected(&self) {
+        self.attempt.failed();
+    }
+}
+
+/// Like the platform's message loop, but connects to peers with a
+/// [`Messenger`]
+pub(crate) async fn messenger_loop(
+    messenger: Arc<dyn Messenger>,
+    id: PeerId,
+    config: WebRtcSocketConfig,
+    channels: MessageLoopChannels<'_>,
+) -> Result<(), Error> {
+    let MessageLoopChannels {
+        requests_sender,
+        mut events_receiver,
+        peer_messages_out_rx,
+        peer_state_tx,
+        peer_info_tx,
+        congestion: _,
+        room_tx,
+        messages_from_peers_tx,
+        mut leave_rx,
+    } = channels;
+    debug!("I am {:?}, connecting to peers with a custom messenger", id);
+    // the signalling loop may be gone already, e.g. if the room was closed
+    // right away, run_room stops us then
+    let _ = requests_sender.unbounded_send(PeerRequest::Uuid(id));
+
+    let mut peer_loops = FuturesUnordered::new();
+    let mut handshake_signals = HashMap::new();
+    // signals of new peers waiting for a free handshake slot, starting with their first one
+    let mut queued_signals: HashMap<PeerId, Vec<PeerSignal>> = HashMap::new();
+    let mut connected_peers = HashMap::new();
+    let mut peer_capabilities = HashMap::new();
+    let mut reconnector = Reconnector::new(&config, peer_info_tx);
+    let mut handshakes = Handshakes::new(&config, room_tx);
+    let mut approvals = Approvals::new(&config);
+    let channel_order = channels_by_priority(&config);
+    let mut coalescer = Coalescer::new(&config, &messages_from_peers_tx);
+
+    let timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+    futures::pin_mut!(timeout);
+
+    let mut flush = coalescer.flush_timer();
+
+    loop {
+        // while packets are held back, the rest stay queued until the flush
+        let holding = coalescer.is_holding();
+        let mut next_peer_message_out = Box::pin(
+            async {
+                if holding {
+                    future::pending().await
+                } else {
+                    next_peer_message_out(peer_messages_out_rx, &channel_order).await
+                }
+            }
+            .fuse(),
+        );
+
+        select! {
+            _ = (&mut timeout).fuse() => {
+                // see the uuid above
+                let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
+                timeout.reset(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+            }
+
+            _ = leave_rx => {
+                debug!("Leaving room");
+                for peer in reconnector.peers() {
+                    // the socket may have been dropped, that's fine
+                    let _ = peer_state_tx.unbounded_send((peer.clone(), PeerState::Disconnected));
+                }
+                break;
+            }
+
+            _ = peer_loops.select_next_some() => {
+                debug!("peer finished");
+            }
+
+            event = reconnector.next_event().fuse() => {
+                match event {
+                    AttemptEvent::StateChanged(peer, state) => {
+                        match state {
+                            PeerState::Connecting | PeerState::Connected => {}
+                            PeerState::Reconnecting => {
+                                // The next attempt needs its own signalling
+                                handshake_signals.remove(&peer);
+                            }
+                            PeerState::Disconnected => {
+                                handshake_signals.remove(&peer);
+                                queued_signals.remove(&peer);
+                                connected_peers.remove(&peer);
+                                peer_capabilities.remove(&peer);
+                                // sends fail as for any other disconnected peer again, and so do
+                                // requests waiting for a response
+                                for channel in &messages_from_peers_tx {
+                                    channel.disconnected(&peer);
+                                }
+                            }
+                        }
+                        if let Some(rejected) = handshakes.state_changed(&peer, state, &requests_sender) {
+                            reconnector.disconnect(&rejected);
+                        }
+                        let _ = peer_state_tx.unbounded_send((peer, state));
+                    }
+                    AttemptEvent::Offer(attempt) => {
+                        let capabilities = peer_capabilities.get(attempt.peer());
+                        peer_loops.push(connect_peer(&messenger, attempt, true, vec![], capabilities, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, &config));
+                    }
+                    AttemptEvent::Answer(attempt) => {
+                        let peer = attempt.peer().clone();
+                        let capabilities = peer_capabilities.get(&peer);
+                        let signals = queued_signals.remove(&peer).unwrap_or_default();
+                        peer_loops.push(connect_peer(&messenger, attempt, false, signals, capabilities, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, &config));
+                    }
+                    AttemptEvent::TimedOut(peer) => {
+                        let _ = peer_state_tx.unbounded_send((peer.clone(), PeerState::Disconnected));
+                        return Err(Error::PeerConnectTimeout(peer));
+                    }
+                }
+            }
+
+            approved = approvals.next_approved().fuse() => {
+                match approved {
+                    Approved::NewPeer(peer) => reconnector.start(&peer),
+                    Approved::Offer(sender, signals) => {
+                        // the other side initiates, see `Messenger::connect`
+                        let Some(attempt) = reconnector.accept_or_queue(&sender) else {
+                            queued_signals.insert(sender, signals);
+                            continue;
+                        };
+                        let capabilities = peer_capabilities.get(&sender);
+                        peer_loops.push(connect_peer(&messenger, attempt, false, signals, capabilities, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, &config));
+                    }
+                }
+            }
+
+            event = events_receiver.select_next_some() => {
+                match event {
+                    PeerEvent::NewPeer(peer) => approvals.new_peer(peer),
+                    PeerEvent::Signal { sender, data: PeerSignal::Handshake(data) } => {
+                        if let Some(rejected) = handshakes.received(sender, data) {
+                            reconnector.disconnect(&rejected);
+                        }
+                    }
+                    PeerEvent::Signal { sender, data } => {
+                        let Some(data) = approvals.hold(&sender, data) else {
+                            continue;
+                        };
+                        if let Some(signals) = queued_signals.get_mut(&sender) {
+                            signals.push(data);
+                            continue;
+                        }
+                        let Some(handshake) = handshake_signals.get(&sender) else {
+                            approvals.offered(sender, data);
+                            continue;
+                        };
+                        if handshake.unbounded_send(data).is_err() {
+                            debug!("ignoring signal from {sender:?}, its messenger stopped listening");
+                        }
+                    }
+                    PeerEvent::PeerCapabilities { peer, capabilities } => {
+                        approvals.capabilities(peer.clone(), capabilities.clone());
+                        peer_capabilities.insert(peer, capabilities);
+                    }
+                    PeerEvent::PeerName { peer, name } => approvals.named(peer, name),
+                    PeerEvent::PeerRole { peer, role } => approvals.role(peer, role),
+                    // Handled by the signalling loop, or only meaningful to WebRTC
+                    _ => {}
+                }
+            }
+
+            _ = &mut flush => {
+                drop(next_peer_message_out);
+                for message in coalescer.flush(peer_messages_out_rx, &channel_order) {
+                    forward_to_peer(message, &connected_peers, &messages_from_peers_tx, config.strict);
+                }
+            }
+
+            message = next_peer_message_out => {
+                match message {
+                    (channel_index, Some((peer, packet))) => {
+                        drop(next_peer_message_out);
+                        let messages = coalescer.collect((channel_index, peer, packet), peer_messages_out_rx, &channel_order);
+                        for message in messages {
+                            forward_to_peer(message, &connected_peers, &messages_from_peers_tx, config.strict);
+                        }
+                        flush = coalescer.flush_timer();
+                    }
+                    (_, None) => {
+                        // the socket was dropped
+                        debug!("Outgoing message queue closed");
+                        break;
+                    }
+                }
+            }
+
+            complete => break
+        }
+    }
+
+    if config.flush_timeout_ms > 0 {
+        let flush = async {
+            for message in coalescer.flush_all(peer_messages_out_rx, &channel_order) {
+                forward_to_peer(
+                    message,
+                    &connected_peers,
+                    &messages_from_peers_tx,
+                    config.strict,
+                );
+            }
+            // closes the queues to the peers, so their loops end once they're empty
+            connected_peers.clear();
+            while peer_loops.next().await.is_some() {}
+        };
+        let timeout = Delay::new(Duration::from_millis(config.flush_timeout_ms));
+        select! {
+            _ = flush.fuse() => debug!("flushed queued packets"),
+            _ = timeout.fuse() => warn!("gave up flushing queued packets"),
+        }
+    }
+    Ok(())
+}
+
+/// Hands a peer to the messenger, and sends our packets to it once it's
+/// connected
+///
+/// `signals` are the ones that arrived before we started connecting.
+#[allow(clippy::too_many_arguments)]
+fn connect_peer(
+    messenger: &Arc<dyn Messenger>,
+    attempt: AttemptReporter,
+    initiator: bool,
+    signals: Vec<PeerSignal>,
+    capabilities: Option<&Vec<String>>,
+    requests_sender: &UnboundedSender<PeerRequest>,
+    messages_from_peers_tx: &[IncomingSender],
+    handshake_signals: &mut HashMap<PeerId, UnboundedSender<PeerSignal>>,
+    connected_peers: &mut HashMap<PeerId, Vec<UnboundedSender<PooledPacket>>>,
+    config: &WebRtcSocketConfig,
+) -> impl Future<Output = ()> {
+    let peer = attempt.peer().clone();
+    let open_channels = open_channels_with(&peer, config, capabilities, messages_from_peers_tx);
+    let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+    for signal in signals {
+        signal_sender
+            .unbounded_send(signal)
+            .expect("the receiver is right here");
+    }
+    handshake_signals.insert(peer.clone(), signal_sender);
+    let (to_peer_data_tx, to_peer_data_rx) = new_senders_and_receivers(config);
+    connected_peers.insert(peer.clone(), to_peer_data_tx);
+
+    let default_info = ConnectionInfo {
+        max_message_size: None,
+        channels: config
+            .channels
+            .iter()
+            .zip(&open_channels)
+            .map(|(channel, &open)| {
+                open.then_some(ChannelInfo {
+                    ordered: channel.ordered,
+                    max_retransmits: channel.max_retransmits,
+                })
+            })
+            .collect(),
+    };
+    let connect = messenger.connect(MessengerPeer {
+        id: peer.clone(),
+        initiator,
+        open_channels: open_channels.clone(),
+        signaller: Signaller {
+            signal_peer: SignalPeer::new(peer.clone(), requests_sender.clone()),
+            receiver: signal_receiver,
+        },
+        incoming: IncomingPackets {
+            peer,
+            channels: messages_from_peers_tx.to_vec(),
+            coalesce: config.channels.iter().map(|c| c.coalesce).collect(),
+            attempt: attempt.clone(),
+        },
+    });
+    peer_loop(
+        connect,
+        to_peer_data_rx,
+        messages_from_peers_tx.to_vec(),
+        open_channels,
+        attempt,
+        default_info,
+    )
+}
+
+async fn peer_loop(
+    connect: ConnectFuture,
+    to_peer_message_rx: Vec<UnboundedReceiver<PooledPacket>>,
+    channels: Vec<IncomingSender>,
+    open_channels: Vec<bool>,
+    attempt: AttemptReporter,
+    default_info: ConnectionInfo,
+) {
+    let mut connection = match connect.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            warn!("connecting to {:?} failed: {err}", attempt.peer());
+            attempt.failed();
+            return;
+        }
+    };
+    attempt.connected(connection.connection_info().unwrap_or(default_info));
+
+    let mut packets = stream::select_all(
+        to_peer_message_rx
+            .into_iter()
+            .enumerate()
+            .map(|(channel, rx)| rx.map(move |packet| (channel, packet))),
+    );
+    while let Some((channel, packet)) = packets.next().await {
+        if !open_channels[channel] {
+            // raced with the socket learning that the channel isn't open
+            channels[channel].dropped(attempt.peer(), packet);
+            continue;
+        }
+        if let Err(err) = connection.send(channel, &packet) {
+            warn!("failed to send to {:?}: {err}", attempt.peer());
+            channels[channel].send_failed(attempt.peer(), packet);
+            attempt.failed();
+            return;
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Instrumentation with the [`metrics`](https://docs.rs/metrics) facade
+//!
+//! Only recorded with the `metrics` feature, otherwise these do nothing. The
+//! application picks an exporter, e.g. prometheus, by installing a recorder.
+
+/// Counts a packet handed to a data channel
+pub(crate) fn packet_sent(channel: usize, len: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        let channel = channel.to_string();
+        ::metrics::counter!("matchbox_socket_packets_sent", "channel" => channel.clone())
+            .increment(1);
+        ::metrics::counter!("matchbox_socket_bytes_sent", "channel" => channel)
+            .increment(len as u64);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (channel, len);
+}
+
+/// Counts the packets returned by one receive call on a channel, which are
+/// all the packets that were queued up for it
+pub(crate) fn packets_received<'a>(channel: usize, packets: impl Iterator<Item = &'a [u8]>) {
+    #[cfg(feature = "metrics")]
+    {
+        let (count, bytes) = packets.fold((0, 0), |(count, bytes), packet| {
+            (count + 1, bytes + packet.len())
+        });
+        let channel = channel.to_string();
+        ::metrics::counter!("matchbox_socket_packets_received", "channel" => channel.clone())
+            .increment(count as u64);
+        ::metrics::counter!("matchbox_socket_bytes_received", "channel" => channel.clone())
+            .increment(bytes as u64);
+        ::metrics::gauge!("matchbox_socket_receive_queue_depth", "channel" => channel)
+            .set(count as f64);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (channel, packets);
+}
+
+/// Records how many queued packets were flushed at once, only known when
+/// coalescing or batching sends, see
+/// [`ChannelConfig::coalesce`](crate::ChannelConfig::coalesce)
+pub(crate) fn send_burst(packets: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!("matchbox_socket_send_queue_depth").record(packets as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = packets;
+}
+
+/// Sets the number of currently connected peers
+pub(crate) fn connected_peers(count: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("matchbox_socket_connected_peers").set(count as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = count;
+}
+
+/// Records how long it took to connect to a peer, from the start of the
+/// handshake that succeeded
+pub(crate) fn handshake_finished(duration_ms: f64) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!("matchbox_socket_handshake_duration_seconds").record(duration_ms / 1000.);
+    #[cfg(not(feature = "metrics"))]
+    let _ = duration_ms;
+}
+
+#[cfg(all(test, feature = "metrics", not(target_arch = "wasm32")))]
+mod tests {
+    use std::collections::HashMap;
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+    use super::{packet_sent, packets_received};
+
+    /// Takes the counters by name and channel, each counted since the last
+    /// snapshot
+    fn counters(snapshotter: &Snapshotter) -> HashMap<(String, String), u64> {
+        let snapshot = snapshotter.snapshot().into_vec();
+        snapshot
+            .into_iter()
+            .filter_map(|(key, _, _, value)| {
+                let value = match value {
+                    DebugValue::Counter(value) => value,
+                    _ => return None,
+                };
+                let key = key.key();
+                let channel = key.labels().find(|label| label.key() == "channel")?;
+                let name = (key.name().to_string(), channel.value().to_string());
+                Some((name, value))
+            })
+            .collect()
+    }
+
+    fn key(name: &str, channel: &str) -> (String, String) {
+        (format!("matchbox_socket_{}", name), channel.to_string())
+    }
+
+    #[test]
+    fn packet_counters_increase() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            packet_sent(0, 5);
+            let counted = counters(&snapshotter);
+            assert_eq!(counted[&key("packets_sent", "0")], 1);
+            assert_eq!(counted[&key("bytes_sent", "0")], 5);
+
+            packet_sent(0, 3);
+            packet_sent(0, 4);
+            packet_sent(1, 7);
+            let packets: [&[u8]; 2] = [b"ab", b"cde"];
+            packets_received(0, packets.iter().copied());
+            let counted = counters(&snapshotter);
+            assert_eq!(counted[&key("packets_sent", "0")], 2);
+            assert_eq!(counted[&key("bytes_sent", "0")], 7);
+            assert_eq!(counted[&key("packets_sent", "1")], 1);
+            assert_eq!(counted[&key("packets_received", "0")], 2);
+            assert_eq!(counted[&key("bytes_received", "0")], 5);
+        });
+    }
+}
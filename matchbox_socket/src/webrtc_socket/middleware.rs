@@ -0,0 +1,59 @@
+use std::{fmt, sync::Arc};
+
+use log::trace;
+
+use crate::webrtc_socket::messages::PeerId;
+
+/// What the message loop does with a packet after a [`PacketHook`] saw it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketAction {
+    /// Send or deliver the packet, with any changes the hook made to it
+    Forward,
+    /// Drop the packet silently
+    Drop,
+}
+
+type HookFn = dyn Fn(&PeerId, &mut Box<[u8]>) -> PacketAction + Send + Sync;
+
+/// Sees every packet sent to or received from a peer in the message loop,
+/// and may change or drop it
+///
+/// Useful for filtering, logging or metrics, or for e.g. appending a
+/// checksum to outgoing packets, and checking and stripping it from
+/// incoming ones. See [`WebRtcSocketConfig::on_outgoing`] and
+/// [`WebRtcSocketConfig::on_incoming`].
+///
+/// [`WebRtcSocketConfig::on_outgoing`]: crate::WebRtcSocketConfig::on_outgoing
+/// [`WebRtcSocketConfig::on_incoming`]: crate::WebRtcSocketConfig::on_incoming
+#[derive(Clone)]
+pub struct PacketHook(Arc<HookFn>);
+
+impl PacketHook {
+    /// Creates a hook from a function that gets the id of the peer and the
+    /// packet, and returns whether to forward it
+    ///
+    /// The function runs in the message loop, so it should be quick.
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(&PeerId, &mut Box<[u8]>) -> PacketAction + Send + Sync + 'static,
+    {
+        Self(Arc::new(hook))
+    }
+
+    /// Runs the hook, returns the packet to forward, if any
+    pub(crate) fn apply(&self, peer: &PeerId, mut packet: Box<[u8]>) -> Option<Box<[u8]>> {
+        match (self.0)(peer, &mut packet) {
+            PacketAction::Forward => Some(packet),
+            PacketAction::Drop => {
+                trace!("hook dropped packet for {peer:?}");
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PacketHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketHook").finish_non_exhaustive()
+    }
+}
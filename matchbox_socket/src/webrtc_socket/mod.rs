@@ -1,20 +1,72 @@
-use std::pin::Pin;
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io::IoSlice,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use futures::{future::Fuse, Future, FutureExt, StreamExt};
+use futures::{
+    future::{Either, Fuse},
+    Future, FutureExt, StreamExt,
+};
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures_util::select;
-use log::debug;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
 
+use crate::Error;
+
+mod approval;
+mod backoff;
+mod candidate_preference;
+mod channel_presets;
+mod channel_stats;
+mod channel_subset;
+mod channels_mut;
+mod coalesce;
+mod congestion;
+mod diagnostics;
+mod endpoint;
+mod exchange;
+mod fingerprint;
+mod handshake;
+mod identity;
+mod liveness;
+mod matchmaking;
 mod messages;
+mod messenger;
+mod metrics;
+mod middleware;
+mod observer;
+mod peer_states;
+mod platform_relay;
+mod pool;
+mod reconnect;
+mod recording;
+mod session;
 mod signal_peer;
+mod signalling_url;
 
 const KEEP_ALIVE_INTERVAL: u64 = 10_000;
 
+/// Maximum size of a single message from the signalling server, in bytes
+const MAX_SIGNALLING_MESSAGE_SIZE: usize = 64 * 1024;
+
 // TODO: maybe use cfg-if to make this slightly tidier
 #[cfg(not(target_arch = "wasm32"))]
 mod native {
+    mod latency;
     mod message_loop;
     mod signalling_loop;
+    pub use latency::*;
     pub use message_loop::*;
     pub use signalling_loop::*;
 }
@@ -23,8 +75,12 @@ mod native {
 mod wasm {
     mod message_loop;
     mod signalling_loop;
+    #[cfg(feature = "visibility")]
+    mod visibility;
     pub use message_loop::*;
     pub use signalling_loop::*;
+    #[cfg(feature = "visibility")]
+    pub use visibility::*;
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -32,7 +88,47 @@ use native::*;
 #[cfg(target_arch = "wasm32")]
 use wasm::*;
 
+pub use approval::{ApprovalFuture, IncomingPeer, IncomingPeerApprover, PeerApproval};
+pub(crate) use approval::{Approvals, Approved};
+pub use backoff::BackoffPolicy;
+pub use candidate_preference::CandidatePreference;
+pub(crate) use channel_stats::ChannelCounters;
+pub use channel_stats::ChannelStats;
+pub use channel_subset::CHANNELS_CAPABILITY_PREFIX;
+pub(crate) use channel_subset::{open_channels_with, UnopenedPeers};
+pub use channels_mut::{ChannelMut, ChannelsMut};
+use congestion::PeerCongestion;
+pub use congestion::{Congestion, CongestionLevel};
+pub use diagnostics::SocketDiagnostics;
+pub use endpoint::{probe_endpoint, select_best_endpoint, Endpoint, EndpointLatency};
+pub(crate) use exchange::Exchanges;
+pub use exchange::IncomingRequest;
+pub use fingerprint::FingerprintVerifier;
+pub(crate) use handshake::Handshakes;
+pub use handshake::{HandshakeValidator, PeerHandshake};
+use identity::Identities;
+pub use identity::{AppUserId, IdentityMove};
+pub use liveness::ChannelLiveness;
+use liveness::Liveness;
 use messages::*;
+pub use messages::{
+    peer_id_from_u128, peer_id_to_u128, short_peer_id, MatchmakingRegion, PeerEvent, PeerId,
+    PeerRequest, PeerRole, PeerSignal, RoomClosedBy, RoomInfo, RoomMetadata, SignallingErrorCode,
+};
+pub use messenger::{
+    ConnectFuture, IncomingPackets, MaybeSend, MaybeSendSync, Messenger, MessengerConnection,
+    MessengerError, MessengerPeer, Signaller,
+};
+pub use middleware::{PacketAction, PacketHook};
+use peer_states::PeerStates;
+pub use platform_relay::{PlatformRelay, RelayMessenger, RelayPacket};
+pub(crate) use pool::IncomingSender;
+pub use pool::{PacketPool, PooledPacket};
+pub use recording::{PacketDirection, RecordedPacket, Recorder, Replay};
+pub use session::SocketSession;
+pub(crate) use signalling_url::{
+    parse_room_url, room_url_next, room_url_on_same_server, room_url_on_server,
+};
 use uuid::Uuid;
 
 type Packet = Box<[u8]>;
@@ -40,11 +136,15 @@ type Packet = Box<[u8]>;
 /// General configuration options for a WebRtc connection.
 ///
 /// See [`WebRtcSocket::new_with_config`]
-#[derive(Debug)]
+///
+/// The configuration can be (de)serialized, so it can be stored in a settings
+/// file and loaded at runtime. Missing fields fall back to their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WebRtcSocketConfig {
     /// The url for the room to connect to
     ///
-    /// This is a websocket url, starting with `ws://` or `ws://` followed by
+    /// This is a websocket url, starting with `ws://` or `wss://` followed by
     /// the hostname and path to a matchbox server, followed by a room id and
     /// optional query parameters.
     ///
@@ -53,16 +153,361 @@ pub struct WebRtcSocketConfig {
     /// or: `wss://matchbox.example.com/your_game?next=2`
     ///
     /// The last form will pair player in the order they connect.
+    ///
+    /// IPv6 addresses go in brackets, e.g. `ws://[2001:db8::1]:3536/your_game`,
+    /// and internationalized hostnames may be given as they are. If the url
+    /// can't be parsed, the message loop resolves with [`Error::InvalidUrl`]
+    /// right away. On pages served over https, `ws://` urls are blocked by
+    /// the browser, see [`WebRtcSocketConfig::upgrade_insecure_signalling`].
     pub room_url: String,
     /// Configuration for the (single) ICE server
+    ///
+    /// TURN servers handed out by the signalling server are used in addition
+    /// to it.
     pub ice_server: RtcIceServerConfig,
+    /// Which ICE candidates to try first
+    ///
+    /// ICE pairs up candidates by priority, so a deployment where one address
+    /// family is routed but broken can raise the working one, or push relays
+    /// to the end to avoid paying for TURN traffic when a direct route exists.
+    /// Applied to the priorities of local and remote candidates alike, so it
+    /// takes effect even if the other peer leaves it at the default.
+    pub candidate_preference: CandidatePreference,
     /// Configuration for one or multiple reliable or unreliable data channels
     pub channels: Vec<ChannelConfig>,
+    /// Display name to register with the signalling server
+    ///
+    /// The server may sanitize it or make it unique within the room, see
+    /// [`WebRtcSocket::peer_name`] for the name that was actually assigned.
+    pub display_name: Option<String>,
+    /// Version of this client, declared to the signalling server when joining
+    ///
+    /// Servers may only accept some versions, e.g. semver ranges, and reject
+    /// others with
+    /// [`SignallingError::VersionMismatch`](crate::SignallingError::VersionMismatch).
+    /// Servers matching versions strictly also reject us if it differs from
+    /// the room's [`RoomMetadata::version`]. Rooms we
+    /// create require this version, unless set otherwise with
+    /// [`WebRtcSender::set_room_metadata`].
+    pub client_version: Option<String>,
+    /// Features this client supports, e.g. `"voice"` or `"zstd"`, advertised
+    /// to the other peers in the room before we're announced to them
+    ///
+    /// See [`WebRtcSocket::peer_capabilities`] for those of other peers. The
+    /// server drops empty and duplicate ones, ones longer than 64 characters,
+    /// and any past the first 32.
+    pub capabilities: Vec<String>,
+    /// Token of a slot another peer reserved for us in a `next` room, see
+    /// [`WebRtcSender::reserve_slots`]
+    ///
+    /// Claiming it lets us into the reserving peer's group, even if strangers
+    /// are waiting for it.
+    pub slot_token: Option<String>,
+    /// How many times to try reconnecting to a peer after its connection
+    /// failed, before reporting it as [`PeerState::Disconnected`]
+    pub reconnect_attempts: u16,
+    /// Delays between the attempts to reconnect to a peer
+    pub reconnect_backoff: BackoffPolicy,
+    /// How long to wait for a peer to rejoin once reconnecting to it failed,
+    /// in milliseconds, or 0 to report it as disconnected right away
+    ///
+    /// Lets a game hold a dropped player's slot. Until then, the peer stays
+    /// [`PeerState::Reconnecting`], and if a peer with the same
+    /// [id](WebRtcSocketConfig::peer_id) rejoins the room, we connect to it
+    /// again as if the connection never dropped.
+    pub reconnect_grace_period_ms: u64,
+    /// Peers we were connected to before restarting, whose offers are
+    /// answered right away, even beyond
+    /// [`WebRtcSocketConfig::max_concurrent_handshakes`]
+    ///
+    /// Set by [`WebRtcSocketConfig::resume_session`].
+    pub resumed_peers: Vec<PeerId>,
+    /// Which players the peers of a resumed session were, see
+    /// [`WebRtcReceiver::associate`]
+    ///
+    /// Set by [`WebRtcSocketConfig::resume_session`].
+    pub resumed_identities: Vec<(PeerId, AppUserId)>,
+    /// Maximum number of handshakes to run at the same time, or 0 for no limit
+    ///
+    /// When many peers show up at once, e.g. when a `next=8` room fills up,
+    /// handshakes with the remaining peers are queued until one finishes.
+    /// Offers from new peers wait in the same queue, and are answered before
+    /// we send offers of our own. Peers we were already connected to are
+    /// accepted right away when they reconnect. The other peer's
+    /// [`peer_connect_timeout_ms`](WebRtcSocketConfig::peer_connect_timeout_ms)
+    /// covers the time its offer waits here.
+    pub max_concurrent_handshakes: usize,
+    /// Id to register with the signalling server, instead of a random uuid
+    ///
+    /// It has to be unique among all peers connected to the server. Mostly
+    /// useful for making tests and logs deterministic. Unless it's a uuid,
+    /// the server has to accept custom ids, see `matchbox_server`'s
+    /// `--custom-peer-ids`.
+    pub peer_id: Option<PeerId>,
+    /// DTLS certificate to use for all peer connections, in the format
+    /// returned by [`WebRtcSocket::certificate_pem`]
+    ///
+    /// Persisting it lets a host keep the same fingerprint across restarts,
+    /// so peers can pin it. If unset, a new certificate is generated for
+    /// every socket. Only used on native, browsers manage certificates
+    /// themselves.
+    pub certificate_pem: Option<String>,
+    /// Checks the DTLS fingerprint each peer announces in its session
+    /// description before connecting to it
+    ///
+    /// Lets applications compare it with one they got through another
+    /// channel. A rejected fingerprint fails the handshake, so the connection
+    /// is retried according to [`WebRtcSocketConfig::reconnect_attempts`] and
+    /// then reported as [`PeerState::Disconnected`]. Not (de)serialized.
+    #[serde(skip)]
+    pub fingerprint_verifier: Option<FingerprintVerifier>,
+    /// Data to send each peer once we're connected to it, e.g. the version
+    /// or schema of the application's protocol
+    ///
+    /// It's relayed by the signalling server as is, so it reads the same on
+    /// every platform. Peers get it from [`WebRtcSocket::peer_handshakes`],
+    /// and again after reconnecting. Peers that don't set any don't send
+    /// any, so their handshake never shows up.
+    pub handshake_data: Option<Vec<u8>>,
+    /// Checks the [`WebRtcSocketConfig::handshake_data`] of each peer, and
+    /// disconnects it if it's rejected
+    ///
+    /// Rejected peers are reported as [`PeerState::Disconnected`] and never
+    /// show up in [`WebRtcSocket::peer_handshakes`]. Not (de)serialized.
+    #[serde(skip)]
+    pub handshake_validator: Option<HandshakeValidator>,
+    /// Decides whether to connect to each peer of the room, before any
+    /// connection to it is set up
+    ///
+    /// Asked about peers that offer to connect to us, and about peers that
+    /// join after us, which we offer to, so a host can enforce player caps or
+    /// ban lists even if the signalling server doesn't. Signals of the peer
+    /// are held back until it resolves. Rejected peers are never connected
+    /// to and never show up in [`WebRtcSocket::peer_state`], they're asked
+    /// about again if they offer again. Not (de)serialized.
+    #[serde(skip)]
+    pub on_incoming_peer: Option<IncomingPeerApprover>,
+    /// Sees every packet before it's sent to a peer, and may change or drop
+    /// it
+    ///
+    /// Runs in the message loop, before packets of
+    /// [`ChannelConfig::coalesce`] channels are batched, so it sees the
+    /// packets as they were sent. Not (de)serialized.
+    #[serde(skip)]
+    pub on_outgoing: Option<PacketHook>,
+    /// Sees every packet received from a peer, and may change or drop it
+    ///
+    /// Runs in the message loop, after batches were split up again, and
+    /// before the packets are handed to the socket. Not (de)serialized.
+    #[serde(skip)]
+    pub on_incoming: Option<PacketHook>,
+    /// Only watch who is in the room, without joining it or connecting to
+    /// anyone
+    ///
+    /// Useful for server browsers and spectator counts. The peers in the room
+    /// are available through [`WebRtcSocket::room_peers`], no peers ever
+    /// connect, so [`WebRtcSocket::wait_for_peers`] never resolves.
+    /// [`WebRtcSocketConfig::channels`] may be empty.
+    pub signalling_only: bool,
+    /// Wait in the signalling server's matchmaking queue before joining a
+    /// room, instead of joining [`WebRtcSocketConfig::room_url`] itself
+    ///
+    /// The server groups peers by their latency to its
+    /// [regions](WebRtcSocket::matchmaking_regions) and tells each group which
+    /// room to join, on the same server. Natively, latencies are measured and
+    /// reported by the socket. Browsers can't, so on wasm the application
+    /// has to measure them, e.g. with a ping to each region, and report them
+    /// with [`WebRtcSender::report_latency`]. Only applies to the first room,
+    /// rooms joined later with [`WebRtcSocket::join_room`] are joined directly.
+    pub matchmaking: bool,
+    /// Maximum number of unused packet buffers to keep around for reuse, or 0
+    /// to not pool packets at all
+    ///
+    /// Received packets are copied into buffers from the pool, which only
+    /// return to it when packets from [`WebRtcSocket::receive_pooled_on_channel`]
+    /// are dropped. Packets sent with [`ChannelSender::send_pooled`] return
+    /// their buffers once they are handed to the data channel, see
+    /// [`WebRtcSocket::packet_pool`].
+    pub packet_pool_size: usize,
+    /// How long to hold back an outgoing packet, in milliseconds, so packets
+    /// queued in the meantime are sent in the same burst, or 0 to send every
+    /// packet right away
+    ///
+    /// A couple of milliseconds cut down on wakeups of the message loop for
+    /// hosts sending many packets, at the cost of that much added latency.
+    /// Combines well with [`ChannelConfig::coalesce`], which then has more
+    /// packets to batch.
+    pub send_batch_delay_ms: u64,
+    /// Whether to keep packets that couldn't be delivered, so they can be
+    /// re-routed or reported, see [`WebRtcSocket::failed_sends`]
+    ///
+    /// Otherwise they are only counted in [`ChannelStats::messages_dropped`]
+    /// and [`ChannelStats::send_failures`].
+    pub dead_letters: bool,
+    /// How long to keep sending queued packets after leaving a room or
+    /// dropping the socket, in milliseconds, or 0 to drop them right away
+    ///
+    /// Packets queued before then are handed to the data channels of their
+    /// peers before the connections are closed, so e.g. a final "game over"
+    /// packet isn't lost. The message loop only finishes once they are, or
+    /// when this runs out. Peers that are still connecting hold it up until
+    /// it runs out.
+    pub flush_timeout_ms: u64,
+    /// Sends queued packets on a fixed tick of this many milliseconds, or 0
+    /// to not tick
+    ///
+    /// Decouples the send rate from the frame rate: a game can queue packets
+    /// every frame, and they go out in one burst per tick, batched by
+    /// [`ChannelConfig::coalesce`]. Ticks are aligned to the system clock, so
+    /// peers with the same tick send at roughly the same time. Takes
+    /// precedence over [`WebRtcSocketConfig::send_batch_delay_ms`].
+    pub send_tick_ms: u64,
+    /// How long to wait for the connection to the signalling server, in
+    /// milliseconds, or 0 to wait as long as the platform does
+    ///
+    /// When it runs out, the message loop fails with
+    /// [`Error::SignallingTimeout`]. Applies every time the socket joins a
+    /// room.
+    pub signalling_timeout_ms: u64,
+    /// How long the first handshake with a peer may take, in milliseconds, or
+    /// 0 to wait as long as ICE does
+    ///
+    /// If it runs out before we connected to anyone in the room, the message
+    /// loop fails with [`Error::PeerConnectTimeout`], so e.g. a "Connect"
+    /// button in a menu can give up quickly. After that, only the peer that
+    /// timed out is reported as [`PeerState::Disconnected`], so a slow late
+    /// joiner doesn't end a running session. Reconnects are covered by
+    /// [`WebRtcSocketConfig::reconnect_attempts`] instead.
+    pub peer_connect_timeout_ms: u64,
+    /// How long to wait for the response to a [`ChannelSender::request`], in
+    /// milliseconds, or 0 to wait until the peer disconnects
+    ///
+    /// When it runs out, the request fails with [`Error::RequestTimeout`].
+    pub request_timeout_ms: u64,
+    /// How many times in a row to try reconnecting to the signalling server
+    /// after the connection failed, or 0 to give up right away
+    ///
+    /// Only covers [retryable](crate::Error::is_retryable) failures, e.g. not
+    /// a full room.
+    ///
+    /// Progress is reported by [`WebRtcSocket::signalling_state`]. The socket
+    /// registers with the server again after reconnecting, peers that are
+    /// already connected stay connected.
+    pub signalling_reconnect_attempts: u16,
+    /// Delays between the attempts to reconnect to the signalling server
+    ///
+    /// A busy server may ask for a longer delay, see
+    /// [`SignallingError::ServerBusy`](crate::SignallingError::ServerBusy).
+    pub signalling_backoff: BackoffPolicy,
+    /// Other signalling servers to fail over to, in order, e.g.
+    /// `"wss://backup.example.com"`
+    ///
+    /// Only their scheme, host and port are used, the room stays the one of
+    /// [`WebRtcSocketConfig::room_url`]. Every attempt to reconnect goes to
+    /// the next server, wrapping around to the room url's own, and there are
+    /// at least as many attempts as fallbacks, see
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`]. The server in
+    /// use is reported by [`WebRtcSocket::signalling_url`].
+    pub fallback_signalling_urls: Vec<String>,
+    /// Whether to switch `ws://` room urls to `wss://` on pages served over
+    /// https
+    ///
+    /// Browsers block insecure websockets on such pages as mixed content, so
+    /// without this, the message loop fails with
+    /// [`Error::InsecureSignallingUrl`] instead. Only matters on wasm.
+    pub upgrade_insecure_signalling: bool,
+    /// How browsers hand received data channel messages to the socket
+    ///
+    /// Only matters on wasm, see [`BinaryType`].
+    pub binary_type: BinaryType,
+    /// How long to collect received messages of a data channel before
+    /// handing them to the socket in one go, in milliseconds, or 0 to hand
+    /// over every message right away
+    ///
+    /// Only used on wasm. A burst of small packets then wakes the socket's
+    /// receivers once, instead of once per message, at the cost of that much
+    /// added latency.
+    pub receive_batch_ms: u64,
+    /// Whether the socket panics when it's misused or one of its own
+    /// invariants breaks, instead of logging an error and carrying on
+    ///
+    /// Covers sends that fail, e.g. with [`ChannelSender::send`] after the
+    /// message loop stopped, channel indices that don't exist, and data
+    /// channels closing before they opened. Without it, failed sends are
+    /// dropped, unknown channels have nothing to receive, and the handshake
+    /// of a broken connection fails, so a released game never aborts because
+    /// of the networking layer. Defaults to being on in debug builds only, so
+    /// mistakes show up during development.
+    pub strict: bool,
+    /// Tuning of the native WebRTC stack, see [`NativeSocketConfig`]
+    pub native: NativeSocketConfig,
+}
+
+/// How browsers hand received binary data channel messages to the socket,
+/// see [`WebRtcSocketConfig::binary_type`]
+///
+/// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel/binaryType>
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryType {
+    /// Messages arrive as `ArrayBuffer`s, which are copied right away,
+    /// without converting each message from a `Blob`
+    #[default]
+    ArrayBuffer,
+    /// Messages arrive as `Blob`s, which are read asynchronously, in order
+    ///
+    /// Costs an extra round trip through the browser's event loop per
+    /// message, for browsers that keep blobs off the JavaScript heap.
+    Blob,
+}
+
+/// Tuning of the native WebRTC stack, see [`WebRtcSocketConfig::native`]
+///
+/// Ignored on wasm, where the browser decides these. The defaults suit
+/// games on desktops; servers and constrained devices may want to bind to a
+/// single interface or detect dead peers sooner.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NativeSocketConfig {
+    /// Largest message peers may send, in bytes, announced in the
+    /// `max-message-size` attribute of our session descriptions, or `None`
+    /// to not announce one
+    ///
+    /// Peers that don't see one assume the 64 KiB default of RFC 8841.
+    /// Browsers don't send larger messages than announced, native peers
+    /// report the size in [`ConnectionInfo::max_message_size`]. Capped at
+    /// the 65535 bytes the native stack can receive.
+    pub sctp_max_message_size: Option<usize>,
+    /// How long the DTLS handshake and opening the data channels may take
+    /// once ICE connected, in milliseconds, or 0 to wait forever
+    ///
+    /// Fails the handshake with peers that are reachable, but never finish
+    /// DTLS, e.g. behind middleboxes dropping its large packets, long before
+    /// [`WebRtcSocketConfig::peer_connect_timeout_ms`] would.
+    pub dtls_handshake_timeout_ms: u64,
+    /// How long ICE goes without hearing from a peer before the connection
+    /// counts as disconnected, in milliseconds, or `None` for the default of
+    /// 5 seconds
+    pub ice_disconnected_timeout_ms: Option<u64>,
+    /// How long ICE goes without hearing from a peer before the connection
+    /// fails, and the peer is dropped, in milliseconds, or `None` for the
+    /// default of 25 seconds
+    pub ice_failed_timeout_ms: Option<u64>,
+    /// How often ICE sends keepalives to a peer it has nothing else to send
+    /// to, in milliseconds, or `None` for the default of 2 seconds
+    pub ice_keepalive_interval_ms: Option<u64>,
+    /// Names of the network interfaces to gather ICE candidates on, e.g.
+    /// `eth0`, or empty for all of them
+    ///
+    /// Keeps connections off e.g. docker bridges or VPN interfaces peers
+    /// can't reach anyway.
+    pub interfaces: Vec<String>,
 }
 
 /// Configuration options for an ICE server connection.
 /// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCIceServer#example>
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RtcIceServerConfig {
     /// An ICE server instance can have several URLs
     pub urls: Vec<String>,
@@ -78,7 +523,7 @@ pub struct RtcIceServerConfig {
 
 /// Configuration options for a data channel
 /// See also: https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelConfig {
     /// Whether messages sent on the channel are guaranteed to arrive in order
     /// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel/ordered>
@@ -86,6 +531,84 @@ pub struct ChannelConfig {
     /// Maximum number of retransmit attempts of a message before giving up
     /// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel/maxRetransmits>
     pub max_retransmits: Option<u16>,
+    /// How urgently packets on this channel should be sent
+    ///
+    /// When several channels have queued outgoing packets, the ones with the
+    /// highest priority are sent first. On wasm, this also sets the data
+    /// channel's priority, see also:
+    /// <https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel/priority>
+    #[serde(default)]
+    pub priority: ChannelPriority,
+    /// Whether to combine packets queued for the same peer into a single
+    /// data channel message
+    ///
+    /// Cuts the per-message overhead for games sending many tiny packets per
+    /// frame. All packets queued when the socket gets around to sending are
+    /// batched, up to a size limit. Both sides need to have this enabled.
+    #[serde(default)]
+    pub coalesce: bool,
+    /// Only open this channel with peers that advertise this capability, if
+    /// we do as well, see [`WebRtcSocketConfig::capabilities`]
+    ///
+    /// E.g. a voice channel that spectators without `"voice"` don't need.
+    /// Sending on it to a peer it isn't opened with fails with
+    /// [`Error::ChannelNotOpen`]. Both sides need the same channels in the
+    /// same order, whether they open them or not.
+    #[serde(default)]
+    pub required_capability: Option<String>,
+    /// Whether [`ChannelSender::request`] can be used on this channel
+    ///
+    /// Every packet on the channel then carries a small header, so responses
+    /// can be matched up with their requests. Both sides need to have this
+    /// enabled. Best used with a reliable channel, a lost request or response
+    /// is only noticed when [`WebRtcSocketConfig::request_timeout_ms`] runs
+    /// out.
+    #[serde(default)]
+    pub requests: bool,
+    /// How often to send a keep-alive message to every peer on this channel,
+    /// in milliseconds, or `None` to only send what the application sends
+    ///
+    /// Lets the other side tell a quiet channel from a dead one, see
+    /// [`ChannelConfig::silence_timeout_ms`]. Set it well below the other
+    /// side's silence timeout.
+    #[serde(default)]
+    pub keep_alive_interval_ms: Option<u64>,
+    /// How long a peer may stay silent on this channel before it counts as
+    /// stalled, in milliseconds, or `None` to not watch the channel
+    ///
+    /// E.g. a voice channel may die while the game channel is fine. Reported
+    /// by [`WebRtcSocket::channel_liveness_changes`], the peer stays
+    /// connected. Keep-alives on channels with this or
+    /// [`ChannelConfig::keep_alive_interval_ms`] set are never received, so
+    /// both sides should set both.
+    #[serde(default)]
+    pub silence_timeout_ms: Option<u64>,
+    /// Whether packets that are valid UTF-8 are sent as string messages
+    /// instead of binary ones
+    ///
+    /// For chatting with JavaScript peers that don't use matchbox and expect
+    /// strings, see [`ChannelSender::send_text`]. Packets that aren't valid
+    /// UTF-8, e.g. with [`ChannelConfig::requests`] headers, are still sent
+    /// as binary. Received string messages end up as packets either way.
+    #[serde(default)]
+    pub text: bool,
+}
+
+/// Priority of a data channel relative to the socket's other channels
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelPriority {
+    /// For bulk data that may be delayed by everything else
+    VeryLow,
+    /// The default priority of a data channel
+    #[default]
+    Low,
+    /// Sent before default priority channels
+    Medium,
+    /// For time critical packets, such as inputs or voice
+    High,
 }
 
 impl ChannelConfig {
@@ -94,6 +617,13 @@ impl ChannelConfig {
         ChannelConfig {
             ordered: false,
             max_retransmits: Some(0),
+            priority: ChannelPriority::default(),
+            coalesce: false,
+            required_capability: None,
+            requests: false,
+            keep_alive_interval_ms: None,
+            silence_timeout_ms: None,
+            text: false,
         }
     }
 
@@ -102,6 +632,13 @@ impl ChannelConfig {
         ChannelConfig {
             ordered: true,
             max_retransmits: None,
+            priority: ChannelPriority::default(),
+            coalesce: false,
+            required_capability: None,
+            requests: false,
+            keep_alive_interval_ms: None,
+            silence_timeout_ms: None,
+            text: false,
         }
     }
 }
@@ -111,7 +648,44 @@ impl Default for WebRtcSocketConfig {
         WebRtcSocketConfig {
             room_url: "ws://localhost:3536/example_room".to_string(),
             ice_server: RtcIceServerConfig::default(),
+            candidate_preference: CandidatePreference::default(),
             channels: vec![ChannelConfig::unreliable()],
+            display_name: None,
+            client_version: None,
+            capabilities: vec![],
+            slot_token: None,
+            reconnect_attempts: 0,
+            reconnect_backoff: BackoffPolicy::default(),
+            reconnect_grace_period_ms: 0,
+            resumed_peers: vec![],
+            resumed_identities: vec![],
+            max_concurrent_handshakes: 8,
+            peer_id: None,
+            certificate_pem: None,
+            fingerprint_verifier: None,
+            handshake_data: None,
+            handshake_validator: None,
+            on_incoming_peer: None,
+            on_outgoing: None,
+            on_incoming: None,
+            signalling_only: false,
+            matchmaking: false,
+            packet_pool_size: 0,
+            send_batch_delay_ms: 0,
+            dead_letters: false,
+            flush_timeout_ms: 0,
+            send_tick_ms: 0,
+            signalling_timeout_ms: 0,
+            peer_connect_timeout_ms: 0,
+            request_timeout_ms: 5000,
+            signalling_reconnect_attempts: 0,
+            signalling_backoff: BackoffPolicy::default(),
+            fallback_signalling_urls: vec![],
+            upgrade_insecure_signalling: false,
+            binary_type: BinaryType::default(),
+            receive_batch_ms: 0,
+            strict: cfg!(debug_assertions),
+            native: NativeSocketConfig::default(),
         }
     }
 }
@@ -130,23 +704,282 @@ impl Default for RtcIceServerConfig {
     }
 }
 
+/// Sent from the socket to the message loop to switch rooms
+#[derive(Debug)]
+enum RoomCommand {
+    Join(String),
+    Leave,
+    /// Stays in the room, but reconnects right away if needed, see
+    /// [`WebRtcSender::resume`]
+    Resume,
+}
+
+/// The state of the connection to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerState {
+    /// We're trying to establish a connection to a newly discovered peer
+    Connecting,
+    /// The data channels to the peer are open
+    Connected,
+    /// The connection failed, and we're trying to establish a new one, or
+    /// waiting for the peer to rejoin
+    ///
+    /// See [`WebRtcSocketConfig::reconnect_attempts`] and
+    /// [`WebRtcSocketConfig::reconnect_grace_period_ms`]
+    Reconnecting,
+    /// The connection failed and won't be retried
+    Disconnected,
+}
+
+/// The state of the connection to the signalling server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SignallingState {
+    /// Connecting to the server of the current room for the first time
+    Connecting,
+    /// Connected to the server, peers can join
+    Connected,
+    /// The connection failed, the given attempt to reconnect starts after the
+    /// delay
+    ///
+    /// See [`WebRtcSocketConfig::signalling_reconnect_attempts`]
+    Reconnecting {
+        /// Number of the attempt, the first one being `1`
+        attempt: u16,
+        /// Time until the attempt starts, counted from when the state changed
+        delay: Duration,
+    },
+    /// Not connected to a server, and not trying to either
+    Disconnected,
+}
+
+/// What happened when a paused socket was resumed, see
+/// [`WebRtcSender::resume`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResumeEvent {
+    /// We're connected to the signalling server again, either the connection
+    /// survived the pause or it was re-established right away
+    Resumed,
+    /// The connection was lost while paused, and reconnecting right away
+    /// failed
+    ///
+    /// Further attempts follow
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`].
+    ResumptionFailed {
+        /// Why reconnecting failed
+        reason: String,
+    },
+}
+
+/// How far along a socket is in getting everyone it plays with connected,
+/// see [`WebRtcReceiver::lobby_state`]
+///
+/// Driven by the `next` parameter of the room url, or the group size the
+/// server advertises for the room, so a lobby screen only needs to show it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LobbyState {
+    /// Connecting to the signalling server, or waiting in the matchmaking
+    /// queue, see [`WebRtcSocketConfig::matchmaking`]
+    Searching,
+    /// In a room, but not everyone is connected yet
+    WaitingForPlayers {
+        /// Number of connected peers, counting ourselves
+        current: usize,
+        /// The group size of the room, or `None` if it doesn't have one, in
+        /// which case more peers may join at any time
+        needed: Option<usize>,
+    },
+    /// Connected to the whole group
+    AllPeersConnected,
+    /// The message loop stopped or the room was closed, see
+    /// [`WebRtcReceiver::is_closed`] and [`WebRtcReceiver::room_closed_by`]
+    Failed,
+}
+
+/// Something that happened in a room joined with
+/// [`WebRtcSender::join_extra_room`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExtraRoomEvent {
+    /// A peer joined the room, starting with the peers that were in it when
+    /// we joined, and us
+    PeerJoined {
+        /// Id of the room
+        room: String,
+        /// The peer that joined
+        peer: PeerId,
+    },
+    /// A peer left the room or disconnected from the signalling server
+    PeerLeft {
+        /// Id of the room
+        room: String,
+        /// The peer that left
+        peer: PeerId,
+    },
+    /// A peer sent a message to the room, see
+    /// [`WebRtcSender::send_extra_room_message`]
+    Message {
+        /// Id of the room
+        room: String,
+        /// The peer that sent the message
+        sender: PeerId,
+        /// The message
+        data: String,
+    },
+}
+
+/// Parameters of the connection to a peer, as they were actually negotiated
+///
+/// These may differ from what was asked for in [`WebRtcSocketConfig`],
+/// depending on the WebRTC implementations on both ends.
+/// See [`WebRtcReceiver::connection_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Largest message the peer accepts, in bytes, or `None` if there's no
+    /// limit
+    ///
+    /// Taken from the `max-message-size` attribute of the peer's session
+    /// description, which defaults to 64 KiB if the peer didn't set it.
+    pub max_message_size: Option<usize>,
+    /// The data channels, in the order of [`WebRtcSocketConfig::channels`]
+    ///
+    /// `None` for channels that aren't opened with this peer, see
+    /// [`ChannelConfig::required_capability`].
+    pub channels: Vec<Option<ChannelInfo>>,
+}
+
+/// Parameters of a single data channel, see [`ConnectionInfo`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    /// Whether messages are delivered in order
+    pub ordered: bool,
+    /// Maximum number of retransmits of a message, or `None` if messages are
+    /// retransmitted until they arrive
+    pub max_retransmits: Option<u16>,
+}
+
+/// The DTLS certificate a socket uses for all its peer connections
+#[derive(Debug, Clone)]
+pub(crate) struct LocalCertificate {
+    /// The certificate and private key, see [`WebRtcSocket::certificate_pem`]
+    pem: String,
+    /// See [`WebRtcSocket::local_fingerprint`]
+    fingerprint: String,
+}
+
 /// Contains the interface end of a full-mesh web rtc connection
 ///
 /// Used to send and receive messages from other peers
 #[derive(Debug)]
 pub struct WebRtcSocket {
-    messages_from_peers: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
-    new_connected_peers: futures_channel::mpsc::UnboundedReceiver<PeerId>,
-    peer_messages_out: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
-    peers: Vec<PeerId>,
+    sender: WebRtcSender,
+    receiver: WebRtcReceiver,
+}
+
+/// The sending half of a [`WebRtcSocket`], see [`WebRtcSocket::split`]
+///
+/// Cheap to clone, and can be moved to (and shared between) other threads, so
+/// sending doesn't have to happen in the same place as receiving.
+#[derive(Debug, Clone)]
+pub struct WebRtcSender {
+    peer_messages_out: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, PooledPacket)>>,
+    requests: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    room_commands: futures_channel::mpsc::UnboundedSender<RoomCommand>,
+    recorder: Option<Arc<Recorder>>,
+    pool: PacketPool,
+    channel_stats: Vec<Arc<ChannelCounters>>,
+    unopened_peers: Vec<Arc<UnopenedPeers>>,
+    exchanges: Vec<Option<Arc<Exchanges>>>,
+    upgrade_insecure_signalling: bool,
+    strict: bool,
+    /// See [`WebRtcSender::pause`], shared with the message loop
+    paused: Arc<AtomicBool>,
+    #[cfg(all(target_arch = "wasm32", feature = "visibility"))]
+    _visibility: Option<Arc<VisibilityListener>>,
+}
+
+/// A handle for sending packets on a single data channel
+///
+/// Like [`WebRtcSender`], it can be cloned and handed out to multiple systems
+/// or threads that need to enqueue outgoing packets concurrently.
+#[derive(Debug, Clone)]
+pub struct ChannelSender {
+    tx: futures_channel::mpsc::UnboundedSender<(PeerId, PooledPacket)>,
+    index: usize,
+    recorder: Option<Arc<Recorder>>,
+    pool: PacketPool,
+    stats: Arc<ChannelCounters>,
+    unopened: Arc<UnopenedPeers>,
+    exchanges: Option<Arc<Exchanges>>,
+    strict: bool,
+}
+
+// the sending halves are meant to be moved to other threads, keep them that way
+#[cfg(not(target_arch = "wasm32"))]
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<WebRtcSender>();
+    assert_send_sync::<ChannelSender>();
+};
+
+/// The receiving half of a [`WebRtcSocket`], see [`WebRtcSocket::split`]
+///
+/// Keeps track of connected peers and receives messages on all channels.
+#[derive(Debug)]
+pub struct WebRtcReceiver {
+    messages_from_peers: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, PooledPacket)>>,
+    requests_from_peers: Vec<futures_channel::mpsc::UnboundedReceiver<IncomingRequest>>,
+    peer_state_changes: futures_channel::mpsc::UnboundedReceiver<(PeerId, PeerState)>,
+    /// Whether the message loop is gone, i.e. `peer_state_changes` closed
+    closed: bool,
+    peer_states: PeerStates,
+    identities: Identities,
+    room_rx: futures_channel::mpsc::UnboundedReceiver<RoomUpdate>,
+    peer_names: HashMap<PeerId, String>,
+    peer_roles: HashMap<PeerId, PeerRole>,
+    peer_capabilities: HashMap<PeerId, Vec<String>>,
+    room_info: Option<RoomInfo>,
+    room_metadata: Option<RoomMetadata>,
+    room_peers: Vec<PeerId>,
+    /// The pages of a peer list still being received
+    room_peers_pages: Option<Vec<PeerId>>,
+    matchmaking_regions: Vec<MatchmakingRegion>,
+    signalling_state: SignallingState,
+    signalling_state_changes: Vec<SignallingState>,
+    signalling_url: Option<String>,
+    resume_events: Vec<ResumeEvent>,
+    room_closed_by: Option<RoomClosedBy>,
+    room_messages: Vec<(PeerId, String)>,
+    /// The peers in the rooms we joined besides our own
+    extra_rooms: BTreeMap<String, Vec<PeerId>>,
+    extra_room_events: Vec<ExtraRoomEvent>,
+    peer_handshakes: Vec<PeerHandshake>,
+    idle_warnings: Vec<Duration>,
+    pending_introductions: usize,
+    server_queue_position: usize,
+    channel_liveness_changes: Vec<ChannelLiveness>,
+    dead_letters: futures_channel::mpsc::UnboundedReceiver<(PeerId, usize, PooledPacket)>,
+    /// See [`RoomUpdate::Group`]
+    group_next: Option<usize>,
+    /// See [`RoomUpdate::Group`]
+    queued: bool,
+    lobby_state: LobbyState,
+    lobby_state_changes: Vec<LobbyState>,
+    peer_info_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, ConnectionInfo)>,
+    connection_infos: HashMap<PeerId, ConnectionInfo>,
+    congestion: Arc<PeerCongestion>,
     id: PeerId,
+    certificate: Option<LocalCertificate>,
+    /// The room url we were configured with, until we connect
+    room_url: String,
+    channels: Vec<ChannelConfig>,
+    recorder: Option<Arc<Recorder>>,
+    strict: bool,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
 // TODO: figure out if it's possible to implement Send in wasm as well
 #[cfg(target_arch = "wasm32")]
-pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = ()>>>;
+pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = Result<(), Error>>>>;
 
 impl WebRtcSocket {
     /// Create a new connection to the given room with a single unreliable data channel
@@ -165,65 +998,306 @@ impl WebRtcSocket {
     /// Create a new connection with the given [`WebRtcSocketConfig`]
     ///
     /// The returned future should be awaited in order for messages to be sent and received.
+    /// It resolves with an [`Error`] if the socket was closed because of a problem,
+    /// including [`Error::MessageLoopPanicked`] if the message loop panicked.
+    ///
+    /// If [`WebRtcSocketConfig::certificate_pem`] or
+    /// [`WebRtcSocketConfig::room_url`] is invalid, the future resolves with
+    /// [`Error::InvalidCertificate`], [`Error::InvalidUrl`] or
+    /// [`Error::InsecureSignallingUrl`] right away.
+    ///
+    /// There's no separate constructor for configurations loaded from a
+    /// settings file, deserialize the [`WebRtcSocketConfig`] and pass it here:
+    ///
+    /// ```
+    /// use matchbox_socket::{WebRtcSocket, WebRtcSocketConfig};
+    ///
+    /// let config: WebRtcSocketConfig =
+    ///     serde_json::from_str(r#"{ "room_url": "ws://localhost:3536/example" }"#).unwrap();
+    /// let (socket, message_loop) = WebRtcSocket::new_with_config(config);
+    /// ```
     #[must_use]
     pub fn new_with_config(config: WebRtcSocketConfig) -> (Self, MessageLoopFuture) {
-        if config.channels.is_empty() {
+        Self::new_with_transport(config, None)
+    }
+
+    /// Like [`WebRtcSocket::new_with_config`], but connects to peers with the
+    /// given [`Messenger`] instead of WebRTC
+    ///
+    /// Peers are still found through the signalling server, so all peers in
+    /// a room need to use the same kind of messenger. The ICE and certificate
+    /// settings of the configuration are ignored.
+    #[must_use]
+    pub fn new_with_messenger(
+        config: WebRtcSocketConfig,
+        messenger: impl Messenger + 'static,
+    ) -> (Self, MessageLoopFuture) {
+        Self::new_with_transport(config, Some(Arc::new(messenger)))
+    }
+
+    fn new_with_transport(
+        mut config: WebRtcSocketConfig,
+        messenger: Option<Arc<dyn Messenger>>,
+    ) -> (Self, MessageLoopFuture) {
+        if config.channels.is_empty() && !config.signalling_only {
             panic!("You need to configure at least one channel in WebRtcSocketConfig");
         }
 
+        // resolved up front, so every peer connection uses the same certificate
+        let (certificate, certificate_error) =
+            match local_certificate(config.certificate_pem.as_deref()) {
+                Ok(certificate) => (certificate, None),
+                Err(e) => {
+                    error!("{e}");
+                    (None, Some(e))
+                }
+            };
+        config.certificate_pem = certificate.as_ref().map(|c| c.pem.clone());
+        let url_error = match parse_room_url(&config.room_url, config.upgrade_insecure_signalling) {
+            Ok(url) => {
+                config.room_url = url;
+                None
+            }
+            Err(e) => {
+                error!("{e}");
+                Some(e)
+            }
+        };
+        let upgrade_insecure = config.upgrade_insecure_signalling;
+        let fallback_error = config.fallback_signalling_urls.iter_mut().find_map(|url| {
+            match parse_room_url(url, upgrade_insecure) {
+                Ok(parsed) => {
+                    *url = parsed;
+                    None
+                }
+                Err(e) => {
+                    error!("{e}");
+                    Some(e)
+                }
+            }
+        });
+
+        let pool = PacketPool::new(config.packet_pool_size);
+        let channel_stats: Vec<_> = config
+            .channels
+            .iter()
+            .map(|_| Arc::new(ChannelCounters::default()))
+            .collect();
+        let unopened_peers: Vec<_> = config
+            .channels
+            .iter()
+            .map(|_| Arc::new(UnopenedPeers::default()))
+            .collect();
+        let (requests_from_peers_tx, requests_from_peers): (Vec<_>, Vec<_>) = config
+            .channels
+            .iter()
+            .map(|_| futures_channel::mpsc::unbounded())
+            .unzip();
+        let exchanges: Vec<_> = config
+            .channels
+            .iter()
+            .zip(requests_from_peers_tx)
+            .enumerate()
+            .map(|(index, (channel, tx))| {
+                channel
+                    .requests
+                    .then(|| Arc::new(Exchanges::new(index, config.request_timeout_ms, tx)))
+            })
+            .collect();
+        let (dead_letters_tx, dead_letters) = futures_channel::mpsc::unbounded();
+        let dead_letters_tx = config.dead_letters.then_some(dead_letters_tx);
         let (messages_from_peers_tx, messages_from_peers) = new_senders_and_receivers(&config);
-        let (new_connected_peers_tx, new_connected_peers) = futures_channel::mpsc::unbounded();
+        let messages_from_peers_tx = messages_from_peers_tx
+            .into_iter()
+            .zip(&channel_stats)
+            .zip(&config.channels)
+            .zip(&unopened_peers)
+            .zip(&exchanges)
+            .enumerate()
+            .map(|(index, ((((tx, stats), channel), unopened), exchanges))| {
+                IncomingSender::new(tx, pool.clone(), stats.clone(), index, channel.coalesce)
+                    .with_dead_letters(dead_letters_tx.clone())
+                    .with_unopened_peers(unopened.clone())
+                    .with_exchanges(exchanges.clone())
+                    .with_incoming_hook(config.on_incoming.clone())
+                    .with_liveness(
+                        channel.keep_alive_interval_ms.is_some()
+                            || channel.silence_timeout_ms.is_some(),
+                    )
+            })
+            .collect();
+        let (peer_state_tx, peer_state_changes) = futures_channel::mpsc::unbounded();
         let (peer_messages_out_tx, peer_messages_out_rx) = new_senders_and_receivers(&config);
+        let (room_tx, room_rx) = futures_channel::mpsc::unbounded();
+        let (peer_info_tx, peer_info_rx) = futures_channel::mpsc::unbounded();
+        let congestion = Arc::new(PeerCongestion::default());
+        let (requests_sender, requests_receiver) = futures_channel::mpsc::unbounded();
+        let (room_commands_tx, room_commands) = futures_channel::mpsc::unbounded();
+        let paused = Arc::new(AtomicBool::new(false));
+        #[cfg(all(target_arch = "wasm32", feature = "visibility"))]
+        let visibility =
+            VisibilityListener::new(paused.clone(), room_commands_tx.clone()).map(Arc::new);
 
         // Would perhaps be smarter to let signalling server decide this...
-        let id = Uuid::new_v4().to_string();
+        let id = config
+            .peer_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
 
-        (
-            Self {
+        let socket = Self {
+            sender: WebRtcSender {
+                peer_messages_out: peer_messages_out_tx,
+                requests: requests_sender.clone(),
+                room_commands: room_commands_tx,
+                upgrade_insecure_signalling: config.upgrade_insecure_signalling,
+                strict: config.strict,
+                #[cfg(all(target_arch = "wasm32", feature = "visibility"))]
+                _visibility: visibility,
+                paused: paused.clone(),
+                recorder: None,
+                pool,
+                channel_stats,
+                unopened_peers,
+                exchanges,
+            },
+            receiver: WebRtcReceiver {
                 id: id.clone(),
                 messages_from_peers,
-                peer_messages_out: peer_messages_out_tx,
-                new_connected_peers,
-                peers: vec![],
+                requests_from_peers,
+                peer_state_changes,
+                closed: false,
+                peer_states: PeerStates::default(),
+                identities: Identities::new(&config.resumed_identities),
+                room_rx,
+                peer_names: HashMap::new(),
+                peer_roles: HashMap::new(),
+                peer_capabilities: HashMap::new(),
+                room_info: None,
+                room_metadata: None,
+                room_peers: vec![],
+                room_peers_pages: None,
+                matchmaking_regions: vec![],
+                signalling_state: SignallingState::Connecting,
+                signalling_state_changes: vec![SignallingState::Connecting],
+                signalling_url: None,
+                resume_events: vec![],
+                room_closed_by: None,
+                room_messages: vec![],
+                extra_rooms: BTreeMap::new(),
+                extra_room_events: vec![],
+                peer_handshakes: vec![],
+                idle_warnings: vec![],
+                pending_introductions: 0,
+                server_queue_position: 0,
+                channel_liveness_changes: vec![],
+                dead_letters,
+                group_next: room_url_next(&config.room_url),
+                queued: config.matchmaking,
+                lobby_state: LobbyState::Searching,
+                lobby_state_changes: vec![LobbyState::Searching],
+                peer_info_rx,
+                connection_infos: HashMap::new(),
+                congestion: congestion.clone(),
+                certificate,
+                room_url: config.room_url.clone(),
+                channels: config.channels.clone(),
+                recorder: None,
+                strict: config.strict,
             },
-            Box::pin(run_socket(
-                config,
-                id,
-                peer_messages_out_rx,
-                new_connected_peers_tx,
-                messages_from_peers_tx,
-            )),
-        )
+        };
+
+        let message_loop: MessageLoopFuture =
+            match certificate_error.or(url_error).or(fallback_error) {
+                Some(e) => Box::pin(async move { Err(e) }),
+                None => Box::pin(catch_panics(run_socket(
+                    config,
+                    messenger,
+                    paused,
+                    id,
+                    SocketChannels {
+                        requests_sender,
+                        requests_receiver,
+                        peer_messages_out_rx,
+                        peer_state_tx,
+                        messages_from_peers_tx,
+                        room_tx,
+                        peer_info_tx,
+                        congestion,
+                        room_commands,
+                    },
+                ))),
+            };
+        (socket, message_loop)
+    }
+
+    /// Records all packets sent and received through this socket
+    ///
+    /// Received packets are recorded when they are handed out by
+    /// [`WebRtcSocket::receive_on_channel`], so a [`Replay`] of the recording
+    /// delivers them at the same points in time.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        let recorder = Arc::new(recorder);
+        self.sender.recorder = Some(recorder.clone());
+        self.receiver.recorder = Some(recorder);
+        self
+    }
+
+    /// Splits the socket into independent sending and receiving halves
+    ///
+    /// The [`WebRtcSender`] can be cloned and sent to other threads, while the
+    /// [`WebRtcReceiver`] keeps track of peers and incoming messages.
+    pub fn split(self) -> (WebRtcSender, WebRtcReceiver) {
+        (self.sender, self.receiver)
     }
 
     /// Returns a future that resolves when the given number of peers have connected
-    pub async fn wait_for_peers(&mut self, peers: usize) -> Vec<PeerId> {
-        debug!("waiting for peers to join");
-        let mut addrs = vec![];
-        while let Some(id) = self.new_connected_peers.next().await {
-            addrs.push(id.clone());
-            if addrs.len() == peers {
-                debug!("all peers joined");
-                self.peers.extend(addrs.clone());
-                return addrs;
-            }
-        }
-        panic!("Signal server died")
+    pub async fn wait_for_peers(&mut self, peers: usize) -> Result<Vec<PeerId>, Error> {
+        self.receiver.wait_for_peers(peers).await
     }
 
     /// Check if new peers have connected and if so add them as peers
     pub fn accept_new_connections(&mut self) -> Vec<PeerId> {
-        let mut ids = Vec::new();
-        while let Ok(Some(id)) = self.new_connected_peers.try_next() {
-            self.peers.push(id.clone());
-            ids.push(id);
-        }
-        ids
+        self.receiver.accept_new_connections()
+    }
+
+    /// See [`WebRtcReceiver::try_accept_new_connections`]
+    pub fn try_accept_new_connections(&mut self) -> Result<Vec<PeerId>, Error> {
+        self.receiver.try_accept_new_connections()
+    }
+
+    /// See [`WebRtcReceiver::is_closed`]
+    pub fn is_closed(&self) -> bool {
+        self.receiver.is_closed()
     }
 
     /// Returns a Vec of the ids of the connected peers
     pub fn connected_peers(&self) -> Vec<PeerId> {
-        self.peers.clone() // TODO: could probably be an iterator or reference instead?
+        self.receiver.connected_peers()
+    }
+
+    /// See [`WebRtcReceiver::connected_peer_ids`]
+    pub fn connected_peer_ids(&self) -> &[PeerId] {
+        self.receiver.connected_peer_ids()
+    }
+
+    /// See [`WebRtcReceiver::is_connected`]
+    pub fn is_connected(&self, id: &PeerId) -> bool {
+        self.receiver.is_connected(id)
+    }
+
+    /// See [`WebRtcReceiver::disconnected_peers`]
+    pub fn disconnected_peers(&self) -> Vec<PeerId> {
+        self.receiver.disconnected_peers()
+    }
+
+    /// See [`WebRtcReceiver::peer_generation`]
+    pub fn peer_generation(&self) -> u64 {
+        self.receiver.peer_generation()
+    }
+
+    /// See [`WebRtcReceiver::peers_changed_since`]
+    pub fn peers_changed_since(&self, generation: u64) -> Vec<(PeerId, PeerState)> {
+        self.receiver.peers_changed_since(generation)
     }
 
     /// Call this where you want to handle new received messages from the default channel (with index 0) which will be the only
@@ -233,28 +1307,16 @@ impl WebRtcSocket {
     ///
     /// See also: [`WebRtcSocket::receive_on_channel`]
     pub fn receive(&mut self) -> Vec<(PeerId, Packet)> {
-        self.receive_on_channel(0)
+        self.receiver.receive()
     }
 
     /// Call this where you want to handle new received messages from a specific channel as configured in [`WebRtcSocketConfig::channels`].
     /// The index of a channel is its index in the vec [`WebRtcSocketConfig::channels`] as you configured it before
     /// (or 0 for the default channel if you use the default configuration).
     ///
-    /// messages are removed from the socket when called   
+    /// messages are removed from the socket when called
     pub fn receive_on_channel(&mut self, index: usize) -> Vec<(PeerId, Packet)> {
-        std::iter::repeat_with(|| {
-            self.messages_from_peers
-                .get_mut(index)
-                .unwrap_or_else(|| panic!("No data channel with index {}", index))
-                .try_next()
-        })
-        // .map_while(|poll| match p { // map_while is nightly-only :(
-        .take_while(|p| !p.is_err())
-        .map(|p| match p.unwrap() {
-            Some((peer_id, packet)) => (peer_id, packet),
-            None => todo!("Handle connection closed??"),
-        })
-        .collect()
+        self.receiver.receive_on_channel(index)
     }
 
     /// Send a packet to the given peer on the default channel (with index 0) which will be the only
@@ -262,7 +1324,7 @@ impl WebRtcSocket {
     ///
     /// See also [`WebRtcSocket::send_on_channel`]
     pub fn send<T: Into<PeerId>>(&mut self, packet: Packet, id: T) {
-        self.send_on_channel(packet, id, 0);
+        self.sender.send(packet, id);
     }
 
     /// Send a packet to the given peer on a specific channel as configured in [`WebRtcSocketConfig::channels`].
@@ -270,88 +1332,2310 @@ impl WebRtcSocket {
     /// The index of a channel is its index in the vec [`WebRtcSocketConfig::channels`] as you configured it before
     /// (or 0 for the default channel if you use the default configuration).
     pub fn send_on_channel<T: Into<PeerId>>(&mut self, packet: Packet, id: T, index: usize) {
-        self.peer_messages_out
-            .get(index)
-            .unwrap_or_else(|| panic!("No data channel with index {}", index))
-            .unbounded_send((id.into(), packet))
-            .expect("send_to failed");
+        self.sender.send_on_channel(packet, id, index);
     }
 
-    /// Returns the id of this peer
-    pub fn id(&self) -> &PeerId {
-        &self.id
+    /// See [`WebRtcSender::send_text`]
+    pub fn send_text<T: Into<PeerId>>(&mut self, text: &str, id: T) {
+        self.sender.send_text(text, id);
     }
-}
 
-async fn run_socket(
-    config: WebRtcSocketConfig,
-    id: PeerId,
-    peer_messages_out_rx: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
-) {
-    debug!("Starting WebRtcSocket message loop");
+    /// See [`WebRtcSender::send_text_on_channel`]
+    pub fn send_text_on_channel<T: Into<PeerId>>(&mut self, text: &str, id: T, index: usize) {
+        self.sender.send_text_on_channel(text, id, index);
+    }
 
-    let (requests_sender, requests_receiver) = futures_channel::mpsc::unbounded::<PeerRequest>();
-    let (events_sender, events_receiver) = futures_channel::mpsc::unbounded::<PeerEvent>();
+    /// Like [`WebRtcSocket::send_on_channel`], but returns an error instead of
+    /// panicking if the message loop has stopped
+    pub fn try_send_on_channel<T: Into<PeerId>>(
+        &mut self,
+        packet: Packet,
+        id: T,
+        index: usize,
+    ) -> Result<(), Error> {
+        self.sender.try_send_on_channel(packet, id, index)
+    }
 
-    let signalling_loop_fut =
-        signalling_loop(config.room_url.clone(), requests_receiver, events_sender);
+    /// Send the same packet to the given peer on several channels at once
+    ///
+    /// See [`WebRtcSender::send_on`]
+    pub fn send_on<T: Into<PeerId>>(&mut self, channels: &[usize], packet: Packet, id: T) {
+        self.sender.send_on(channels, packet, id);
+    }
 
-    let message_loop_fut = message_loop(
-        id,
-        config,
-        requests_sender,
-        events_receiver,
-        peer_messages_out_rx,
-        new_connected_peers_tx,
-        messages_from_peers_tx,
-    );
+    /// See [`WebRtcSender::try_send_on`]
+    pub fn try_send_on<T: Into<PeerId>>(
+        &mut self,
+        channels: &[usize],
+        packet: Packet,
+        id: T,
+    ) -> Result<(), Error> {
+        self.sender.try_send_on(channels, packet, id)
+    }
 
-    let mut message_loop_done = Box::pin(message_loop_fut.fuse());
-    let mut signalling_loop_done = Box::pin(signalling_loop_fut.fuse());
-    loop {
-        select! {
-            _ = message_loop_done => {
-                debug!("Message loop completed");
-                break;
-            }
+    /// See [`WebRtcSender::all_channels`]
+    pub fn all_channels(&self) -> Vec<usize> {
+        self.sender.all_channels()
+    }
 
-            _ = signalling_loop_done => {
-                debug!("Signalling loop completed");
-                // todo!{"reconnect?"}
-            }
+    /// Returns the state of the connection to the given peer
+    ///
+    /// See [`WebRtcReceiver::peer_state`]
+    pub fn peer_state(&self, id: &PeerId) -> Option<PeerState> {
+        self.receiver.peer_state(id)
+    }
 
-            complete => break
-        }
+    /// Returns the display name of the given peer (or this peer), if it registered one
+    ///
+    /// See [`WebRtcSocketConfig::display_name`]
+    pub fn peer_name(&self, id: &PeerId) -> Option<&str> {
+        self.receiver.peer_name(id)
     }
-}
 
-pub(crate) fn new_senders_and_receivers<T>(
-    config: &WebRtcSocketConfig,
-) -> (Vec<UnboundedSender<T>>, Vec<UnboundedReceiver<T>>) {
-    (0..config.channels.len())
-        .map(|_| futures_channel::mpsc::unbounded())
-        .unzip()
-}
+    /// Returns the role the signalling server gave the given peer (or this
+    /// peer), once it told us
+    ///
+    /// See [`WebRtcReceiver::peer_role`]
+    pub fn peer_role(&self, id: &PeerId) -> Option<PeerRole> {
+        self.receiver.peer_role(id)
+    }
+
+    /// Returns the features the given peer supports
+    ///
+    /// See [`WebRtcReceiver::peer_capabilities`]
+    pub fn peer_capabilities(&self, id: &PeerId) -> &[String] {
+        self.receiver.peer_capabilities(id)
+    }
+
+    /// See [`WebRtcReceiver::associate`]
+    pub fn associate(&mut self, peer: PeerId, user: AppUserId) {
+        self.receiver.associate(peer, user)
+    }
+
+    /// See [`WebRtcReceiver::dissociate`]
+    pub fn dissociate(&mut self, peer: &PeerId) -> Option<AppUserId> {
+        self.receiver.dissociate(peer)
+    }
+
+    /// See [`WebRtcReceiver::user_of`]
+    pub fn user_of(&self, peer: &PeerId) -> Option<&AppUserId> {
+        self.receiver.user_of(peer)
+    }
+
+    /// See [`WebRtcReceiver::peer_of`]
+    pub fn peer_of(&self, user: &str) -> Option<&PeerId> {
+        self.receiver.peer_of(user)
+    }
+
+    /// See [`WebRtcReceiver::identity_moves`]
+    pub fn identity_moves(&mut self) -> Vec<IdentityMove> {
+        self.receiver.identity_moves()
+    }
+
+    /// Returns the rules the signalling server enforces for our room
+    ///
+    /// See [`WebRtcReceiver::room_info`]
+    pub fn room_info(&self) -> Option<RoomInfo> {
+        self.receiver.room_info()
+    }
+
+    /// Returns the description of our room, if its creator set one
+    ///
+    /// See [`WebRtcReceiver::room_metadata`]
+    pub fn room_metadata(&self) -> Option<&RoomMetadata> {
+        self.receiver.room_metadata()
+    }
+
+    /// Describes our room, if we created it
+    ///
+    /// See [`WebRtcSender::set_room_metadata`]
+    pub fn set_room_metadata(&self, metadata: RoomMetadata) -> Result<(), Error> {
+        self.sender.set_room_metadata(metadata)
+    }
+
+    /// Ends the match for everyone in our room, if we created it
+    ///
+    /// See [`WebRtcSender::close_room`]
+    pub fn close_room(&self) -> Result<(), Error> {
+        self.sender.close_room()
+    }
+
+    /// See [`WebRtcReceiver::room_closed_by`]
+    pub fn room_closed_by(&mut self) -> Option<&RoomClosedBy> {
+        self.receiver.room_closed_by()
+    }
+
+    /// See [`WebRtcReceiver::idle_warnings`]
+    pub fn idle_warnings(&mut self) -> Vec<Duration> {
+        self.receiver.idle_warnings()
+    }
+
+    /// See [`WebRtcReceiver::pending_introductions`]
+    pub fn pending_introductions(&mut self) -> usize {
+        self.receiver.pending_introductions()
+    }
+
+    /// See [`WebRtcReceiver::server_queue_position`]
+    pub fn server_queue_position(&mut self) -> Option<usize> {
+        self.receiver.server_queue_position()
+    }
+
+    /// See [`WebRtcReceiver::channel_liveness_changes`]
+    pub fn channel_liveness_changes(&mut self) -> Vec<ChannelLiveness> {
+        self.receiver.channel_liveness_changes()
+    }
+
+    /// Keeps our room from being closed for being idle
+    ///
+    /// See [`WebRtcSender::keep_room_alive`]
+    pub fn keep_room_alive(&self) -> Result<(), Error> {
+        self.sender.keep_room_alive()
+    }
+
+    /// Resolves once our room is closed, see [`WebRtcReceiver::room_closed`]
+    pub async fn room_closed(&mut self) -> Result<RoomClosedBy, Error> {
+        self.receiver.room_closed().await
+    }
+
+    /// Holds free slots of our group for the peers we give the tokens to
+    ///
+    /// See [`WebRtcSender::reserve_slots`]
+    pub fn reserve_slots<T: Into<String>>(
+        &self,
+        tokens: impl IntoIterator<Item = T>,
+    ) -> Result<(), Error> {
+        self.sender.reserve_slots(tokens)
+    }
+
+    /// See [`WebRtcReceiver::room_peers`]
+    pub fn room_peers(&self) -> &[PeerId] {
+        self.receiver.room_peers()
+    }
+
+    /// See [`WebRtcReceiver::peer_list_complete`]
+    pub fn peer_list_complete(&self) -> bool {
+        self.receiver.peer_list_complete()
+    }
+
+    /// See [`WebRtcReceiver::matchmaking_regions`]
+    pub fn matchmaking_regions(&self) -> &[MatchmakingRegion] {
+        self.receiver.matchmaking_regions()
+    }
+
+    /// Reports our latency to a matchmaking region
+    ///
+    /// See [`WebRtcSender::report_latency`]
+    pub fn report_latency<T: Into<String>>(
+        &self,
+        region: T,
+        latency: Duration,
+    ) -> Result<(), Error> {
+        self.sender.report_latency(region, latency)
+    }
+
+    /// See [`WebRtcReceiver::signalling_state`]
+    pub fn signalling_state(&self) -> SignallingState {
+        self.receiver.signalling_state()
+    }
+
+    /// See [`WebRtcReceiver::signalling_url`]
+    pub fn signalling_url(&self) -> Option<&str> {
+        self.receiver.signalling_url()
+    }
+
+    /// See [`WebRtcReceiver::signalling_state_changes`]
+    pub fn signalling_state_changes(&mut self) -> Vec<SignallingState> {
+        self.receiver.signalling_state_changes()
+    }
+
+    /// Stops sending keep-alives, e.g. while a mobile tab is in the background
+    ///
+    /// See [`WebRtcSender::pause`]
+    pub fn pause(&self) {
+        self.sender.pause();
+    }
+
+    /// Reconnects right away if the connection was lost while paused
+    ///
+    /// See [`WebRtcSender::resume`]
+    pub fn resume(&self) {
+        self.sender.resume();
+    }
+
+    /// See [`WebRtcSender::is_paused`]
+    pub fn is_paused(&self) -> bool {
+        self.sender.is_paused()
+    }
+
+    /// See [`WebRtcReceiver::resume_events`]
+    pub fn resume_events(&mut self) -> Vec<ResumeEvent> {
+        self.receiver.resume_events()
+    }
+
+    /// See [`WebRtcReceiver::lobby_state`]
+    pub fn lobby_state(&self) -> LobbyState {
+        self.receiver.lobby_state()
+    }
+
+    /// See [`WebRtcReceiver::lobby_state_changes`]
+    pub fn lobby_state_changes(&mut self) -> Vec<LobbyState> {
+        self.receiver.lobby_state_changes()
+    }
+
+    /// Returns the fingerprint of the DTLS certificate this socket uses
+    ///
+    /// See [`WebRtcReceiver::local_fingerprint`]
+    pub fn local_fingerprint(&self) -> Option<&str> {
+        self.receiver.local_fingerprint()
+    }
+
+    /// Returns the DTLS certificate this socket uses, including its private key
+    ///
+    /// See [`WebRtcReceiver::certificate_pem`]
+    pub fn certificate_pem(&self) -> Option<&str> {
+        self.receiver.certificate_pem()
+    }
+
+    /// Returns the negotiated parameters of the connection to the given peer
+    ///
+    /// See [`WebRtcReceiver::connection_info`]
+    pub fn connection_info(&self, id: &PeerId) -> Option<&ConnectionInfo> {
+        self.receiver.connection_info(id)
+    }
+
+    /// See [`WebRtcReceiver::congestion`]
+    pub fn congestion(&self, id: &PeerId) -> Option<Congestion> {
+        self.receiver.congestion(id)
+    }
+
+    /// Sends a message to everyone else in the room through the signalling
+    /// server
+    ///
+    /// See [`WebRtcSender::send_room_message`]
+    pub fn send_room_message<T: Into<String>>(&self, data: T) -> Result<(), Error> {
+        self.sender.send_room_message(data)
+    }
+
+    /// See [`WebRtcReceiver::receive_room_messages`]
+    pub fn receive_room_messages(&mut self) -> Vec<(PeerId, String)> {
+        self.receiver.receive_room_messages()
+    }
+
+    /// See [`WebRtcSender::join_extra_room`]
+    pub fn join_extra_room<T: Into<String>>(&self, room: T) -> Result<(), Error> {
+        self.sender.join_extra_room(room)
+    }
+
+    /// See [`WebRtcSender::leave_extra_room`]
+    pub fn leave_extra_room<T: Into<String>>(&self, room: T) -> Result<(), Error> {
+        self.sender.leave_extra_room(room)
+    }
+
+    /// See [`WebRtcSender::send_extra_room_message`]
+    pub fn send_extra_room_message<T: Into<String>, D: Into<String>>(
+        &self,
+        room: T,
+        data: D,
+    ) -> Result<(), Error> {
+        self.sender.send_extra_room_message(room, data)
+    }
+
+    /// See [`WebRtcReceiver::extra_rooms`]
+    pub fn extra_rooms(&mut self) -> Vec<String> {
+        self.receiver.extra_rooms()
+    }
+
+    /// See [`WebRtcReceiver::extra_room_peers`]
+    pub fn extra_room_peers(&mut self, room: &str) -> &[PeerId] {
+        self.receiver.extra_room_peers(room)
+    }
+
+    /// See [`WebRtcReceiver::extra_room_events`]
+    pub fn extra_room_events(&mut self) -> Vec<ExtraRoomEvent> {
+        self.receiver.extra_room_events()
+    }
+
+    /// See [`WebRtcReceiver::peer_handshakes`]
+    pub fn peer_handshakes(&mut self) -> Vec<PeerHandshake> {
+        self.receiver.peer_handshakes()
+    }
+
+    /// Returns the packets that couldn't be delivered since the last call
+    ///
+    /// See [`WebRtcReceiver::failed_sends`]
+    pub fn failed_sends(&mut self) -> Vec<(PeerId, usize, Packet)> {
+        self.receiver.failed_sends()
+    }
+
+    /// Returns the counters of the channel with the given index
+    ///
+    /// See [`WebRtcSender::channel_stats`]
+    pub fn channel_stats(&self, index: usize) -> ChannelStats {
+        self.sender.channel_stats(index)
+    }
+
+    /// Returns a snapshot of the signalling connection, the peers by state
+    /// and the counters of all channels
+    ///
+    /// Peer states are as of the last
+    /// [`WebRtcSocket::accept_new_connections`].
+    ///
+    /// There are no round trip times to peers, see [`SocketDiagnostics`] for
+    /// how to measure them instead.
+    pub fn diagnostics(&self) -> SocketDiagnostics {
+        let channels = (0..self.sender.channel_stats.len())
+            .map(|index| self.sender.channel_stats(index))
+            .collect();
+        SocketDiagnostics::new(
+            self.receiver.signalling_state,
+            self.receiver.peer_states.states(),
+            channels,
+        )
+    }
+
+    /// Moves this peer and everyone in its room to another room
+    ///
+    /// See [`WebRtcSender::migrate_room`]
+    pub fn migrate_room<T: Into<String>>(&self, room: T, next: Option<usize>) -> Result<(), Error> {
+        self.sender.migrate_room(room, next)
+    }
+
+    /// Disconnects from all peers and the signalling server, keeping the
+    /// socket around so it can [`join_room`](WebRtcSocket::join_room) later
+    ///
+    /// See [`WebRtcSender::leave_room`]
+    pub fn leave_room(&self) -> Result<(), Error> {
+        self.sender.leave_room()
+    }
+
+    /// Leaves the current room (if any) and joins the room with the given url
+    ///
+    /// See [`WebRtcSender::join_room`]
+    pub fn join_room<T: Into<String>>(&self, room_url: T) -> Result<(), Error> {
+        self.sender.join_room(room_url)
+    }
+
+    /// Returns a cloneable [`ChannelSender`] for the channel with the given index
+    pub fn channel_sender(&self, index: usize) -> ChannelSender {
+        self.sender.channel(index)
+    }
+
+    /// Borrows all channels at once, to receive on some and send on others in
+    /// the same scope
+    ///
+    /// ```no_run
+    /// # use matchbox_socket::WebRtcSocket;
+    /// # fn update(socket: &mut WebRtcSocket) {
+    /// let mut channels = socket.channels_mut();
+    /// let [inputs, acks] = channels.get_many_mut([0, 1]).unwrap();
+    /// for (peer, _input) in inputs.receive() {
+    ///     acks.send(Box::new([1]), peer);
+    /// }
+    /// # }
+    /// ```
+    pub fn channels_mut(&mut self) -> ChannelsMut<'_> {
+        let sender = &self.sender;
+        let receiver = &mut self.receiver;
+        let recorder = receiver.recorder.as_deref();
+        let channels = receiver
+            .messages_from_peers
+            .iter_mut()
+            .zip(receiver.requests_from_peers.iter_mut())
+            .enumerate()
+            .map(|(index, (messages, requests))| {
+                ChannelMut::new(index, messages, requests, recorder, sender.channel(index))
+            })
+            .collect();
+        ChannelsMut(channels)
+    }
+
+    /// See [`WebRtcReceiver::receive_pooled_on_channel`]
+    pub fn receive_pooled_on_channel(&mut self, index: usize) -> Vec<(PeerId, PooledPacket)> {
+        self.receiver.receive_pooled_on_channel(index)
+    }
+
+    /// See [`WebRtcReceiver::receive_text`]
+    pub fn receive_text(&mut self) -> Vec<(PeerId, String)> {
+        self.receiver.receive_text()
+    }
+
+    /// See [`WebRtcReceiver::receive_text_on_channel`]
+    pub fn receive_text_on_channel(&mut self, index: usize) -> Vec<(PeerId, String)> {
+        self.receiver.receive_text_on_channel(index)
+    }
+
+    /// See [`WebRtcReceiver::receive_requests_on_channel`]
+    pub fn receive_requests_on_channel(&mut self, index: usize) -> Vec<IncomingRequest> {
+        self.receiver.receive_requests_on_channel(index)
+    }
+
+    /// See [`WebRtcSender::respond`]
+    pub fn respond(&self, request: &IncomingRequest, packet: Packet) -> Result<(), Error> {
+        self.sender.respond(request, packet)
+    }
+
+    /// See [`WebRtcSender::packet_pool`]
+    pub fn packet_pool(&self) -> &PacketPool {
+        self.sender.packet_pool()
+    }
+
+    /// Returns the id of this peer
+    pub fn id(&self) -> &PeerId {
+        self.receiver.id()
+    }
+
+    /// See [`WebRtcReceiver::export_session`]
+    pub fn export_session(&self) -> SocketSession {
+        self.receiver.export_session()
+    }
+}
+
+impl WebRtcSender {
+    /// Send a packet to the given peer on the default channel (with index 0)
+    ///
+    /// See also [`WebRtcSender::send_on_channel`]
+    pub fn send<T: Into<PeerId>>(&self, packet: Packet, id: T) {
+        self.send_on_channel(packet, id, 0);
+    }
+
+    /// Send a packet to the given peer on a specific channel as configured in [`WebRtcSocketConfig::channels`].
+    pub fn send_on_channel<T: Into<PeerId>>(&self, packet: Packet, id: T, index: usize) {
+        self.channel(index).send(packet, id);
+    }
+
+    /// Send text to the given peer on the default channel (with index 0)
+    ///
+    /// See also [`WebRtcSender::send_text_on_channel`]
+    pub fn send_text<T: Into<PeerId>>(&self, text: &str, id: T) {
+        self.send_text_on_channel(text, id, 0);
+    }
+
+    /// Send text to the given peer on a specific channel, see
+    /// [`ChannelSender::send_text`]
+    pub fn send_text_on_channel<T: Into<PeerId>>(&self, text: &str, id: T, index: usize) {
+        self.channel(index).send_text(text, id);
+    }
+
+    /// Like [`WebRtcSender::send_on_channel`], but returns an error instead of
+    /// panicking if the message loop has stopped
+    pub fn try_send_on_channel<T: Into<PeerId>>(
+        &self,
+        packet: Packet,
+        id: T,
+        index: usize,
+    ) -> Result<(), Error> {
+        self.channel(index).try_send(packet, id)
+    }
+
+    /// Send the same packet to the given peer on each of the given channels,
+    /// e.g. from [`WebRtcSender::all_channels`]
+    ///
+    /// All or nothing: if one of the channels doesn't exist or isn't opened
+    /// with the peer, it isn't sent on any of them. Panics like
+    /// [`ChannelSender::send`] then, see [`WebRtcSender::try_send_on`].
+    pub fn send_on<T: Into<PeerId>>(&self, channels: &[usize], packet: Packet, id: T) {
+        if let Err(e) = self.try_send_on(channels, packet, id) {
+            panic_if_strict(self.strict, format_args!("send_to failed: {e}"));
+        }
+    }
+
+    /// Like [`WebRtcSender::send_on`], but returns an error instead of
+    /// panicking
+    ///
+    /// Fails with [`Error::ChannelNotOpen`] if one of the channels doesn't
+    /// exist or isn't opened with the peer, before sending anything.
+    pub fn try_send_on<T: Into<PeerId>>(
+        &self,
+        channels: &[usize],
+        packet: Packet,
+        id: T,
+    ) -> Result<(), Error> {
+        let id = id.into();
+        if let Some(&channel) = channels
+            .iter()
+            .find(|&&index| index >= self.peer_messages_out.len())
+        {
+            return Err(Error::ChannelNotOpen { peer: id, channel });
+        }
+        let senders: Vec<_> = channels.iter().map(|&index| self.channel(index)).collect();
+        for sender in &senders {
+            sender.check_open(&id)?;
+        }
+        for sender in senders {
+            sender.try_send(packet.clone(), id.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the indices of all channels, in the order of
+    /// [`WebRtcSocketConfig::channels`]
+    pub fn all_channels(&self) -> Vec<usize> {
+        (0..self.peer_messages_out.len()).collect()
+    }
+
+    /// Returns the pool received packets are allocated from
+    ///
+    /// Take buffers for outgoing packets from it, and send them with
+    /// [`ChannelSender::send_pooled`] to reuse them too.
+    pub fn packet_pool(&self) -> &PacketPool {
+        &self.pool
+    }
+
+    /// Returns a [`ChannelSender`] for the channel with the given index
+    ///
+    /// The index of a channel is its index in the vec [`WebRtcSocketConfig::channels`].
+    ///
+    /// Panics if there's no such channel, see [`WebRtcSocketConfig::strict`].
+    /// Otherwise sending on it fails like after the message loop stopped.
+    pub fn channel(&self, index: usize) -> ChannelSender {
+        let tx = match self.peer_messages_out.get(index) {
+            Some(tx) => tx.clone(),
+            None => {
+                panic_if_strict(
+                    self.strict,
+                    format_args!("No data channel with index {}", index),
+                );
+                futures_channel::mpsc::unbounded().0
+            }
+        };
+        ChannelSender {
+            tx,
+            index,
+            recorder: self.recorder.clone(),
+            pool: self.pool.clone(),
+            stats: self.channel_stats.get(index).cloned().unwrap_or_default(),
+            unopened: self.unopened_peers.get(index).cloned().unwrap_or_default(),
+            exchanges: self.exchanges.get(index).cloned().flatten(),
+            strict: self.strict,
+        }
+    }
+
+    /// Answers a request from [`WebRtcReceiver::receive_requests_on_channel`],
+    /// on the channel it was sent on
+    ///
+    /// Fails like [`ChannelSender::try_send`].
+    pub fn respond(&self, request: &IncomingRequest, packet: Packet) -> Result<(), Error> {
+        let channel = self.channel(request.channel());
+        channel.check_open(&request.peer)?;
+        channel.enqueue(
+            request.peer.clone(),
+            Exchanges::response(&self.pool, request, &packet),
+        )
+    }
+
+    /// Returns the counters of the channel with the given index, see
+    /// [`ChannelSender::stats`]
+    pub fn channel_stats(&self, index: usize) -> ChannelStats {
+        match self.channel_stats.get(index) {
+            Some(stats) => stats.snapshot(),
+            None => {
+                panic_if_strict(
+                    self.strict,
+                    format_args!("No data channel with index {}", index),
+                );
+                ChannelStats::default()
+            }
+        }
+    }
+
+    /// Sends a message to everyone else in the room through the signalling
+    /// server, e.g. lobby state
+    ///
+    /// Unlike packets, these reach peers before they're connected. If the
+    /// server keeps an event log for the room, peers joining later get the
+    /// latest messages too. Messages sent before the socket joined the room
+    /// are dropped by the server.
+    pub fn send_room_message<T: Into<String>>(&self, data: T) -> Result<(), Error> {
+        self.send_request(PeerRequest::RoomMessage(data.into()))
+    }
+
+    /// Joins another room besides ours on the same connection to the
+    /// signalling server, e.g. a global chat next to the match room
+    ///
+    /// We aren't connected to the peers of extra rooms, only told who is in
+    /// them, see [`WebRtcReceiver::extra_room_events`], and exchange messages
+    /// with them, see [`WebRtcSender::send_extra_room_message`]. Extra rooms
+    /// are joined again after reconnecting to the signalling server, but
+    /// left when joining another room with [`WebRtcSender::join_room`].
+    /// `matchbox_server` lets a peer join up to 8 of them.
+    ///
+    /// Like room messages, joins requested before the message loop runs are
+    /// dropped.
+    pub fn join_extra_room<T: Into<String>>(&self, room: T) -> Result<(), Error> {
+        self.send_request(PeerRequest::JoinExtraRoom(room.into()))
+    }
+
+    /// Leaves a room joined with [`WebRtcSender::join_extra_room`]
+    pub fn leave_extra_room<T: Into<String>>(&self, room: T) -> Result<(), Error> {
+        self.send_request(PeerRequest::LeaveExtraRoom(room.into()))
+    }
+
+    /// Sends a message to everyone else in a room joined with
+    /// [`WebRtcSender::join_extra_room`]
+    ///
+    /// Unlike [`WebRtcSender::send_room_message`], messages aren't kept for
+    /// peers joining later.
+    pub fn send_extra_room_message<T: Into<String>, D: Into<String>>(
+        &self,
+        room: T,
+        data: D,
+    ) -> Result<(), Error> {
+        self.send_request(PeerRequest::ExtraRoomMessage {
+            room: room.into(),
+            data: data.into(),
+        })
+    }
+
+    /// Describes our room, e.g. its game mode and map, for peers joining it
+    /// and the signalling server's `/rooms` listing
+    ///
+    /// Only the peer that created the room may describe it, the server
+    /// ignores everyone else. Metadata set before the message loop runs is
+    /// dropped, like room messages.
+    pub fn set_room_metadata(&self, metadata: RoomMetadata) -> Result<(), Error> {
+        self.send_request(PeerRequest::SetRoomMetadata(metadata))
+    }
+
+    /// Ends the match for everyone in our room, e.g. when the host quits to
+    /// the menu
+    ///
+    /// Everyone, including us, disconnects from their peers and leaves the
+    /// room, see [`WebRtcReceiver::room_closed`]. Only the peer that created
+    /// the room may close it, the server ignores everyone else.
+    pub fn close_room(&self) -> Result<(), Error> {
+        self.send_request(PeerRequest::CloseRoom)
+    }
+
+    /// Keeps our room from being closed for being idle, e.g. after
+    /// [`WebRtcReceiver::idle_warnings`] warned about it
+    ///
+    /// The signalling server may close rooms nobody sent anything to it for
+    /// a while, keep-alives of the socket itself don't count. Any other
+    /// request, like a room message, keeps the room alive as well.
+    pub fn keep_room_alive(&self) -> Result<(), Error> {
+        self.send_request(PeerRequest::KeepRoomAlive)
+    }
+
+    /// Holds free slots of our group in a `next` room for the peers we give
+    /// the tokens to, e.g. friends we invited
+    ///
+    /// Strangers can't take the reserved slots, they wait for the next group
+    /// instead. Peers claim a slot by setting its token as their
+    /// [`WebRtcSocketConfig::slot_token`]. The server gives the slots to
+    /// strangers after a while, and ignores reservations once our group is
+    /// complete, or beyond its free slots.
+    pub fn reserve_slots<T: Into<String>>(
+        &self,
+        tokens: impl IntoIterator<Item = T>,
+    ) -> Result<(), Error> {
+        let tokens = tokens.into_iter().map(Into::into).collect();
+        self.send_request(PeerRequest::ReserveSlots(tokens))
+    }
+
+    /// Reports our latency to a region of the matchmaking queue, see
+    /// [`WebRtcSocketConfig::matchmaking`]
+    ///
+    /// Only needed on wasm, native sockets measure their latencies
+    /// themselves. Regions the server doesn't know are ignored, and so are
+    /// reports while we're not queued.
+    pub fn report_latency<T: Into<String>>(
+        &self,
+        region: T,
+        latency: Duration,
+    ) -> Result<(), Error> {
+        let latencies = HashMap::from([(region.into(), latency.as_millis() as u64)]);
+        self.send_request(PeerRequest::Latency(latencies))
+    }
+
+    /// Moves this peer and everyone in its room to another room
+    ///
+    /// This is meant for moving from a lobby to a game room: existing
+    /// connections are kept, and peers already waiting in the target room are
+    /// connected to as usual. `next` works like the `next` query parameter of
+    /// [`WebRtcSocketConfig::room_url`].
+    pub fn migrate_room<T: Into<String>>(&self, room: T, next: Option<usize>) -> Result<(), Error> {
+        let room = room.into();
+        self.send_request(PeerRequest::MigrateRoom { room, next })
+    }
+
+    /// Disconnects from all peers and the signalling server
+    ///
+    /// All peers are reported as [`PeerState::Disconnected`]. The message loop
+    /// keeps running, so the socket can [`join_room`](WebRtcSender::join_room)
+    /// later, e.g. to return to a lobby after a match.
+    pub fn leave_room(&self) -> Result<(), Error> {
+        self.send_room_command(RoomCommand::Leave)
+    }
+
+    /// Leaves the current room (if any) and joins the room with the given url
+    ///
+    /// See [`WebRtcSocketConfig::room_url`] for details on the room url. If
+    /// it's invalid, or blocked as mixed content, this fails with
+    /// [`Error::InvalidUrl`] or [`Error::InsecureSignallingUrl`] and we stay
+    /// in the current room.
+    pub fn join_room<T: Into<String>>(&self, room_url: T) -> Result<(), Error> {
+        let room_url = parse_room_url(&room_url.into(), self.upgrade_insecure_signalling)?;
+        self.send_room_command(RoomCommand::Join(room_url))
+    }
+
+    /// Stops sending keep-alives to the signalling server, e.g. while a
+    /// mobile browser tab is in the background, where timers are frozen and
+    /// connections die anyway
+    ///
+    /// If the connection to the signalling server is lost while paused, we
+    /// wait for [`WebRtcSender::resume`] instead of using up
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`]. Peer
+    /// connections are left alone. With the `visibility` feature, wasm
+    /// sockets are paused while the page is hidden.
+    pub fn pause(&self) {
+        set_paused(&self.paused, &self.room_commands, true);
+    }
+
+    /// Sends keep-alives again, and reconnects to the signalling server right
+    /// away if the connection was lost while paused
+    ///
+    /// The outcome is reported by [`WebRtcReceiver::resume_events`]. Does
+    /// nothing unless the socket is paused.
+    pub fn resume(&self) {
+        set_paused(&self.paused, &self.room_commands, false);
+    }
+
+    /// Whether the socket is paused, see [`WebRtcSender::pause`]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn send_room_command(&self, command: RoomCommand) -> Result<(), Error> {
+        self.room_commands
+            .unbounded_send(command)
+            .map_err(|_| Error::MessageLoopStopped)
+    }
+
+    fn send_request(&self, request: PeerRequest) -> Result<(), Error> {
+        self.requests
+            .unbounded_send(request)
+            .map_err(|_| Error::MessageLoopStopped)
+    }
+}
+
+impl ChannelSender {
+    /// Send a packet to the given peer on this channel
+    ///
+    /// Panics if the message loop has stopped, or the channel isn't opened
+    /// with the peer, see [`ChannelSender::try_send`]. Without
+    /// [`WebRtcSocketConfig::strict`], the packet is dropped instead.
+    pub fn send<T: Into<PeerId>>(&self, packet: Packet, id: T) {
+        let result = self.try_send(packet, id);
+        self.check_sent(result);
+    }
+
+    /// Send a packet to the given peer on this channel
+    ///
+    /// Fails with [`Error::MessageLoopStopped`] once the message loop has
+    /// stopped, e.g. because it panicked, and with [`Error::ChannelNotOpen`]
+    /// if the channel isn't opened with the peer.
+    pub fn try_send<T: Into<PeerId>>(&self, packet: Packet, id: T) -> Result<(), Error> {
+        self.try_send_pooled(packet.into(), id)
+    }
+
+    /// Send text to the given peer on this channel, as a string message if
+    /// the channel is configured with [`ChannelConfig::text`]
+    ///
+    /// Received with [`WebRtcReceiver::receive_text_on_channel`], or as a
+    /// packet of its UTF-8 bytes. Panics like [`ChannelSender::send`].
+    pub fn send_text<T: Into<PeerId>>(&self, text: &str, id: T) {
+        self.send(text.as_bytes().into(), id);
+    }
+
+    /// Send a packet made up of several slices to the given peer on this
+    /// channel, e.g. a header and a payload
+    ///
+    /// The slices are copied straight into a buffer from the socket's
+    /// [`PacketPool`], so they don't have to be concatenated first. Panics if
+    /// the message loop has stopped, see [`ChannelSender::try_send_vectored`].
+    pub fn send_vectored<T: Into<PeerId>>(&self, parts: &[IoSlice<'_>], id: T) {
+        let result = self.try_send_vectored(parts, id);
+        self.check_sent(result);
+    }
+
+    /// Like [`ChannelSender::send_vectored`], but returns an error instead of
+    /// panicking if the message loop has stopped
+    pub fn try_send_vectored<T: Into<PeerId>>(
+        &self,
+        parts: &[IoSlice<'_>],
+        id: T,
+    ) -> Result<(), Error> {
+        self.try_send_pooled(self.pool.packet_from_slices(parts), id)
+    }
+
+    /// Send a packet from the socket's [`PacketPool`] to the given peer on
+    /// this channel
+    ///
+    /// Its buffer goes back to the pool once it's handed to the data channel.
+    /// Panics if the message loop has stopped, see
+    /// [`ChannelSender::try_send_pooled`].
+    pub fn send_pooled<T: Into<PeerId>>(&self, packet: PooledPacket, id: T) {
+        let result = self.try_send_pooled(packet, id);
+        self.check_sent(result);
+    }
+
+    fn check_sent(&self, result: Result<(), Error>) {
+        if let Err(e) = result {
+            panic_if_strict(self.strict, format_args!("send_to failed: {e}"));
+        }
+    }
+
+    /// Like [`ChannelSender::send_pooled`], but returns an error instead of
+    /// panicking if the message loop has stopped
+    pub fn try_send_pooled<T: Into<PeerId>>(
+        &self,
+        packet: PooledPacket,
+        id: T,
+    ) -> Result<(), Error> {
+        let id = id.into();
+        self.check_open(&id)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(PacketDirection::Sent, &id, self.index, &packet);
+        }
+        let packet = match &self.exchanges {
+            Some(_) => Exchanges::plain(&self.pool, &packet),
+            None => packet,
+        };
+        self.enqueue(id, packet)
+    }
+
+    /// Sends a request to the given peer on this channel, and waits for its
+    /// response
+    ///
+    /// The peer gets it from [`WebRtcReceiver::receive_requests_on_channel`]
+    /// and answers with [`WebRtcSender::respond`]. The request is queued right
+    /// away, the returned future only waits for the response. Requests and
+    /// responses aren't recorded by a [`Recorder`].
+    ///
+    /// Fails with [`Error::RequestTimeout`] after
+    /// [`WebRtcSocketConfig::request_timeout_ms`], and with
+    /// [`Error::RequestFailed`] if the peer disconnects first or
+    /// [`ChannelConfig::requests`] isn't enabled for this channel.
+    pub fn request<T: Into<PeerId>>(
+        &self,
+        packet: Packet,
+        id: T,
+    ) -> impl Future<Output = Result<Packet, Error>> + 'static {
+        let id = id.into();
+        let sent = match &self.exchanges {
+            Some(exchanges) => {
+                let (request_id, request, response) = exchanges.request(&self.pool, &id, &packet);
+                match self
+                    .check_open(&id)
+                    .and_then(|_| self.enqueue(id.clone(), request))
+                {
+                    Ok(()) => Ok((exchanges.clone(), request_id, response)),
+                    Err(e) => {
+                        exchanges.cancel(&id, request_id);
+                        Err(e)
+                    }
+                }
+            }
+            None => Err(Error::RequestFailed {
+                peer: id.clone(),
+                channel: self.index,
+            }),
+        };
+        async move {
+            let (exchanges, request_id, response) = sent?;
+            exchanges.response_to(id, request_id, response).await
+        }
+    }
+
+    fn check_open(&self, id: &PeerId) -> Result<(), Error> {
+        if self.unopened.contains(id) {
+            return Err(Error::ChannelNotOpen {
+                peer: id.clone(),
+                channel: self.index,
+            });
+        }
+        Ok(())
+    }
+
+    fn enqueue(&self, id: PeerId, packet: PooledPacket) -> Result<(), Error> {
+        let len = packet.len();
+        self.tx
+            .unbounded_send((id, packet))
+            .map_err(|_| Error::MessageLoopStopped)?;
+        metrics::packet_sent(self.index, len);
+        self.stats.record_sent(len);
+        Ok(())
+    }
+
+    /// Returns the messages and bytes sent and received on this channel so
+    /// far, and how many messages were lost on the way out
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.snapshot()
+    }
+}
+
+impl WebRtcReceiver {
+    /// Returns a future that resolves when the given number of peers have connected
+    ///
+    /// Fails with [`Error::MessageLoopStopped`] if the message loop stops
+    /// first.
+    pub async fn wait_for_peers(&mut self, peers: usize) -> Result<Vec<PeerId>, Error> {
+        debug!("waiting for peers to join");
+        let mut addrs = vec![];
+        while let Some((id, state)) = self.peer_state_changes.next().await {
+            if self.update_peer_state(id.clone(), state) {
+                addrs.push(id);
+            }
+            if addrs.len() == peers {
+                debug!("all peers joined");
+                self.update_room();
+                self.update_connection_infos();
+                return Ok(addrs);
+            }
+        }
+        self.closed = true;
+        Err(Error::MessageLoopStopped)
+    }
+
+    /// Check if new peers have connected and if so add them as peers
+    pub fn accept_new_connections(&mut self) -> Vec<PeerId> {
+        self.update_room();
+        let mut ids = Vec::new();
+        loop {
+            match self.peer_state_changes.try_next() {
+                Ok(Some((id, state))) => {
+                    if self.update_peer_state(id.clone(), state) {
+                        ids.push(id);
+                    }
+                }
+                // the message loop dropped its end
+                Ok(None) => {
+                    self.closed = true;
+                    break;
+                }
+                // nothing new
+                Err(_) => break,
+            }
+        }
+        self.update_connection_infos();
+        self.update_lobby_state();
+        ids
+    }
+
+    /// Like [`WebRtcReceiver::accept_new_connections`], but fails with
+    /// [`Error::MessageLoopStopped`] once the message loop has stopped
+    ///
+    /// An empty list then means that nothing changed, rather than that the
+    /// socket is dead. Peers that connected before the loop stopped are
+    /// still returned by the last successful call.
+    pub fn try_accept_new_connections(&mut self) -> Result<Vec<PeerId>, Error> {
+        let was_closed = self.closed;
+        let ids = self.accept_new_connections();
+        if was_closed || (self.closed && ids.is_empty()) {
+            Err(Error::MessageLoopStopped)
+        } else {
+            Ok(ids)
+        }
+    }
+
+    /// Returns whether the message loop has stopped, e.g. because the
+    /// future it runs in resolved or was dropped
+    ///
+    /// Nothing will be received or connect anymore, the reason is the result
+    /// of the message loop future. Updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns the state of the connection to the given peer
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`]. Peers that are
+    /// [`PeerState::Reconnecting`] are still included in
+    /// [`WebRtcReceiver::connected_peers`], while disconnected ones are removed.
+    pub fn peer_state(&self, id: &PeerId) -> Option<PeerState> {
+        self.peer_states.get(id)
+    }
+
+    /// Returns whether the peer is newly connected
+    fn update_peer_state(&mut self, id: PeerId, state: PeerState) -> bool {
+        debug!("{id:?} is now {state:?}");
+        if state == PeerState::Disconnected {
+            self.connection_infos.remove(&id);
+            self.congestion.remove(&id);
+        }
+        let newly_connected = self.peer_states.update(id, state);
+        metrics::connected_peers(self.peer_states.connected().len());
+        newly_connected
+    }
+
+    /// Returns a Vec of the ids of the connected peers, in the order they
+    /// connected
+    ///
+    /// See [`WebRtcReceiver::connected_peer_ids`] to look at them without
+    /// copying them.
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.peer_states.connected().to_vec()
+    }
+
+    /// Like [`WebRtcReceiver::connected_peers`], without copying the ids
+    pub fn connected_peer_ids(&self) -> &[PeerId] {
+        self.peer_states.connected()
+    }
+
+    /// Returns whether the peer is in [`WebRtcReceiver::connected_peers`],
+    /// without going through all of them
+    pub fn is_connected(&self, id: &PeerId) -> bool {
+        self.peer_states.is_connected(id)
+    }
+
+    /// Returns the ids of the peers that disconnected, and didn't connect
+    /// again since, in no particular order
+    pub fn disconnected_peers(&self) -> Vec<PeerId> {
+        self.peer_states.disconnected().cloned().collect()
+    }
+
+    /// Returns the generation of the peer states, which goes up with every
+    /// change of a [`WebRtcReceiver::peer_state`]
+    ///
+    /// Pass it to [`WebRtcReceiver::peers_changed_since`] later to only get
+    /// what changed in the meantime.
+    pub fn peer_generation(&self) -> u64 {
+        self.peer_states.generation()
+    }
+
+    /// Returns the peers whose state changed after the given
+    /// [`WebRtcReceiver::peer_generation`], with their current states, in
+    /// the order of their last change
+    ///
+    /// Lets each part of a game that follows the peers, e.g. a scoreboard and
+    /// the netcode, catch up without going through everyone in a large room
+    /// every frame. Updated by [`WebRtcReceiver::accept_new_connections`].
+    ///
+    /// ```no_run
+    /// # use matchbox_socket::{PeerState, WebRtcSocket};
+    /// # fn frame(socket: &mut WebRtcSocket, seen: &mut u64) {
+    /// socket.accept_new_connections();
+    /// for (peer, state) in socket.peers_changed_since(*seen) {
+    ///     if state == PeerState::Disconnected {
+    ///         println!("{peer} left");
+    ///     }
+    /// }
+    /// *seen = socket.peer_generation();
+    /// # }
+    /// ```
+    pub fn peers_changed_since(&self, generation: u64) -> Vec<(PeerId, PeerState)> {
+        self.peer_states.changed_since(generation)
+    }
+
+    /// Receive messages from the default channel (with index 0)
+    ///
+    /// See also: [`WebRtcReceiver::receive_on_channel`]
+    pub fn receive(&mut self) -> Vec<(PeerId, Packet)> {
+        self.receive_on_channel(0)
+    }
+
+    /// Receive messages from a specific channel as configured in [`WebRtcSocketConfig::channels`].
+    ///
+    /// messages are removed from the receiver when called
+    pub fn receive_on_channel(&mut self, index: usize) -> Vec<(PeerId, Packet)> {
+        self.receive_pooled_on_channel(index)
+            .into_iter()
+            .map(|(peer, packet)| (peer, packet.into_boxed_slice()))
+            .collect()
+    }
+
+    /// Receive text from the default channel (with index 0)
+    ///
+    /// See also: [`WebRtcReceiver::receive_text_on_channel`]
+    pub fn receive_text(&mut self) -> Vec<(PeerId, String)> {
+        self.receive_text_on_channel(0)
+    }
+
+    /// Receive text from a specific channel, e.g. sent with
+    /// [`ChannelSender::send_text`] or as string messages by a JavaScript
+    /// peer
+    ///
+    /// Packets that aren't valid UTF-8 are dropped with a warning. Messages
+    /// are removed from the receiver when called, like with
+    /// [`WebRtcReceiver::receive_on_channel`].
+    pub fn receive_text_on_channel(&mut self, index: usize) -> Vec<(PeerId, String)> {
+        self.receive_on_channel(index)
+            .into_iter()
+            .filter_map(
+                |(peer, packet)| match String::from_utf8(packet.into_vec()) {
+                    Ok(text) => Some((peer, text)),
+                    Err(e) => {
+                        warn!("dropping packet from {peer:?} that isn't text: {e}");
+                        None
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /// Like [`WebRtcReceiver::receive_on_channel`], but the buffers of the
+    /// packets go back to the socket's [`PacketPool`] when they are dropped
+    ///
+    /// See [`WebRtcSocketConfig::packet_pool_size`].
+    pub fn receive_pooled_on_channel(&mut self, index: usize) -> Vec<(PeerId, PooledPacket)> {
+        let Some(channel) = self.messages_from_peers.get_mut(index) else {
+            panic_if_strict(
+                self.strict,
+                format_args!("No data channel with index {}", index),
+            );
+            return vec![];
+        };
+        drain_packets(channel, index, self.recorder.as_deref())
+    }
+
+    /// Call this where you want to handle requests received on the channel
+    /// with the given index, see [`ChannelSender::request`]
+    ///
+    /// Requests are removed from the socket when called. Answer them with
+    /// [`WebRtcSender::respond`], packets that aren't requests are received
+    /// as usual.
+    pub fn receive_requests_on_channel(&mut self, index: usize) -> Vec<IncomingRequest> {
+        let Some(requests) = self.requests_from_peers.get_mut(index) else {
+            panic_if_strict(
+                self.strict,
+                format_args!("No data channel with index {}", index),
+            );
+            return vec![];
+        };
+        drain_requests(requests)
+    }
+
+    /// Returns the id of this peer
+    pub fn id(&self) -> &PeerId {
+        &self.id
+    }
+
+    /// Returns what it takes to rejoin the room as this peer after a crash or
+    /// restart, see [`WebRtcSocketConfig::resume_session`]
+    ///
+    /// Meant to be saved whenever peers come and go. Updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn export_session(&self) -> SocketSession {
+        let mut peers: Vec<_> = self.peer_states.present().cloned().collect();
+        peers.sort();
+        SocketSession {
+            room_url: self
+                .signalling_url
+                .as_ref()
+                .unwrap_or(&self.room_url)
+                .clone(),
+            peer_id: self.id.clone(),
+            certificate_pem: self.certificate_pem().map(str::to_string),
+            peers,
+            channels: self.channels.clone(),
+            identities: self.identities.associations(),
+        }
+    }
+
+    /// Returns the display name of the given peer (or this peer), if it registered one
+    pub fn peer_name(&self, id: &PeerId) -> Option<&str> {
+        self.peer_names.get(id).map(String::as_str)
+    }
+
+    /// Returns the role the signalling server gave the given peer (or this
+    /// peer), once it told us
+    ///
+    /// The peer that created the room is its host unless the server assigns
+    /// roles otherwise, so every peer agrees on who the host is.
+    pub fn peer_role(&self, id: &PeerId) -> Option<PeerRole> {
+        self.peer_roles.get(id).copied()
+    }
+
+    /// Returns the features the given peer supports, empty if it didn't
+    /// advertise any
+    ///
+    /// Known before the peer is connected, so features can be negotiated
+    /// without a round trip, see [`WebRtcSocketConfig::capabilities`].
+    pub fn peer_capabilities(&self, id: &PeerId) -> &[String] {
+        self.peer_capabilities
+            .get(id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Remembers which player of the game a peer is, e.g. once it sent its
+    /// account id, replacing what either was associated with before
+    ///
+    /// Associations are kept when peers disconnect, are exported with
+    /// [`WebRtcReceiver::export_session`], and survive reconnections, which
+    /// keep the [`PeerId`]. A player associated with a new peer, e.g. after
+    /// restarting their game, is reported by
+    /// [`WebRtcReceiver::identity_moves`].
+    pub fn associate(&mut self, peer: PeerId, user: AppUserId) {
+        self.identities.associate(peer, user)
+    }
+
+    /// Forgets which player a peer is, returning the player
+    pub fn dissociate(&mut self, peer: &PeerId) -> Option<AppUserId> {
+        self.identities.dissociate(peer)
+    }
+
+    /// Returns the player a peer was associated with, see
+    /// [`WebRtcReceiver::associate`]
+    pub fn user_of(&self, peer: &PeerId) -> Option<&AppUserId> {
+        self.identities.user(peer)
+    }
+
+    /// Returns the peer a player was last associated with, see
+    /// [`WebRtcReceiver::associate`]
+    ///
+    /// The peer may have disconnected since, see
+    /// [`WebRtcReceiver::is_connected`].
+    pub fn peer_of(&self, user: &str) -> Option<&PeerId> {
+        self.identities.peer(user)
+    }
+
+    /// Returns the players that were associated with a new peer since the
+    /// last call, oldest first
+    ///
+    /// Lets a game hand a returning player's state over to their new peer.
+    pub fn identity_moves(&mut self) -> Vec<IdentityMove> {
+        self.identities.take_moves()
+    }
+
+    /// Returns the configuration the signalling server advertised for our
+    /// room, if it has any
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`], e.g. to size a
+    /// lobby by [`RoomInfo::max_peers`].
+    pub fn room_info(&self) -> Option<RoomInfo> {
+        self.room_info
+    }
+
+    /// Returns the description of our room, if its creator set one
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`].
+    pub fn room_metadata(&self) -> Option<&RoomMetadata> {
+        self.room_metadata.as_ref()
+    }
+
+    /// Returns the ids of the peers in the room, sorted
+    ///
+    /// Only known with [`WebRtcSocketConfig::signalling_only`], otherwise
+    /// always empty. Updated by [`WebRtcReceiver::accept_new_connections`].
+    ///
+    /// Large rooms are sent by the signalling server in pages, which are
+    /// collected as they arrive and replace the peers all at once when the
+    /// last one came in, see [`WebRtcReceiver::peer_list_complete`].
+    pub fn room_peers(&self) -> &[PeerId] {
+        &self.room_peers
+    }
+
+    /// Returns whether [`WebRtcReceiver::room_peers`] is up to date, i.e. no
+    /// pages of a newer peer list are still on their way
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`].
+    pub fn peer_list_complete(&self) -> bool {
+        self.room_peers_pages.is_none()
+    }
+
+    /// Returns the regions of the signalling server's matchmaking queue,
+    /// e.g. to measure our latency to them on wasm
+    ///
+    /// Only known while waiting in the queue, see
+    /// [`WebRtcSocketConfig::matchmaking`]. Updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn matchmaking_regions(&self) -> &[MatchmakingRegion] {
+        &self.matchmaking_regions
+    }
+
+    /// Returns the messages sent to the room since the last call, with the ids
+    /// of their senders, oldest first
+    ///
+    /// Includes the messages replayed from the room's event log when joining
+    /// it, see [`WebRtcSender::send_room_message`].
+    pub fn receive_room_messages(&mut self) -> Vec<(PeerId, String)> {
+        self.update_room();
+        std::mem::take(&mut self.room_messages)
+    }
+
+    /// Returns the ids of the rooms we joined besides our own, see
+    /// [`WebRtcSender::join_extra_room`]
+    ///
+    /// Rooms show up once the signalling server confirmed that we joined.
+    pub fn extra_rooms(&mut self) -> Vec<String> {
+        self.update_room();
+        self.extra_rooms.keys().cloned().collect()
+    }
+
+    /// Returns the peers in a room we joined besides our own, including us,
+    /// sorted
+    pub fn extra_room_peers(&mut self, room: &str) -> &[PeerId] {
+        self.update_room();
+        self.extra_rooms
+            .get(room)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns who joined and left the rooms we joined besides our own, and
+    /// what they sent to them, since the last call, oldest first
+    pub fn extra_room_events(&mut self) -> Vec<ExtraRoomEvent> {
+        self.update_room();
+        std::mem::take(&mut self.extra_room_events)
+    }
+
+    /// Returns the handshakes peers sent since the last call, oldest first
+    ///
+    /// Only contains the ones [`WebRtcSocketConfig::handshake_validator`]
+    /// accepted, see [`WebRtcSocketConfig::handshake_data`].
+    pub fn peer_handshakes(&mut self) -> Vec<PeerHandshake> {
+        self.update_room();
+        std::mem::take(&mut self.peer_handshakes)
+    }
+
+    /// Returns the packets that couldn't be delivered since the last call, as
+    /// `(peer, channel, packet)`, oldest first
+    ///
+    /// Packets end up here when their peer disconnected or is reconnecting,
+    /// or the data channel failed to send them. Always empty unless
+    /// [`WebRtcSocketConfig::dead_letters`] is set.
+    pub fn failed_sends(&mut self) -> Vec<(PeerId, usize, Packet)> {
+        let mut packets = vec![];
+        while let Ok(Some((peer, channel, packet))) = self.dead_letters.try_next() {
+            packets.push((peer, channel, packet.into_boxed_slice()));
+        }
+        packets
+    }
+
+    /// Returns the state of the connection to the signalling server, e.g. to
+    /// show "retrying in 5s…" while reconnecting
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`].
+    pub fn signalling_state(&self) -> SignallingState {
+        self.signalling_state
+    }
+
+    /// Returns the url of our room on the signalling server we last
+    /// connected to, which may be one of the
+    /// [fallbacks](WebRtcSocketConfig::fallback_signalling_urls)
+    ///
+    /// `None` until we connect. Updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn signalling_url(&self) -> Option<&str> {
+        self.signalling_url.as_deref()
+    }
+
+    /// Returns the states the connection to the signalling server went
+    /// through since the last call, oldest first
+    ///
+    /// Unlike [`WebRtcReceiver::signalling_state`], this doesn't miss short
+    /// lived states, e.g. to show that the server couldn't be reached, rather
+    /// than that nobody else joined yet. Starts with
+    /// [`SignallingState::Connecting`] for each room we join, and ends with
+    /// [`SignallingState::Disconnected`] when we leave it.
+    pub fn signalling_state_changes(&mut self) -> Vec<SignallingState> {
+        self.update_room();
+        std::mem::take(&mut self.signalling_state_changes)
+    }
+
+    /// Returns what happened when the socket was resumed since the last
+    /// call, oldest first, see [`WebRtcSender::resume`]
+    pub fn resume_events(&mut self) -> Vec<ResumeEvent> {
+        self.update_room();
+        std::mem::take(&mut self.resume_events)
+    }
+
+    /// Returns the warnings that our room is about to be closed for being
+    /// idle since the last call, with the time left until then, oldest first
+    ///
+    /// Call [`WebRtcSender::keep_room_alive`] to keep it open, or leave it
+    /// before it's closed. Once it's closed, [`WebRtcReceiver::room_closed`]
+    /// resolves with [`RoomClosedBy::Server`].
+    pub fn idle_warnings(&mut self) -> Vec<Duration> {
+        self.update_room();
+        std::mem::take(&mut self.idle_warnings)
+    }
+
+    /// Returns how many peers of our room haven't been told about us yet
+    ///
+    /// Signalling servers may pace introductions when many peers join a large
+    /// room at once, e.g. `matchbox_server`'s `introductions_per_sec` limit,
+    /// so offers trickle in instead of all arriving together. Lets games show
+    /// that more peers are on their way. Always 0 otherwise.
+    pub fn pending_introductions(&mut self) -> usize {
+        self.update_room();
+        self.pending_introductions
+    }
+
+    /// Returns our position in the queue of peers waiting to join, starting
+    /// at 1, while the signalling server is at capacity
+    ///
+    /// `matchbox_server` queues peers beyond its `max_peers` and `max_rooms`
+    /// limits when `queue_when_busy` is set, and lets them in as others
+    /// leave. Lets games show how many players are ahead. `None` once we're
+    /// let in, or if we never had to wait.
+    pub fn server_queue_position(&mut self) -> Option<usize> {
+        self.update_room();
+        Some(self.server_queue_position).filter(|position| *position > 0)
+    }
+
+    /// Returns which peers stalled or resumed on channels with
+    /// [`ChannelConfig::silence_timeout_ms`] since the last call, oldest first
+    pub fn channel_liveness_changes(&mut self) -> Vec<ChannelLiveness> {
+        self.update_room();
+        std::mem::take(&mut self.channel_liveness_changes)
+    }
+
+    /// Returns who closed our room, if it was closed, see
+    /// [`WebRtcSender::close_room`]
+    ///
+    /// Reset when we join another room.
+    pub fn room_closed_by(&mut self) -> Option<&RoomClosedBy> {
+        self.update_room();
+        self.room_closed_by.as_ref()
+    }
+
+    /// Resolves once our room is closed, by its host or the signalling
+    /// server, right away if it already was
+    ///
+    /// By then, the socket is disconnecting from its peers and has left the
+    /// room, as with [`WebRtcSender::leave_room`], so everyone ends the match
+    /// at the same time. Fails with [`Error::MessageLoopStopped`] if the
+    /// message loop stops first.
+    pub async fn room_closed(&mut self) -> Result<RoomClosedBy, Error> {
+        self.update_room();
+        loop {
+            if let Some(closed_by) = &self.room_closed_by {
+                return Ok(closed_by.clone());
+            }
+            match self.room_rx.next().await {
+                Some(update) => self.apply_room_update(update),
+                None => return Err(Error::MessageLoopStopped),
+            }
+        }
+    }
+
+    /// Returns how far along we are in getting connected to the whole group
+    ///
+    /// Updated by [`WebRtcReceiver::accept_new_connections`].
+    pub fn lobby_state(&self) -> LobbyState {
+        self.lobby_state
+    }
+
+    /// Returns the lobby states we went through since the last call, oldest
+    /// first, e.g. to play a sound when everyone is connected
+    ///
+    /// Starts with [`LobbyState::Searching`]. Updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn lobby_state_changes(&mut self) -> Vec<LobbyState> {
+        std::mem::take(&mut self.lobby_state_changes)
+    }
+
+    /// Returns the negotiated parameters of the connection to the given peer
+    ///
+    /// Available once the peer is connected, updated by
+    /// [`WebRtcReceiver::accept_new_connections`].
+    pub fn connection_info(&self, id: &PeerId) -> Option<&ConnectionInfo> {
+        self.connection_infos.get(id)
+    }
+
+    /// Returns how congested the link to the given peer is
+    ///
+    /// Sampled from the data channels a few times per second, so games can
+    /// e.g. send snapshots less often while the link can't keep up. `None`
+    /// for peers that aren't connected, always
+    /// [`CongestionLevel::Light`] with a custom [`Messenger`].
+    pub fn congestion(&self, id: &PeerId) -> Option<Congestion> {
+        self.is_connected(id).then(|| self.congestion.get(id))
+    }
+
+    /// Returns the fingerprint of the DTLS certificate this socket uses
+    ///
+    /// Formatted like the `a=fingerprint` line of the SDP, e.g.
+    /// `"sha-256 0A:1B:..."`, so applications can display it or peers can pin
+    /// it. Always `None` on wasm.
+    pub fn local_fingerprint(&self) -> Option<&str> {
+        self.certificate.as_ref().map(|c| c.fingerprint.as_str())
+    }
+
+    /// Returns the DTLS certificate this socket uses, including its private key
+    ///
+    /// Pass it to [`WebRtcSocketConfig::certificate_pem`] to keep the same
+    /// fingerprint when the socket is recreated. Keep it secret. Always `None`
+    /// on wasm.
+    pub fn certificate_pem(&self) -> Option<&str> {
+        self.certificate.as_ref().map(|c| c.pem.as_str())
+    }
+
+    fn update_room(&mut self) {
+        while let Ok(Some(update)) = self.room_rx.try_next() {
+            self.apply_room_update(update);
+        }
+    }
+
+    fn apply_room_update(&mut self, update: RoomUpdate) {
+        match update {
+            RoomUpdate::PeerName { peer, name } => {
+                self.peer_names.insert(peer, name);
+            }
+            RoomUpdate::PeerRole { peer, role } => {
+                self.peer_roles.insert(peer, role);
+            }
+            RoomUpdate::PeerCapabilities { peer, capabilities } => {
+                self.peer_capabilities.insert(peer, capabilities);
+            }
+            RoomUpdate::Info(info) => {
+                let channels = self.messages_from_peers.len();
+                match info.and_then(|info| info.channels) {
+                    Some(expected) if expected != channels => {
+                        warn!("the room expects {expected} channels, but we have {channels}")
+                    }
+                    _ => {}
+                }
+                self.room_info = info;
+            }
+            RoomUpdate::Peers(peers) => {
+                self.room_peers = peers;
+                self.room_peers_pages = None;
+            }
+            RoomUpdate::PeersPage(page) => self
+                .room_peers_pages
+                .get_or_insert_with(Vec::new)
+                .extend(page),
+            RoomUpdate::PeerListComplete => {
+                if let Some(peers) = self.room_peers_pages.take() {
+                    self.room_peers = peers;
+                }
+            }
+            RoomUpdate::MatchmakingRegions(regions) => self.matchmaking_regions = regions,
+            RoomUpdate::Signalling(state) if state != self.signalling_state => {
+                self.signalling_state = state;
+                self.signalling_state_changes.push(state);
+            }
+            RoomUpdate::Signalling(_) => {}
+            RoomUpdate::SignallingUrl(url) => self.signalling_url = Some(url),
+            RoomUpdate::Resume(event) => self.resume_events.push(event),
+            RoomUpdate::Message { sender, data } => self.room_messages.push((sender, data)),
+            RoomUpdate::Metadata(metadata) => self.room_metadata = metadata,
+            RoomUpdate::Closed(closed_by) => self.room_closed_by = closed_by,
+            RoomUpdate::Group { next, queued } => {
+                self.group_next = next;
+                self.queued = queued;
+            }
+            RoomUpdate::PeerHandshake(handshake) => self.peer_handshakes.push(handshake),
+            RoomUpdate::IdleWarning(closes_in) => self.idle_warnings.push(closes_in),
+            RoomUpdate::IntroductionsPending(pending) => self.pending_introductions = pending,
+            RoomUpdate::ServerQueuePosition(position) => self.server_queue_position = position,
+            RoomUpdate::ExtraRoomPeers { room, peers } => self.update_extra_room(room, peers),
+            RoomUpdate::ExtraRoomMessage { room, sender, data } => {
+                let message = ExtraRoomEvent::Message { room, sender, data };
+                self.extra_room_events.push(message);
+            }
+            RoomUpdate::ExtraRoomLeft(Some(room)) => {
+                self.extra_rooms.remove(&room);
+            }
+            RoomUpdate::ExtraRoomLeft(None) => self.extra_rooms.clear(),
+            RoomUpdate::ChannelLiveness(change) => self.channel_liveness_changes.push(change),
+        }
+    }
+
+    /// Reports who joined or left a room we joined besides our own
+    ///
+    /// Reconnecting to the signalling server sends the peers of the room
+    /// again, only the differences are reported.
+    fn update_extra_room(&mut self, room: String, peers: Vec<PeerId>) {
+        let known = self.extra_rooms.remove(&room).unwrap_or_default();
+        for peer in known.iter().filter(|peer| !peers.contains(peer)) {
+            self.extra_room_events.push(ExtraRoomEvent::PeerLeft {
+                room: room.clone(),
+                peer: peer.clone(),
+            });
+        }
+        for peer in peers.iter().filter(|peer| !known.contains(peer)) {
+            self.extra_room_events.push(ExtraRoomEvent::PeerJoined {
+                room: room.clone(),
+                peer: peer.clone(),
+            });
+        }
+        self.extra_rooms.insert(room, peers);
+    }
+
+    fn update_lobby_state(&mut self) {
+        let current = self.peer_states.connected().len() + 1;
+        let needed = self
+            .room_info
+            .and_then(|info| info.next)
+            .or(self.group_next);
+        let connecting = self.signalling_state == SignallingState::Connecting;
+        let state = if self.closed || self.room_closed_by.is_some() {
+            LobbyState::Failed
+        } else if self.queued || (connecting && self.peer_states.connected().is_empty()) {
+            LobbyState::Searching
+        } else if needed.is_some_and(|needed| current >= needed) {
+            LobbyState::AllPeersConnected
+        } else {
+            LobbyState::WaitingForPlayers { current, needed }
+        };
+        if state != self.lobby_state {
+            debug!("lobby is now {state:?}");
+            self.lobby_state = state;
+            self.lobby_state_changes.push(state);
+        }
+    }
+
+    fn update_connection_infos(&mut self) {
+        while let Ok(Some((id, info))) = self.peer_info_rx.try_next() {
+            // the peer may have disconnected again in the meantime
+            if self.is_connected(&id) {
+                self.connection_infos.insert(id, info);
+            }
+        }
+    }
+}
+
+/// Resolves with [`Error::MessageLoopPanicked`] if the future panics
+///
+/// The future is dropped right away, which closes all the channels to the
+/// socket, so sending starts failing too.
+async fn catch_panics(future: impl Future<Output = Result<(), Error>>) -> Result<(), Error> {
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = panic_message(panic.as_ref());
+            error!("message loop panicked: {message}");
+            Err(Error::MessageLoopPanicked(message))
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Pauses or resumes a socket, see [`WebRtcSender::pause`]
+fn set_paused(paused: &AtomicBool, room_commands: &UnboundedSender<RoomCommand>, pause: bool) {
+    let was_paused = paused.swap(pause, Ordering::Relaxed);
+    if was_paused && !pause {
+        // the message loop may have stopped, there's nothing to resume then
+        let _ = room_commands.unbounded_send(RoomCommand::Resume);
+    }
+}
+
+/// The message loop's ends of the channels to the socket, kept across rooms
+struct SocketChannels {
+    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    peer_messages_out_rx: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, PooledPacket)>>,
+    peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    messages_from_peers_tx: Vec<IncomingSender>,
+    room_tx: futures_channel::mpsc::UnboundedSender<RoomUpdate>,
+    peer_info_tx: futures_channel::mpsc::UnboundedSender<(PeerId, ConnectionInfo)>,
+    congestion: Arc<PeerCongestion>,
+    room_commands: futures_channel::mpsc::UnboundedReceiver<RoomCommand>,
+}
+
+/// What the platform's message loop needs to connect to the peers of a
+/// room, see [`message_loop`]
+pub(crate) struct MessageLoopChannels<'a> {
+    pub requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    pub events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
+    pub peer_messages_out_rx:
+        &'a mut [futures_channel::mpsc::UnboundedReceiver<(PeerId, PooledPacket)>],
+    pub peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    pub peer_info_tx: futures_channel::mpsc::UnboundedSender<(PeerId, ConnectionInfo)>,
+    pub congestion: Arc<PeerCongestion>,
+    pub room_tx: futures_channel::mpsc::UnboundedSender<RoomUpdate>,
+    pub messages_from_peers_tx: Vec<IncomingSender>,
+    pub leave_rx: futures_channel::oneshot::Receiver<()>,
+}
+
+async fn run_socket(
+    config: WebRtcSocketConfig,
+    messenger: Option<Arc<dyn Messenger>>,
+    paused: Arc<AtomicBool>,
+    id: PeerId,
+    mut channels: SocketChannels,
+) -> Result<(), Error> {
+    debug!("Starting WebRtcSocket message loop");
+
+    let mut room_url = Some(config.room_url.clone());
+    // only the first room goes through the queue
+    let mut matchmaking = config.matchmaking;
+    loop {
+        let url = match room_url.take() {
+            Some(url) => url,
+            // Not in a room, wait until we're asked to join one
+            None => match channels.room_commands.next().await {
+                Some(RoomCommand::Join(url)) => url,
+                // without a room, there's no connection to resume either
+                Some(RoomCommand::Leave | RoomCommand::Resume) => continue,
+                None => break,
+            },
+        };
+
+        let config = WebRtcSocketConfig {
+            room_url: url,
+            matchmaking: std::mem::take(&mut matchmaking),
+            ..config.clone()
+        };
+        let command = run_room(
+            config,
+            messenger.clone(),
+            &paused,
+            id.clone(),
+            &mut channels,
+        )
+        .await;
+        // the signalling loop is dropped with the room, so it doesn't get to
+        // report this itself
+        let _ = channels
+            .room_tx
+            .unbounded_send(RoomUpdate::Signalling(SignallingState::Disconnected));
+        let command = command?;
+
+        match command {
+            Some(RoomCommand::Join(url)) => room_url = Some(url),
+            // resuming is handled within the room
+            Some(RoomCommand::Leave | RoomCommand::Resume) => {}
+            // The socket was dropped
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Runs the signalling and message loops for a single room
+///
+/// Returns the command that made us leave the room, if any.
+async fn run_room(
+    config: WebRtcSocketConfig,
+    messenger: Option<Arc<dyn Messenger>>,
+    paused: &AtomicBool,
+    id: PeerId,
+    channels: &mut SocketChannels,
+) -> Result<Option<RoomCommand>, Error> {
+    let SocketChannels {
+        requests_sender,
+        requests_receiver,
+        peer_messages_out_rx,
+        peer_state_tx,
+        messages_from_peers_tx,
+        room_tx,
+        peer_info_tx,
+        congestion,
+        room_commands,
+    } = channels;
+    let (events_sender, events_receiver) = futures_channel::mpsc::unbounded::<PeerEvent>();
+    let (leave_tx, leave_rx) = futures_channel::oneshot::channel();
+    let (resume_tx, mut resume_rx) = futures_channel::mpsc::unbounded();
+
+    // Requests queued up for the previous room are meaningless in this one
+    while let Ok(Some(request)) = requests_receiver.try_next() {
+        debug!("dropping request for previous room: {request:?}");
+    }
+    // and so are its rules, the server sends the new ones when we join
+    let _ = room_tx.unbounded_send(RoomUpdate::Info(None));
+    let _ = room_tx.unbounded_send(RoomUpdate::Metadata(None));
+    let _ = room_tx.unbounded_send(RoomUpdate::Peers(vec![]));
+    let _ = room_tx.unbounded_send(RoomUpdate::MatchmakingRegions(vec![]));
+    let _ = room_tx.unbounded_send(RoomUpdate::Closed(None));
+    let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomLeft(None));
+    let _ = room_tx.unbounded_send(RoomUpdate::Group {
+        next: room_url_next(&config.room_url),
+        queued: config.matchmaking,
+    });
+
+    // observers and queued sockets never join the room, so they don't need
+    // a name. Queued sockets send theirs once they're matched into a room.
+    let joins_room = !config.signalling_only && !config.matchmaking;
+    let name = config.display_name.as_ref().filter(|_| joins_room);
+    // `requests_receiver` is still ours until the signalling loop starts
+    // below, so these sends can't fail
+    if let Some(name) = name {
+        // needs to be sent before the message loop sends our id
+        requests_sender
+            .unbounded_send(PeerRequest::Name(name.clone()))
+            .expect("failed to send name");
+    }
+    if let Some(version) = config.client_version.as_ref().filter(|_| joins_room) {
+        requests_sender
+            .unbounded_send(PeerRequest::Version(version.clone()))
+            .expect("failed to send version");
+    }
+    if !config.capabilities.is_empty() && joins_room {
+        requests_sender
+            .unbounded_send(PeerRequest::Capabilities(config.capabilities.clone()))
+            .expect("failed to send capabilities");
+    }
+    if let Some(token) = config.slot_token.as_ref().filter(|_| joins_room) {
+        requests_sender
+            .unbounded_send(PeerRequest::ClaimSlot(token.clone()))
+            .expect("failed to send slot token");
+    }
+
+    let signalling_loop_fut = signalling_with_reconnects(
+        config.clone(),
+        paused,
+        &mut resume_rx,
+        requests_receiver,
+        events_sender,
+        room_tx.clone(),
+    );
+
+    // resolves with the url of the room to join next, if any
+    let message_loop_fut = if config.signalling_only {
+        Either::Left(Either::Left(
+            observer::observer_loop(requests_sender.clone(), events_receiver, leave_rx)
+                .map(|res| res.map(|()| None)),
+        ))
+    } else if config.matchmaking {
+        Either::Left(Either::Right(matchmaking::queue_loop(
+            config.room_url.clone(),
+            requests_sender.clone(),
+            events_receiver,
+            leave_rx,
+        )))
+    } else if let Some(messenger) = messenger {
+        Either::Right(Either::Right(
+            messenger::messenger_loop(
+                messenger,
+                id,
+                config,
+                MessageLoopChannels {
+                    requests_sender: requests_sender.clone(),
+                    events_receiver,
+                    peer_messages_out_rx,
+                    peer_state_tx: peer_state_tx.clone(),
+                    peer_info_tx: peer_info_tx.clone(),
+                    congestion: congestion.clone(),
+                    room_tx: room_tx.clone(),
+                    messages_from_peers_tx: messages_from_peers_tx.to_vec(),
+                    leave_rx,
+                },
+            )
+            .map(|res| res.map(|()| None)),
+        ))
+    } else {
+        Either::Right(Either::Left(
+            message_loop(
+                id,
+                config,
+                MessageLoopChannels {
+                    requests_sender: requests_sender.clone(),
+                    events_receiver,
+                    peer_messages_out_rx,
+                    peer_state_tx: peer_state_tx.clone(),
+                    peer_info_tx: peer_info_tx.clone(),
+                    congestion: congestion.clone(),
+                    room_tx: room_tx.clone(),
+                    messages_from_peers_tx: messages_from_peers_tx.to_vec(),
+                    leave_rx,
+                },
+            )
+            .map(|res| res.map(|()| None)),
+        ))
+    };
+
+    let mut message_loop_done = Box::pin(message_loop_fut.fuse());
+    let mut signalling_loop_done = Box::pin(signalling_loop_fut.fuse());
+    let mut leave_tx = Some(leave_tx);
+    let mut command = None;
+    loop {
+        select! {
+            res = message_loop_done => {
+                debug!("Message loop completed");
+                if let Some(url) = res? {
+                    // matched into a room by the matchmaking queue
+                    command = Some(RoomCommand::Join(url));
+                }
+                break;
+            }
+
+            res = signalling_loop_done => {
+                debug!("Signalling loop completed");
+                if let Some(closed_by) = res? {
+                    debug!("room closed by {closed_by:?}");
+                    let _ = room_tx.unbounded_send(RoomUpdate::Closed(Some(closed_by)));
+                    // disconnect from the peers as if we left, so nobody is
+                    // left half-connected
+                    if let Some(leave_tx) = leave_tx.take() {
+                        let _ = leave_tx.send(());
+                    }
+                    command = Some(RoomCommand::Leave);
+                }
+                // todo!{"reconnect?"}
+            }
+
+            next_command = room_commands.select_next_some() => {
+                if let RoomCommand::Resume = next_command {
+                    // a dead connection fails on the next send, so we find
+                    // out right away instead of at the next keep-alive
+                    let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
+                    let _ = resume_tx.unbounded_send(());
+                    continue;
+                }
+                // Let the message loop disconnect from its peers before we
+                // close the connection to the signalling server
+                if let Some(leave_tx) = leave_tx.take() {
+                    let _ = leave_tx.send(());
+                }
+                command = Some(next_command);
+            }
+
+            complete => break
+        }
+    }
+    Ok(command)
+}
+
+/// Runs the signalling loop, reconnecting with backoff when the connection
+/// to the server fails, see [`WebRtcSocketConfig::signalling_reconnect_attempts`]
+///
+/// Only [retryable](Error::is_retryable) errors are retried.
+///
+/// Once we've been connected, giving up on the server doesn't fail the room,
+/// peers that are already connected stay connected.
+///
+/// While [paused](WebRtcSender::pause), a lost connection isn't retried until
+/// we're resumed, which skips the backoff.
+///
+/// Resolves with who closed the room, if it was closed.
+async fn signalling_with_reconnects(
+    config: WebRtcSocketConfig,
+    paused: &AtomicBool,
+    resume_rx: &mut futures_channel::mpsc::UnboundedReceiver<()>,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
+    room_tx: futures_channel::mpsc::UnboundedSender<RoomUpdate>,
+) -> Result<Option<RoomClosedBy>, Error> {
+    // the socket may have been dropped, that's fine
+    let set_state = |state| {
+        let _ = room_tx.unbounded_send(RoomUpdate::Signalling(state));
+    };
+    let report = |event| {
+        let _ = room_tx.unbounded_send(RoomUpdate::Resume(event));
+    };
+    set_state(SignallingState::Connecting);
+
+    let mut registration = vec![];
+    let mut room_url = config.room_url.clone();
+    let servers: Vec<String> = std::iter::once(&config.room_url)
+        .chain(&config.fallback_signalling_urls)
+        .cloned()
+        .collect();
+    // the server we're connected to or trying to connect to
+    let mut server = 0;
+    let max_attempts = config
+        .signalling_reconnect_attempts
+        .max(config.fallback_signalling_urls.len() as u16);
+    let mut connected_before = false;
+    let mut failures = 0;
+    // whether this is the first attempt after being resumed
+    let mut resuming = false;
+    loop {
+        // the room may have been migrated since we last connected
+        room_url = room_url_on_server(&servers[server], &room_url);
+        let result = match signalling_connect(&room_url, config.signalling_timeout_ms).await {
+            Ok(connection) => {
+                connected_before = true;
+                let _ = room_tx.unbounded_send(RoomUpdate::SignallingUrl(room_url.clone()));
+                set_state(SignallingState::Connected);
+                if std::mem::take(&mut resuming) {
+                    report(ResumeEvent::Resumed);
+                }
+                let signalling = signalling_loop(
+                    connection,
+                    &mut registration,
+                    &mut room_url,
+                    requests_receiver,
+                    events_sender.clone(),
+                    room_tx.clone(),
+                    paused,
+                )
+                .fuse();
+                futures::pin_mut!(signalling);
+                let result = loop {
+                    select! {
+                        result = signalling => break result,
+                        // the connection survived the pause, as far as we
+                        // can tell
+                        _ = resume_rx.select_next_some() => report(ResumeEvent::Resumed),
+                    }
+                };
+                // the connection worked until it was lost, so the next one
+                // gets all its attempts again. Rejections by the server
+                // count against the attempts instead.
+                if matches!(result, Err(Error::SignallingConnection(_))) {
+                    failures = 0;
+                }
+                result
+            }
+            Err(e) => {
+                if std::mem::take(&mut resuming) {
+                    report(ResumeEvent::ResumptionFailed {
+                        reason: e.to_string(),
+                    });
+                }
+                Err(e)
+            }
+        };
+
+        match result {
+            Err(e) if e.is_retryable() && paused.load(Ordering::Relaxed) => {
+                // background tabs lose their connections, there's no point
+                // in trying again before we're in the foreground
+                warn!("{e} while paused, reconnecting once resumed");
+                set_state(SignallingState::Disconnected);
+                if resume_rx.next().await.is_none() {
+                    return Ok(None);
+                }
+                resuming = true;
+            }
+            Err(e) if e.is_retryable() && failures < max_attempts => {
+                failures += 1;
+                server = (server + 1) % servers.len();
+                let mut delay = config.signalling_backoff.delay(failures);
+                if let Error::Signalling(e) = &e {
+                    delay = delay.max(e.retry_after().unwrap_or_default());
+                }
+                warn!(
+                    "{e}, reconnect attempt {failures} to {:?} in {delay:?}",
+                    servers[server]
+                );
+                set_state(SignallingState::Reconnecting {
+                    attempt: failures,
+                    delay,
+                });
+                select! {
+                    _ = futures_timer::Delay::new(delay).fuse() => {}
+                    resumed = resume_rx.next() => resuming = resumed.is_some(),
+                }
+            }
+            Err(Error::SignallingConnection(e)) if connected_before => {
+                warn!("giving up on the signalling server, keeping the connected peers: {e}");
+                set_state(SignallingState::Disconnected);
+                return Ok(None);
+            }
+            result => {
+                set_state(SignallingState::Disconnected);
+                return result;
+            }
+        }
+    }
+}
+
+/// Reads the `max-message-size` attribute from a session description
+///
+/// See [`ConnectionInfo::max_message_size`].
+pub(crate) fn sdp_max_message_size(sdp: &str) -> Option<usize> {
+    let announced = sdp
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("a=max-message-size:"))
+        .and_then(|size| size.trim().parse::<usize>().ok());
+    match announced {
+        // no limit
+        Some(0) => None,
+        Some(size) => Some(size),
+        // default from RFC 8841
+        None => Some(64 * 1024),
+    }
+}
+
+/// Announces `max_message_size` in a session description, replacing any
+/// `max-message-size` attribute it had
+///
+/// The attribute goes after the `sctp-port` one of the data channel media
+/// section, see [`sdp_max_message_size`].
+pub(crate) fn sdp_with_max_message_size(sdp: &str, max_message_size: usize) -> String {
+    let mut announced = String::with_capacity(sdp.len() + 32);
+    for line in sdp.split_inclusive('\n') {
+        if line.trim().starts_with("a=max-message-size:") {
+            continue;
+        }
+        announced.push_str(line);
+        if line.trim().starts_with("a=sctp-port:") {
+            announced.push_str(&format!("a=max-message-size:{max_message_size}\r\n"));
+        }
+    }
+    announced
+}
+
+/// Fails with `error` if the future doesn't resolve within `timeout_ms`
+///
+/// A timeout of 0 waits forever.
+pub(crate) async fn with_timeout<T>(
+    future: impl Future<Output = T>,
+    timeout_ms: u64,
+    error: Error,
+) -> Result<T, Error> {
+    if timeout_ms == 0 {
+        return Ok(future.await);
+    }
+    crate::time::timeout(Duration::from_millis(timeout_ms), future)
+        .await
+        .map_err(|_| error)
+}
+
+/// Parses a message from the signalling server, rejecting oversized ones
+///
+/// Returns `None` for json that isn't an event we know, e.g. one added in a
+/// newer version of the protocol, see [`crate::protocol`].
+pub(crate) fn parse_event(message: &str) -> Result<Option<PeerEvent>, Error> {
+    if message.len() > MAX_SIGNALLING_MESSAGE_SIZE {
+        return Err(Error::InvalidMessage(format!(
+            "message of {} bytes exceeds the limit of {MAX_SIGNALLING_MESSAGE_SIZE}",
+            message.len()
+        )));
+    }
+    let value: serde_json::Value = serde_json::from_str(message)
+        .map_err(|err| Error::InvalidMessage(format!("couldn't parse peer event: {err}")))?;
+    match serde_json::from_value(value) {
+        Ok(event) => Ok(Some(event)),
+        Err(err) => {
+            warn!("ignoring unknown peer event: {err}");
+            Ok(None)
+        }
+    }
+}
+
+/// Channel indices ordered from highest to lowest priority
+///
+/// Channels with equal priority keep the order they were configured in.
+pub(crate) fn channels_by_priority(config: &WebRtcSocketConfig) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..config.channels.len()).collect();
+    indices.sort_by_key(|&index| Reverse(config.channels[index].priority));
+    indices
+}
+
+/// Waits for the next outgoing packet, taking it from the highest priority
+/// channel that has one queued
+///
+/// Resolves to the index of the channel, and `None` if its sender was dropped.
+pub(crate) async fn next_peer_message_out(
+    peer_messages_out_rx: &mut [UnboundedReceiver<(PeerId, PooledPacket)>],
+    channel_order: &[usize],
+) -> (usize, Option<(PeerId, PooledPacket)>) {
+    futures::future::poll_fn(|cx: &mut Context<'_>| {
+        for &index in channel_order {
+            if let Poll::Ready(message) = peer_messages_out_rx[index].poll_next_unpin(cx) {
+                return Poll::Ready((index, message));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Panics with the given message with [`WebRtcSocketConfig::strict`], only
+/// logs it as an error otherwise
+pub(crate) fn panic_if_strict(strict: bool, message: std::fmt::Arguments<'_>) {
+    if strict {
+        panic!("{}", message);
+    }
+    error!("{}", message);
+}
+
+/// Takes the packets waiting on a channel, recording them and counting them
+/// in the metrics
+fn drain_packets(
+    channel: &mut futures_channel::mpsc::UnboundedReceiver<(PeerId, PooledPacket)>,
+    index: usize,
+    recorder: Option<&Recorder>,
+) -> Vec<(PeerId, PooledPacket)> {
+    // stops at the last packet, or when the message loop is gone, see
+    // [`WebRtcReceiver::is_closed`]
+    let packets: Vec<_> = std::iter::from_fn(|| channel.try_next().ok().flatten()).collect();
+    if let Some(recorder) = recorder {
+        for (peer, packet) in &packets {
+            recorder.record(PacketDirection::Received, peer, index, packet);
+        }
+    }
+    metrics::packets_received(index, packets.iter().map(|(_, packet)| &packet[..]));
+    packets
+}
+
+fn drain_requests(
+    requests: &mut futures_channel::mpsc::UnboundedReceiver<IncomingRequest>,
+) -> Vec<IncomingRequest> {
+    std::iter::from_fn(|| requests.try_next().ok().flatten()).collect()
+}
+
+/// Hands an outgoing message to the loop of its peer, or drops it if the peer
+/// isn't connected
+pub(crate) fn forward_to_peer(
+    (channel_index, peer, packet): (usize, PeerId, PooledPacket),
+    connected_peers: &HashMap<PeerId, Vec<UnboundedSender<PooledPacket>>>,
+    messages_from_peers_tx: &[IncomingSender],
+    strict: bool,
+) {
+    let senders = match connected_peers.get(&peer) {
+        Some(senders) => senders,
+        None => {
+            warn!("dropping packet for disconnected peer {peer:?}");
+            messages_from_peers_tx[channel_index].dropped(&peer, packet);
+            return;
+        }
+    };
+    let Some(sender) = senders.get(channel_index) else {
+        panic_if_strict(
+            strict,
+            format_args!(
+                "Unexpected data channel index during send: {}",
+                channel_index
+            ),
+        );
+        return;
+    };
+    if let Err(e) = sender.unbounded_send(packet) {
+        debug!("dropping packet for reconnecting peer {peer:?}");
+        messages_from_peers_tx[channel_index].dropped(&peer, e.into_inner());
+    }
+}
+
+pub(crate) fn new_senders_and_receivers<T>(
+    config: &WebRtcSocketConfig,
+) -> (Vec<UnboundedSender<T>>, Vec<UnboundedReceiver<T>>) {
+    (0..config.channels.len())
+        .map(|_| futures_channel::mpsc::unbounded())
+        .unzip()
+}
+
+/// Whether all data channels of a connection opened, see [`wait_for_ready`]
+type ChannelsReady = Result<(), &'static str>;
 
 fn create_data_channels_ready_fut(
     config: &WebRtcSocketConfig,
 ) -> (
     Vec<futures_channel::mpsc::Sender<u8>>,
-    Pin<Box<Fuse<impl Future<Output = ()>>>>,
+    Pin<Box<Fuse<impl Future<Output = ChannelsReady>>>>,
 ) {
     let (senders, receivers) = (0..config.channels.len())
         .map(|_| futures_channel::mpsc::channel(1))
         .unzip();
 
-    (senders, Box::pin(wait_for_ready(receivers).fuse()))
+    (
+        senders,
+        Box::pin(wait_for_ready(receivers, config.strict).fuse()),
+    )
 }
 
-async fn wait_for_ready(channel_ready_rx: Vec<futures_channel::mpsc::Receiver<u8>>) {
+/// Fails if a data channel closed before it was ready, and we're not
+/// [strict](WebRtcSocketConfig::strict)
+async fn wait_for_ready(
+    channel_ready_rx: Vec<futures_channel::mpsc::Receiver<u8>>,
+    strict: bool,
+) -> ChannelsReady {
     for mut receiver in channel_ready_rx {
         if receiver.next().await.is_none() {
-            panic!("Sender closed before channel was ready");
+            panic_if_strict(
+                strict,
+                format_args!("Sender closed before channel was ready"),
+            );
+            return Err("a data channel closed before it was ready");
         }
     }
+    Ok(())
 }
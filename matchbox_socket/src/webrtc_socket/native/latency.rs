@@ -0,0 +1,61 @@
+use std::{
+    error::Error,
+    net::{ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use webrtc::stun::{
+    agent::TransactionId,
+    message::{Message, BINDING_REQUEST, BINDING_SUCCESS},
+    uri::Uri,
+};
+
+/// How long to wait for the answer of a STUN server
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default port of STUN servers
+const STUN_PORT: u16 = 3478;
+
+/// Measures the round trip time of a binding request to the STUN server at
+/// the given url, e.g. `"stun:stun.l.google.com:19302"`
+pub async fn stun_latency(url: &str) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let uri = Uri::parse_uri(url)?;
+    let port = uri.port.unwrap_or(STUN_PORT);
+    // std sockets block, so keep them away from the executor
+    let (result_tx, result_rx) = futures_channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = result_tx.send(blocking_stun_latency(&uri.host, port));
+    });
+    result_rx.await?
+}
+
+fn blocking_stun_latency(host: &str, port: u16) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let server = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or("STUN server has no address")?;
+    let socket = UdpSocket::bind(if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+
+    let mut request = Message::new();
+    request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])?;
+    let sent_at = Instant::now();
+    socket.send(&request.raw)?;
+
+    let mut buf = [0; 1024];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        let mut response = Message::new();
+        if response.unmarshal_binary(&buf[..len]).is_err() {
+            continue;
+        }
+        if response.transaction_id == request.transaction_id && response.typ == BINDING_SUCCESS {
+            return Ok(sent_at.elapsed());
+        }
+    }
+}
@@ -1,97 +1,161 @@
 use async_compat::CompatExt;
 use bytes::Bytes;
 use futures::{
-    future::FusedFuture, stream::FuturesUnordered, Future, FutureExt, SinkExt, StreamExt,
+    future::{self, FusedFuture},
+    stream::FuturesUnordered,
+    Future, FutureExt, StreamExt,
 };
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures_timer::Delay;
 use futures_util::{lock::Mutex, select};
 use log::{debug, error, trace, warn};
+use rcgen::KeyPair;
 use std::time::Duration;
-use std::{collections::HashMap, pin::Pin, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::Arc,
+};
 use webrtc::{
-    api::APIBuilder,
+    api::{setting_engine::SettingEngine, APIBuilder},
     data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel},
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_connection_state::RTCIceConnectionState,
         ice_server::RTCIceServer,
     },
     peer_connection::{
-        configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
-        RTCPeerConnection,
+        certificate::RTCCertificate, configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
 };
 
 use crate::webrtc_socket::{
-    create_data_channels_ready_fut, new_senders_and_receivers, ChannelConfig,
+    channels_by_priority, create_data_channels_ready_fut, forward_to_peer,
+    new_senders_and_receivers, next_peer_message_out, open_channels_with, panic_if_strict,
+    Approvals, Approved, CandidatePreference, ChannelConfig,
 };
 use crate::webrtc_socket::{
-    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal},
+    coalesce::Coalescer,
+    congestion::{PeerCongestion, CONGESTION_SAMPLE_INTERVAL_MS},
+    fingerprint::verify_remote_fingerprint,
+    liveness::KEEP_ALIVE,
+    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal, RoomUpdate},
+    reconnect::{AttemptEvent, AttemptReporter, Reconnector},
+    recording::now_ms,
+    sdp_max_message_size, sdp_with_max_message_size,
     signal_peer::SignalPeer,
-    Packet, WebRtcSocketConfig, KEEP_ALIVE_INTERVAL,
+    ChannelInfo, ConnectionInfo, Handshakes, IncomingSender, Liveness, LocalCertificate,
+    MessageLoopChannels, PeerState, PooledPacket, RtcIceServerConfig, WebRtcSocketConfig,
+    KEEP_ALIVE_INTERVAL,
 };
+use crate::Error;
+
+/// The largest message webrtc-rs data channels can receive
+const MAX_RECEIVE_MESSAGE_SIZE: usize = u16::MAX as usize;
 
 pub async fn message_loop(
     id: PeerId,
     config: WebRtcSocketConfig,
-    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
-    events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    peer_messages_out_rx: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
-) {
-    message_loop_impl(
-        id,
-        &config,
-        requests_sender,
-        events_receiver,
-        peer_messages_out_rx,
-        new_connected_peers_tx,
-        messages_from_peers_tx,
-    )
-    // web-rtc is tokio-based so we use compat here to make it work with other async run-times
-    .compat()
-    .await
+    channels: MessageLoopChannels<'_>,
+) -> Result<(), Error> {
+    message_loop_impl(id, &config, channels)
+        // web-rtc is tokio-based so we use compat here to make it work with other async run-times
+        .compat()
+        .await
 }
 
 async fn message_loop_impl(
     id: PeerId,
     config: &WebRtcSocketConfig,
-    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
-    mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    mut peer_messages_out_rx: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
-) {
+    channels: MessageLoopChannels<'_>,
+) -> Result<(), Error> {
+    let MessageLoopChannels {
+        requests_sender,
+        mut events_receiver,
+        peer_messages_out_rx,
+        peer_state_tx,
+        peer_info_tx,
+        congestion,
+        room_tx,
+        messages_from_peers_tx,
+        mut leave_rx,
+    } = channels;
     debug!("Entering native WebRtcSocket message loop");
 
     debug!("I am {:?}", id);
 
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
+    // the signalling loop may be gone already, e.g. if the room was closed
+    // right away, run_room stops us then
+    let _ = requests_sender.unbounded_send(PeerRequest::Uuid(id));
 
     let mut peer_loops_a = FuturesUnordered::new();
     let mut peer_loops_b = FuturesUnordered::new();
     let mut handshake_signals = HashMap::new();
+    // signals of new peers waiting for a free handshake slot, starting with their offer
+    let mut queued_offers: HashMap<PeerId, Vec<PeerSignal>> = HashMap::new();
     let mut connected_peers = HashMap::new();
+    let mut reconnector = Reconnector::new(config, peer_info_tx);
+    let mut handshakes = Handshakes::new(config, room_tx.clone());
+    let mut approvals = Approvals::new(config);
+    let mut liveness = Liveness::new(config);
+    let channel_order = channels_by_priority(config);
+    let mut coalescer = Coalescer::new(config, &messages_from_peers_tx);
+    // handed out by the signalling server, used in addition to our own
+    let mut server_ice_servers = vec![];
+    // decide which channels we open with each peer
+    let mut peer_capabilities = HashMap::new();
 
     let timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL));
     futures::pin_mut!(timeout);
+    let mut check_liveness = liveness.next_check();
+
+    let mut flush = coalescer.flush_timer();
 
     loop {
-        let mut next_peer_messages_out: FuturesUnordered<_> = peer_messages_out_rx
-            .iter_mut()
-            .enumerate()
-            .map(|(index, rx)| async move { (index, rx.next().await) })
-            .collect();
+        // while packets are held back, the rest stay queued until the flush
+        let holding = coalescer.is_holding();
+        let mut next_peer_message_out = Box::pin(
+            async {
+                if holding {
+                    future::pending().await
+                } else {
+                    next_peer_message_out(peer_messages_out_rx, &channel_order).await
+                }
+            }
+            .fuse(),
+        );
 
         select! {
             _ = (&mut timeout).fuse() => {
-                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
+                // see the uuid above
+                let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
                 timeout.reset(Duration::from_millis(KEEP_ALIVE_INTERVAL));
             }
 
+            _ = &mut check_liveness => {
+                let (keep_alives, changes) = liveness.check(now_ms() as u64, &messages_from_peers_tx);
+                for (channel, peer) in keep_alives {
+                    forward_to_peer((channel, peer, PooledPacket::from(KEEP_ALIVE.to_vec())), &connected_peers, &messages_from_peers_tx, config.strict);
+                }
+                for change in changes {
+                    // the socket may have been dropped, that's fine
+                    let _ = room_tx.unbounded_send(RoomUpdate::ChannelLiveness(change));
+                }
+                check_liveness = liveness.next_check();
+            }
+
+            _ = leave_rx => {
+                debug!("Leaving room");
+                let peers: HashSet<_> = connected_peers.keys().chain(handshake_signals.keys()).chain(reconnector.peers()).cloned().collect();
+                for peer in peers {
+                    // the socket may have been dropped, that's fine
+                    let _ = peer_state_tx.unbounded_send((peer, PeerState::Disconnected));
+                }
+                break;
+            }
+
             _ = peer_loops_a.select_next_some() => {
                 debug!("peer finished");
             },
@@ -99,35 +163,112 @@ async fn message_loop_impl(
                 debug!("peer finished");
             },
 
+            event = reconnector.next_event().fuse() => {
+                match event {
+                    AttemptEvent::StateChanged(peer, state) => {
+                        match state {
+                            PeerState::Connecting => {}
+                            PeerState::Connected => liveness.connected(&peer, now_ms() as u64),
+                            PeerState::Reconnecting => {
+                                // The next handshake needs its own signalling
+                                handshake_signals.remove(&peer);
+                                liveness.disconnected(&peer);
+                            }
+                            PeerState::Disconnected => {
+                                handshake_signals.remove(&peer);
+                                liveness.disconnected(&peer);
+                                queued_offers.remove(&peer);
+                                connected_peers.remove(&peer);
+                                peer_capabilities.remove(&peer);
+                                // sends fail as for any other disconnected peer again, and so do
+                                // requests waiting for a response
+                                for channel in &messages_from_peers_tx {
+                                    channel.disconnected(&peer);
+                                }
+                            }
+                        }
+                        if let Some(rejected) = handshakes.state_changed(&peer, state, &requests_sender) {
+                            reconnector.disconnect(&rejected);
+                        }
+                        // the socket may have been dropped, that's fine
+                        let _ = peer_state_tx.unbounded_send((peer, state));
+                    }
+                    AttemptEvent::Offer(attempt) => {
+                        let open = open_channels_with(attempt.peer(), config, peer_capabilities.get(attempt.peer()), &messages_from_peers_tx);
+                        peer_loops_a.push(offer_peer(attempt, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, config, server_ice_servers.clone(), open, congestion.clone()));
+                    }
+                    AttemptEvent::Answer(attempt) => {
+                        let peer = attempt.peer().clone();
+                        let open = open_channels_with(&peer, config, peer_capabilities.get(&peer), &messages_from_peers_tx);
+                        let signals = queued_offers.remove(&peer).unwrap_or_default();
+                        peer_loops_b.push(answer_peer(attempt, signals, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, config, server_ice_servers.clone(), open, congestion.clone()));
+                    }
+                    AttemptEvent::TimedOut(peer) => {
+                        let _ = peer_state_tx.unbounded_send((peer.clone(), PeerState::Disconnected));
+                        return Err(Error::PeerConnectTimeout(peer));
+                    }
+                }
+            }
+
+            approved = approvals.next_approved().fuse() => {
+                match approved {
+                    Approved::NewPeer(peer) => reconnector.start(&peer),
+                    Approved::Offer(sender, signals) => {
+                        // We didn't start signalling with this peer, assume we're the accepting part
+                        let Some(attempt) = reconnector.accept_or_queue(&sender) else {
+                            queued_offers.insert(sender, signals);
+                            continue;
+                        };
+                        let open = open_channels_with(&sender, config, peer_capabilities.get(&sender), &messages_from_peers_tx);
+                        peer_loops_b.push(answer_peer(attempt, signals, &requests_sender, &messages_from_peers_tx, &mut handshake_signals, &mut connected_peers, config, server_ice_servers.clone(), open, congestion.clone()));
+                    }
+                }
+            }
+
             message = events_receiver.next().fuse() => {
                 if let Some(event) = message {
                     debug!("{:?}", event);
                     match event {
-                        PeerEvent::NewPeer(peer_uuid) => {
-                            let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
-                            handshake_signals.insert(peer_uuid.clone(), signal_sender);
-                            let signal_peer = SignalPeer::new(peer_uuid.clone(), requests_sender.clone());
-                            let handshake_fut = handshake_offer(signal_peer, signal_receiver, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), config);
-                            let (to_peer_data_tx, to_peer_data_rx) = new_senders_and_receivers(config);
-
-                            connected_peers.insert(peer_uuid, to_peer_data_tx);
-                            peer_loops_a.push(peer_loop(handshake_fut, to_peer_data_rx));
+                        PeerEvent::NewPeer(peer_uuid) => approvals.new_peer(peer_uuid),
+                        PeerEvent::Signal { sender, data: PeerSignal::Handshake(data) } => {
+                            if let Some(rejected) = handshakes.received(sender, data) {
+                                reconnector.disconnect(&rejected);
+                            }
                         }
                         PeerEvent::Signal { sender, data } => {
-                            let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
-                                let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
-                                let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
-                                let (to_peer_data_tx, to_peer_data_rx) = new_senders_and_receivers(config);
-                                // We didn't start signalling with this peer, assume we're the accepting part
-                                let handshake_fut = handshake_accept(signal_peer, from_peer_receiver, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), config);
-                                connected_peers.insert(sender, to_peer_data_tx);
-                                let peer_loop_fut = peer_loop(handshake_fut, to_peer_data_rx);
-                                peer_loops_b.push(peer_loop_fut);
-                                from_peer_sender
-                            });
-                            from_peer_sender.unbounded_send(data)
-                                .expect("failed to forward signal to handshaker");
+                            // the rest of the handshake waits with its offer
+                            let Some(data) = approvals.hold(&sender, data) else {
+                                continue;
+                            };
+                            if let Some(signals) = queued_offers.get_mut(&sender) {
+                                signals.push(data);
+                                continue;
+                            }
+                            let Some(handshake) = handshake_signals.get(&sender) else {
+                                if !matches!(data, PeerSignal::Offer(_)) {
+                                    // Left over from a connection attempt we already gave up on
+                                    warn!("ignoring signal from {sender:?} without a handshake in progress: {data:?}");
+                                    continue;
+                                }
+                                approvals.offered(sender, data);
+                                continue;
+                            };
+                            if let Err(e) = handshake.unbounded_send(data) {
+                                panic_if_strict(config.strict, format_args!("failed to forward signal to handshaker: {e}"));
+                            }
+                        }
+                        PeerEvent::RoomMigrated { room, next } => {
+                            debug!("moved to room {room:?} (next: {next:?})");
+                        }
+                        PeerEvent::IceServers(servers) => server_ice_servers = servers,
+                        PeerEvent::PeerCapabilities { peer, capabilities } => {
+                            approvals.capabilities(peer.clone(), capabilities.clone());
+                            peer_capabilities.insert(peer, capabilities);
                         }
+                        PeerEvent::PeerName { peer, name } => approvals.named(peer, name),
+                        PeerEvent::PeerRole { peer, role } => approvals.role(peer, role),
+                        // Handled by the signalling loop
+                        PeerEvent::Error(_) | PeerEvent::RoomPolicy(_) | PeerEvent::RoomPeers(_) | PeerEvent::RoomPeersPage(_) | PeerEvent::PeerListComplete | PeerEvent::RoomMessage { .. } | PeerEvent::RoomMetadata(_) | PeerEvent::MatchmakingRegions(_) | PeerEvent::MatchFound { .. } | PeerEvent::MigrationRejected(_) | PeerEvent::RoomClosed { .. } | PeerEvent::RoomIdle { .. } | PeerEvent::SignalsThrottled { .. } | PeerEvent::IntroductionsPending(_) | PeerEvent::ServerQueuePosition(_) | PeerEvent::ExtraRoomPeers { .. } | PeerEvent::ExtraRoomMessage { .. } => {}
                     }
                 } else {
                     // Disconnected from signalling server
@@ -135,16 +276,24 @@ async fn message_loop_impl(
             }
 
             // TODO: maybe use some forward trait instead?
-            message = next_peer_messages_out.next().fuse() => {
+            _ = &mut flush => {
+                drop(next_peer_message_out);
+                for message in coalescer.flush(peer_messages_out_rx, &channel_order) {
+                    forward_to_peer(message, &connected_peers, &messages_from_peers_tx, config.strict);
+                }
+            }
+
+            message = next_peer_message_out => {
                 match message {
-                    Some((channel_index, Some((peer, packet)))) => {
-                        let senders = connected_peers.get_mut(&peer)
-                            .unwrap_or_else(|| panic!("couldn't find data channel for peer {}", peer));
-                        let sender = senders.get_mut(channel_index)
-                            .unwrap_or_else(|| panic!("Unexpected data channel index during send: {}", channel_index));
-                        sender.unbounded_send(packet).unwrap();
+                    (channel_index, Some((peer, packet))) => {
+                        drop(next_peer_message_out);
+                        let messages = coalescer.collect((channel_index, peer, packet), peer_messages_out_rx, &channel_order);
+                        for message in messages {
+                            forward_to_peer(message, &connected_peers, &messages_from_peers_tx, config.strict);
+                        }
+                        flush = coalescer.flush_timer();
                     },
-                    Some((_, None)) | None => {
+                    (_, None) => {
                         // Receiver end of outgoing message channel closed,
                         // which most likely means the socket was dropped.
                         // There could probably be cleaner ways to handle this,
@@ -158,16 +307,129 @@ async fn message_loop_impl(
             complete => break
         }
     }
+
+    if config.flush_timeout_ms > 0 {
+        let flush = async {
+            for message in coalescer.flush_all(peer_messages_out_rx, &channel_order) {
+                forward_to_peer(
+                    message,
+                    &connected_peers,
+                    &messages_from_peers_tx,
+                    config.strict,
+                );
+            }
+            // closes the queues to the peers, so their loops end once they're empty
+            connected_peers.clear();
+            while peer_loops_a.next().await.is_some() {}
+            while peer_loops_b.next().await.is_some() {}
+        };
+        let timeout = Delay::new(Duration::from_millis(config.flush_timeout_ms));
+        select! {
+            _ = flush.fuse() => debug!("flushed queued packets"),
+            _ = timeout.fuse() => warn!("gave up flushing queued packets"),
+        }
+    }
+    Ok(())
+}
+
+/// Starts connecting to a peer by sending it an offer
+#[allow(clippy::too_many_arguments)]
+fn offer_peer<'a>(
+    attempt: AttemptReporter,
+    requests_sender: &UnboundedSender<PeerRequest>,
+    messages_from_peers_tx: &[IncomingSender],
+    handshake_signals: &mut HashMap<PeerId, UnboundedSender<PeerSignal>>,
+    connected_peers: &mut HashMap<PeerId, Vec<UnboundedSender<PooledPacket>>>,
+    config: &'a WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    congestion: Arc<PeerCongestion>,
+) -> impl Future<Output = ()> + 'a {
+    let peer = attempt.peer().clone();
+    let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+    handshake_signals.insert(peer.clone(), signal_sender);
+    let signal_peer = SignalPeer::new(peer.clone(), requests_sender.clone());
+    let handshake_fut = handshake_offer(
+        signal_peer,
+        signal_receiver,
+        attempt.clone(),
+        messages_from_peers_tx.to_vec(),
+        config,
+        server_ice_servers,
+        open_channels,
+    );
+    let (to_peer_data_tx, to_peer_data_rx) = new_senders_and_receivers(config);
+
+    connected_peers.insert(peer, to_peer_data_tx);
+    peer_loop(
+        handshake_fut,
+        to_peer_data_rx,
+        messages_from_peers_tx.to_vec(),
+        attempt,
+        config,
+        congestion,
+    )
+}
+
+/// Starts connecting to a peer by answering its offer
+///
+/// `signals` are the ones that arrived before the handshake started,
+/// starting with the offer.
+#[allow(clippy::too_many_arguments)]
+fn answer_peer<'a>(
+    attempt: AttemptReporter,
+    signals: Vec<PeerSignal>,
+    requests_sender: &UnboundedSender<PeerRequest>,
+    messages_from_peers_tx: &[IncomingSender],
+    handshake_signals: &mut HashMap<PeerId, UnboundedSender<PeerSignal>>,
+    connected_peers: &mut HashMap<PeerId, Vec<UnboundedSender<PooledPacket>>>,
+    config: &'a WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    congestion: Arc<PeerCongestion>,
+) -> impl Future<Output = ()> + 'a {
+    let peer = attempt.peer().clone();
+    let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+    for signal in signals {
+        signal_sender
+            .unbounded_send(signal)
+            .expect("the receiver is right here");
+    }
+    handshake_signals.insert(peer.clone(), signal_sender);
+    let signal_peer = SignalPeer::new(peer.clone(), requests_sender.clone());
+    let handshake_fut = handshake_accept(
+        signal_peer,
+        signal_receiver,
+        attempt.clone(),
+        messages_from_peers_tx.to_vec(),
+        config,
+        server_ice_servers,
+        open_channels,
+    );
+    let (to_peer_data_tx, to_peer_data_rx) = new_senders_and_receivers(config);
+
+    connected_peers.insert(peer, to_peer_data_tx);
+    peer_loop(
+        handshake_fut,
+        to_peer_data_rx,
+        messages_from_peers_tx.to_vec(),
+        attempt,
+        config,
+        congestion,
+    )
 }
+
 struct CandidateTrickle {
     signal_peer: SignalPeer,
+    preference: CandidatePreference,
     pending: Mutex<Vec<String>>,
 }
 
 impl CandidateTrickle {
-    fn new(signal_peer: SignalPeer) -> Self {
+    fn new(signal_peer: SignalPeer, preference: CandidatePreference) -> Self {
         Self {
             signal_peer,
+            preference,
             pending: Default::default(),
         }
     }
@@ -177,13 +439,14 @@ impl CandidateTrickle {
         peer_connection: &RTCPeerConnection,
         candidate: RTCIceCandidate,
     ) {
-        let candidate_init = match candidate.to_json() {
+        let mut candidate_init = match candidate.to_json() {
             Ok(candidate_init) => candidate_init,
             Err(err) => {
                 error!("failed to convert ice candidate to candidate init, ignoring: {err}");
                 return;
             }
         };
+        candidate_init.candidate = self.preference.apply(&candidate_init.candidate);
 
         let candidate_json =
             serde_json::to_string(&candidate_init).expect("failed to serialize candidate to json");
@@ -211,13 +474,15 @@ impl CandidateTrickle {
     async fn listen_for_remote_candidates(
         peer_connection: Arc<RTCPeerConnection>,
         mut signal_receiver: UnboundedReceiver<PeerSignal>,
+        preference: CandidatePreference,
     ) -> Result<(), Box<dyn std::error::Error>> {
         while let Some(signal) = signal_receiver.next().await {
             match signal {
                 PeerSignal::IceCandidate(candidate_json) => {
                     debug!("received ice candidate: {candidate_json:?}");
                     match serde_json::from_str::<RTCIceCandidateInit>(&candidate_json) {
-                        Ok(candidate_init) => {
+                        Ok(mut candidate_init) => {
+                            candidate_init.candidate = preference.apply(&candidate_init.candidate);
                             peer_connection.add_ice_candidate(candidate_init).await?;
                         }
                         Err(err) => {
@@ -231,6 +496,9 @@ impl CandidateTrickle {
                 PeerSignal::Answer(_) => {
                     warn!("Got an unexpected Answer, while waiting for IceCandidate. Ignoring.")
                 }
+                PeerSignal::Custom(_) | PeerSignal::Handshake(_) => {
+                    warn!("Got an unexpected Custom signal, while waiting for IceCandidate. Ignoring.")
+                }
             }
         }
 
@@ -241,19 +509,30 @@ impl CandidateTrickle {
 async fn handshake_offer(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    mut new_peer_tx: UnboundedSender<PeerId>,
-    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    attempt: AttemptReporter,
+    from_peer_message_tx: Vec<IncomingSender>,
     config: &WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
 ) -> Result<
     (
         PeerId,
-        Vec<Arc<RTCDataChannel>>,
+        Vec<Option<Arc<RTCDataChannel>>>,
         Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
     ),
     Box<dyn std::error::Error>,
 > {
     debug!("making offer");
-    let (connection, trickle) = create_rtc_peer_connection(signal_peer.clone(), config).await?;
+    let (connection, trickle) = create_rtc_peer_connection(
+        signal_peer.clone(),
+        config,
+        server_ice_servers,
+        attempt.clone(),
+    )
+    .await?;
+    let mut dtls_timeout = Box::pin(
+        dtls_handshake_timeout(&connection, config.native.dtls_handshake_timeout_ms).fuse(),
+    );
 
     let (channel_ready_tx, mut wait_for_channels) = create_data_channels_ready_fut(config);
     let data_channels = create_data_channels(
@@ -262,6 +541,8 @@ async fn handshake_offer(
         signal_peer.id.clone(),
         from_peer_message_tx,
         &config.channels,
+        &open_channels,
+        config.strict,
     )
     .await;
 
@@ -269,7 +550,7 @@ async fn handshake_offer(
     let offer = connection.create_offer(None).await?;
     let sdp = offer.sdp.clone();
     connection.set_local_description(offer).await?;
-    signal_peer.send(PeerSignal::Offer(sdp));
+    signal_peer.send(PeerSignal::Offer(announce_max_message_size(config, sdp)));
 
     let answer = loop {
         let signal = signal_receiver
@@ -287,31 +568,45 @@ async fn handshake_offer(
             PeerSignal::IceCandidate(_) => {
                 warn!("Got an unexpected IceCandidate, while waiting for Answer. Ignoring.")
             }
+            PeerSignal::Custom(_) | PeerSignal::Handshake(_) => {
+                warn!("Got an unexpected Custom signal, while waiting for Answer. Ignoring.")
+            }
         };
     };
 
+    verify_remote_fingerprint(config, &signal_peer.id, &answer)?;
     let remote_description = RTCSessionDescription::answer(answer)?;
     connection
         .set_remote_description(remote_description)
         .await?;
 
     trickle.send_pending_candidates().await;
+    let info = connection_info(&connection, &data_channels).await;
     let mut trickle_fut = Box::pin(
-        CandidateTrickle::listen_for_remote_candidates(connection, signal_receiver).fuse(),
+        CandidateTrickle::listen_for_remote_candidates(
+            connection,
+            signal_receiver,
+            config.candidate_preference,
+        )
+        .fuse(),
     );
 
     loop {
         select! {
-            _ = wait_for_channels => {
+            ready = wait_for_channels => {
+                ready?;
                 break;
             },
+            _ = dtls_timeout => {
+                return Err("DTLS handshake timed out".into());
+            },
             // TODO: this means that the signalling is down, should return an
             // error
             _ = trickle_fut => continue,
         };
     }
 
-    new_peer_tx.send(signal_peer.id.clone()).await.unwrap();
+    attempt.connected(info);
 
     Ok((signal_peer.id, data_channels, trickle_fut))
 }
@@ -319,19 +614,30 @@ async fn handshake_offer(
 async fn handshake_accept(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    mut new_peer_tx: UnboundedSender<PeerId>,
-    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    attempt: AttemptReporter,
+    from_peer_message_tx: Vec<IncomingSender>,
     config: &WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
 ) -> Result<
     (
         PeerId,
-        Vec<Arc<RTCDataChannel>>,
+        Vec<Option<Arc<RTCDataChannel>>>,
         Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
     ),
     Box<dyn std::error::Error>,
 > {
     debug!("handshake_accept");
-    let (connection, trickle) = create_rtc_peer_connection(signal_peer.clone(), config).await?;
+    let (connection, trickle) = create_rtc_peer_connection(
+        signal_peer.clone(),
+        config,
+        server_ice_servers,
+        attempt.clone(),
+    )
+    .await?;
+    let mut dtls_timeout = Box::pin(
+        dtls_handshake_timeout(&connection, config.native.dtls_handshake_timeout_ms).fuse(),
+    );
 
     let (channel_ready_tx, mut wait_for_channels) = create_data_channels_ready_fut(config);
     let data_channels = create_data_channels(
@@ -340,6 +646,8 @@ async fn handshake_accept(
         signal_peer.id.clone(),
         from_peer_message_tx,
         &config.channels,
+        &open_channels,
+        config.strict,
     )
     .await;
 
@@ -354,58 +662,125 @@ async fn handshake_accept(
         }
     };
     debug!("received offer");
+    verify_remote_fingerprint(config, &signal_peer.id, &offer)?;
     let remote_description = RTCSessionDescription::offer(offer)?;
     connection
         .set_remote_description(remote_description)
         .await?;
 
     let answer = connection.create_answer(None).await?;
-    signal_peer.send(PeerSignal::Answer(answer.sdp.clone()));
+    signal_peer.send(PeerSignal::Answer(announce_max_message_size(
+        config,
+        answer.sdp.clone(),
+    )));
     connection.set_local_description(answer).await?;
     // Can only send candidates after sending the local description.
     trickle.send_pending_candidates().await;
+    let info = connection_info(&connection, &data_channels).await;
     let mut trickle_fut = Box::pin(
-        CandidateTrickle::listen_for_remote_candidates(Arc::clone(&connection), signal_receiver)
-            .fuse(),
+        CandidateTrickle::listen_for_remote_candidates(
+            Arc::clone(&connection),
+            signal_receiver,
+            config.candidate_preference,
+        )
+        .fuse(),
     );
 
     loop {
         select! {
-            _ = wait_for_channels => {
+            ready = wait_for_channels => {
+                ready?;
                 break;
             },
+            _ = dtls_timeout => {
+                return Err("DTLS handshake timed out".into());
+            },
             // TODO: this means that the signalling is down, should return an
             // error
             _ = trickle_fut => continue,
         };
     }
 
-    new_peer_tx.send(signal_peer.id.clone()).await.unwrap();
+    attempt.connected(info);
 
     Ok((signal_peer.id, data_channels, trickle_fut))
 }
 
+/// Parses the given DTLS certificate, or generates a new one
+pub(crate) fn local_certificate(pem: Option<&str>) -> Result<Option<LocalCertificate>, Error> {
+    let certificate = match pem {
+        Some(pem) => RTCCertificate::from_pem(pem),
+        None => KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)
+            .map_err(webrtc::Error::from)
+            .and_then(RTCCertificate::from_key_pair),
+    }
+    .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+
+    let fingerprint = certificate
+        .get_fingerprints()
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::InvalidCertificate("no fingerprint".to_string()))?;
+    Ok(Some(LocalCertificate {
+        pem: certificate.serialize_pem(),
+        // uppercase, like in the SDP
+        fingerprint: format!(
+            "{} {}",
+            fingerprint.algorithm,
+            fingerprint.value.to_uppercase()
+        ),
+    }))
+}
+
 async fn create_rtc_peer_connection(
     signal_peer: SignalPeer,
     config: &WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    attempt: AttemptReporter,
 ) -> Result<(Arc<RTCPeerConnection>, Arc<CandidateTrickle>), Box<dyn std::error::Error>> {
-    let api = APIBuilder::new().build();
+    let native = &config.native;
+    let candidate_preference = config.candidate_preference;
+    let mut setting_engine = SettingEngine::default();
+    setting_engine.set_ice_timeouts(
+        native
+            .ice_disconnected_timeout_ms
+            .map(Duration::from_millis),
+        native.ice_failed_timeout_ms.map(Duration::from_millis),
+        native.ice_keepalive_interval_ms.map(Duration::from_millis),
+    );
+    if !native.interfaces.is_empty() {
+        let interfaces = native.interfaces.clone();
+        setting_engine.set_interface_filter(Box::new(move |name| {
+            interfaces.iter().any(|interface| interface == name)
+        }));
+    }
+    let api = APIBuilder::new()
+        .with_setting_engine(setting_engine)
+        .build();
 
-    let ice_server = &config.ice_server;
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
+    let certificates = match &config.certificate_pem {
+        Some(pem) => vec![RTCCertificate::from_pem(pem)?],
+        None => vec![],
+    };
+    let ice_servers = std::iter::once(&config.ice_server)
+        .chain(&server_ice_servers)
+        .map(|ice_server| RTCIceServer {
             urls: ice_server.urls.clone(),
             username: ice_server.username.clone().unwrap_or_default(),
             credential: ice_server.credential.clone().unwrap_or_default(),
             ..Default::default()
-        }],
+        })
+        .collect();
+    let config = RTCConfiguration {
+        certificates,
+        ice_servers,
         ..Default::default()
     };
 
     let connection = api.new_peer_connection(config).await?;
     let connection = Arc::new(connection);
 
-    let trickle = Arc::new(CandidateTrickle::new(signal_peer));
+    let trickle = Arc::new(CandidateTrickle::new(signal_peer, candidate_preference));
 
     let connection2 = Arc::downgrade(&connection);
     let trickle2 = trickle.clone();
@@ -425,21 +800,93 @@ async fn create_rtc_peer_connection(
 
     connection.on_peer_connection_state_change(Box::new(move |s| {
         debug!("Peer Connection State has changed: {}", s);
+        if s == RTCPeerConnectionState::Failed {
+            attempt.failed();
+        }
         Box::pin(async {})
     }));
 
     Ok((connection, trickle))
 }
 
+/// Resolves once the connection took longer than `timeout_ms` to finish the
+/// DTLS handshake after ICE connected, never if `timeout_ms` is 0
+fn dtls_handshake_timeout(
+    connection: &RTCPeerConnection,
+    timeout_ms: u64,
+) -> impl Future<Output = ()> {
+    let (ice_connected_tx, mut ice_connected) = futures_channel::mpsc::unbounded();
+    connection.on_ice_connection_state_change(Box::new(move |s| {
+        debug!("ICE Connection State has changed: {}", s);
+        if s == RTCIceConnectionState::Connected {
+            let _ = ice_connected_tx.unbounded_send(());
+        }
+        Box::pin(async {})
+    }));
+    async move {
+        if timeout_ms == 0 || ice_connected.next().await.is_none() {
+            return futures::future::pending().await;
+        }
+        Delay::new(Duration::from_millis(timeout_ms)).await;
+    }
+}
+
+/// Announces [`NativeSocketConfig::sctp_max_message_size`] in a local
+/// session description
+///
+/// [`NativeSocketConfig::sctp_max_message_size`]: crate::NativeSocketConfig::sctp_max_message_size
+fn announce_max_message_size(config: &WebRtcSocketConfig, sdp: String) -> String {
+    match config.native.sctp_max_message_size {
+        Some(size) => sdp_with_max_message_size(&sdp, size.clamp(1, MAX_RECEIVE_MESSAGE_SIZE)),
+        None => sdp,
+    }
+}
+
+/// Reads back the parameters the connection ended up with
+async fn connection_info(
+    connection: &RTCPeerConnection,
+    data_channels: &[Option<Arc<RTCDataChannel>>],
+) -> ConnectionInfo {
+    let max_message_size = match connection.remote_description().await {
+        Some(description) => sdp_max_message_size(&description.sdp),
+        None => None,
+    };
+    let channels = data_channels
+        .iter()
+        .map(|channel| {
+            channel.as_ref().map(|channel| ChannelInfo {
+                ordered: channel.ordered(),
+                // webrtc-rs makes channels without any retransmit or lifetime
+                // limits reliable, including ones asking for zero retransmits
+                max_retransmits: (channel.max_retransmits() != 0
+                    || channel.max_packet_lifetime() != 0)
+                    .then(|| channel.max_retransmits()),
+            })
+        })
+        .collect();
+    ConnectionInfo {
+        max_message_size,
+        channels,
+    }
+}
+
 async fn create_data_channels(
     connection: &RTCPeerConnection,
     mut channel_ready: Vec<futures_channel::mpsc::Sender<u8>>,
     peer_id: PeerId,
-    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    from_peer_message_tx: Vec<IncomingSender>,
     channel_configs: &[ChannelConfig],
-) -> Vec<Arc<RTCDataChannel>> {
+    open_channels: &[bool],
+    strict: bool,
+) -> Vec<Option<Arc<RTCDataChannel>>> {
     let mut channels = vec![];
     for (i, channel_config) in channel_configs.iter().enumerate() {
+        if !open_channels[i] {
+            // nothing to wait for
+            channel_ready.pop().unwrap().try_send(1).unwrap();
+            channels.push(None);
+            continue;
+        }
         let channel = create_data_channel(
             connection,
             channel_ready.pop().unwrap(),
@@ -447,10 +894,11 @@ async fn create_data_channels(
             from_peer_message_tx.get(i).unwrap().clone(),
             channel_config,
             i,
+            strict,
         )
         .await;
 
-        channels.push(channel);
+        channels.push(Some(channel));
     }
 
     channels
@@ -460,9 +908,10 @@ async fn create_data_channel(
     connection: &RTCPeerConnection,
     mut channel_ready: futures_channel::mpsc::Sender<u8>,
     peer_id: PeerId,
-    from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
+    from_peer_message_tx: IncomingSender,
     channel_config: &ChannelConfig,
     channel_index: usize,
+    strict: bool,
 ) -> Arc<RTCDataChannel> {
     let config = RTCDataChannelInit {
         ordered: Some(channel_config.ordered),
@@ -479,11 +928,22 @@ async fn create_data_channel(
     channel.on_open(Box::new(move || {
         debug!("Data channel ready");
         Box::pin(async move {
-            channel_ready.try_send(1).unwrap();
+            if let Err(e) = channel_ready.try_send(1) {
+                panic_if_strict(
+                    strict,
+                    format_args!("failed to notify about open channel: {e}"),
+                );
+            }
         })
     }));
 
-    setup_data_channel(&channel, peer_id, from_peer_message_tx).await;
+    setup_data_channel(
+        &channel,
+        peer_id,
+        from_peer_message_tx,
+        channel_config.coalesce,
+    )
+    .await;
 
     channel
 }
@@ -491,7 +951,8 @@ async fn create_data_channel(
 async fn setup_data_channel(
     data_channel: &RTCDataChannel,
     peer_id: PeerId,
-    from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
+    from_peer_message_tx: IncomingSender,
+    coalesce: bool,
 ) {
     data_channel.on_close(Box::new(move || {
         // TODO: handle this somehow
@@ -506,11 +967,7 @@ async fn setup_data_channel(
     }));
 
     data_channel.on_message(Box::new(move |message| {
-        let packet = (*message.data).into();
-        debug!("rx {:?}", packet);
-        from_peer_message_tx
-            .unbounded_send((peer_id.clone(), packet))
-            .unwrap();
+        from_peer_message_tx.send(&peer_id, &message.data, coalesce);
         Box::pin(async move {})
     }));
 }
@@ -520,15 +977,26 @@ async fn peer_loop(
         Output = Result<
             (
                 PeerId,
-                Vec<Arc<RTCDataChannel>>,
+                Vec<Option<Arc<RTCDataChannel>>>,
                 Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
             ),
             Box<dyn std::error::Error>,
         >,
     >,
-    mut to_peer_message_rx: Vec<UnboundedReceiver<Packet>>,
+    mut to_peer_message_rx: Vec<UnboundedReceiver<PooledPacket>>,
+    channels: Vec<IncomingSender>,
+    attempt: AttemptReporter,
+    config: &WebRtcSocketConfig,
+    congestion: Arc<PeerCongestion>,
 ) {
-    let (_peer_id, data_channels, mut trickle_fut) = handshake_fut.await.unwrap();
+    let (_peer_id, data_channels, mut trickle_fut) = match handshake_fut.await {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            warn!("handshake with {:?} failed: {err}", attempt.peer());
+            attempt.failed();
+            return;
+        }
+    };
 
     assert_eq!(
         data_channels.len(),
@@ -536,27 +1004,93 @@ async fn peer_loop(
         "amount of data channels and receivers differ"
     );
 
+    let attempt = &attempt;
     let mut message_loop_futs: FuturesUnordered<_> = data_channels
         .iter()
         .zip(to_peer_message_rx.iter_mut())
-        .map(|(data_channel, rx)| async move {
-            while let Some(message) = rx.next().await {
-                trace!("sending packet {:?}", message);
-                let message = message.clone();
-                let message = Bytes::from(message);
-                data_channel.send(&message).await.unwrap();
-            }
-        })
+        .zip(&channels)
+        .zip(&config.channels)
+        .map(
+            |(((data_channel, rx), channel), channel_config)| async move {
+                let data_channel = match data_channel {
+                    Some(data_channel) => data_channel,
+                    None => {
+                        // raced with the socket learning that the channel isn't open
+                        while let Some(packet) = rx.next().await {
+                            channel.dropped(attempt.peer(), packet);
+                        }
+                        return true;
+                    }
+                };
+                while let Some(packet) = rx.next().await {
+                    trace!("sending packet {:?}", packet);
+                    let sent = match std::str::from_utf8(&packet) {
+                        Ok(text) if channel_config.text => {
+                            data_channel.send_text(text.to_string()).await
+                        }
+                        _ => data_channel.send(&Bytes::copy_from_slice(&packet)).await,
+                    };
+                    if let Err(err) = sent {
+                        warn!("failed to send to {:?}: {err}", attempt.peer());
+                        channel.send_failed(attempt.peer(), packet);
+                        attempt.failed();
+                        return false;
+                    }
+                }
+                true
+            },
+        )
         .collect();
 
+    let mut sample_congestion =
+        Box::pin(sample_congestion(&data_channels, attempt.peer(), &congestion).fuse());
     loop {
         select! {
-            _ = message_loop_futs.next() => break,
+            // the queues are closed together, wait until all of them are
+            // empty, so none of them is cut short
+            closed = message_loop_futs.next() => match closed {
+                Some(true) => continue,
+                Some(false) => return,
+                None => break,
+            },
+            _ = sample_congestion => {},
             // TODO: this means that the signalling is down, should return an
             // error
             _ = trickle_fut => continue,
         }
     }
 
+    if config.flush_timeout_ms > 0 {
+        // sent packets may still be buffered by the data channels
+        let drained = async {
+            for data_channel in data_channels.iter().flatten() {
+                while data_channel.buffered_amount().await > 0 {
+                    Delay::new(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        let timeout = Delay::new(Duration::from_millis(config.flush_timeout_ms));
+        select! {
+            _ = drained.fuse() => {},
+            _ = timeout.fuse() => warn!("gave up flushing data channels to {:?}", attempt.peer()),
+        }
+    }
+
     // TODO: clear on_message?
 }
+
+/// Keeps sampling how much data the data channels to `peer` have buffered
+async fn sample_congestion(
+    data_channels: &[Option<Arc<RTCDataChannel>>],
+    peer: &PeerId,
+    congestion: &PeerCongestion,
+) {
+    loop {
+        Delay::new(Duration::from_millis(CONGESTION_SAMPLE_INTERVAL_MS)).await;
+        let mut buffered_bytes = 0;
+        for data_channel in data_channels.iter().flatten() {
+            buffered_bytes += data_channel.buffered_amount().await;
+        }
+        congestion.sample(peer, buffered_bytes);
+    }
+}
@@ -1,19 +1,66 @@
-use async_tungstenite::{async_std::connect_async, tungstenite::Message};
+use async_tungstenite::{
+    async_std::{connect_async, ConnectStream},
+    tungstenite::Message,
+    WebSocketStream,
+};
 use futures::{pin_mut, FutureExt, SinkExt, StreamExt};
 use futures_util::select;
 use log::{debug, warn};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use crate::webrtc_socket::messages::{PeerEvent, PeerRequest};
+use crate::webrtc_socket::messages::{PeerEvent, PeerRequest, RoomClosedBy, RoomUpdate};
+use crate::webrtc_socket::{parse_event, room_url_on_same_server, with_timeout};
+use crate::{Error, SignallingError};
 
+pub type SignallingConnection = WebSocketStream<ConnectStream>;
+
+/// Whether we run on a page served over https, never on native
+pub fn page_is_secure() -> bool {
+    false
+}
+
+/// Opens the websocket to the signalling server
+pub async fn signalling_connect(
+    room_url: &str,
+    timeout_ms: u64,
+) -> Result<SignallingConnection, Error> {
+    let (wsio, _response) = with_timeout(
+        connect_async(room_url),
+        timeout_ms,
+        Error::SignallingTimeout,
+    )
+    .await?
+    .map_err(|e| Error::SignallingConnection(e.to_string()))?;
+    Ok(wsio)
+}
+
+/// Forwards requests and events until the socket is dropped, the room is
+/// closed or the connection is lost
+///
+/// Resolves with who closed the room, if it was closed.
+/// Sends the `registration` requests first, and adds new ones to it, so they
+/// can be repeated after reconnecting. Likewise, `room_url` is pointed at the
+/// new room when ours is migrated.
 pub async fn signalling_loop(
-    room_url: String,
-    mut requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    mut wsio: SignallingConnection,
+    registration: &mut Vec<PeerRequest>,
+    room_url: &mut String,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
     events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
-) {
+    room_tx: futures_channel::mpsc::UnboundedSender<RoomUpdate>,
+    paused: &AtomicBool,
+) -> Result<Option<RoomClosedBy>, Error> {
     debug!("Signalling loop started");
-    let (mut wsio, _response) = connect_async(&room_url)
-        .await
-        .expect("failed to connect to signalling server");
+    for request in registration.iter() {
+        let request = serde_json::to_string(request).expect("serializing request");
+        debug!("-> {}", request);
+        wsio.send(Message::Text(request))
+            .await
+            .map_err(|e| Error::SignallingConnection(e.to_string()))?;
+    }
 
     loop {
         let next_request = requests_receiver.next().fuse();
@@ -23,31 +70,121 @@ pub async fn signalling_loop(
 
         select! {
             request = next_request => {
+                let request = match request {
+                    Some(request) => request,
+                    // the socket was dropped
+                    None => break,
+                };
+                if request == PeerRequest::KeepAlive && paused.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if request.is_registration() {
+                    registration.push(request.clone());
+                }
+                if let PeerRequest::LeaveExtraRoom(room) = &request {
+                    // reconnects don't have to join it again
+                    registration.retain(|r| !matches!(r, PeerRequest::JoinExtraRoom(joined) if joined == room));
+                    let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomLeft(Some(room.clone())));
+                }
                 let request = serde_json::to_string(&request).expect("serializing request");
                 debug!("-> {}", request);
-                wsio.send(Message::Text(request)).await.expect("request send error");
+                wsio.send(Message::Text(request)).await.map_err(|e| Error::SignallingConnection(e.to_string()))?;
             }
 
             message = next_websocket_message => {
                 match message {
                     Some(Ok(Message::Text(message))) => {
                         debug!("{}", message);
-                        let event: PeerEvent = serde_json::from_str(&message)
-                            .unwrap_or_else(|err| panic!("couldn't parse peer event: {}.\nEvent: {}", err, message));
-                        events_sender.unbounded_send(event).unwrap();
+                        let Some(event) = parse_event(&message)? else {
+                            continue;
+                        };
+                        match event {
+                            PeerEvent::Error(code) => return Err(SignallingError::from(code).into()),
+                            PeerEvent::RoomClosed { host } => return Ok(Some(host.into())),
+                            PeerEvent::PeerName { peer, name } => {
+                                // the socket may have been dropped, that's fine
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerName { peer: peer.clone(), name: name.clone() });
+                                // the message loop asks whether to connect to the peer by it
+                                events_sender.unbounded_send(PeerEvent::PeerName { peer, name }).unwrap();
+                            }
+                            PeerEvent::PeerRole { peer, role } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerRole { peer: peer.clone(), role });
+                                events_sender.unbounded_send(PeerEvent::PeerRole { peer, role }).unwrap();
+                            }
+                            PeerEvent::PeerCapabilities { peer, capabilities } => {
+                                // the message loop picks the channels to open with the peer by them
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerCapabilities { peer: peer.clone(), capabilities: capabilities.clone() });
+                                events_sender.unbounded_send(PeerEvent::PeerCapabilities { peer, capabilities }).unwrap();
+                            }
+                            PeerEvent::RoomPolicy(info) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Info(Some(info)));
+                            }
+                            PeerEvent::RoomPeers(peers) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Peers(peers));
+                            }
+                            PeerEvent::RoomPeersPage(peers) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeersPage(peers));
+                            }
+                            PeerEvent::PeerListComplete => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerListComplete);
+                            }
+                            PeerEvent::RoomMessage { sender, data } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Message { sender, data });
+                            }
+                            PeerEvent::RoomMetadata(metadata) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Metadata(Some(metadata)));
+                            }
+                            PeerEvent::MatchmakingRegions(regions) => {
+                                // the queue loop measures our latency to them
+                                let _ = room_tx.unbounded_send(RoomUpdate::MatchmakingRegions(regions.clone()));
+                                events_sender.unbounded_send(PeerEvent::MatchmakingRegions(regions)).unwrap();
+                            }
+                            PeerEvent::RoomMigrated { room, next } => {
+                                // reconnects have to register in the new room
+                                *room_url = room_url_on_same_server(room_url, &room, next);
+                                let _ = room_tx.unbounded_send(RoomUpdate::SignallingUrl(room_url.clone()));
+                                // the new room may not have any rules or metadata
+                                let _ = room_tx.unbounded_send(RoomUpdate::Info(None));
+                                let _ = room_tx.unbounded_send(RoomUpdate::Metadata(None));
+                                let _ = room_tx.unbounded_send(RoomUpdate::Group { next, queued: false });
+                                let _ = room_tx.unbounded_send(RoomUpdate::IntroductionsPending(0));
+                                events_sender.unbounded_send(PeerEvent::RoomMigrated { room, next }).unwrap();
+                            }
+                            PeerEvent::IntroductionsPending(pending) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::IntroductionsPending(pending));
+                            }
+                            PeerEvent::ServerQueuePosition(position) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ServerQueuePosition(position));
+                            }
+                            PeerEvent::ExtraRoomPeers { room, peers } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomPeers { room, peers });
+                            }
+                            PeerEvent::ExtraRoomMessage { room, sender, data } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomMessage { room, sender, data });
+                            }
+                            PeerEvent::RoomIdle { closes_in_secs } => {
+                                let closes_in = Duration::from_secs(closes_in_secs);
+                                let _ = room_tx.unbounded_send(RoomUpdate::IdleWarning(closes_in));
+                            }
+                            PeerEvent::MigrationRejected(code) => {
+                                warn!("the room wasn't migrated: {}", SignallingError::from(code));
+                            }
+                            PeerEvent::SignalsThrottled { receiver } => {
+                                warn!("the signalling server is dropping our signals to {:?}", receiver);
+                            }
+                            event => events_sender.unbounded_send(event).unwrap(),
+                        }
                     },
                     Some(Ok(message)) => {
                         warn!("ignoring unexpected non-text message from signalling server: {:?}", message)
                     },
-                    Some(Err(e)) => {
-                        // TODO: propagate errors or recover
-                        panic!("WebSocket error {:?}", e)
-                    },
-                    None => {} // Disconnected from signalling server
+                    Some(Err(e)) => return Err(Error::SignallingConnection(e.to_string())),
+                    None => return Err(Error::SignallingConnection("closed by the server".to_string())),
                 };
             }
 
             complete => break
         }
     }
+    Ok(None)
 }
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use futures_util::select;
+use log::debug;
+
+use crate::{
+    webrtc_socket::{
+        messages::{PeerEvent, PeerRequest},
+        KEEP_ALIVE_INTERVAL,
+    },
+    Error,
+};
+
+/// Replaces the message loop when a socket is only watching a room, see
+/// [`WebRtcSocketConfig::signalling_only`](crate::WebRtcSocketConfig::signalling_only)
+///
+/// The peers of the room are forwarded to the socket by the signalling loop,
+/// so this just keeps the connection to the signalling server alive.
+pub(crate) async fn observer_loop(
+    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
+    mut leave_rx: futures_channel::oneshot::Receiver<()>,
+) -> Result<(), Error> {
+    debug!("Observing room");
+    requests_sender
+        .unbounded_send(PeerRequest::Observe)
+        .expect("failed to send observe request");
+
+    let timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+    futures::pin_mut!(timeout);
+
+    loop {
+        select! {
+            _ = (&mut timeout).fuse() => {
+                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
+                timeout.reset(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+            }
+
+            _ = leave_rx => {
+                debug!("Leaving room");
+                break;
+            }
+
+            event = events_receiver.next() => match event {
+                Some(event) => debug!("ignoring {event:?} while observing"),
+                // Disconnected from signalling server
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
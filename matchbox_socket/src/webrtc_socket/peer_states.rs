@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::webrtc_socket::{messages::PeerId, PeerState};
+
+/// The states of all peers the socket heard of, kept sorted into connected
+/// and disconnected peers as they change, so asking about them doesn't scan
+/// the whole room
+///
+/// Every change bumps a generation, so consumers can ask only for what
+/// changed since they last looked, see
+/// [`WebRtcReceiver::peers_changed_since`](crate::WebRtcReceiver::peers_changed_since).
+#[derive(Debug, Default)]
+pub(crate) struct PeerStates {
+    states: HashMap<PeerId, PeerState>,
+    /// Peers that connected and didn't disconnect since, including
+    /// reconnecting ones, in the order they connected
+    connected: Vec<PeerId>,
+    connected_set: HashSet<PeerId>,
+    disconnected: HashSet<PeerId>,
+    generation: u64,
+    /// The generation of the last change of each peer
+    changed_at: HashMap<PeerId, u64>,
+    /// The peers by the generation of their last change
+    changes: BTreeMap<u64, PeerId>,
+}
+
+impl PeerStates {
+    pub fn get(&self, id: &PeerId) -> Option<PeerState> {
+        self.states.get(id).copied()
+    }
+
+    /// Records the new state of a peer, returns whether it's newly connected
+    pub fn update(&mut self, id: PeerId, state: PeerState) -> bool {
+        if self.states.get(&id) == Some(&state) {
+            return false;
+        }
+        let newly_connected = match state {
+            PeerState::Connected => self.connected_set.insert(id.clone()),
+            PeerState::Connecting | PeerState::Reconnecting => false,
+            PeerState::Disconnected => {
+                if self.connected_set.remove(&id) {
+                    self.connected.retain(|peer| peer != &id);
+                }
+                false
+            }
+        };
+        if newly_connected {
+            self.connected.push(id.clone());
+        }
+        if state == PeerState::Disconnected {
+            self.disconnected.insert(id.clone());
+        } else {
+            self.disconnected.remove(&id);
+        }
+
+        self.generation += 1;
+        if let Some(previous) = self.changed_at.insert(id.clone(), self.generation) {
+            self.changes.remove(&previous);
+        }
+        self.changes.insert(self.generation, id.clone());
+        self.states.insert(id, state);
+        newly_connected
+    }
+
+    pub fn connected(&self) -> &[PeerId] {
+        &self.connected
+    }
+
+    pub fn is_connected(&self, id: &PeerId) -> bool {
+        self.connected_set.contains(id)
+    }
+
+    pub fn disconnected(&self) -> impl Iterator<Item = &PeerId> {
+        self.disconnected.iter()
+    }
+
+    /// Peers that didn't disconnect, connected or not
+    pub fn present(&self) -> impl Iterator<Item = &PeerId> {
+        self.states
+            .keys()
+            .filter(move |peer| !self.disconnected.contains(*peer))
+    }
+
+    pub fn states(&self) -> impl Iterator<Item = &PeerState> {
+        self.states.values()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The peers that changed after the given generation, with their current
+    /// states, in the order of their last change
+    pub fn changed_since(&self, generation: u64) -> Vec<(PeerId, PeerState)> {
+        self.changes
+            .range(generation.saturating_add(1)..)
+            .map(|(_, peer)| (peer.clone(), self.states[peer]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerStates;
+    use crate::webrtc_socket::PeerState;
+
+    #[test]
+    fn only_changes_since_a_generation_are_returned() {
+        let mut peers = PeerStates::default();
+        let (a, b) = ("a".to_string(), "b".to_string());
+        assert!(!peers.update(a.clone(), PeerState::Connecting));
+        assert!(peers.update(a.clone(), PeerState::Connected));
+        assert!(peers.update(b.clone(), PeerState::Connected));
+        let seen = peers.generation();
+        assert_eq!(peers.connected(), [a.clone(), b.clone()]);
+        assert!(peers.changed_since(seen).is_empty());
+
+        // repeated states aren't changes
+        assert!(!peers.update(b.clone(), PeerState::Connected));
+        assert_eq!(peers.generation(), seen);
+
+        assert!(!peers.update(a.clone(), PeerState::Reconnecting));
+        assert!(!peers.update(b.clone(), PeerState::Disconnected));
+        assert!(!peers.update(a.clone(), PeerState::Connected));
+        assert_eq!(
+            peers.changed_since(seen),
+            vec![
+                (b.clone(), PeerState::Disconnected),
+                (a.clone(), PeerState::Connected)
+            ]
+        );
+        assert_eq!(peers.connected(), ["a".to_string()]);
+        assert!(peers.is_connected(&a) && !peers.is_connected(&b));
+        assert_eq!(peers.disconnected().collect::<Vec<_>>(), [&b]);
+        assert_eq!(peers.present().collect::<Vec<_>>(), [&a]);
+        assert_eq!(peers.changed_since(0).len(), 2);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use log::debug;
+
+use crate::webrtc_socket::messenger::{
+    ConnectFuture, IncomingPackets, MaybeSendSync, Messenger, MessengerConnection, MessengerError,
+    MessengerPeer,
+};
+
+/// A packet received through a [`PlatformRelay`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayPacket {
+    /// The relay address of the peer that sent it
+    pub from: String,
+    /// The channel it was sent on, by its index in
+    /// [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    pub channel: usize,
+    /// The packet itself
+    pub data: Box<[u8]>,
+}
+
+/// Sends packets through a platform's relay network, e.g. Steam Datagram
+/// Relay through `ISteamNetworkingMessages`, or a console's peer-to-peer
+/// service
+///
+/// Wrap it in a [`RelayMessenger`] to use it with
+/// [`WebRtcSocket::new_with_messenger`](crate::WebRtcSocket::new_with_messenger).
+/// Peers still find each other through the signalling server, which also
+/// carries their relay addresses, e.g. Steam ids, so the platform's own
+/// lobbies aren't needed.
+///
+/// Methods are called from the message loop and mustn't block.
+pub trait PlatformRelay: MaybeSendSync {
+    /// Our own address on the relay, which is handed to the peers
+    fn local_address(&self) -> String;
+
+    /// Sends a packet to the peer with the given relay address
+    ///
+    /// How it's sent, e.g. reliably or not, is up to the relay, typically
+    /// depending on the channel. An error reconnects to the peer.
+    fn send_to(&self, address: &str, channel: usize, packet: &[u8]) -> Result<(), MessengerError>;
+
+    /// Takes the packets received since the last call
+    fn receive(&self) -> Vec<RelayPacket>;
+
+    /// Called once we know the address of a peer, before any packets are
+    /// sent to it, e.g. to accept its session requests
+    fn open(&self, _address: &str) {}
+
+    /// Called when the connection to a peer is dropped, e.g. to close its
+    /// session
+    fn close(&self, _address: &str) {}
+}
+
+/// The peers we're connected to, by their relay address
+type Routes = Arc<Mutex<HashMap<String, Arc<IncomingPackets>>>>;
+
+/// A [`Messenger`] that connects to peers through a [`PlatformRelay`]
+///
+/// Received packets are only handed to the socket by [`RelayMessenger::pump`],
+/// so keep a clone around and call it regularly, e.g. every frame right
+/// after running the platform's callbacks.
+pub struct RelayMessenger<R> {
+    relay: Arc<R>,
+    routes: Routes,
+}
+
+impl<R> Clone for RelayMessenger<R> {
+    fn clone(&self) -> Self {
+        Self {
+            relay: self.relay.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+impl<R: PlatformRelay> RelayMessenger<R> {
+    /// Creates a messenger that sends through the given relay
+    pub fn new(relay: R) -> Self {
+        Self {
+            relay: Arc::new(relay),
+            routes: Default::default(),
+        }
+    }
+
+    /// The relay packets are sent through
+    pub fn relay(&self) -> &R {
+        &self.relay
+    }
+
+    /// Hands the packets the relay received to the socket
+    ///
+    /// Packets from addresses we aren't connected to are dropped.
+    pub fn pump(&self) {
+        let packets = self.relay.receive();
+        let routes = lock(&self.routes);
+        for packet in packets {
+            match routes.get(&packet.from) {
+                Some(incoming) => incoming.deliver(packet.channel, &packet.data),
+                None => debug!("dropping packet from unknown address {:?}", packet.from),
+            }
+        }
+    }
+}
+
+impl<R: PlatformRelay + 'static> Messenger for RelayMessenger<R> {
+    fn connect(&self, mut peer: MessengerPeer) -> ConnectFuture {
+        let (relay, routes) = (self.relay.clone(), self.routes.clone());
+        Box::pin(async move {
+            // the initiator tells its address first, the other side answers
+            // with its own
+            if peer.initiator {
+                peer.signaller.send(relay.local_address());
+            }
+            let address = peer
+                .signaller
+                .receive()
+                .await
+                .ok_or("gave up waiting for the peer's relay address")?;
+            if !peer.initiator {
+                peer.signaller.send(relay.local_address());
+            }
+
+            debug!("reaching {:?} through relay address {address:?}", peer.id);
+            relay.open(&address);
+            let incoming = Arc::new(peer.incoming);
+            lock(&routes).insert(address.clone(), incoming.clone());
+            let connection: Box<dyn MessengerConnection> = Box::new(RelayConnection {
+                relay,
+                routes,
+                address,
+                incoming,
+            });
+            Ok(connection)
+        })
+    }
+}
+
+struct RelayConnection<R: PlatformRelay> {
+    relay: Arc<R>,
+    routes: Routes,
+    address: String,
+    /// Our route to the socket, so a reconnect's route isn't removed
+    incoming: Arc<IncomingPackets>,
+}
+
+impl<R: PlatformRelay> MessengerConnection for RelayConnection<R> {
+    fn send(&mut self, channel: usize, packet: &[u8]) -> Result<(), MessengerError> {
+        self.relay.send_to(&self.address, channel, packet)
+    }
+}
+
+impl<R: PlatformRelay> Drop for RelayConnection<R> {
+    fn drop(&mut self) {
+        let mut routes = lock(&self.routes);
+        // unless a newer connection to the same peer replaced it
+        if let Some(incoming) = routes.get(&self.address) {
+            if Arc::ptr_eq(incoming, &self.incoming) {
+                routes.remove(&self.address);
+                drop(routes);
+                self.relay.close(&self.address);
+            }
+        }
+    }
+}
+
+fn lock(routes: &Routes) -> MutexGuard<'_, HashMap<String, Arc<IncomingPackets>>> {
+    routes.lock().expect("relay routes lock poisoned")
+}
@@ -0,0 +1,357 @@
+use std::{
+    fmt,
+    io::IoSlice,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use futures_channel::mpsc::UnboundedSender;
+use log::{debug, trace};
+
+use crate::webrtc_socket::{
+    channel_subset::UnopenedPeers,
+    coalesce::split_batch,
+    exchange,
+    liveness::{LastReceived, KEEP_ALIVE},
+    messages::PeerId,
+    recording::now_ms,
+    ChannelCounters, Exchanges, PacketHook,
+};
+
+/// Reusable buffers for packets, see [`WebRtcSocketConfig::packet_pool_size`](crate::WebRtcSocketConfig::packet_pool_size)
+///
+/// Cheap to clone, all clones share the same buffers.
+#[derive(Clone)]
+pub struct PacketPool(Arc<PoolInner>);
+
+struct PoolInner {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl PacketPool {
+    /// Creates a pool keeping at most `max_buffers` unused buffers around
+    ///
+    /// With `0`, packets aren't pooled at all.
+    pub fn new(max_buffers: usize) -> Self {
+        Self(Arc::new(PoolInner {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }))
+    }
+
+    /// Returns a packet with a copy of the given data, reusing a buffer from
+    /// the pool if there is one
+    pub fn packet_from(&self, data: &[u8]) -> PooledPacket {
+        self.packet_from_slices(&[IoSlice::new(data)])
+    }
+
+    /// Returns a packet with the given slices copied into it back to back,
+    /// e.g. a header and a payload
+    pub fn packet_from_slices(&self, parts: &[IoSlice<'_>]) -> PooledPacket {
+        let len = parts.iter().map(|part| part.len()).sum();
+        let (mut buffer, pool) = if self.0.max_buffers == 0 {
+            (Vec::with_capacity(len), None)
+        } else {
+            (self.take_buffer(), Some(self.clone()))
+        };
+        buffer.reserve(len);
+        for part in parts {
+            buffer.extend_from_slice(part);
+        }
+        PooledPacket { data: buffer, pool }
+    }
+
+    /// Returns the number of unused buffers in the pool
+    pub fn available(&self) -> usize {
+        self.buffers().len()
+    }
+
+    fn take_buffer(&self) -> Vec<u8> {
+        self.buffers().pop().unwrap_or_default()
+    }
+
+    fn put_buffer(&self, mut buffer: Vec<u8>) {
+        let mut buffers = self.buffers();
+        if buffers.len() < self.0.max_buffers {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    fn buffers(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.0.buffers.lock().expect("packet pool lock poisoned")
+    }
+}
+
+impl fmt::Debug for PacketPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketPool")
+            .field("available", &self.available())
+            .field("max_buffers", &self.0.max_buffers)
+            .finish()
+    }
+}
+
+/// A packet that returns its buffer to a [`PacketPool`] when dropped
+///
+/// Dereferences to the packet's bytes. Packets that aren't from a pool, e.g.
+/// created with [`From`], are simply freed.
+pub struct PooledPacket {
+    data: Vec<u8>,
+    pool: Option<PacketPool>,
+}
+
+impl PooledPacket {
+    /// Takes the bytes out of the packet, its buffer doesn't go back to the
+    /// pool
+    pub fn into_boxed_slice(mut self) -> Box<[u8]> {
+        std::mem::take(&mut self.data).into_boxed_slice()
+    }
+}
+
+impl Drop for PooledPacket {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            if self.data.capacity() > 0 {
+                pool.put_buffer(std::mem::take(&mut self.data));
+            }
+        }
+    }
+}
+
+impl Deref for PooledPacket {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledPacket {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl From<Vec<u8>> for PooledPacket {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, pool: None }
+    }
+}
+
+impl From<Box<[u8]>> for PooledPacket {
+    fn from(data: Box<[u8]>) -> Self {
+        Self::from(Vec::from(data))
+    }
+}
+
+impl fmt::Debug for PooledPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.data.fmt(f)
+    }
+}
+
+impl PartialEq for PooledPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl Eq for PooledPacket {}
+
+/// Hands the messages received on a data channel to the socket, copied into
+/// buffers from its pool
+///
+/// Also carries the channel's counters, and the peers it isn't opened with,
+/// so the message loop can update them.
+#[derive(Debug, Clone)]
+pub(crate) struct IncomingSender {
+    tx: UnboundedSender<(PeerId, PooledPacket)>,
+    pool: PacketPool,
+    stats: Arc<ChannelCounters>,
+    index: usize,
+    coalesce: bool,
+    dead_letters: Option<UnboundedSender<(PeerId, usize, PooledPacket)>>,
+    unopened: Arc<UnopenedPeers>,
+    exchanges: Option<Arc<Exchanges>>,
+    on_incoming: Option<PacketHook>,
+    last_received: Option<Arc<LastReceived>>,
+}
+
+impl IncomingSender {
+    pub fn new(
+        tx: UnboundedSender<(PeerId, PooledPacket)>,
+        pool: PacketPool,
+        stats: Arc<ChannelCounters>,
+        index: usize,
+        coalesce: bool,
+    ) -> Self {
+        Self {
+            tx,
+            pool,
+            stats,
+            index,
+            coalesce,
+            dead_letters: None,
+            unopened: Arc::default(),
+            exchanges: None,
+            on_incoming: None,
+            last_received: None,
+        }
+    }
+
+    /// Matches up requests and responses on the channel, see
+    /// [`ChannelConfig::requests`](crate::ChannelConfig::requests)
+    pub fn with_exchanges(mut self, exchanges: Option<Arc<Exchanges>>) -> Self {
+        self.exchanges = exchanges;
+        self
+    }
+
+    /// Runs received packets through a hook, see
+    /// [`WebRtcSocketConfig::on_incoming`](crate::WebRtcSocketConfig::on_incoming)
+    pub fn with_incoming_hook(mut self, on_incoming: Option<PacketHook>) -> Self {
+        self.on_incoming = on_incoming;
+        self
+    }
+
+    /// Keeps track of when peers were last heard from, and drops keep-alive
+    /// messages, see
+    /// [`ChannelConfig::silence_timeout_ms`](crate::ChannelConfig::silence_timeout_ms)
+    pub fn with_liveness(mut self, enabled: bool) -> Self {
+        self.last_received = enabled.then(Arc::default);
+        self
+    }
+
+    /// When `peer` was last heard from on the channel, in milliseconds since
+    /// the epoch, if the channel keeps track of it
+    pub fn last_received(&self, peer: &PeerId) -> Option<u64> {
+        self.last_received.as_ref()?.get(peer)
+    }
+
+    /// Forgets about a peer that disconnected, failing the requests waiting
+    /// for its responses
+    pub fn disconnected(&self, peer: &PeerId) {
+        self.set_open(peer, true);
+        if let Some(exchanges) = &self.exchanges {
+            exchanges.disconnected(peer);
+        }
+        if let Some(last_received) = &self.last_received {
+            last_received.remove(peer);
+        }
+    }
+
+    /// Shares the peers the channel isn't opened with with the socket, so it
+    /// can refuse to send to them
+    pub fn with_unopened_peers(mut self, unopened: Arc<UnopenedPeers>) -> Self {
+        self.unopened = unopened;
+        self
+    }
+
+    /// Records whether the channel is opened with `peer`, see
+    /// [`ChannelConfig::required_capability`](crate::ChannelConfig::required_capability)
+    pub fn set_open(&self, peer: &PeerId, open: bool) {
+        self.unopened.set_open(peer, open);
+    }
+
+    /// Whether the channel is opened with `peer`, see
+    /// [`IncomingSender::set_open`]
+    pub fn is_open(&self, peer: &PeerId) -> bool {
+        !self.unopened.contains(peer)
+    }
+
+    /// Hands undeliverable packets to the socket, see
+    /// [`WebRtcSocketConfig::dead_letters`](crate::WebRtcSocketConfig::dead_letters)
+    pub fn with_dead_letters(
+        mut self,
+        dead_letters: Option<UnboundedSender<(PeerId, usize, PooledPacket)>>,
+    ) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
+    /// The counters of the channel, shared with its senders
+    pub fn stats(&self) -> Arc<ChannelCounters> {
+        self.stats.clone()
+    }
+
+    /// Counts an outgoing packet for a peer we're not connected to, and hands
+    /// it back to the socket if it keeps dead letters
+    pub fn dropped(&self, peer: &PeerId, packet: PooledPacket) {
+        self.stats.record_dropped();
+        self.dead_letter(peer, packet);
+    }
+
+    /// Counts an outgoing packet the data channel failed to send, and hands
+    /// it back to the socket if it keeps dead letters
+    pub fn send_failed(&self, peer: &PeerId, packet: PooledPacket) {
+        self.stats.record_send_failure();
+        self.dead_letter(peer, packet);
+    }
+
+    fn dead_letter(&self, peer: &PeerId, packet: PooledPacket) {
+        let dead_letters = match &self.dead_letters {
+            Some(dead_letters) => dead_letters,
+            None => return,
+        };
+        // a batch is handed back as the packets the application sent
+        let packets = if self.coalesce {
+            split_batch(&packet)
+                .into_iter()
+                .map(|packet| self.pool.packet_from(packet))
+                .collect()
+        } else {
+            vec![packet]
+        };
+        for packet in packets {
+            let packet = match &self.exchanges {
+                Some(_) => self.pool.packet_from(exchange::payload(&packet)),
+                None => packet,
+            };
+            // the socket may have been dropped, that's fine
+            let _ = dead_letters.unbounded_send((peer.clone(), self.index, packet));
+        }
+    }
+
+    /// Forwards a message from a peer, splitting it up first if the channel
+    /// is coalescing
+    pub fn send(&self, peer: &PeerId, message: &[u8], coalesce: bool) {
+        if let Some(last_received) = &self.last_received {
+            last_received.record(peer, now_ms() as u64);
+            if message == KEEP_ALIVE {
+                trace!("keep-alive from {peer:?}");
+                return;
+            }
+        }
+        let packets = if coalesce {
+            split_batch(message)
+        } else {
+            vec![message]
+        };
+        for packet in packets {
+            debug!("rx {:?}", packet);
+            self.stats.record_received(packet.len());
+            let hooked;
+            let packet = match &self.on_incoming {
+                Some(hook) => match hook.apply(peer, packet.into()) {
+                    Some(packet) => {
+                        hooked = packet;
+                        &hooked[..]
+                    }
+                    None => continue,
+                },
+                None => packet,
+            };
+            let packet = match &self.exchanges {
+                Some(exchanges) => match exchanges.receive(peer, packet) {
+                    Some(packet) => packet,
+                    None => continue,
+                },
+                None => packet,
+            };
+            let packet = self.pool.packet_from(packet);
+            // the socket may have been dropped, that's fine
+            let _ = self.tx.unbounded_send((peer.clone(), packet));
+        }
+    }
+}
@@ -0,0 +1,655 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_timer::Delay;
+use log::{debug, warn};
+
+use crate::webrtc_socket::{
+    messages::PeerId, metrics, recording::now_ms, BackoffPolicy, ConnectionInfo, PeerState,
+    WebRtcSocketConfig,
+};
+
+/// How long a reconnection handshake may take before it counts as failed
+const RECONNECT_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+
+/// Reports the outcome of a single connection attempt back to the message loop
+///
+/// Every attempt has its own generation, so reports from attempts that were
+/// already given up on (e.g. a connection that failed after it was replaced)
+/// are ignored.
+#[derive(Debug, Clone)]
+pub(crate) struct AttemptReporter {
+    peer: PeerId,
+    generation: u64,
+    connected_tx: UnboundedSender<(PeerId, u64, ConnectionInfo)>,
+    failed_tx: UnboundedSender<(PeerId, u64)>,
+}
+
+impl AttemptReporter {
+    pub fn peer(&self) -> &PeerId {
+        &self.peer
+    }
+
+    /// The data channels to the peer are open
+    pub fn connected(&self, info: ConnectionInfo) {
+        // the message loop may already be gone, that's fine
+        let _ = self
+            .connected_tx
+            .unbounded_send((self.peer.clone(), self.generation, info));
+    }
+
+    /// The handshake or the connection failed
+    pub fn failed(&self) {
+        let _ = self
+            .failed_tx
+            .unbounded_send((self.peer.clone(), self.generation));
+    }
+}
+
+/// Something the message loop needs to act on
+#[derive(Debug)]
+pub(crate) enum AttemptEvent {
+    /// The state of a peer changed and should be reported to the socket
+    StateChanged(PeerId, PeerState),
+    /// Time to send an offer to the peer, for the first or a repeated attempt
+    Offer(AttemptReporter),
+    /// Time to answer the queued offer of a new peer, see
+    /// [`Reconnector::accept_or_queue`]
+    Answer(AttemptReporter),
+    /// The first handshake with the peer took too long before we connected
+    /// to anyone, see [`WebRtcSocketConfig::peer_connect_timeout_ms`]
+    TimedOut(PeerId),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TimerAction {
+    Retry,
+    Timeout,
+    ConnectTimeout,
+    GraceExpired,
+}
+
+struct Timer {
+    delay: Delay,
+    peer: PeerId,
+    generation: u64,
+    action: TimerAction,
+}
+
+impl Future for Timer {
+    type Output = (PeerId, u64, TimerAction);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.delay
+            .poll_unpin(cx)
+            .map(|()| (self.peer.clone(), self.generation, self.action))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Waiting for a free handshake slot before sending an offer, or
+    /// answering one if we aren't the offerer
+    Queued,
+    /// A handshake is in progress
+    Handshaking,
+    /// The data channels are open
+    Connected,
+    /// Waiting before the next attempt
+    Backoff,
+    /// Out of attempts, waiting for the peer to rejoin, see
+    /// [`WebRtcSocketConfig::reconnect_grace_period_ms`]
+    Grace,
+}
+
+#[derive(Debug)]
+struct Attempts {
+    generation: u64,
+    failures: u16,
+    phase: Phase,
+    /// Whether we send the offers, the other side just waits for them
+    offerer: bool,
+    /// When the current handshake started, in milliseconds
+    handshake_started_ms: f64,
+    /// Identifies the grace period we're in, if any, so a timer from an
+    /// earlier one doesn't end it
+    grace: Option<u64>,
+}
+
+/// Keeps track of connection attempts and schedules reconnects with backoff
+///
+/// Also limits how many handshakes we start at the same time, see
+/// [`WebRtcSocketConfig::max_concurrent_handshakes`].
+pub(crate) struct Reconnector {
+    max_attempts: u16,
+    backoff: BackoffPolicy,
+    grace_period: Option<Duration>,
+    max_handshakes: usize,
+    /// Peers of a resumed session, see [`WebRtcSocketConfig::resumed_peers`]
+    resumed: HashSet<PeerId>,
+    connect_timeout: Option<Duration>,
+    /// Whether we connected to a peer yet, after that connect timeouts only
+    /// disconnect the peer that timed out
+    connected_once: bool,
+    next_generation: u64,
+    peers: HashMap<PeerId, Attempts>,
+    offer_queue: VecDeque<PeerId>,
+    /// New peers whose offers wait for a free handshake slot
+    answer_queue: VecDeque<PeerId>,
+    state_changes: VecDeque<(PeerId, PeerState)>,
+    timers: FuturesUnordered<Timer>,
+    peer_info_tx: UnboundedSender<(PeerId, ConnectionInfo)>,
+    connected_tx: UnboundedSender<(PeerId, u64, ConnectionInfo)>,
+    connected_rx: UnboundedReceiver<(PeerId, u64, ConnectionInfo)>,
+    failed_tx: UnboundedSender<(PeerId, u64)>,
+    failed_rx: UnboundedReceiver<(PeerId, u64)>,
+}
+
+impl Reconnector {
+    /// Creates a reconnector that reports the negotiated parameters of new
+    /// connections to `peer_info_tx`
+    pub fn new(
+        config: &WebRtcSocketConfig,
+        peer_info_tx: UnboundedSender<(PeerId, ConnectionInfo)>,
+    ) -> Self {
+        let (connected_tx, connected_rx) = futures_channel::mpsc::unbounded();
+        let (failed_tx, failed_rx) = futures_channel::mpsc::unbounded();
+        Self {
+            max_attempts: config.reconnect_attempts,
+            backoff: config.reconnect_backoff,
+            grace_period: Some(config.reconnect_grace_period_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            max_handshakes: config.max_concurrent_handshakes,
+            resumed: config.resumed_peers.iter().cloned().collect(),
+            connect_timeout: Some(config.peer_connect_timeout_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            connected_once: false,
+            next_generation: 0,
+            peers: HashMap::new(),
+            offer_queue: VecDeque::new(),
+            answer_queue: VecDeque::new(),
+            state_changes: VecDeque::new(),
+            timers: FuturesUnordered::new(),
+            peer_info_tx,
+            connected_tx,
+            connected_rx,
+            failed_tx,
+            failed_rx,
+        }
+    }
+
+    /// Starts connecting to a new peer by sending it an offer
+    ///
+    /// The offer is handed out by [`Reconnector::next_event`] as soon as
+    /// there's a free handshake slot.
+    pub fn start(&mut self, peer: &PeerId) {
+        match self.peers.get(peer).map(|attempts| attempts.phase) {
+            Some(Phase::Connected) => {
+                // announced again after it reconnected to the signalling server
+                debug!("already connected to {peer:?}");
+                return;
+            }
+            Some(Phase::Grace) => {
+                self.rejoin(peer, true, Phase::Queued);
+            }
+            _ => {
+                self.track(peer, true, Phase::Queued);
+            }
+        }
+        self.offer_queue.push_back(peer.clone());
+    }
+
+    /// Accepts an offer from the given peer, unless it's a new peer and all
+    /// handshake slots are taken
+    ///
+    /// Offers of new peers are queued then, and handed out as
+    /// [`AttemptEvent::Answer`] by [`Reconnector::next_event`] once a slot is
+    /// free. Offers of peers we know, e.g. ones reconnecting or from a resumed
+    /// session, are always accepted right away.
+    pub fn accept_or_queue(&mut self, peer: &PeerId) -> Option<AttemptReporter> {
+        if self.peers.contains_key(peer) || self.resumed.contains(peer) || self.has_free_slot() {
+            return Some(self.accept(peer));
+        }
+        debug!("all handshake slots are taken, queueing the offer of {peer:?}");
+        self.track(peer, false, Phase::Queued);
+        self.answer_queue.push_back(peer.clone());
+        None
+    }
+
+    /// Accepts an offer from the given peer
+    ///
+    /// If we're waiting for the peer to reconnect or rejoin, this continues
+    /// the current attempt, otherwise it starts connecting to a new peer.
+    /// The offer is accepted right away, even if that exceeds the handshake
+    /// limit.
+    fn accept(&mut self, peer: &PeerId) -> AttemptReporter {
+        let first_contact = !self.peers.contains_key(peer);
+        match self.peers.get_mut(peer) {
+            Some(attempts) if attempts.phase == Phase::Grace => {
+                let generation = self.rejoin(peer, false, Phase::Handshaking);
+                self.reporter(peer.clone(), generation)
+            }
+            Some(attempts) if !attempts.offerer && attempts.phase != Phase::Connected => {
+                attempts.phase = Phase::Handshaking;
+                attempts.handshake_started_ms = now_ms();
+                let generation = attempts.generation;
+                self.reporter(peer.clone(), generation)
+            }
+            _ => {
+                let generation = self.track(peer, false, Phase::Handshaking);
+                if first_contact {
+                    self.schedule_connect_timeout(peer, generation);
+                }
+                self.reporter(peer.clone(), generation)
+            }
+        }
+    }
+
+    /// Returns all peers we're connected or trying to connect to
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    /// Gives up on a peer right away, without reconnecting, e.g. because
+    /// its handshake was rejected
+    ///
+    /// Reports from its current attempt are ignored from now on.
+    pub fn disconnect(&mut self, peer: &PeerId) {
+        if self.peers.remove(peer).is_some() {
+            self.state_changes
+                .push_back((peer.clone(), PeerState::Disconnected));
+        }
+    }
+
+    fn track(&mut self, peer: &PeerId, offerer: bool, phase: Phase) -> u64 {
+        let generation = self.new_generation();
+        self.peers.insert(
+            peer.clone(),
+            Attempts {
+                generation,
+                failures: 0,
+                phase,
+                offerer,
+                handshake_started_ms: now_ms(),
+                grace: None,
+            },
+        );
+        self.state_changes
+            .push_back((peer.clone(), PeerState::Connecting));
+        generation
+    }
+
+    /// Connects to a peer again that rejoined during its grace period
+    ///
+    /// The peer stays [`PeerState::Reconnecting`] until it's connected, and
+    /// the grace period keeps running in case this fails as well.
+    fn rejoin(&mut self, peer: &PeerId, offerer: bool, phase: Phase) -> u64 {
+        debug!("{peer:?} rejoined during its grace period");
+        let generation = self.new_generation();
+        let attempts = self.peers.get_mut(peer).expect("peer is tracked");
+        attempts.generation = generation;
+        attempts.phase = phase;
+        attempts.offerer = offerer;
+        attempts.handshake_started_ms = now_ms();
+        generation
+    }
+
+    /// Whether the attempt is the one currently in progress for its peer
+    #[cfg(target_arch = "wasm32")]
+    pub fn is_current(&self, attempt: &AttemptReporter) -> bool {
+        self.peers
+            .get(&attempt.peer)
+            .map_or(false, |attempts| attempts.generation == attempt.generation)
+    }
+
+    /// Waits for the next connection attempt to change state
+    pub async fn next_event(&mut self) -> AttemptEvent {
+        futures::future::poll_fn(|cx| self.poll_event(cx)).await
+    }
+
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<AttemptEvent> {
+        loop {
+            if let Some((peer, state)) = self.state_changes.pop_front() {
+                return Poll::Ready(AttemptEvent::StateChanged(peer, state));
+            }
+
+            if let Poll::Ready(Some((peer, generation, info))) =
+                self.connected_rx.poll_next_unpin(cx)
+            {
+                if let Some(attempts) = self.current(&peer, generation) {
+                    attempts.failures = 0;
+                    attempts.phase = Phase::Connected;
+                    attempts.grace = None;
+                    metrics::handshake_finished(now_ms() - attempts.handshake_started_ms);
+                    self.connected_once = true;
+                    // sent before the state, so it's there once the peer shows up as connected
+                    let _ = self.peer_info_tx.unbounded_send((peer.clone(), info));
+                    return Poll::Ready(AttemptEvent::StateChanged(peer, PeerState::Connected));
+                }
+                continue;
+            }
+
+            if let Poll::Ready(Some((peer, generation))) = self.failed_rx.poll_next_unpin(cx) {
+                if let Some(state) = self.fail(&peer, generation) {
+                    return Poll::Ready(AttemptEvent::StateChanged(peer, state));
+                }
+                continue;
+            }
+
+            if let Poll::Ready(Some((peer, generation, action))) = self.timers.poll_next_unpin(cx) {
+                match action {
+                    TimerAction::Retry => {
+                        if let Some(attempts) = self.current(&peer, generation) {
+                            debug!("retrying connection to {peer:?}");
+                            attempts.phase = Phase::Queued;
+                            self.offer_queue.push_back(peer);
+                        }
+                    }
+                    TimerAction::Timeout => {
+                        let phase = self.current(&peer, generation).map(|a| a.phase);
+                        if matches!(phase, Some(Phase::Handshaking | Phase::Backoff)) {
+                            warn!("reconnecting to {peer:?} timed out");
+                            if let Some(state) = self.fail(&peer, generation) {
+                                return Poll::Ready(AttemptEvent::StateChanged(peer, state));
+                            }
+                        }
+                    }
+                    TimerAction::GraceExpired => {
+                        let grace = self.peers.get(&peer).and_then(|a| a.grace);
+                        if grace == Some(generation) {
+                            warn!("{peer:?} didn't rejoin in time");
+                            self.peers.remove(&peer);
+                            return Poll::Ready(AttemptEvent::StateChanged(
+                                peer,
+                                PeerState::Disconnected,
+                            ));
+                        }
+                    }
+                    TimerAction::ConnectTimeout => {
+                        let phase = self.current(&peer, generation).map(|a| a.phase);
+                        if phase == Some(Phase::Handshaking) {
+                            warn!("connecting to {peer:?} timed out");
+                            self.peers.remove(&peer);
+                            if self.connected_once {
+                                // a late joiner shouldn't end a running session
+                                return Poll::Ready(AttemptEvent::StateChanged(
+                                    peer,
+                                    PeerState::Disconnected,
+                                ));
+                            }
+                            return Poll::Ready(AttemptEvent::TimedOut(peer));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if let Some(event) = self.next_handshake() {
+                return Poll::Ready(event);
+            }
+
+            return Poll::Pending;
+        }
+    }
+
+    /// Whether another handshake may start without exceeding
+    /// [`WebRtcSocketConfig::max_concurrent_handshakes`]
+    fn has_free_slot(&self) -> bool {
+        let handshakes = self
+            .peers
+            .values()
+            .filter(|attempts| attempts.phase == Phase::Handshaking)
+            .count();
+        self.max_handshakes == 0 || handshakes < self.max_handshakes
+    }
+
+    /// Takes the next queued answer or offer, if there is a free handshake
+    /// slot
+    ///
+    /// Answers go first, their peers are already waiting for them.
+    fn next_handshake(&mut self) -> Option<AttemptEvent> {
+        loop {
+            if !self.has_free_slot() {
+                return None;
+            }
+
+            let (peer, offerer) = match self.answer_queue.pop_front() {
+                Some(peer) => (peer, false),
+                None => (self.offer_queue.pop_front()?, true),
+            };
+            let attempts = match self.peers.get_mut(&peer) {
+                Some(attempts)
+                    if attempts.phase == Phase::Queued && attempts.offerer == offerer =>
+                {
+                    attempts
+                }
+                // gave up on the peer while it was queued
+                _ => continue,
+            };
+            attempts.phase = Phase::Handshaking;
+            attempts.handshake_started_ms = now_ms();
+            let (generation, failures) = (attempts.generation, attempts.failures);
+            if failures > 0 || attempts.grace.is_some() {
+                let timeout = Duration::from_millis(RECONNECT_HANDSHAKE_TIMEOUT_MS);
+                self.schedule(&peer, generation, TimerAction::Timeout, timeout);
+            } else {
+                self.schedule_connect_timeout(&peer, generation);
+            }
+            let attempt = self.reporter(peer, generation);
+            return Some(if offerer {
+                AttemptEvent::Offer(attempt)
+            } else {
+                AttemptEvent::Answer(attempt)
+            });
+        }
+    }
+
+    /// Limits the first handshake with a peer, if configured
+    fn schedule_connect_timeout(&mut self, peer: &PeerId, generation: u64) {
+        if let Some(timeout) = self.connect_timeout {
+            self.schedule(peer, generation, TimerAction::ConnectTimeout, timeout);
+        }
+    }
+
+    /// Gives up on the current attempt, and schedules a new one if allowed
+    fn fail(&mut self, peer: &PeerId, generation: u64) -> Option<PeerState> {
+        self.current(peer, generation)?;
+        let next_generation = self.new_generation();
+        let attempts = self.peers.get_mut(peer).expect("peer is tracked");
+
+        if attempts.failures >= self.max_attempts {
+            if let Some(grace_period) = self.grace_period {
+                attempts.phase = Phase::Grace;
+                attempts.generation = next_generation;
+                if attempts.grace.is_none() {
+                    debug!("waiting {grace_period:?} for {peer:?} to rejoin");
+                    attempts.grace = Some(next_generation);
+                    let action = TimerAction::GraceExpired;
+                    self.schedule(peer, next_generation, action, grace_period);
+                }
+                return Some(PeerState::Reconnecting);
+            }
+            warn!("giving up on connection to {peer:?}");
+            self.peers.remove(peer);
+            return Some(PeerState::Disconnected);
+        }
+
+        attempts.failures += 1;
+        let backoff = self.backoff.delay(attempts.failures);
+        attempts.phase = Phase::Backoff;
+        attempts.generation = next_generation;
+        debug!(
+            "connection to {peer:?} failed, reconnect attempt {} in {backoff:?}",
+            attempts.failures
+        );
+
+        if attempts.offerer {
+            // the handshake timeout starts once the offer leaves the queue
+            self.schedule(peer, next_generation, TimerAction::Retry, backoff);
+        } else {
+            let timeout = backoff + Duration::from_millis(RECONNECT_HANDSHAKE_TIMEOUT_MS);
+            self.schedule(peer, next_generation, TimerAction::Timeout, timeout);
+        }
+
+        Some(PeerState::Reconnecting)
+    }
+
+    fn schedule(&mut self, peer: &PeerId, generation: u64, action: TimerAction, after: Duration) {
+        self.timers.push(Timer {
+            delay: Delay::new(after),
+            peer: peer.clone(),
+            generation,
+            action,
+        });
+    }
+
+    fn current(&mut self, peer: &PeerId, generation: u64) -> Option<&mut Attempts> {
+        self.peers
+            .get_mut(peer)
+            .filter(|attempts| attempts.generation == generation)
+    }
+
+    fn new_generation(&mut self) -> u64 {
+        self.next_generation += 1;
+        self.next_generation
+    }
+
+    fn reporter(&self, peer: PeerId, generation: u64) -> AttemptReporter {
+        AttemptReporter {
+            peer,
+            generation,
+            connected_tx: self.connected_tx.clone(),
+            failed_tx: self.failed_tx.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, FutureExt};
+
+    use super::{AttemptEvent, AttemptReporter, Reconnector};
+    use crate::webrtc_socket::{BackoffPolicy, ConnectionInfo, PeerState, WebRtcSocketConfig};
+
+    fn reconnector(reconnect_attempts: u16) -> Reconnector {
+        let config = WebRtcSocketConfig {
+            reconnect_attempts,
+            reconnect_backoff: BackoffPolicy {
+                initial_delay_ms: 10,
+                multiplier: 1.,
+                max_delay_ms: 10,
+                jitter: 0.,
+            },
+            ..Default::default()
+        };
+        let (peer_info_tx, _peer_info_rx) = futures_channel::mpsc::unbounded();
+        Reconnector::new(&config, peer_info_tx)
+    }
+
+    fn state_change(reconnector: &mut Reconnector) -> (String, PeerState) {
+        match block_on(reconnector.next_event()) {
+            AttemptEvent::StateChanged(peer, state) => (peer, state),
+            event => panic!("expected a state change, got {:?}", event),
+        }
+    }
+
+    fn offer(reconnector: &mut Reconnector) -> AttemptReporter {
+        match block_on(reconnector.next_event()) {
+            AttemptEvent::Offer(attempt) => attempt,
+            event => panic!("expected an offer, got {:?}", event),
+        }
+    }
+
+    fn info() -> ConnectionInfo {
+        ConnectionInfo {
+            max_message_size: None,
+            channels: vec![],
+        }
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_after_a_backoff() {
+        let peer = "peer".to_string();
+        let mut reconnector = reconnector(2);
+        reconnector.start(&peer);
+        assert_eq!(
+            state_change(&mut reconnector),
+            (peer.clone(), PeerState::Connecting)
+        );
+        let attempt = offer(&mut reconnector);
+        assert_eq!(attempt.peer(), &peer);
+
+        attempt.failed();
+        assert_eq!(
+            state_change(&mut reconnector),
+            (peer.clone(), PeerState::Reconnecting)
+        );
+        // the retry waits for the backoff
+        assert!(reconnector.next_event().now_or_never().is_none());
+        let retry = offer(&mut reconnector);
+
+        retry.connected(info());
+        assert_eq!(state_change(&mut reconnector), (peer, PeerState::Connected));
+    }
+
+    #[test]
+    fn peers_are_given_up_on_after_the_last_attempt() {
+        let peer = "peer".to_string();
+        let mut reconnector = reconnector(2);
+        reconnector.start(&peer);
+        state_change(&mut reconnector);
+        let mut attempt = offer(&mut reconnector);
+
+        for _ in 0..2 {
+            attempt.failed();
+            assert_eq!(
+                state_change(&mut reconnector),
+                (peer.clone(), PeerState::Reconnecting)
+            );
+            attempt = offer(&mut reconnector);
+        }
+        attempt.failed();
+        assert_eq!(
+            state_change(&mut reconnector),
+            (peer, PeerState::Disconnected)
+        );
+        assert_eq!(reconnector.peers().count(), 0);
+    }
+
+    #[test]
+    fn reports_from_replaced_attempts_are_ignored() {
+        let peer = "peer".to_string();
+        let mut reconnector = reconnector(1);
+        reconnector.start(&peer);
+        state_change(&mut reconnector);
+        let first = offer(&mut reconnector);
+
+        first.failed();
+        state_change(&mut reconnector);
+        let retry = offer(&mut reconnector);
+
+        // e.g. the first connection failing late, after it was replaced
+        first.failed();
+        first.connected(info());
+        assert!(reconnector.next_event().now_or_never().is_none());
+
+        retry.connected(info());
+        assert_eq!(
+            state_change(&mut reconnector),
+            (peer.clone(), PeerState::Connected)
+        );
+        // and mustn't disconnect the peer that's connected now
+        first.failed();
+        assert!(reconnector.next_event().now_or_never().is_none());
+        assert_eq!(reconnector.peers().collect::<Vec<_>>(), vec![&peer]);
+    }
+}
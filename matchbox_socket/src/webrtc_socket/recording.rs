@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+    sync::Mutex,
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::webrtc_socket::{messages::PeerId, Packet};
+
+/// Whether a [`RecordedPacket`] was sent or received by the recording socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketDirection {
+    /// Sent to the peer
+    Sent,
+    /// Received from the peer
+    Received,
+}
+
+/// A single packet, as written by a [`Recorder`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedPacket {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    /// Whether the packet was sent or received
+    pub direction: PacketDirection,
+    /// The peer the packet was sent to or received from
+    pub peer: PeerId,
+    /// The index of the channel, see [`WebRtcSocketConfig::channels`](crate::WebRtcSocketConfig::channels)
+    pub channel: usize,
+    /// The packet itself
+    pub data: Packet,
+}
+
+/// Writes all packets sent and received by a socket, one json object per line
+///
+/// See [`WebRtcSocket::with_recorder`](crate::WebRtcSocket::with_recorder),
+/// recordings can be played back with [`Replay`].
+pub struct Recorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+    started_at_ms: f64,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("started_at_ms", &self.started_at_ms)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Recorder {
+    /// Creates a recorder writing to the given writer, e.g. a [`std::fs::File`]
+    ///
+    /// Packets are written as they pass through the socket, so wrapping the
+    /// writer in a [`std::io::BufWriter`] is a good idea.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            started_at_ms: now_ms(),
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        direction: PacketDirection,
+        peer: &PeerId,
+        channel: usize,
+        data: &[u8],
+    ) {
+        let packet = RecordedPacket {
+            at_ms: (now_ms() - self.started_at_ms).max(0.) as u64,
+            direction,
+            peer: peer.clone(),
+            channel,
+            data: data.into(),
+        };
+        let line = serde_json::to_string(&packet).expect("error serializing recorded packet");
+        let mut writer = self.writer.lock().expect("recorder lock poisoned");
+        if let Err(e) = writeln!(writer, "{line}") {
+            error!("failed to record packet: {e:?}");
+        }
+    }
+
+    /// Flushes the underlying writer
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().expect("recorder lock poisoned").flush()
+    }
+}
+
+pub(crate) use crate::time::now_ms;
+
+/// Plays back a session recorded by a [`Recorder`]
+///
+/// Hands out the received packets with the same api as
+/// [`WebRtcReceiver`](crate::WebRtcReceiver), but on a clock that only moves
+/// when told to, so a desync can be reproduced frame by frame without any
+/// live peers.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    packets: Vec<RecordedPacket>,
+    received: Vec<VecDeque<RecordedPacket>>,
+    now_ms: u64,
+}
+
+impl Replay {
+    /// Creates a replay of the given packets
+    pub fn new(packets: Vec<RecordedPacket>) -> Self {
+        let mut received: Vec<VecDeque<RecordedPacket>> = vec![];
+        for packet in &packets {
+            if packet.direction != PacketDirection::Received {
+                continue;
+            }
+            if received.len() <= packet.channel {
+                received.resize_with(packet.channel + 1, VecDeque::new);
+            }
+            received[packet.channel].push_back(packet.clone());
+        }
+        for channel in &mut received {
+            channel.make_contiguous().sort_by_key(|packet| packet.at_ms);
+        }
+        Self {
+            packets,
+            received,
+            now_ms: 0,
+        }
+    }
+
+    /// Reads a recording, as written by a [`Recorder`]
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut packets = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let packet = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            packets.push(packet);
+        }
+        Ok(Self::new(packets))
+    }
+
+    /// Returns all recorded packets, sent and received, in recording order
+    pub fn packets(&self) -> &[RecordedPacket] {
+        &self.packets
+    }
+
+    /// Returns the current time of the replay, in milliseconds since the
+    /// recording started
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// Moves the replay clock forward by the given number of milliseconds
+    pub fn advance(&mut self, ms: u64) {
+        self.now_ms += ms;
+    }
+
+    /// Moves the replay clock to the end of the recording
+    pub fn advance_to_end(&mut self) {
+        let end = self.packets.iter().map(|packet| packet.at_ms).max();
+        self.now_ms = self.now_ms.max(end.unwrap_or_default());
+    }
+
+    /// Returns true if all received packets have been handed out
+    pub fn is_finished(&self) -> bool {
+        self.received.iter().all(VecDeque::is_empty)
+    }
+
+    /// Receive recorded messages from the default channel (with index 0)
+    ///
+    /// See also: [`Replay::receive_on_channel`]
+    pub fn receive(&mut self) -> Vec<(PeerId, Packet)> {
+        self.receive_on_channel(0)
+    }
+
+    /// Receive the messages the recording socket received on the given
+    /// channel up until the current time of the replay
+    ///
+    /// messages are removed from the replay when called
+    pub fn receive_on_channel(&mut self, index: usize) -> Vec<(PeerId, Packet)> {
+        let now_ms = self.now_ms;
+        let channel = match self.received.get_mut(index) {
+            Some(channel) => channel,
+            None => return vec![],
+        };
+        let due = channel.partition_point(|packet| packet.at_ms <= now_ms);
+        channel
+            .drain(..due)
+            .map(|packet| (packet.peer, packet.data))
+            .collect()
+    }
+}
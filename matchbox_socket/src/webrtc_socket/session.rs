@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use crate::webrtc_socket::{messages::PeerId, AppUserId, ChannelConfig, WebRtcSocketConfig};
+
+/// How often to try registering again when resuming a session, unless
+/// [`WebRtcSocketConfig::signalling_reconnect_attempts`] says otherwise
+///
+/// The server may not have noticed yet that the crashed socket is gone, and
+/// rejects our id with [`SignallingError::IdTaken`](crate::SignallingError::IdTaken)
+/// until then.
+const RESUME_SIGNALLING_ATTEMPTS: u16 = 5;
+
+/// What a socket needs to rejoin its room as the same peer after a crash or
+/// restart
+///
+/// Returned by [`WebRtcSocket::export_session`](crate::WebRtcSocket::export_session),
+/// and passed to [`WebRtcSocketConfig::resume_session`] to pick up where the
+/// socket left off. It can be (de)serialized, so it can be written to disk
+/// and read back by the restarted game.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketSession {
+    /// The url of the room the socket was in
+    pub room_url: String,
+    /// The id of the socket, which the other peers recognize it by
+    pub peer_id: PeerId,
+    /// The DTLS certificate of the socket, so peers that pinned its
+    /// fingerprint accept it again
+    pub certificate_pem: Option<String>,
+    /// The peers the socket was connected or connecting to, sorted
+    pub peers: Vec<PeerId>,
+    /// The channels the socket had with them
+    pub channels: Vec<ChannelConfig>,
+    /// Which players the peers were, see
+    /// [`WebRtcReceiver::associate`](crate::WebRtcReceiver::associate)
+    #[serde(default)]
+    pub identities: Vec<(PeerId, AppUserId)>,
+}
+
+impl WebRtcSocketConfig {
+    /// Rejoins the room of an exported [`SocketSession`] with the same id,
+    /// certificate, channels and player associations
+    ///
+    /// Peers still holding our slot, see
+    /// [`WebRtcSocketConfig::reconnect_grace_period_ms`], connect to us again
+    /// as if the connection never dropped, and the handshake limit doesn't
+    /// hold their offers back, see [`WebRtcSocketConfig::resumed_peers`].
+    /// Registering is retried a few times, unless
+    /// [`WebRtcSocketConfig::signalling_reconnect_attempts`] is set, in case
+    /// the server still holds on to the crashed socket.
+    ///
+    /// ```
+    /// use matchbox_socket::{SocketSession, WebRtcSocketConfig};
+    ///
+    /// # fn load(_: &str) -> Option<String> { None }
+    /// let config = match load("session.json") {
+    ///     Some(json) => {
+    ///         let session: SocketSession = serde_json::from_str(&json).unwrap();
+    ///         WebRtcSocketConfig::default().resume_session(session)
+    ///     }
+    ///     None => WebRtcSocketConfig::default(),
+    /// };
+    /// ```
+    pub fn resume_session(mut self, session: SocketSession) -> Self {
+        self.room_url = session.room_url;
+        self.peer_id = Some(session.peer_id);
+        self.certificate_pem = session.certificate_pem;
+        self.resumed_peers = session.peers;
+        self.resumed_identities = session.identities;
+        self.channels = session.channels;
+        if self.signalling_reconnect_attempts == 0 {
+            self.signalling_reconnect_attempts = RESUME_SIGNALLING_ATTEMPTS;
+        }
+        self
+    }
+}
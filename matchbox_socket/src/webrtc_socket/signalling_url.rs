@@ -0,0 +1,87 @@
+use url::Url;
+
+use crate::{webrtc_socket::page_is_secure, Error};
+
+/// Checks that `room_url` is a websocket url with a host, and returns it in
+/// the form both websocket clients accept
+///
+/// Hostnames are converted to punycode, so e.g. `ws://bücher.example/room`
+/// becomes `ws://xn--bcher-kva.example/room`, and IPv6 addresses keep their
+/// brackets, e.g. `ws://[2001:db8::1]:3536/room`.
+///
+/// On pages served over https, `ws://` urls are switched to `wss://` if
+/// `upgrade_insecure` is set, and rejected otherwise, since the browser
+/// would block them.
+pub(crate) fn parse_room_url(room_url: &str, upgrade_insecure: bool) -> Result<String, Error> {
+    let invalid = |reason: &str| Error::InvalidUrl {
+        url: room_url.to_string(),
+        reason: reason.to_string(),
+    };
+    let mut url = Url::parse(room_url).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(invalid("the scheme must be ws or wss"));
+    }
+    if url.host().is_none() {
+        return Err(invalid("there's no host"));
+    }
+    if url.scheme() == "ws" && page_is_secure() {
+        if !upgrade_insecure {
+            return Err(Error::InsecureSignallingUrl(room_url.to_string()));
+        }
+        url.set_scheme("wss")
+            .expect("ws and wss are both special schemes");
+    }
+    Ok(url.into())
+}
+
+/// Returns the `next` query parameter of `room_url`, if it has a valid one
+pub(crate) fn room_url_next(room_url: &str) -> Option<usize> {
+    let url = Url::parse(room_url).ok()?;
+    let next = url.query_pairs().find(|(key, _)| key == "next")?.1;
+    next.parse().ok()
+}
+
+/// Returns the url of the given room, on the server of `room_url`, with the
+/// `next` query parameter if given
+pub(crate) fn room_url_on_same_server(room_url: &str, room: &str, next: Option<usize>) -> String {
+    let server = &room_url[..server_end(room_url)];
+    match next {
+        Some(next) => format!("{server}/{room}?next={next}"),
+        None => format!("{server}/{room}"),
+    }
+}
+
+/// Returns `room_url` with the scheme, host and port of `server_url`, e.g.
+/// to fail over to another signalling server
+pub(crate) fn room_url_on_server(server_url: &str, room_url: &str) -> String {
+    let server = &server_url[..server_end(server_url)];
+    format!("{server}{}", &room_url[server_end(room_url)..])
+}
+
+/// Returns where the scheme, host and port of a url end
+fn server_end(url: &str) -> usize {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    url[host_start..]
+        .find(['/', '?'])
+        .map_or(url.len(), |i| host_start + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{room_url_on_same_server, room_url_on_server};
+
+    #[test]
+    fn rooms_move_between_servers() {
+        assert_eq!(
+            room_url_on_server(
+                "wss://backup.example.com/",
+                "ws://localhost:3536/game?next=2"
+            ),
+            "wss://backup.example.com/game?next=2"
+        );
+        assert_eq!(
+            room_url_on_same_server("ws://localhost:3536/game?next=2", "other", None),
+            "ws://localhost:3536/other"
+        );
+    }
+}
@@ -1,101 +1,267 @@
-use futures::FutureExt;
+use futures::{future, FutureExt};
 use futures::{stream::FuturesUnordered, StreamExt};
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_channel::mpsc::UnboundedReceiver;
 use futures_timer::Delay;
 use futures_util::select;
 use js_sys::{Function, Reflect};
 use log::{debug, error, warn};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use wasm_bindgen::convert::FromWasmAbi;
 use wasm_bindgen::{prelude::*, JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelInit, RtcDataChannelType,
-    RtcIceCandidateInit, RtcIceGatheringState, RtcPeerConnection, RtcPeerConnectionIceEvent,
-    RtcSdpType, RtcSessionDescriptionInit,
+    Blob, Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelInit,
+    RtcDataChannelType, RtcIceCandidateInit, RtcIceConnectionState, RtcIceGatheringState,
+    RtcPeerConnection, RtcPeerConnectionIceEvent, RtcPriorityType, RtcSdpType,
+    RtcSessionDescriptionInit,
 };
 
-use crate::webrtc_socket::{create_data_channels_ready_fut, ChannelConfig};
 use crate::webrtc_socket::{
-    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal},
+    channels_by_priority, create_data_channels_ready_fut, next_peer_message_out,
+    open_channels_with, panic_if_strict, Approvals, Approved, CandidatePreference, ChannelConfig,
+    ChannelPriority,
+};
+use crate::webrtc_socket::{
+    coalesce::Coalescer,
+    congestion::CONGESTION_SAMPLE_INTERVAL_MS,
+    fingerprint::verify_remote_fingerprint,
+    liveness::KEEP_ALIVE,
+    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal, RoomUpdate},
+    reconnect::{AttemptEvent, AttemptReporter, Reconnector},
+    recording::now_ms,
+    sdp_max_message_size,
     signal_peer::SignalPeer,
-    Packet, WebRtcSocketConfig, KEEP_ALIVE_INTERVAL,
+    BinaryType, ChannelInfo, ConnectionInfo, Handshakes, IncomingSender, Liveness,
+    LocalCertificate, MessageLoopChannels, PeerState, PooledPacket, RtcIceServerConfig,
+    WebRtcSocketConfig, KEEP_ALIVE_INTERVAL,
 };
+use crate::Error;
 
 pub async fn message_loop(
     id: PeerId,
     config: WebRtcSocketConfig,
-    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
-    mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    mut peer_messages_out_rx: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
-) {
+    channels: MessageLoopChannels<'_>,
+) -> Result<(), Error> {
+    let MessageLoopChannels {
+        requests_sender,
+        mut events_receiver,
+        peer_messages_out_rx,
+        peer_state_tx,
+        peer_info_tx,
+        congestion,
+        room_tx,
+        messages_from_peers_tx,
+        mut leave_rx,
+    } = channels;
     debug!("Entering WebRtcSocket message loop");
 
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
+    // the signalling loop may be gone already, e.g. if the room was closed
+    // right away, run_room stops us then
+    let _ = requests_sender.unbounded_send(PeerRequest::Uuid(id));
 
     let mut offer_handshakes = FuturesUnordered::new();
     let mut accept_handshakes = FuturesUnordered::new();
     let mut handshake_signals = HashMap::new();
-    let mut data_channels: HashMap<PeerId, Vec<RtcDataChannel>> = HashMap::new();
+    // signals of new peers waiting for a free handshake slot, starting with their offer
+    let mut queued_offers: HashMap<PeerId, Vec<PeerSignal>> = HashMap::new();
+    // `None` for channels that aren't opened with the peer
+    let mut data_channels: HashMap<PeerId, Vec<Option<RtcDataChannel>>> = HashMap::new();
+    let mut reconnector = Reconnector::new(&config, peer_info_tx);
+    let mut handshakes = Handshakes::new(&config, room_tx.clone());
+    let mut approvals = Approvals::new(&config);
+    let mut liveness = Liveness::new(&config);
+    let channel_order = channels_by_priority(&config);
+    let mut coalescer = Coalescer::new(&config, &messages_from_peers_tx);
+    // handed out by the signalling server, used in addition to our own
+    let mut server_ice_servers = vec![];
+    // decide which channels we open with each peer
+    let mut peer_capabilities = HashMap::new();
 
     let mut timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL)).fuse();
+    let mut sample_congestion =
+        Delay::new(Duration::from_millis(CONGESTION_SAMPLE_INTERVAL_MS)).fuse();
+    let mut check_liveness = liveness.next_check();
 
-    loop {
-        let mut next_peer_messages_out: FuturesUnordered<_> = peer_messages_out_rx
-            .iter_mut()
-            .enumerate()
-            .map(|(index, r)| async move { (index, r.next().await) })
-            .collect();
+    let mut flush = coalescer.flush_timer();
 
-        let mut next_peer_message_out = next_peer_messages_out.next().fuse();
+    loop {
+        // while packets are held back, the rest stay queued until the flush
+        let holding = coalescer.is_holding();
+        let mut next_peer_message_out = Box::pin(
+            async {
+                if holding {
+                    future::pending().await
+                } else {
+                    next_peer_message_out(peer_messages_out_rx, &channel_order).await
+                }
+            }
+            .fuse(),
+        );
 
         select! {
             _ = &mut timeout => {
-                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
+                // see the uuid above
+                let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
                 timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL)).fuse();
             }
 
-            res = offer_handshakes.select_next_some() => {
-                check(&res);
-                let (peer, channels) = res.unwrap();
-                data_channels.insert(peer.clone(), channels);
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer).expect("send failed");
+            _ = &mut sample_congestion => {
+                for (peer, channels) in &data_channels {
+                    let buffered_bytes = channels
+                        .iter()
+                        .flatten()
+                        .map(|channel| channel.buffered_amount() as usize)
+                        .sum();
+                    congestion.sample(peer, buffered_bytes);
+                }
+                sample_congestion = Delay::new(Duration::from_millis(CONGESTION_SAMPLE_INTERVAL_MS)).fuse();
+            }
+
+            _ = &mut check_liveness => {
+                let (keep_alives, changes) = liveness.check(now_ms() as u64, &messages_from_peers_tx);
+                for (channel, peer) in keep_alives {
+                    send_to_peer((channel, peer, PooledPacket::from(KEEP_ALIVE.to_vec())), &data_channels, &messages_from_peers_tx, &config.channels, config.strict);
+                }
+                for change in changes {
+                    // the socket may have been dropped, that's fine
+                    let _ = room_tx.unbounded_send(RoomUpdate::ChannelLiveness(change));
+                }
+                check_liveness = liveness.next_check();
+            }
+
+            _ = leave_rx => {
+                debug!("Leaving room");
+                for channel in data_channels.values().flatten().flatten() {
+                    channel.close();
+                }
+                let peers: HashSet<_> = data_channels.keys().chain(handshake_signals.keys()).chain(reconnector.peers()).cloned().collect();
+                for peer in peers {
+                    // the socket may have been dropped, that's fine
+                    let _ = peer_state_tx.unbounded_send((peer, PeerState::Disconnected));
+                }
+                break;
+            }
+
+            (attempt, res) = offer_handshakes.select_next_some() => {
+                handshake_finished(&reconnector, &mut data_channels, attempt, res);
             },
-            res = accept_handshakes.select_next_some() => {
-                // TODO: this could be de-duplicated
-                check(&res);
-                let (peer, channels) = res.unwrap();
-                data_channels.insert(peer.clone(), channels);
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer).expect("send failed");
+            (attempt, res) = accept_handshakes.select_next_some() => {
+                handshake_finished(&reconnector, &mut data_channels, attempt, res);
             },
 
+            event = reconnector.next_event().fuse() => {
+                match event {
+                    AttemptEvent::StateChanged(peer, state) => {
+                        match state {
+                            PeerState::Connecting => {}
+                            PeerState::Connected => {
+                                debug!("Notifying about new peer");
+                                liveness.connected(&peer, now_ms() as u64);
+                            }
+                            PeerState::Reconnecting => {
+                                // The next handshake needs its own signalling
+                                handshake_signals.remove(&peer);
+                                liveness.disconnected(&peer);
+                            }
+                            PeerState::Disconnected => {
+                                handshake_signals.remove(&peer);
+                                liveness.disconnected(&peer);
+                                queued_offers.remove(&peer);
+                                // still open if we gave up on the peer, e.g. over its handshake
+                                for channel in data_channels.remove(&peer).into_iter().flatten().flatten() {
+                                    channel.close();
+                                }
+                                peer_capabilities.remove(&peer);
+                                // sends fail as for any other disconnected peer again, and so do
+                                // requests waiting for a response
+                                for channel in &messages_from_peers_tx {
+                                    channel.disconnected(&peer);
+                                }
+                            }
+                        }
+                        if let Some(rejected) = handshakes.state_changed(&peer, state, &requests_sender) {
+                            reconnector.disconnect(&rejected);
+                        }
+                        // the socket may have been dropped, that's fine
+                        let _ = peer_state_tx.unbounded_send((peer, state));
+                    }
+                    AttemptEvent::Offer(attempt) => {
+                        let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+                        handshake_signals.insert(attempt.peer().clone(), signal_sender);
+                        let signal_peer = SignalPeer::new(attempt.peer().clone(), requests_sender.clone());
+                        let open = open_channels_with(attempt.peer(), &config, peer_capabilities.get(attempt.peer()), &messages_from_peers_tx);
+                        offer_handshakes.push(handshake_offer(signal_peer, signal_receiver, messages_from_peers_tx.clone(), &config, server_ice_servers.clone(), open, attempt));
+                    }
+                    AttemptEvent::Answer(attempt) => {
+                        let peer = attempt.peer().clone();
+                        let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+                        for signal in queued_offers.remove(&peer).unwrap_or_default() {
+                            signal_sender.unbounded_send(signal).expect("the receiver is right here");
+                        }
+                        handshake_signals.insert(peer.clone(), signal_sender);
+                        let signal_peer = SignalPeer::new(peer.clone(), requests_sender.clone());
+                        let open = open_channels_with(&peer, &config, peer_capabilities.get(&peer), &messages_from_peers_tx);
+                        accept_handshakes.push(handshake_accept(signal_peer, signal_receiver, messages_from_peers_tx.clone(), &config, server_ice_servers.clone(), open, attempt));
+                    }
+                    AttemptEvent::TimedOut(peer) => {
+                        let _ = peer_state_tx.unbounded_send((peer.clone(), PeerState::Disconnected));
+                        return Err(Error::PeerConnectTimeout(peer));
+                    }
+                }
+            }
+
+            approved = approvals.next_approved().fuse() => {
+                match approved {
+                    Approved::NewPeer(peer) => reconnector.start(&peer),
+                    Approved::Offer(sender, signals) => {
+                        // We didn't start signalling with this peer, assume we're the accepting part
+                        let Some(attempt) = reconnector.accept_or_queue(&sender) else {
+                            queued_offers.insert(sender, signals);
+                            continue;
+                        };
+                        let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
+                        for signal in signals {
+                            from_peer_sender.unbounded_send(signal).expect("the receiver is right here");
+                        }
+                        let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
+                        let open = open_channels_with(&sender, &config, peer_capabilities.get(&sender), &messages_from_peers_tx);
+                        accept_handshakes.push(handshake_accept(signal_peer, from_peer_receiver, messages_from_peers_tx.clone(), &config, server_ice_servers.clone(), open, attempt));
+                        handshake_signals.insert(sender, from_peer_sender);
+                    }
+                }
+            }
+
             message = events_receiver.next() => {
                 if let Some(event) = message {
                     debug!("{:?}", event);
 
                     match event {
-                        PeerEvent::NewPeer(peer_uuid) => {
-                            let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
-                            handshake_signals.insert(peer_uuid.clone(), signal_sender);
-                            let signal_peer = SignalPeer::new(peer_uuid, requests_sender.clone());
-                            offer_handshakes.push(handshake_offer(signal_peer, signal_receiver, messages_from_peers_tx.clone(), &config));
+                        PeerEvent::NewPeer(peer_uuid) => approvals.new_peer(peer_uuid),
+                        PeerEvent::Signal { sender, data: PeerSignal::Handshake(data) } => {
+                            if let Some(rejected) = handshakes.received(sender, data) {
+                                reconnector.disconnect(&rejected);
+                            }
                         }
                         PeerEvent::Signal { sender, data } => {
-                            let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
-                                let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
-                                let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
-                                // We didn't start signalling with this peer, assume we're the accepting part
-                                accept_handshakes.push(handshake_accept(signal_peer, from_peer_receiver, messages_from_peers_tx.clone(), &config));
-                                from_peer_sender
-                            });
+                            // the rest of the handshake waits with its offer
+                            let Some(data) = approvals.hold(&sender, data) else {
+                                continue;
+                            };
+                            if let Some(signals) = queued_offers.get_mut(&sender) {
+                                signals.push(data);
+                                continue;
+                            }
+                            let Some(from_peer_sender) = handshake_signals.get(&sender) else {
+                                if !matches!(data, PeerSignal::Offer(_)) {
+                                    // Left over from a connection attempt we already gave up on
+                                    warn!("ignoring signal from {sender:?} without a handshake in progress: {data:?}");
+                                    continue;
+                                }
+                                approvals.offered(sender, data);
+                                continue;
+                            };
                             if let Err(e) = from_peer_sender.unbounded_send(data) {
                                 if e.is_disconnected() && data_channels.contains_key(&sender) {
                                     // when the handshake finishes, it currently drops the receiver.
@@ -110,6 +276,18 @@ pub async fn message_loop(
                                 }
                             }
                         }
+                        PeerEvent::RoomMigrated { room, next } => {
+                            debug!("moved to room {room:?} (next: {next:?})");
+                        }
+                        PeerEvent::IceServers(servers) => server_ice_servers = servers,
+                        PeerEvent::PeerCapabilities { peer, capabilities } => {
+                            approvals.capabilities(peer.clone(), capabilities.clone());
+                            peer_capabilities.insert(peer, capabilities);
+                        }
+                        PeerEvent::PeerName { peer, name } => approvals.named(peer, name),
+                        PeerEvent::PeerRole { peer, role } => approvals.role(peer, role),
+                        // Handled by the signalling loop
+                        PeerEvent::Error(_) | PeerEvent::RoomPolicy(_) | PeerEvent::RoomPeers(_) | PeerEvent::RoomPeersPage(_) | PeerEvent::PeerListComplete | PeerEvent::RoomMessage { .. } | PeerEvent::RoomMetadata(_) | PeerEvent::MatchmakingRegions(_) | PeerEvent::MatchFound { .. } | PeerEvent::MigrationRejected(_) | PeerEvent::RoomClosed { .. } | PeerEvent::RoomIdle { .. } | PeerEvent::SignalsThrottled { .. } | PeerEvent::IntroductionsPending(_) | PeerEvent::ServerQueuePosition(_) | PeerEvent::ExtraRoomPeers { .. } | PeerEvent::ExtraRoomMessage { .. } => {}
                     }
                 } else {
                     error!("Disconnected from signalling server!");
@@ -117,22 +295,24 @@ pub async fn message_loop(
                 }
             }
 
+            _ = &mut flush => {
+                drop(next_peer_message_out);
+                for message in coalescer.flush(peer_messages_out_rx, &channel_order) {
+                    send_to_peer(message, &data_channels, &messages_from_peers_tx, &config.channels, config.strict);
+                }
+            }
+
             message = next_peer_message_out => {
                 match message {
-                    Some((channel_index, Some((peer, packet)))) => {
-                        let data_channel = data_channels.get(&peer)
-                            .expect("couldn't find data channel for peer")
-                            .get(channel_index)
-                            .unwrap_or_else(|| panic!("couldn't find data channel with index {}", channel_index));
-
-                        if let Err(err) = data_channel.send_with_u8_array(&packet) {
-                            // This likely means the other peer disconnected
-                            // todo: we should probably remove the data channel object in this case
-                            // and try reconnecting. For now we will just stop panicking.
-                            error!("Failed to send: {err:?}");
+                    (channel_index, Some((peer, packet))) => {
+                        drop(next_peer_message_out);
+                        let messages = coalescer.collect((channel_index, peer, packet), peer_messages_out_rx, &channel_order);
+                        for message in messages {
+                            send_to_peer(message, &data_channels, &messages_from_peers_tx, &config.channels, config.strict);
                         }
+                        flush = coalescer.flush_timer();
                     },
-                    Some((_, None)) | None => {
+                    (_, None) => {
                         // Receiver end of outgoing message channel closed,
                         // which most likely means the socket was dropped.
                         // There could probably be cleaner ways to handle this,
@@ -146,18 +326,134 @@ pub async fn message_loop(
             complete => break
         }
     }
+
+    if config.flush_timeout_ms > 0 {
+        // the data channels send what they buffered before closing, so
+        // handing the queued packets to them is enough
+        let flush = async {
+            for message in coalescer.flush_all(peer_messages_out_rx, &channel_order) {
+                send_to_peer(
+                    message,
+                    &data_channels,
+                    &messages_from_peers_tx,
+                    &config.channels,
+                    config.strict,
+                );
+            }
+        };
+        let timeout = Delay::new(Duration::from_millis(config.flush_timeout_ms));
+        select! {
+            _ = flush.fuse() => debug!("flushed queued packets"),
+            _ = timeout.fuse() => warn!("gave up flushing queued packets"),
+        }
+    }
     debug!("Message loop finished");
+    Ok(())
+}
+
+/// Sends an outgoing message on the data channel of its peer, or drops it if
+/// the peer isn't connected
+fn send_to_peer(
+    (channel_index, peer, packet): (usize, PeerId, PooledPacket),
+    data_channels: &HashMap<PeerId, Vec<Option<RtcDataChannel>>>,
+    messages_from_peers_tx: &[IncomingSender],
+    channels: &[ChannelConfig],
+    strict: bool,
+) {
+    let data_channel = match data_channels.get(&peer) {
+        Some(data_channels) => data_channels,
+        None => {
+            warn!("dropping packet for disconnected peer {peer:?}");
+            messages_from_peers_tx[channel_index].dropped(&peer, packet);
+            return;
+        }
+    };
+    let Some(data_channel) = data_channel.get(channel_index) else {
+        panic_if_strict(
+            strict,
+            format_args!("couldn't find data channel with index {}", channel_index),
+        );
+        return;
+    };
+    let data_channel = match data_channel {
+        Some(data_channel) => data_channel,
+        None => {
+            // raced with the socket learning that the channel isn't open
+            messages_from_peers_tx[channel_index].dropped(&peer, packet);
+            return;
+        }
+    };
+
+    let sent = match std::str::from_utf8(&packet) {
+        Ok(text) if channels[channel_index].text => data_channel.send_with_str(text),
+        _ => data_channel.send_with_u8_array(&packet),
+    };
+    if let Err(err) = sent {
+        // This likely means the other peer disconnected
+        // todo: we should probably remove the data channel object in this case
+        // and try reconnecting. For now we will just stop panicking.
+        error!("Failed to send: {err:?}");
+        messages_from_peers_tx[channel_index].send_failed(&peer, packet);
+    }
+}
+
+type HandshakeResult =
+    Result<(PeerId, Vec<Option<RtcDataChannel>>, ConnectionInfo), Box<dyn std::error::Error>>;
+
+fn handshake_finished(
+    reconnector: &Reconnector,
+    data_channels: &mut HashMap<PeerId, Vec<Option<RtcDataChannel>>>,
+    attempt: AttemptReporter,
+    res: HandshakeResult,
+) {
+    match res {
+        // A handshake we already gave up on may still complete, ignore it
+        Ok((peer, channels, info)) if reconnector.is_current(&attempt) => {
+            data_channels.insert(peer, channels);
+            attempt.connected(info);
+        }
+        Ok(_) => {}
+        Err(err) => {
+            warn!("handshake with {:?} failed: {err}", attempt.peer());
+            attempt.failed();
+        }
+    }
 }
 
 async fn handshake_offer(
+    signal_peer: SignalPeer,
+    signal_receiver: UnboundedReceiver<PeerSignal>,
+    messages_from_peers_tx: Vec<IncomingSender>,
+    config: &WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    attempt: AttemptReporter,
+) -> (AttemptReporter, HandshakeResult) {
+    let res = try_handshake_offer(
+        signal_peer,
+        signal_receiver,
+        messages_from_peers_tx,
+        config,
+        server_ice_servers,
+        open_channels,
+        attempt.clone(),
+    )
+    .await;
+    (attempt, res)
+}
+
+async fn try_handshake_offer(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    messages_from_peers_tx: Vec<IncomingSender>,
     config: &WebRtcSocketConfig,
-) -> Result<(PeerId, Vec<RtcDataChannel>), Box<dyn std::error::Error>> {
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    attempt: AttemptReporter,
+) -> HandshakeResult {
     debug!("making offer");
 
-    let conn = create_rtc_peer_connection(config);
+    let conn = create_rtc_peer_connection(config, &server_ice_servers, attempt);
     let (channel_ready_tx, mut wait_for_channels) = create_data_channels_ready_fut(config);
 
     let data_channels = create_data_channels(
@@ -165,7 +461,8 @@ async fn handshake_offer(
         messages_from_peers_tx,
         signal_peer.id.clone(),
         channel_ready_tx,
-        &config.channels,
+        config,
+        &open_channels,
     );
 
     // Create offer
@@ -212,6 +509,8 @@ async fn handshake_offer(
         };
     };
 
+    verify_remote_fingerprint(config, &signal_peer.id, &sdp)?;
+
     // Set remote description
     let mut remote_description = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
     remote_description.sdp(&sdp);
@@ -222,6 +521,7 @@ async fn handshake_offer(
 
     // send ICE candidates to remote peer
     let signal_peer_ice = signal_peer.clone();
+    let preference = config.candidate_preference;
     let onicecandidate: Box<dyn FnMut(RtcPeerConnectionIceEvent)> = Box::new(
         move |event: RtcPeerConnectionIceEvent| {
             let candidate_json = match event.candidate() {
@@ -234,6 +534,7 @@ async fn handshake_offer(
                     "null".to_string()
                 }
             };
+            let candidate_json = preference.apply_to_json(&candidate_json);
 
             debug!("sending IceCandidate signal: {candidate_json:?}");
             signal_peer_ice.send(PeerSignal::IceCandidate(candidate_json));
@@ -247,21 +548,22 @@ async fn handshake_offer(
     // handle pending ICE candidates
     for candidate in received_candidates {
         debug!("offerer: adding ice candidate {candidate:?}");
-        try_add_rtc_ice_candidate(&conn, &candidate).await;
+        try_add_rtc_ice_candidate(&conn, &candidate, preference).await;
     }
 
     // select for channel ready or ice candidates
     debug!("waiting for data channels to open");
     loop {
         select! {
-            _ = wait_for_channels => {
+            ready = wait_for_channels => {
+                ready?;
                 debug!("channel ready");
                 break;
             }
             msg = signal_receiver.next() => {
                 if let Some(PeerSignal::IceCandidate(candidate)) = msg {
                     debug!("offerer: received ice candidate {candidate:?}");
-                    try_add_rtc_ice_candidate(&conn, &candidate).await;
+                    try_add_rtc_ice_candidate(&conn, &candidate, preference).await;
                 }
             }
         };
@@ -284,11 +586,17 @@ async fn handshake_offer(
         conn.ice_gathering_state()
     );
 
-    Ok((signal_peer.id, data_channels))
+    let info = connection_info(&conn, &data_channels);
+    Ok((signal_peer.id, data_channels, info))
 }
 
-async fn try_add_rtc_ice_candidate(connection: &RtcPeerConnection, candidate_string: &str) {
-    let parsed_candidate = match js_sys::JSON::parse(candidate_string) {
+async fn try_add_rtc_ice_candidate(
+    connection: &RtcPeerConnection,
+    candidate_string: &str,
+    preference: CandidatePreference,
+) {
+    let candidate_string = preference.apply_to_json(candidate_string);
+    let parsed_candidate = match js_sys::JSON::parse(&candidate_string) {
         Ok(c) => c,
         Err(err) => {
             error!("failed to parse candidate json: {err:?}");
@@ -311,21 +619,47 @@ async fn try_add_rtc_ice_candidate(connection: &RtcPeerConnection, candidate_str
 }
 
 async fn handshake_accept(
+    signal_peer: SignalPeer,
+    signal_receiver: UnboundedReceiver<PeerSignal>,
+    messages_from_peers_tx: Vec<IncomingSender>,
+    config: &WebRtcSocketConfig,
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    attempt: AttemptReporter,
+) -> (AttemptReporter, HandshakeResult) {
+    let res = try_handshake_accept(
+        signal_peer,
+        signal_receiver,
+        messages_from_peers_tx,
+        config,
+        server_ice_servers,
+        open_channels,
+        attempt.clone(),
+    )
+    .await;
+    (attempt, res)
+}
+
+async fn try_handshake_accept(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    messages_from_peers_tx: Vec<IncomingSender>,
     config: &WebRtcSocketConfig,
-) -> Result<(PeerId, Vec<RtcDataChannel>), Box<dyn std::error::Error>> {
+    server_ice_servers: Vec<RtcIceServerConfig>,
+    open_channels: Vec<bool>,
+    attempt: AttemptReporter,
+) -> HandshakeResult {
     debug!("handshake_accept");
 
-    let conn = create_rtc_peer_connection(config);
+    let conn = create_rtc_peer_connection(config, &server_ice_servers, attempt);
     let (channel_ready_tx, mut wait_for_channels) = create_data_channels_ready_fut(config);
     let data_channels = create_data_channels(
         conn.clone(),
         messages_from_peers_tx,
         signal_peer.id.clone(),
         channel_ready_tx,
-        &config.channels,
+        config,
+        &open_channels,
     );
 
     let mut received_candidates = vec![];
@@ -350,6 +684,7 @@ async fn handshake_accept(
         }
     };
     debug!("received offer");
+    verify_remote_fingerprint(config, &signal_peer.id, &offer)?;
 
     // Set remote description
     {
@@ -392,6 +727,7 @@ async fn handshake_accept(
 
     // send ICE candidates to remote peer
     let signal_peer_ice = signal_peer.clone();
+    let preference = config.candidate_preference;
     // todo: exactly the same as offer, dedup?
     let onicecandidate: Box<dyn FnMut(RtcPeerConnectionIceEvent)> = Box::new(
         move |event: RtcPeerConnectionIceEvent| {
@@ -405,6 +741,7 @@ async fn handshake_accept(
                     "null".to_string()
                 }
             };
+            let candidate_json = preference.apply_to_json(&candidate_json);
 
             debug!("sending IceCandidate signal: {candidate_json:?}");
             signal_peer_ice.send(PeerSignal::IceCandidate(candidate_json));
@@ -418,21 +755,22 @@ async fn handshake_accept(
     // handle pending ICE candidates
     for candidate in received_candidates {
         debug!("accepter: adding ice candidate {candidate:?}");
-        try_add_rtc_ice_candidate(&conn, &candidate).await;
+        try_add_rtc_ice_candidate(&conn, &candidate, preference).await;
     }
 
     // select for channel ready or ice candidates
     debug!("waiting for data channel to open");
     loop {
         select! {
-            _ = wait_for_channels => {
+            ready = wait_for_channels => {
+                ready?;
                 debug!("channel ready");
                 break;
             }
             msg = signal_receiver.next() => {
                 if let Some(PeerSignal::IceCandidate(candidate)) = msg {
                     debug!("accepter: received ice candidate: {candidate:?}");
-                    try_add_rtc_ice_candidate(&conn, &candidate).await;
+                    try_add_rtc_ice_candidate(&conn, &candidate, preference).await;
                 }
             }
         };
@@ -455,10 +793,21 @@ async fn handshake_accept(
         conn.ice_gathering_state()
     );
 
-    Ok((signal_peer.id, data_channels))
+    let info = connection_info(&conn, &data_channels);
+    Ok((signal_peer.id, data_channels, info))
 }
 
-fn create_rtc_peer_connection(config: &WebRtcSocketConfig) -> RtcPeerConnection {
+/// Browsers generate and manage the DTLS certificate themselves, so there's
+/// nothing to load or report
+pub(crate) fn local_certificate(_pem: Option<&str>) -> Result<Option<LocalCertificate>, Error> {
+    Ok(None)
+}
+
+fn create_rtc_peer_connection(
+    config: &WebRtcSocketConfig,
+    server_ice_servers: &[RtcIceServerConfig],
+    attempt: AttemptReporter,
+) -> RtcPeerConnection {
     #[derive(Serialize)]
     struct IceServerConfig {
         urls: Vec<String>,
@@ -467,22 +816,24 @@ fn create_rtc_peer_connection(config: &WebRtcSocketConfig) -> RtcPeerConnection
     }
 
     let mut peer_config = RtcConfiguration::new();
-    let ice_server = &config.ice_server;
-    let ice_server_config = IceServerConfig {
-        urls: ice_server.urls.clone(),
-        username: ice_server.username.clone().unwrap_or_default(),
-        credential: ice_server.credential.clone().unwrap_or_default(),
-    };
-    let ice_server_config_list = [ice_server_config];
+    let ice_server_config_list: Vec<_> = std::iter::once(&config.ice_server)
+        .chain(server_ice_servers)
+        .map(|ice_server| IceServerConfig {
+            urls: ice_server.urls.clone(),
+            username: ice_server.username.clone().unwrap_or_default(),
+            credential: ice_server.credential.clone().unwrap_or_default(),
+        })
+        .collect();
     peer_config.ice_servers(&serde_wasm_bindgen::to_value(&ice_server_config_list).unwrap());
     let connection = RtcPeerConnection::new_with_configuration(&peer_config).unwrap();
 
     let connection_1 = connection.clone();
     let oniceconnectionstatechange: Box<dyn FnMut(_)> = Box::new(move |_event: JsValue| {
-        debug!(
-            "ice connection state changed: {:?}",
-            connection_1.ice_connection_state()
-        );
+        let state = connection_1.ice_connection_state();
+        debug!("ice connection state changed: {:?}", state);
+        if state == RtcIceConnectionState::Failed {
+            attempt.failed();
+        }
     });
     let oniceconnectionstatechange = Closure::wrap(oniceconnectionstatechange);
     // NOTE: Not attaching a handler on this event causes FF to disconnect after a couple of seconds
@@ -519,37 +870,72 @@ async fn wait_for_ice_gathering_complete(conn: RtcPeerConnection) {
     debug!("Ice gathering completed");
 }
 
+/// Reads back the parameters the connection ended up with
+fn connection_info(
+    conn: &RtcPeerConnection,
+    data_channels: &[Option<RtcDataChannel>],
+) -> ConnectionInfo {
+    let max_message_size = match conn.remote_description() {
+        Some(description) => sdp_max_message_size(&description.sdp()),
+        None => None,
+    };
+    let channels = data_channels
+        .iter()
+        .map(|channel| {
+            channel.as_ref().map(|channel| ChannelInfo {
+                ordered: channel.ordered(),
+                max_retransmits: channel.max_retransmits(),
+            })
+        })
+        .collect();
+    ConnectionInfo {
+        max_message_size,
+        channels,
+    }
+}
+
 fn create_data_channels(
     connection: RtcPeerConnection,
-    mut incoming_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+    mut incoming_tx: Vec<IncomingSender>,
     peer_id: PeerId,
     mut channel_ready: Vec<futures_channel::mpsc::Sender<u8>>,
-    channel_config: &[ChannelConfig],
-) -> Vec<RtcDataChannel> {
-    channel_config
+    config: &WebRtcSocketConfig,
+    open_channels: &[bool],
+) -> Vec<Option<RtcDataChannel>> {
+    config
+        .channels
         .iter()
         .enumerate()
         .map(|(i, channel)| {
-            create_data_channel(
+            let mut channel_ready = channel_ready.pop().unwrap();
+            if !open_channels[i] {
+                // nothing to wait for
+                channel_ready.try_send(1).unwrap();
+                return None;
+            }
+            Some(create_data_channel(
                 connection.clone(),
                 incoming_tx.get_mut(i).unwrap().clone(),
                 peer_id.clone(),
-                channel_ready.pop().unwrap(),
+                channel_ready,
                 channel,
+                config,
                 i,
-            )
+            ))
         })
         .collect()
 }
 
 fn create_data_channel(
     connection: RtcPeerConnection,
-    incoming_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
+    incoming_tx: IncomingSender,
     peer_id: PeerId,
     mut channel_open: futures_channel::mpsc::Sender<u8>,
     channel_config: &ChannelConfig,
+    config: &WebRtcSocketConfig,
     channel_id: usize,
 ) -> RtcDataChannel {
+    let coalesce = channel_config.coalesce;
     let mut data_channel_config = data_channel_config(channel_config);
     data_channel_config.id(channel_id as u16);
 
@@ -558,32 +944,58 @@ fn create_data_channel(
         &data_channel_config,
     );
 
-    channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+    channel.set_binary_type(match config.binary_type {
+        BinaryType::ArrayBuffer => RtcDataChannelType::Arraybuffer,
+        BinaryType::Blob => RtcDataChannelType::Blob,
+    });
+    let batch_window = Some(config.receive_batch_ms)
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis);
+    // blobs are read asynchronously, and batches handed over later, so those
+    // go through a task that keeps the messages in order
+    let received_tx = if config.binary_type == BinaryType::Blob || batch_window.is_some() {
+        let (received_tx, received_rx) = futures_channel::mpsc::unbounded();
+        wasm_bindgen_futures::spawn_local(receive_messages(
+            received_rx,
+            incoming_tx.clone(),
+            peer_id.clone(),
+            coalesce,
+            batch_window,
+        ));
+        Some(received_tx)
+    } else {
+        None
+    };
 
+    let strict = config.strict;
     leaking_channel_event_handler(
         |f| channel.set_onopen(f),
         move |_: JsValue| {
             debug!("Rtc data channel opened :D :D");
-            channel_open
-                .try_send(1)
-                .expect("failed to notify about open connection");
+            if let Err(e) = channel_open.try_send(1) {
+                panic_if_strict(
+                    strict,
+                    format_args!("failed to notify about open connection: {e}"),
+                );
+            }
         },
     );
 
-    leaking_channel_event_handler(
-        |f| channel.set_onmessage(f),
+    leaking_channel_event_handler(|f| channel.set_onmessage(f), {
+        let received_tx = received_tx.clone();
         move |event: MessageEvent| {
             debug!("incoming {:?}", event);
-            if let Ok(arraybuf) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
+            if let Some(received_tx) = &received_tx {
+                let _ = received_tx.unbounded_send(event.data());
+            } else if let Some(text) = event.data().as_string() {
+                incoming_tx.send(&peer_id, text.as_bytes(), coalesce);
+            } else if let Ok(arraybuf) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
                 let uarray = js_sys::Uint8Array::new(&arraybuf);
                 let body = uarray.to_vec();
-
-                incoming_tx
-                    .unbounded_send((peer_id.clone(), body.into_boxed_slice()))
-                    .unwrap();
+                incoming_tx.send(&peer_id, &body, coalesce);
             }
-        },
-    );
+        }
+    });
 
     leaking_channel_event_handler(
         |f| channel.set_onerror(f),
@@ -596,12 +1008,62 @@ fn create_data_channel(
         |f| channel.set_onclose(f),
         move |event: Event| {
             warn!("Channel closed: {:?}", event);
+            if let Some(received_tx) = &received_tx {
+                // lets the task finish once it handed over what's left
+                received_tx.close_channel();
+            }
         },
     );
 
     channel
 }
 
+/// Hands the messages received on a data channel to the socket, in order,
+/// reading blobs and collecting batches as configured
+async fn receive_messages(
+    mut received_rx: UnboundedReceiver<JsValue>,
+    incoming_tx: IncomingSender,
+    peer_id: PeerId,
+    coalesce: bool,
+    batch_window: Option<Duration>,
+) {
+    while let Some(data) = received_rx.next().await {
+        let mut batch = vec![data];
+        if let Some(window) = batch_window {
+            Delay::new(window).await;
+            batch.extend(std::iter::from_fn(|| received_rx.try_next().ok().flatten()));
+        }
+        for data in batch {
+            if let Some(body) = read_message(data).await {
+                incoming_tx.send(&peer_id, &body, coalesce);
+            }
+        }
+    }
+}
+
+/// Copies the contents of a received message, reading it first if it's a
+/// blob, see [`BinaryType::Blob`]
+///
+/// String messages, see [`ChannelConfig::text`], are copied as their UTF-8
+/// bytes.
+async fn read_message(data: JsValue) -> Option<Vec<u8>> {
+    if let Some(text) = data.as_string() {
+        return Some(text.into_bytes());
+    }
+    let buffer = match data.dyn_into::<Blob>() {
+        Ok(blob) => match JsFuture::from(blob.array_buffer()).await {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                error!("failed to read received blob: {e:?}");
+                return None;
+            }
+        },
+        Err(data) => data,
+    };
+    let buffer = buffer.dyn_into::<js_sys::ArrayBuffer>().ok()?;
+    Some(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
 /// Note that this fuction leaks some memory because the rust closure is dropped but still needs to be accessed by javascript of the browser
 ///
 /// See also: https://rustwasm.github.io/wasm-bindgen/api/wasm_bindgen/closure/struct.Closure.html#method.into_js_value
@@ -626,13 +1088,14 @@ fn data_channel_config(channel_config: &ChannelConfig) -> RtcDataChannelInit {
         data_channel_config.max_retransmits(n);
     }
 
-    data_channel_config
-}
+    data_channel_config.priority(match channel_config.priority {
+        ChannelPriority::VeryLow => RtcPriorityType::VeryLow,
+        ChannelPriority::Low => RtcPriorityType::Low,
+        ChannelPriority::Medium => RtcPriorityType::Medium,
+        ChannelPriority::High => RtcPriorityType::High,
+    });
 
-// Expect/unwrap is broken in select for some reason :/
-fn check(res: &Result<(PeerId, Vec<RtcDataChannel>), Box<dyn std::error::Error>>) {
-    // but doing it inside a typed function works fine
-    res.as_ref().expect("handshake failed");
+    data_channel_config
 }
 
 // The bellow is just to wrap Result<JsValue, JsValue> into something sensible-ish
@@ -1,42 +1,184 @@
 use crate::webrtc_socket::messages::*;
+use crate::webrtc_socket::{parse_event, room_url_on_same_server, with_timeout};
+use crate::{Error, SignallingError};
 use futures::{SinkExt, StreamExt};
 use futures_util::select;
-use log::{debug, error};
-use ws_stream_wasm::{WsMessage, WsMeta};
+use js_sys::Reflect;
+use log::{debug, error, warn};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use wasm_bindgen::JsValue;
+use ws_stream_wasm::{WsMessage, WsMeta, WsStream};
 
+/// The websocket is closed when its metadata is dropped, so it's kept around
+pub type SignallingConnection = (WsMeta, WsStream);
+
+/// Whether we run on a page served over https, where browsers block `ws://`
+/// urls as mixed content
+///
+/// Looks at `location`, so it works in workers as well as windows.
+pub fn page_is_secure() -> bool {
+    let protocol = Reflect::get(&js_sys::global(), &JsValue::from_str("location"))
+        .and_then(|location| Reflect::get(&location, &JsValue::from_str("protocol")))
+        .ok()
+        .and_then(|protocol| protocol.as_string());
+    protocol.as_deref() == Some("https:")
+}
+
+/// Opens the websocket to the signalling server
+pub async fn signalling_connect(
+    room_url: &str,
+    timeout_ms: u64,
+) -> Result<SignallingConnection, Error> {
+    with_timeout(
+        WsMeta::connect(room_url, None),
+        timeout_ms,
+        Error::SignallingTimeout,
+    )
+    .await?
+    .map_err(|e| Error::SignallingConnection(e.to_string()))
+}
+
+/// Forwards requests and events until the socket is dropped, the room is
+/// closed or the connection is lost
+///
+/// Resolves with who closed the room, if it was closed.
+/// Sends the `registration` requests first, and adds new ones to it, so they
+/// can be repeated after reconnecting. Likewise, `room_url` is pointed at the
+/// new room when ours is migrated.
 pub async fn signalling_loop(
-    room_url: String,
-    mut requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    (_ws, wsio): SignallingConnection,
+    registration: &mut Vec<PeerRequest>,
+    room_url: &mut String,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
     events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
-) {
-    let (_ws, wsio) = WsMeta::connect(&room_url, None)
-        .await
-        .expect("failed to connect to signalling server");
-
+    room_tx: futures_channel::mpsc::UnboundedSender<RoomUpdate>,
+    paused: &AtomicBool,
+) -> Result<Option<RoomClosedBy>, Error> {
     let mut wsio = wsio.fuse();
+    for request in registration.iter() {
+        let request = serde_json::to_string(request).expect("serializing request");
+        debug!("-> {}", request);
+        wsio.send(WsMessage::Text(request))
+            .await
+            .map_err(|e| Error::SignallingConnection(e.to_string()))?;
+    }
 
     loop {
         select! {
             request = requests_receiver.next() => {
+                let request = match request {
+                    Some(request) => request,
+                    // the socket was dropped
+                    None => break,
+                };
+                if request == PeerRequest::KeepAlive && paused.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if request.is_registration() {
+                    registration.push(request.clone());
+                }
+                if let PeerRequest::LeaveExtraRoom(room) = &request {
+                    // reconnects don't have to join it again
+                    registration.retain(|r| !matches!(r, PeerRequest::JoinExtraRoom(joined) if joined == room));
+                    let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomLeft(Some(room.clone())));
+                }
                 let request = serde_json::to_string(&request).expect("serializing request");
                 debug!("-> {}", request);
-                wsio.send(WsMessage::Text(request)).await.expect("request send error");
+                wsio.send(WsMessage::Text(request)).await.map_err(|e| Error::SignallingConnection(e.to_string()))?;
             }
 
             message = wsio.next() => {
                 match message {
                     Some(WsMessage::Text(message)) => {
                         debug!("{}", message);
-                        let event: PeerEvent = serde_json::from_str(&message)
-                            .unwrap_or_else(|_| panic!("couldn't parse peer event {}", message));
-                        events_sender.unbounded_send(event).unwrap();
+                        let Some(event) = parse_event(&message)? else {
+                            continue;
+                        };
+                        match event {
+                            PeerEvent::Error(code) => return Err(SignallingError::from(code).into()),
+                            PeerEvent::RoomClosed { host } => return Ok(Some(host.into())),
+                            PeerEvent::PeerName { peer, name } => {
+                                // the socket may have been dropped, that's fine
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerName { peer: peer.clone(), name: name.clone() });
+                                // the message loop asks whether to connect to the peer by it
+                                events_sender.unbounded_send(PeerEvent::PeerName { peer, name }).unwrap();
+                            }
+                            PeerEvent::PeerRole { peer, role } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerRole { peer: peer.clone(), role });
+                                events_sender.unbounded_send(PeerEvent::PeerRole { peer, role }).unwrap();
+                            }
+                            PeerEvent::PeerCapabilities { peer, capabilities } => {
+                                // the message loop picks the channels to open with the peer by them
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerCapabilities { peer: peer.clone(), capabilities: capabilities.clone() });
+                                events_sender.unbounded_send(PeerEvent::PeerCapabilities { peer, capabilities }).unwrap();
+                            }
+                            PeerEvent::RoomPolicy(info) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Info(Some(info)));
+                            }
+                            PeerEvent::RoomPeers(peers) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Peers(peers));
+                            }
+                            PeerEvent::RoomPeersPage(peers) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeersPage(peers));
+                            }
+                            PeerEvent::PeerListComplete => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::PeerListComplete);
+                            }
+                            PeerEvent::RoomMessage { sender, data } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Message { sender, data });
+                            }
+                            PeerEvent::RoomMetadata(metadata) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::Metadata(Some(metadata)));
+                            }
+                            PeerEvent::MatchmakingRegions(regions) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::MatchmakingRegions(regions.clone()));
+                                events_sender.unbounded_send(PeerEvent::MatchmakingRegions(regions)).unwrap();
+                            }
+                            PeerEvent::RoomMigrated { room, next } => {
+                                // reconnects have to register in the new room
+                                *room_url = room_url_on_same_server(room_url, &room, next);
+                                let _ = room_tx.unbounded_send(RoomUpdate::SignallingUrl(room_url.clone()));
+                                // the new room may not have any rules or metadata
+                                let _ = room_tx.unbounded_send(RoomUpdate::Info(None));
+                                let _ = room_tx.unbounded_send(RoomUpdate::Metadata(None));
+                                let _ = room_tx.unbounded_send(RoomUpdate::Group { next, queued: false });
+                                let _ = room_tx.unbounded_send(RoomUpdate::IntroductionsPending(0));
+                                events_sender.unbounded_send(PeerEvent::RoomMigrated { room, next }).unwrap();
+                            }
+                            PeerEvent::IntroductionsPending(pending) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::IntroductionsPending(pending));
+                            }
+                            PeerEvent::ServerQueuePosition(position) => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ServerQueuePosition(position));
+                            }
+                            PeerEvent::ExtraRoomPeers { room, peers } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomPeers { room, peers });
+                            }
+                            PeerEvent::ExtraRoomMessage { room, sender, data } => {
+                                let _ = room_tx.unbounded_send(RoomUpdate::ExtraRoomMessage { room, sender, data });
+                            }
+                            PeerEvent::RoomIdle { closes_in_secs } => {
+                                let closes_in = Duration::from_secs(closes_in_secs);
+                                let _ = room_tx.unbounded_send(RoomUpdate::IdleWarning(closes_in));
+                            }
+                            PeerEvent::MigrationRejected(code) => {
+                                warn!("the room wasn't migrated: {}", SignallingError::from(code));
+                            }
+                            PeerEvent::SignalsThrottled { receiver } => {
+                                warn!("the signalling server is dropping our signals to {:?}", receiver);
+                            }
+                            event => events_sender.unbounded_send(event).unwrap(),
+                        }
                     },
                     Some(WsMessage::Binary(_)) => {
                         error!("Received binary data from signal server (expected text). Ignoring.");
                     },
                     None => {
                         error!("Disconnected from signalling server!");
-                        break;
+                        return Err(Error::SignallingConnection("closed by the server".to_string()));
                     }
                 }
             }
@@ -44,4 +186,5 @@ pub async fn signalling_loop(
             complete => break
         }
     }
+    Ok(None)
 }
@@ -0,0 +1,76 @@
+use futures_channel::mpsc::UnboundedSender;
+use log::debug;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::Document;
+
+use crate::webrtc_socket::{set_paused, RoomCommand};
+
+const EVENT: &str = "visibilitychange";
+
+thread_local! {
+    /// The JS side of the listeners, which can't leave the main thread
+    static LISTENERS: RefCell<HashMap<u64, Listener>> = RefCell::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Pauses the socket while the page is hidden, and resumes it once it's
+/// visible again, see [`WebRtcSender::pause`](crate::WebRtcSender::pause)
+///
+/// Only an id for the listener, so the socket stays `Send`. Stops listening
+/// when dropped.
+#[derive(Debug)]
+pub struct VisibilityListener {
+    id: u64,
+}
+
+impl VisibilityListener {
+    /// Starts listening, unless there's no document, e.g. in a worker
+    pub(crate) fn new(
+        paused: Arc<AtomicBool>,
+        room_commands: UnboundedSender<RoomCommand>,
+    ) -> Option<Self> {
+        let document = web_sys::window()?.document()?;
+        let page = document.clone();
+        let closure = Closure::wrap(Box::new(move || {
+            let hidden = page.hidden();
+            debug!("page visibility changed, hidden: {hidden}");
+            set_paused(&paused, &room_commands, hidden);
+        }) as Box<dyn FnMut()>);
+        document
+            .add_event_listener_with_callback(EVENT, closure.as_ref().unchecked_ref())
+            .ok()?;
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let listener = Listener { document, closure };
+        LISTENERS.with(|listeners| listeners.borrow_mut().insert(id, listener));
+        Some(Self { id })
+    }
+}
+
+impl Drop for VisibilityListener {
+    fn drop(&mut self) {
+        LISTENERS.with(|listeners| listeners.borrow_mut().remove(&self.id));
+    }
+}
+
+struct Listener {
+    document: Document,
+    closure: Closure<dyn FnMut()>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = self
+            .document
+            .remove_event_listener_with_callback(EVENT, self.closure.as_ref().unchecked_ref());
+    }
+}
@@ -0,0 +1,104 @@
+//! Checks that a socket in a browser can talk to native sockets
+//!
+//! Needs the `cross_play_server` example of `matchbox_test` running, see
+//! `matchbox_test::cross_play`:
+//!
+//! ```sh
+//! cargo run -p matchbox_test --example cross_play_server &
+//! wasm-pack test --headless --chrome matchbox_socket --features cross-play-test
+//! ```
+#![cfg(all(target_arch = "wasm32", feature = "cross-play-test"))]
+
+use std::time::Duration;
+
+use futures_timer::Delay;
+use matchbox_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketConfig};
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// Where `matchbox_test::cross_play::CROSS_PLAY_ADDR` is listening
+const SERVER_URL: &str = "ws://127.0.0.1:3536";
+
+/// How often to check whether a packet came back, and how many times
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const POLL_ATTEMPTS: usize = 1000;
+
+/// Larger than most packets, but within the message size limits of all the
+/// browsers
+const LARGE_PACKET_SIZE: usize = 8 * 1024;
+
+/// Same as `matchbox_test::cross_play::channel_configs`
+fn channel_configs() -> Vec<(&'static str, Vec<ChannelConfig>)> {
+    vec![
+        ("unreliable", vec![ChannelConfig::unreliable()]),
+        ("reliable", vec![ChannelConfig::reliable()]),
+        (
+            "ordered_unreliable",
+            vec![ChannelConfig {
+                ordered: true,
+                ..ChannelConfig::unreliable()
+            }],
+        ),
+        (
+            "limited_retransmits",
+            vec![ChannelConfig {
+                max_retransmits: Some(3),
+                ..ChannelConfig::unreliable()
+            }],
+        ),
+        (
+            "ggrs",
+            WebRtcSocketConfig::default().with_ggrs_channels().channels,
+        ),
+        (
+            "state_sync",
+            WebRtcSocketConfig::default()
+                .with_state_sync_channels()
+                .channels,
+        ),
+    ]
+}
+
+async fn receive(socket: &mut WebRtcSocket, channel: usize) -> Box<[u8]> {
+    for _ in 0..POLL_ATTEMPTS {
+        if let Some((_, packet)) = socket.receive_on_channel(channel).pop() {
+            return packet;
+        }
+        Delay::new(POLL_INTERVAL).await;
+    }
+    panic!("packet on channel {} didn't come back", channel);
+}
+
+#[wasm_bindgen_test]
+async fn native_peers_echo_every_channel() {
+    for (name, channels) in channel_configs() {
+        let channel_count = channels.len();
+        let (mut socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: format!("{SERVER_URL}/cross_play_{name}?next=2"),
+            ice_server: RtcIceServerConfig {
+                urls: vec![],
+                ..Default::default()
+            },
+            channels,
+            ..Default::default()
+        });
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = message_loop.await;
+        });
+        let peers = socket
+            .wait_for_peers(1)
+            .await
+            .expect("native peer didn't connect");
+
+        for channel in 0..channel_count {
+            let small = format!("{name} {channel}").into_bytes().into_boxed_slice();
+            let large = vec![channel as u8; LARGE_PACKET_SIZE].into_boxed_slice();
+            for packet in [small, large] {
+                socket.send_on_channel(packet.clone(), peers[0].clone(), channel);
+                let echoed = receive(&mut socket, channel).await;
+                assert_eq!(echoed, packet, "{} channel {}", name, channel);
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Throughput and round trip latency of the channel presets between two
+//! native sockets
+//!
+//! Both sockets connect through an in-process [`TestServer`], so the numbers
+//! cover the message loops and data channels over the loopback interface,
+//! not a real link. Run with `cargo bench -p matchbox_test`.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use matchbox_socket::{ChannelConfig, WebRtcSocket, WebRtcSocketConfig};
+use matchbox_test::TestServer;
+use tokio::runtime::Runtime;
+
+/// Payload sizes each channel is measured with, in bytes
+const PAYLOAD_SIZES: [usize; 4] = [16, 256, 1024, 8 * 1024];
+
+/// Packets sent per iteration of the throughput benchmarks
+const BURST: usize = 64;
+
+/// How long to wait for packets before assuming they were lost
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn presets() -> Vec<(&'static str, WebRtcSocketConfig)> {
+    vec![
+        (
+            "basic",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::unreliable(), ChannelConfig::reliable()],
+                ..Default::default()
+            },
+        ),
+        ("ggrs", WebRtcSocketConfig::default().with_ggrs_channels()),
+        (
+            "state_sync",
+            WebRtcSocketConfig::default().with_state_sync_channels(),
+        ),
+    ]
+}
+
+/// Connects two sockets with the given configuration
+///
+/// The server has to be kept around for as long as the sockets are used.
+fn connect(runtime: &Runtime, config: WebRtcSocketConfig) -> (TestServer, Vec<WebRtcSocket>) {
+    runtime.block_on(async {
+        let server = TestServer::start();
+        let mut sockets = vec![
+            server.socket_with_config("bench?next=2", config.clone()),
+            server.socket_with_config("bench?next=2", config),
+        ];
+        join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))).await;
+        (server, sockets)
+    })
+}
+
+/// Waits until `count` packets arrived on the given channel
+async fn receive(socket: &mut WebRtcSocket, channel: usize, count: usize) {
+    let deadline = Instant::now() + RECEIVE_TIMEOUT;
+    let mut received = 0;
+    while received < count {
+        received += socket.receive_on_channel(channel).len();
+        assert!(
+            Instant::now() < deadline,
+            "only {} of {} packets arrived",
+            received,
+            count
+        );
+        tokio::task::yield_now().await;
+    }
+}
+
+fn throughput(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to start runtime");
+    for (preset, config) in presets() {
+        let channels = config.channels.len();
+        let (_server, mut sockets) = connect(&runtime, config);
+        let peer = sockets[1].id().clone();
+        let mut group = c.benchmark_group(format!("throughput/{preset}"));
+        for channel in 0..channels {
+            for size in PAYLOAD_SIZES {
+                group.throughput(Throughput::Bytes((size * BURST) as u64));
+                let id = BenchmarkId::new(format!("channel_{channel}"), size);
+                group.bench_function(id, |b| {
+                    b.iter_custom(|iters| {
+                        runtime.block_on(async {
+                            let start = Instant::now();
+                            for _ in 0..iters {
+                                for _ in 0..BURST {
+                                    let packet = vec![0; size].into_boxed_slice();
+                                    sockets[0].send_on_channel(packet, peer.clone(), channel);
+                                }
+                                receive(&mut sockets[1], channel, BURST).await;
+                            }
+                            start.elapsed()
+                        })
+                    })
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+fn round_trip(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to start runtime");
+    for (preset, config) in presets() {
+        let channels = config.channels.len();
+        let (_server, mut sockets) = connect(&runtime, config);
+        let ids = [sockets[0].id().clone(), sockets[1].id().clone()];
+        let mut group = c.benchmark_group(format!("round_trip/{preset}"));
+        for channel in 0..channels {
+            for size in PAYLOAD_SIZES {
+                let id = BenchmarkId::new(format!("channel_{channel}"), size);
+                group.bench_function(id, |b| {
+                    b.iter_custom(|iters| {
+                        runtime.block_on(async {
+                            let start = Instant::now();
+                            for _ in 0..iters {
+                                let packet = vec![0; size].into_boxed_slice();
+                                sockets[0].send_on_channel(packet.clone(), ids[1].clone(), channel);
+                                receive(&mut sockets[1], channel, 1).await;
+                                sockets[1].send_on_channel(packet, ids[0].clone(), channel);
+                                receive(&mut sockets[0], channel, 1).await;
+                            }
+                            start.elapsed()
+                        })
+                    })
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, throughput, round_trip);
+criterion_main!(benches);
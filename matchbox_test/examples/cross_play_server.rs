@@ -0,0 +1,16 @@
+//! Signalling server with native echo peers for the browser cross-play test
+//!
+//! See [`matchbox_test::cross_play`].
+
+use matchbox_server::Args;
+use matchbox_test::{
+    cross_play::{serve_echo_peers, CROSS_PLAY_ADDR},
+    TestServer,
+};
+
+#[tokio::main]
+async fn main() {
+    let server = TestServer::start_on(CROSS_PLAY_ADDR, Args::default());
+    println!("serving cross-play echo peers on {}", server.addr());
+    serve_echo_peers(&server).await;
+}
@@ -0,0 +1,102 @@
+//! Native peers for checking that browsers and native sockets can talk to
+//! each other
+//!
+//! The `cross_play_server` example starts a [`TestServer`] on
+//! [`CROSS_PLAY_ADDR`] and runs [`serve_echo_peers`] on it. The wasm test in
+//! `matchbox_socket/tests/cross_play.rs` then joins the room of every
+//! configuration in [`channel_configs`] from a headless browser, and checks
+//! that everything it sends comes back:
+//!
+//! ```sh
+//! cargo run -p matchbox_test --example cross_play_server &
+//! wasm-pack test --headless --chrome matchbox_socket --features cross-play-test
+//! ```
+
+use futures::future::join_all;
+use matchbox_socket::{ChannelConfig, WebRtcSocket, WebRtcSocketConfig};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use crate::TestServer;
+
+/// Where the wasm test expects the signalling server
+pub const CROSS_PLAY_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3536));
+
+/// How often an echo peer checks for packets
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The channel configurations to check, by the name of their room
+///
+/// The wasm test keeps its own copy of this list, the two have to match.
+pub fn channel_configs() -> Vec<(&'static str, Vec<ChannelConfig>)> {
+    vec![
+        ("unreliable", vec![ChannelConfig::unreliable()]),
+        ("reliable", vec![ChannelConfig::reliable()]),
+        (
+            "ordered_unreliable",
+            vec![ChannelConfig {
+                ordered: true,
+                ..ChannelConfig::unreliable()
+            }],
+        ),
+        (
+            "limited_retransmits",
+            vec![ChannelConfig {
+                max_retransmits: Some(3),
+                ..ChannelConfig::unreliable()
+            }],
+        ),
+        (
+            "ggrs",
+            WebRtcSocketConfig::default().with_ggrs_channels().channels,
+        ),
+        (
+            "state_sync",
+            WebRtcSocketConfig::default()
+                .with_state_sync_channels()
+                .channels,
+        ),
+    ]
+}
+
+/// Keeps a native peer in the room of every configuration of
+/// [`channel_configs`] that sends back all packets it receives, on the
+/// channel they arrived on
+///
+/// Once the peer it echoes to leaves, a new one takes its place, so the
+/// wasm test can be run again. Never returns.
+pub async fn serve_echo_peers(server: &TestServer) {
+    join_all(
+        channel_configs()
+            .into_iter()
+            .map(|(name, channels)| echo_peers(server, name, channels)),
+    )
+    .await;
+}
+
+async fn echo_peers(server: &TestServer, name: &str, channels: Vec<ChannelConfig>) {
+    let room = format!("cross_play_{name}?next=2");
+    loop {
+        let mut socket = server.socket(&room, channels.clone());
+        echo(&mut socket, channels.len()).await;
+    }
+}
+
+/// Echoes packets until the socket's peer disconnected
+async fn echo(socket: &mut WebRtcSocket, channels: usize) {
+    if socket.wait_for_peers(1).await.is_err() {
+        return;
+    }
+    while !socket.connected_peers().is_empty() {
+        for channel in 0..channels {
+            for (peer, packet) in socket.receive_on_channel(channel) {
+                socket.send_on_channel(packet, peer, channel);
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        socket.accept_new_connections();
+    }
+}
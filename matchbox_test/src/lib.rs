@@ -0,0 +1,3247 @@
+//! Helpers for writing integration tests for netcode built on [`matchbox_socket`]
+//!
+//! Spins up an in-process signalling server and connects sockets to it, so
+//! a test only has to deal with the sockets:
+//!
+//! ```no_run
+//! use matchbox_socket::ChannelConfig;
+//!
+//! # async fn example() {
+//! let (_server, mut sockets) =
+//!     matchbox_test::connected_sockets(2, vec![ChannelConfig::reliable()]).await;
+//! let peer = sockets[1].id().clone();
+//! sockets[0].send(Box::new(*b"hello"), peer);
+//! # }
+//! ```
+//!
+//! Everything needs to run inside a tokio runtime.
+
+use futures::future::join_all;
+use matchbox_server::{Args, Cluster};
+use matchbox_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketConfig};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::oneshot;
+
+pub mod cross_play;
+
+/// An in-process signalling server listening on an ephemeral local port
+///
+/// The server shuts down when this is dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    next_id: AtomicUsize,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl TestServer {
+    /// Starts a signalling server with the default configuration
+    pub fn start() -> Self {
+        Self::start_with_args(Args::default())
+    }
+
+    /// Starts a signalling server with the given configuration
+    ///
+    /// [`Args::host`] is ignored, the server always listens on an ephemeral
+    /// local port. [`Args::custom_peer_ids`] is always set, for the
+    /// sequential ids of [`TestServer::socket`].
+    pub fn start_with_args(args: Args) -> Self {
+        Self::start_on(([127, 0, 0, 1], 0).into(), args)
+    }
+
+    /// Starts the given number of signalling servers, clustered so rooms
+    /// span all of them, see [`Cluster`]
+    pub fn start_cluster(size: usize) -> Vec<Self> {
+        // the instances need to know each other's urls before they start
+        let addrs: Vec<SocketAddr> = (0..size)
+            .map(|_| {
+                std::net::TcpListener::bind("127.0.0.1:0")
+                    .and_then(|listener| listener.local_addr())
+                    .expect("no free port")
+            })
+            .collect();
+        let url = |addr: &SocketAddr| format!("http://{addr}");
+        addrs
+            .iter()
+            .map(|addr| {
+                let cluster = Cluster {
+                    url: url(addr),
+                    instances: addrs.iter().filter(|a| *a != addr).map(url).collect(),
+                    secret: "test".to_string(),
+                };
+                Self::start_on(
+                    *addr,
+                    Args {
+                        cluster: Some(cluster),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Starts a signalling server with the given configuration on the given
+    /// address, e.g. a fixed one a browser can be pointed at
+    ///
+    /// [`Args::custom_peer_ids`] is always set, like for
+    /// [`TestServer::start_with_args`].
+    pub fn start_on(addr: SocketAddr, args: Args) -> Self {
+        let args = Args {
+            custom_peer_ids: true,
+            ..args
+        };
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let (addr, server) = warp::serve(matchbox_server::routes(args))
+            .bind_with_graceful_shutdown(addr, async {
+                let _ = shutdown_rx.await;
+            });
+        tokio::spawn(server);
+        Self {
+            addr,
+            next_id: AtomicUsize::new(0),
+            _shutdown: shutdown,
+        }
+    }
+
+    /// Returns the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the url of a room on this server, e.g. for `"my_game?next=2"`
+    pub fn room_url(&self, room: &str) -> String {
+        format!("ws://{}/{}", self.addr, room)
+    }
+
+    /// Creates a socket in the given room, and spawns its message loop
+    ///
+    /// Sockets get sequential ids, `"peer-0"`, `"peer-1"` and so on, in the
+    /// order they are created on this server. The socket doesn't use any ICE
+    /// servers, since all peers are local.
+    pub fn socket(&self, room: &str, channels: Vec<ChannelConfig>) -> WebRtcSocket {
+        self.socket_with_config(
+            room,
+            WebRtcSocketConfig {
+                channels,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`TestServer::socket`], but with the given id
+    pub fn socket_with_id(
+        &self,
+        room: &str,
+        id: &str,
+        channels: Vec<ChannelConfig>,
+    ) -> WebRtcSocket {
+        self.socket_with_config(
+            room,
+            WebRtcSocketConfig {
+                channels,
+                peer_id: Some(id.to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`TestServer::socket`], but with the given configuration
+    ///
+    /// The room url and ICE servers of the configuration are replaced, and a
+    /// sequential id is assigned unless the configuration sets one.
+    pub fn socket_with_config(&self, room: &str, config: WebRtcSocketConfig) -> WebRtcSocket {
+        let peer_id = config.peer_id.or_else(|| {
+            Some(format!(
+                "peer-{}",
+                self.next_id.fetch_add(1, Ordering::Relaxed)
+            ))
+        });
+        let (socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: self.room_url(room),
+            ice_server: RtcIceServerConfig {
+                urls: vec![],
+                ..Default::default()
+            },
+            peer_id,
+            ..config
+        });
+        tokio::spawn(message_loop);
+        socket
+    }
+}
+
+/// Starts a [`TestServer`] and connects the given number of sockets to it
+///
+/// The sockets are returned in the order of their ids, `"peer-0"` first.
+/// Resolves once every socket is connected to all the others.
+pub async fn connected_sockets(
+    players: usize,
+    channels: Vec<ChannelConfig>,
+) -> (TestServer, Vec<WebRtcSocket>) {
+    assert!(players > 0, "need at least one socket");
+
+    let server = TestServer::start();
+    let room = format!("test_room?next={players}");
+    let mut sockets: Vec<_> = (0..players)
+        .map(|_| server.socket(&room, channels.clone()))
+        .collect();
+
+    if players > 1 {
+        join_all(
+            sockets
+                .iter_mut()
+                .map(|socket| socket.wait_for_peers(players - 1)),
+        )
+        .await;
+    }
+
+    (server, sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{self, IoSlice, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures::future::{join, join_all};
+    use matchbox_server::{Args, Limits, Matchmaking, MatchmakingRegion, RoomRule};
+    use matchbox_socket::{
+        probe_endpoint, protocol::CHANNELS_CAPABILITY_PREFIX, select_best_endpoint, BackoffPolicy,
+        CandidatePreference, ChannelConfig, ChannelInfo, ChannelLiveness, ChannelStats, Congestion,
+        CongestionLevel, ConnectFuture, Endpoint, Error, ExtraRoomEvent, FingerprintVerifier,
+        HandshakeValidator, IncomingPackets, IncomingPeerApprover, LobbyState, Messenger,
+        MessengerConnection, MessengerError, MessengerPeer, NativeSocketConfig, PacketAction,
+        PacketDirection, PacketHook, PeerApproval, PeerHandshake, PeerRole, PeerState,
+        PlatformRelay, Recorder, RelayMessenger, RelayPacket, Replay, ResumeEvent, Room,
+        RoomClosedBy, RoomInfo, RoomMetadata, RtcIceServerConfig, SignallingError, SignallingState,
+        SocketSession, SocketSet, WebRtcSocket, WebRtcSocketConfig,
+    };
+    use tokio::time;
+
+    use crate::{connected_sockets, cross_play, TestServer};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn receive_some(socket: &mut WebRtcSocket) -> Vec<(String, Box<[u8]>)> {
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = socket.receive();
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet didn't arrive")
+    }
+
+    #[tokio::test]
+    async fn sockets_exchange_packets() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        assert_eq!(sockets[0].id(), "peer-0");
+        assert_eq!(sockets[1].id(), "peer-1");
+
+        let info = sockets[0]
+            .connection_info(&"peer-1".to_string())
+            .expect("no connection info");
+        assert_eq!(
+            info.channels,
+            vec![Some(ChannelInfo {
+                ordered: true,
+                max_retransmits: None
+            })]
+        );
+
+        sockets[0].send(Box::new(*b"hello"), "peer-1".to_string());
+
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets, vec![("peer-0".to_string(), Box::from(*b"hello"))]);
+    }
+
+    #[tokio::test]
+    async fn split_senders_send_from_other_threads() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let (sender, _receiver) = sockets.remove(0).split();
+        // keep the original, dropping every sender shuts the socket down
+        let moved = sender.clone();
+        std::thread::spawn(move || {
+            moved.send(Box::new(*b"hello"), "peer-1");
+            moved.channel(0).send(Box::new(*b"again"), "peer-1");
+        })
+        .join()
+        .expect("sending thread panicked");
+
+        let mut packets = receive_some(&mut sockets[0]).await;
+        if packets.len() < 2 {
+            packets.extend(receive_some(&mut sockets[0]).await);
+        }
+        assert_eq!(
+            packets,
+            vec![
+                ("peer-0".to_string(), Box::from(*b"hello")),
+                ("peer-0".to_string(), Box::from(*b"again")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn text_channels_carry_strings() {
+        let channel = ChannelConfig {
+            text: true,
+            ..ChannelConfig::reliable()
+        };
+        let (_server, mut sockets) =
+            time::timeout(Duration::from_secs(30), connected_sockets(2, vec![channel]))
+                .await
+                .expect("sockets didn't connect");
+
+        sockets[0].send_text("héllo 👋", "peer-1".to_string());
+        // not text, so dropped by receive_text
+        sockets[0].send(Box::new([0xff]), "peer-1".to_string());
+        sockets[0].send_text("bye", "peer-1".to_string());
+
+        let received = time::timeout(Duration::from_secs(10), async {
+            let mut received = vec![];
+            while received.len() < 2 {
+                received.extend(sockets[1].receive_text());
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            received
+        })
+        .await
+        .expect("text didn't arrive");
+        assert_eq!(
+            received,
+            vec![
+                ("peer-0".to_string(), "héllo 👋".to_string()),
+                ("peer-0".to_string(), "bye".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_stats_count_traffic() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        sockets[0].send(Box::new(*b"hello"), "peer-1".to_string());
+        receive_some(&mut sockets[1]).await;
+        sockets[0].send(Box::new(*b"gone"), "peer-9".to_string());
+
+        let sent = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let stats = sockets[0].channel_stats(0);
+                if stats.messages_dropped > 0 {
+                    return stats;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet for unknown peer wasn't dropped");
+        assert_eq!(
+            sent,
+            ChannelStats {
+                messages_sent: 2,
+                bytes_sent: 9,
+                messages_dropped: 1,
+                ..Default::default()
+            }
+        );
+        let received = sockets[1].channel_sender(0).stats();
+        assert_eq!(received.messages_received, 1);
+        assert_eq!(received.bytes_received, 5);
+    }
+
+    #[tokio::test]
+    async fn undeliverable_packets_are_dead_letters() {
+        let server = TestServer::start();
+        let mut socket = server.socket_with_config(
+            "dead_letters",
+            WebRtcSocketConfig {
+                channels: vec![
+                    ChannelConfig::reliable(),
+                    ChannelConfig {
+                        coalesce: true,
+                        ..ChannelConfig::unreliable()
+                    },
+                ],
+                dead_letters: true,
+                ..Default::default()
+            },
+        );
+        socket.send_on_channel(Box::new(*b"one"), "peer-9", 0);
+        socket.send_on_channel(Box::new(*b"two"), "peer-9", 1);
+        socket.send_on_channel(Box::new(*b"three"), "peer-9", 1);
+
+        let mut failed = vec![];
+        time::timeout(Duration::from_secs(10), async {
+            while failed.len() < 3 {
+                failed.extend(socket.failed_sends());
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packets didn't come back");
+        // coalesced batches come back as the packets that were sent
+        failed.sort();
+        let peer = "peer-9".to_string();
+        assert_eq!(
+            failed,
+            vec![
+                (peer.clone(), 0, Box::from(*b"one")),
+                (peer.clone(), 1, Box::from(*b"three")),
+                (peer, 1, Box::from(*b"two")),
+            ]
+        );
+        assert_eq!(socket.channel_stats(1).messages_dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn queued_packets_are_flushed_when_the_socket_is_dropped() {
+        let server = TestServer::start();
+        let config = WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            flush_timeout_ms: 5000,
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("flush?next=2", config.clone()),
+            server.socket_with_config("flush?next=2", config),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let [mut sender, mut receiver] = sockets;
+
+        for i in 0..50u8 {
+            sender.send(Box::new([i]), receiver.id().clone());
+        }
+        drop(sender);
+
+        let mut received = vec![];
+        time::timeout(Duration::from_secs(10), async {
+            while received.len() < 50 {
+                received.extend(receiver.receive().into_iter().map(|(_, packet)| packet[0]));
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("queued packets were lost");
+        assert_eq!(received, (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn vectored_packet_arrives_joined() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let header = [1, 2];
+        let payload = b"payload";
+        sockets[0].channel_sender(0).send_vectored(
+            &[IoSlice::new(&header), IoSlice::new(payload)],
+            "peer-1".to_string(),
+        );
+
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(&*packets[0].1, b"\x01\x02payload");
+    }
+
+    #[tokio::test]
+    async fn coalesced_packets_arrive_separately() {
+        let channel = ChannelConfig {
+            coalesce: true,
+            ..ChannelConfig::reliable()
+        };
+        let (_server, mut sockets) =
+            time::timeout(Duration::from_secs(30), connected_sockets(2, vec![channel]))
+                .await
+                .expect("sockets didn't connect");
+
+        let sent: Vec<Box<[u8]>> = (0..100u8).map(|i| Box::from([i; 3])).collect();
+        for packet in &sent {
+            sockets[0].send(packet.clone(), "peer-1".to_string());
+        }
+
+        let mut received = vec![];
+        while received.len() < sent.len() {
+            received.extend(
+                receive_some(&mut sockets[1])
+                    .await
+                    .into_iter()
+                    .map(|(_, packet)| packet),
+            );
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn requests_get_their_responses() {
+        let server = TestServer::start();
+        let config = WebRtcSocketConfig {
+            channels: vec![ChannelConfig {
+                requests: true,
+                ..ChannelConfig::reliable()
+            }],
+            request_timeout_ms: 500,
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("requests?next=2", config.clone()),
+            server.socket_with_config("requests?next=2", config),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let channel = sockets[0].channel_sender(0);
+        let response = channel.request(Box::new(*b"ready?"), "peer-1");
+        channel.send(Box::new(*b"plain"), "peer-1");
+
+        let requests = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let requests = sockets[1].receive_requests_on_channel(0);
+                if !requests.is_empty() {
+                    return requests;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("request didn't arrive");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].peer, "peer-0");
+        assert_eq!(&*requests[0].data, b"ready?");
+        sockets[1]
+            .respond(&requests[0], Box::new(*b"ok"))
+            .expect("respond failed");
+        assert_eq!(&*response.await.expect("request failed"), b"ok");
+
+        // other packets on the channel arrive as usual
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets, vec![("peer-0".to_string(), Box::from(*b"plain"))]);
+
+        // a request nobody answers times out
+        let unanswered = channel.request(Box::new(*b"anyone?"), "peer-1").await;
+        assert!(matches!(
+            unanswered,
+            Err(Error::RequestTimeout { channel: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_show_peers_and_queued_packets() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+        sockets[0].accept_new_connections();
+        let diagnostics = sockets[0].diagnostics();
+        assert_eq!(diagnostics.signalling, SignallingState::Connected);
+        assert_eq!(diagnostics.connected_peers, 1);
+        assert_eq!(diagnostics.connecting_peers, 0);
+        assert_eq!(diagnostics.reconnecting_peers, 0);
+        assert_eq!(diagnostics.channels.len(), 1);
+
+        // nothing takes packets off the queue without a running message loop
+        let (mut socket, _message_loop) = WebRtcSocket::new("ws://localhost:1/room");
+        socket.send(Box::new(*b"one"), "peer-1".to_string());
+        socket.send(Box::new(*b"two"), "peer-1".to_string());
+        let diagnostics = socket.diagnostics();
+        assert_eq!(diagnostics.queued(), 2);
+        assert_eq!(diagnostics.connected_peers, 0);
+    }
+
+    #[tokio::test]
+    async fn ticked_packets_go_out_together() {
+        let server = TestServer::start();
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| {
+                server.socket_with_config(
+                    "ticked?next=2",
+                    WebRtcSocketConfig {
+                        channels: vec![ChannelConfig {
+                            coalesce: true,
+                            ..ChannelConfig::reliable()
+                        }],
+                        send_tick_ms: 1000,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let receiver = sockets[1].id().clone();
+        let sent: Vec<Box<[u8]>> = (0..10u8).map(|i| Box::from([i; 3])).collect();
+        for packet in &sent {
+            sockets[0].send(packet.clone(), receiver.clone());
+        }
+        // held back until the next tick
+        assert!(sockets[0].channel_stats(0).queued > 0);
+
+        let received: Vec<_> = receive_some(&mut sockets[1])
+            .await
+            .into_iter()
+            .map(|(_, packet)| packet)
+            .collect();
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn held_back_packets_dont_stall_the_message_loop() {
+        let server = TestServer::start();
+        let mut held = server.socket_with_config(
+            "held?next=2",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                send_batch_delay_ms: 10_000,
+                ..Default::default()
+            },
+        );
+        // starts a burst that isn't sent for a while
+        held.send(Box::new(*b"early"), "nobody".to_string());
+        let mut other = server.socket("held?next=2", vec![ChannelConfig::reliable()]);
+        time::timeout(
+            Duration::from_secs(5),
+            join_all([held.wait_for_peers(1), other.wait_for_peers(1)]),
+        )
+        .await
+        .expect("connecting waited for the batch delay");
+        assert_eq!(held.channel_stats(0).queued, 1);
+    }
+
+    #[tokio::test]
+    async fn batched_packets_arrive_in_order() {
+        let server = TestServer::start();
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| {
+                server.socket_with_config(
+                    "batched?next=2",
+                    WebRtcSocketConfig {
+                        channels: vec![ChannelConfig::reliable()],
+                        send_batch_delay_ms: 5,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let receiver = sockets[1].id().clone();
+        let sent: Vec<Box<[u8]>> = (0..20u8).map(|i| Box::from([i; 3])).collect();
+        for packet in &sent {
+            sockets[0].send(packet.clone(), receiver.clone());
+        }
+
+        let mut received = vec![];
+        while received.len() < sent.len() {
+            received.extend(
+                receive_some(&mut sockets[1])
+                    .await
+                    .into_iter()
+                    .map(|(_, packet)| packet),
+            );
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn handshake_limit_still_connects_everyone() {
+        let server = TestServer::start();
+        let mut sockets: Vec<_> = (0..4)
+            .map(|_| {
+                server.socket_with_config(
+                    "test_room?next=4",
+                    WebRtcSocketConfig {
+                        max_concurrent_handshakes: 1,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(3))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        for socket in &sockets {
+            for peer in socket.connected_peers() {
+                assert_eq!(socket.peer_state(&peer), Some(PeerState::Connected));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_limit_queues_incoming_offers() {
+        let server = TestServer::start();
+        let mut sockets: Vec<_> = (0..4)
+            .map(|_| server.socket("test_room", vec![ChannelConfig::reliable()]))
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(3))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        // everyone else offers to the newcomer at once
+        let mut newcomer = server.socket_with_config(
+            "test_room",
+            WebRtcSocketConfig {
+                max_concurrent_handshakes: 1,
+                ..Default::default()
+            },
+        );
+        time::timeout(Duration::from_secs(30), newcomer.wait_for_peers(4))
+            .await
+            .expect("newcomer didn't connect to everyone")
+            .expect("newcomer lost the signalling server");
+        for peer in newcomer.connected_peers() {
+            assert_eq!(newcomer.peer_state(&peer), Some(PeerState::Connected));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_peers_are_never_connected_to() {
+        let server = TestServer::start();
+        let named = |name: &str| WebRtcSocketConfig {
+            display_name: Some(name.to_string()),
+            ..Default::default()
+        };
+        // offers to the picky socket once it joins
+        let mut banned = server.socket_with_config("test_room", named("banned"));
+        time::sleep(Duration::from_millis(200)).await;
+        let mut picky = server.socket_with_config(
+            "test_room",
+            WebRtcSocketConfig {
+                on_incoming_peer: Some(IncomingPeerApprover::new(|peer| async move {
+                    // decisions may take a while, e.g. to look up a ban list
+                    time::sleep(Duration::from_millis(50)).await;
+                    match peer.name.as_deref() {
+                        Some("banned") => PeerApproval::Reject,
+                        _ => PeerApproval::Accept,
+                    }
+                })),
+                ..Default::default()
+            },
+        );
+        time::sleep(Duration::from_millis(200)).await;
+        // is offered to by the picky socket
+        let mut good = server.socket_with_config("test_room", named("good"));
+
+        time::timeout(Duration::from_secs(30), picky.wait_for_peers(1))
+            .await
+            .expect("picky socket didn't connect")
+            .expect("picky socket lost the signalling server");
+        time::timeout(Duration::from_secs(30), good.wait_for_peers(2))
+            .await
+            .expect("good socket didn't connect to everyone")
+            .expect("good socket lost the signalling server");
+        let banned_id = banned.id().clone();
+        let good_id = good.id().clone();
+        assert_eq!(picky.connected_peers(), vec![good_id]);
+        assert_eq!(picky.peer_state(&banned_id), None);
+        // peers that accept it still connect to it
+        time::timeout(Duration::from_secs(30), banned.wait_for_peers(1))
+            .await
+            .expect("banned socket didn't connect to the good one")
+            .expect("banned socket lost the signalling server");
+        assert_eq!(banned.connected_peers(), vec![good.id().clone()]);
+    }
+
+    #[tokio::test]
+    async fn candidate_preferences_still_connect() {
+        let server = TestServer::start();
+        let preferences = [
+            CandidatePreference::PreferIpv4,
+            CandidatePreference::PreferIpv6,
+            CandidatePreference::RelayLast,
+        ];
+        for (index, preference) in preferences.iter().copied().enumerate() {
+            let room = format!("test_room_{index}?next=2");
+            let mut picky = server.socket_with_config(
+                &room,
+                WebRtcSocketConfig {
+                    candidate_preference: preference,
+                    ..Default::default()
+                },
+            );
+            let mut plain = server.socket_with_config(&room, WebRtcSocketConfig::default());
+            let (picky_connected, plain_connected) = time::timeout(
+                Duration::from_secs(30),
+                join(picky.wait_for_peers(1), plain.wait_for_peers(1)),
+            )
+            .await
+            .unwrap_or_else(|_| panic!("sockets preferring {:?} didn't connect", preference));
+            picky_connected.expect("picky socket lost the signalling server");
+            plain_connected.expect("plain socket lost the signalling server");
+
+            let peer = plain.id().clone();
+            picky.send(Box::new(*b"hello"), peer);
+            let received = time::timeout(Duration::from_secs(5), receive_some(&mut plain))
+                .await
+                .expect("nothing arrived");
+            assert_eq!(&*received[0].1, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_channel_stalls_until_packets_arrive() {
+        let server = TestServer::start();
+        let channel = ChannelConfig {
+            silence_timeout_ms: Some(300),
+            ..ChannelConfig::reliable()
+        };
+        // only one side keeps the channel alive
+        let mut lively = server.socket_with_config(
+            "test_room?next=2",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig {
+                    keep_alive_interval_ms: Some(50),
+                    ..channel.clone()
+                }],
+                ..Default::default()
+            },
+        );
+        let mut quiet = server.socket_with_config(
+            "test_room?next=2",
+            WebRtcSocketConfig {
+                channels: vec![channel],
+                ..Default::default()
+            },
+        );
+        time::timeout(
+            Duration::from_secs(30),
+            join_all([lively.wait_for_peers(1), quiet.wait_for_peers(1)]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(
+            lively.channel_liveness_changes(),
+            vec![ChannelLiveness::Stalled {
+                peer: quiet.id().clone(),
+                channel: 0
+            }]
+        );
+        assert_eq!(quiet.channel_liveness_changes(), vec![]);
+        // keep-alives aren't received as packets
+        assert!(quiet.receive().is_empty());
+
+        quiet.send(Box::new(*b"here"), lively.id().clone());
+        let packets = receive_some(&mut lively).await;
+        assert_eq!(packets, vec![(quiet.id().clone(), Box::from(*b"here"))]);
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            lively.channel_liveness_changes(),
+            vec![ChannelLiveness::Resumed {
+                peer: quiet.id().clone(),
+                channel: 0
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn persisted_certificate_keeps_fingerprint() {
+        let server = TestServer::start();
+        let first = server.socket("test_room?next=2", vec![ChannelConfig::reliable()]);
+        let pem = first.certificate_pem().expect("no certificate").to_string();
+
+        let mut host = server.socket_with_config(
+            "test_room?next=2",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                certificate_pem: Some(pem),
+                ..Default::default()
+            },
+        );
+        assert_eq!(host.local_fingerprint(), first.local_fingerprint());
+        drop(first);
+
+        let mut guest = server.socket("test_room?next=2", vec![ChannelConfig::reliable()]);
+        assert_ne!(guest.local_fingerprint(), host.local_fingerprint());
+        time::timeout(
+            Duration::from_secs(30),
+            join_all([host.wait_for_peers(1), guest.wait_for_peers(1)]),
+        )
+        .await
+        .expect("sockets didn't connect");
+    }
+
+    #[tokio::test]
+    async fn rejected_fingerprint_disconnects() {
+        let server = TestServer::start();
+        let host = server.socket("test_room?next=2", vec![ChannelConfig::reliable()]);
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_by_verifier = seen.clone();
+        let mut guest = server.socket_with_config(
+            "test_room?next=2",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                fingerprint_verifier: Some(FingerprintVerifier::new(move |peer, fingerprint| {
+                    seen_by_verifier
+                        .lock()
+                        .unwrap()
+                        .push((peer.clone(), fingerprint.to_string()));
+                    false
+                })),
+                ..Default::default()
+            },
+        );
+
+        time::timeout(Duration::from_secs(30), async {
+            loop {
+                guest.accept_new_connections();
+                if guest.peer_state(host.id()) == Some(PeerState::Disconnected) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer wasn't disconnected");
+
+        assert!(guest.connected_peers().is_empty());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                host.id().clone(),
+                host.local_fingerprint().unwrap().to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_handshake_disconnects() {
+        let server = TestServer::start();
+        let config = |data: &[u8]| WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            handshake_data: Some(data.to_vec()),
+            ..Default::default()
+        };
+        let mut host = server.socket_with_config(
+            "handshake_room",
+            WebRtcSocketConfig {
+                handshake_validator: Some(HandshakeValidator::new(|_, data| data == b"v1")),
+                ..config(b"v1")
+            },
+        );
+        let mut compatible = server.socket_with_config("handshake_room", config(b"v1"));
+        let incompatible = server.socket_with_config("handshake_room", config(b"v2"));
+
+        let mut handshakes = vec![];
+        time::timeout(Duration::from_secs(30), async {
+            loop {
+                host.accept_new_connections();
+                handshakes.extend(host.peer_handshakes());
+                if !handshakes.is_empty()
+                    && host.peer_state(incompatible.id()) == Some(PeerState::Disconnected)
+                {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("handshakes weren't exchanged");
+
+        assert_eq!(
+            handshakes,
+            vec![PeerHandshake {
+                peer: compatible.id().clone(),
+                data: b"v1".to_vec(),
+            }]
+        );
+        assert_eq!(host.connected_peers(), vec![compatible.id().clone()]);
+        let seen_by_compatible = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let handshakes = compatible.peer_handshakes();
+                if let Some(handshake) = handshakes.into_iter().find(|h| &h.peer == host.id()) {
+                    return handshake;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host's handshake didn't arrive");
+        assert_eq!(seen_by_compatible.data, b"v1");
+    }
+
+    #[tokio::test]
+    async fn peer_rejoining_during_grace_period_is_reconnected() {
+        async fn wait_for_room_peers(observer: &mut WebRtcSocket, peers: &[&String]) {
+            time::timeout(Duration::from_secs(10), async {
+                loop {
+                    observer.accept_new_connections();
+                    if observer.room_peers().iter().eq(peers.iter().copied()) {
+                        break;
+                    }
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("room peers didn't change");
+        }
+
+        let server = TestServer::start();
+        let mut observer = server.socket_with_config(
+            "grace_room",
+            WebRtcSocketConfig {
+                channels: vec![],
+                signalling_only: true,
+                ..Default::default()
+            },
+        );
+        let rejected = Arc::new(Mutex::new(false));
+        let rejected_by_verifier = rejected.clone();
+        // rejects the first host, so the connection to it drops
+        let mut guest = server.socket_with_config(
+            "grace_room",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                reconnect_grace_period_ms: 10_000,
+                fingerprint_verifier: Some(FingerprintVerifier::new(move |_, _| {
+                    let mut rejected = rejected_by_verifier.lock().unwrap();
+                    let accept = *rejected;
+                    *rejected = true;
+                    accept
+                })),
+                ..Default::default()
+            },
+        );
+        // make sure the guest is in the room first, so it sends the offers
+        let guest_id = guest.id().clone();
+        wait_for_room_peers(&mut observer, &[&guest_id]).await;
+        let host_config = || WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            peer_id: Some("host".to_string()),
+            ..Default::default()
+        };
+        let host = server.socket_with_config("grace_room", host_config());
+        let host_id = host.id().clone();
+        wait_for_room_peers(&mut observer, &[&host_id, &guest_id]).await;
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                guest.accept_new_connections();
+                if guest.peer_state(&host_id) == Some(PeerState::Reconnecting) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection didn't drop");
+        assert!(*rejected.lock().unwrap());
+
+        // the host comes back with the same id, once the server let go of it
+        drop(host);
+        wait_for_room_peers(&mut observer, &[&guest_id]).await;
+        let mut host = server.socket_with_config("grace_room", host_config());
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                host.accept_new_connections();
+                guest.accept_new_connections();
+                let state = guest.peer_state(&host_id);
+                assert_ne!(state, Some(PeerState::Disconnected));
+                if state == Some(PeerState::Connected) && !host.connected_peers().is_empty() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host wasn't reconnected");
+    }
+
+    #[tokio::test]
+    async fn crashed_socket_resumes_its_session() {
+        let server = TestServer::start();
+        let rejected = Arc::new(Mutex::new(false));
+        let rejected_by_verifier = rejected.clone();
+        // rejects the first host, so the connection to it drops like after a crash
+        let mut guest = server.socket_with_config(
+            "session_room",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                reconnect_grace_period_ms: 10_000,
+                fingerprint_verifier: Some(FingerprintVerifier::new(move |_, _| {
+                    let mut rejected = rejected_by_verifier.lock().unwrap();
+                    let accept = *rejected;
+                    *rejected = true;
+                    accept
+                })),
+                ..Default::default()
+            },
+        );
+        let guest_id = guest.id().clone();
+        time::sleep(Duration::from_millis(200)).await;
+        let host_id = "host".to_string();
+        let mut host = server.socket_with_config(
+            "session_room",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                peer_id: Some(host_id.clone()),
+                ..Default::default()
+            },
+        );
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                host.accept_new_connections();
+                guest.accept_new_connections();
+                if guest.peer_state(&host_id) == Some(PeerState::Reconnecting) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection didn't drop");
+
+        host.associate(guest_id.clone(), "guest_account".to_string());
+        let session = host.export_session();
+        assert!(session.room_url.ends_with("/session_room"));
+        assert_eq!(session.peer_id, host_id);
+        assert_eq!(session.peers, vec![guest_id.clone()]);
+        assert!(session.certificate_pem.is_some());
+        assert_eq!(
+            session.identities,
+            vec![(guest_id.clone(), "guest_account".to_string())]
+        );
+        let saved = serde_json::to_string(&session).unwrap();
+
+        // the server may still hold on to the crashed socket when it resumes
+        drop(host);
+        let session: SocketSession = serde_json::from_str(&saved).unwrap();
+        let mut host = server.socket_with_config(
+            "session_room",
+            WebRtcSocketConfig::default().resume_session(session),
+        );
+        assert_eq!(host.id(), &host_id);
+        assert_eq!(host.peer_of("guest_account"), Some(&guest_id));
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                host.accept_new_connections();
+                guest.accept_new_connections();
+                let state = guest.peer_state(&host_id);
+                assert_ne!(state, Some(PeerState::Disconnected));
+                if state == Some(PeerState::Connected) && !host.connected_peers().is_empty() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host wasn't reconnected");
+        host.send(Box::new(*b"back"), guest_id);
+        let received = time::timeout(Duration::from_secs(5), receive_some(&mut guest))
+            .await
+            .expect("nothing arrived");
+        assert_eq!(&*received[0].1, b"back");
+    }
+
+    #[tokio::test]
+    async fn peer_is_disconnected_after_grace_period() {
+        let server = TestServer::start();
+        let host = server.socket("test_room?next=2", vec![ChannelConfig::reliable()]);
+        let mut guest = server.socket_with_config(
+            "test_room?next=2",
+            WebRtcSocketConfig {
+                channels: vec![ChannelConfig::reliable()],
+                reconnect_grace_period_ms: 500,
+                fingerprint_verifier: Some(FingerprintVerifier::new(|_, _| false)),
+                ..Default::default()
+            },
+        );
+
+        let mut states = vec![];
+        time::timeout(Duration::from_secs(30), async {
+            loop {
+                guest.accept_new_connections();
+                let state = guest.peer_state(host.id());
+                if states.last() != state.as_ref() {
+                    states.extend(state);
+                }
+                if state == Some(PeerState::Disconnected) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer wasn't disconnected");
+
+        assert!(states.contains(&PeerState::Reconnecting));
+    }
+
+    #[tokio::test]
+    async fn unresponsive_signalling_server_times_out() {
+        // connections end up in the backlog, but the websocket handshake
+        // never gets an answer
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: format!("ws://{}/test_room", listener.local_addr().unwrap()),
+            signalling_timeout_ms: 200,
+            ..Default::default()
+        });
+
+        let result = time::timeout(Duration::from_secs(10), message_loop)
+            .await
+            .expect("message loop didn't time out");
+        assert!(matches!(result, Err(Error::SignallingTimeout)));
+    }
+
+    #[tokio::test]
+    async fn creator_of_the_room_is_its_host() {
+        let server = TestServer::start();
+        let mut host = server.socket("roles_room", vec![ChannelConfig::reliable()]);
+        time::timeout(Duration::from_secs(10), async {
+            while host.peer_role(host.id()).is_none() {
+                host.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host didn't get a role");
+        assert_eq!(host.peer_role(host.id()), Some(PeerRole::Host));
+
+        let mut player = server.socket("roles_room", vec![ChannelConfig::reliable()]);
+        time::timeout(
+            Duration::from_secs(30),
+            join_all([host.wait_for_peers(1), player.wait_for_peers(1)]),
+        )
+        .await
+        .expect("sockets didn't connect");
+        assert_eq!(player.peer_role(host.id()), Some(PeerRole::Host));
+        assert_eq!(player.peer_role(player.id()), Some(PeerRole::Player));
+        assert_eq!(host.peer_role(player.id()), Some(PeerRole::Player));
+    }
+
+    #[tokio::test]
+    async fn rooms_span_clustered_servers() {
+        let servers = TestServer::start_cluster(2);
+        let mut sockets = [
+            servers[0].socket_with_id("cluster_room", "a", vec![ChannelConfig::reliable()]),
+            servers[1].socket_with_id("cluster_room", "b", vec![ChannelConfig::reliable()]),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets on different servers didn't connect");
+
+        sockets[0].send(Box::new(*b"hello"), "b".to_string());
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets[0].0, "a");
+        assert_eq!(&*packets[0].1, b"hello");
+    }
+
+    #[tokio::test]
+    async fn unreachable_signalling_server_fails_over() {
+        let server = TestServer::start();
+        // nothing listens on the port once the listener is gone
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (mut socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: format!("ws://{addr}/failover_room?next=2"),
+            fallback_signalling_urls: vec![format!("ws://{}", server.addr())],
+            signalling_backoff: BackoffPolicy {
+                initial_delay_ms: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        tokio::spawn(message_loop);
+
+        time::timeout(Duration::from_secs(10), async {
+            while socket.signalling_state() != SignallingState::Connected {
+                socket.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("socket didn't fail over");
+        assert_eq!(
+            socket.signalling_url(),
+            Some(server.room_url("failover_room?next=2").as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_signalling_server_is_retried() {
+        // nothing listens on the port once the listener is gone
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (mut socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: format!("ws://{addr}/test_room"),
+            signalling_reconnect_attempts: 2,
+            signalling_backoff: BackoffPolicy {
+                initial_delay_ms: 100,
+                jitter: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let message_loop = tokio::spawn(message_loop);
+
+        let mut states = vec![];
+        while !message_loop.is_finished() {
+            socket.accept_new_connections();
+            let state = socket.signalling_state();
+            if states.last() != Some(&state) {
+                states.push(state);
+            }
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        socket.accept_new_connections();
+        states.push(socket.signalling_state());
+
+        let result = message_loop.await.unwrap();
+        assert!(matches!(result, Err(Error::SignallingConnection(_))));
+        assert_eq!(
+            states,
+            vec![
+                SignallingState::Connecting,
+                SignallingState::Reconnecting {
+                    attempt: 1,
+                    delay: Duration::from_millis(100)
+                },
+                SignallingState::Reconnecting {
+                    attempt: 2,
+                    delay: Duration::from_millis(200)
+                },
+                SignallingState::Disconnected,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn paused_socket_reconnects_when_resumed() {
+        async fn wait_for_state(socket: &mut WebRtcSocket, state: SignallingState) {
+            time::timeout(Duration::from_secs(10), async {
+                while socket.signalling_state() != state {
+                    socket.accept_new_connections();
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("signalling state didn't change");
+        }
+        async fn wait_for_resume(socket: &mut WebRtcSocket) -> Vec<ResumeEvent> {
+            time::timeout(Duration::from_secs(10), async {
+                loop {
+                    let events = socket.resume_events();
+                    if !events.is_empty() {
+                        return events;
+                    }
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("socket didn't resume")
+        }
+
+        // nothing listens on the port until the server starts below
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (mut socket, message_loop) =
+            WebRtcSocket::new(format!("ws://{addr}/background?next=2"));
+        // without reconnect attempts, the message loop would give up on the
+        // server right away if we weren't paused
+        socket.pause();
+        let message_loop = tokio::spawn(message_loop);
+        wait_for_state(&mut socket, SignallingState::Disconnected).await;
+
+        // the server is still down
+        assert!(socket.is_paused());
+        socket.resume();
+        assert!(!socket.is_paused());
+        assert!(matches!(
+            wait_for_resume(&mut socket).await.as_slice(),
+            [ResumeEvent::ResumptionFailed { .. }]
+        ));
+        let result = time::timeout(Duration::from_secs(10), message_loop)
+            .await
+            .expect("message loop didn't give up")
+            .unwrap();
+        assert!(matches!(result, Err(Error::SignallingConnection(_))));
+
+        let (mut socket, message_loop) =
+            WebRtcSocket::new(format!("ws://{addr}/background?next=2"));
+        socket.pause();
+        tokio::spawn(message_loop);
+        wait_for_state(&mut socket, SignallingState::Disconnected).await;
+
+        let (_shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let (_, server) = warp::serve(matchbox_server::routes(Args::default()))
+            .bind_with_graceful_shutdown(addr, async {
+                let _ = shutdown_rx.await;
+            });
+        tokio::spawn(server);
+        socket.resume();
+        assert_eq!(wait_for_resume(&mut socket).await, [ResumeEvent::Resumed]);
+        assert_eq!(socket.signalling_state(), SignallingState::Connected);
+
+        // and a peer can still join
+        let (mut other, message_loop) = WebRtcSocket::new(format!("ws://{addr}/background?next=2"));
+        tokio::spawn(message_loop);
+        time::timeout(
+            Duration::from_secs(30),
+            join_all([socket.wait_for_peers(1), other.wait_for_peers(1)]),
+        )
+        .await
+        .expect("sockets didn't connect");
+    }
+
+    #[tokio::test]
+    async fn signalling_state_changes_are_reported_per_room() {
+        let server = TestServer::start();
+        let mut socket = server.socket("lobby", vec![ChannelConfig::reliable()]);
+
+        async fn changes_until(
+            socket: &mut WebRtcSocket,
+            last: SignallingState,
+        ) -> Vec<SignallingState> {
+            let mut states = vec![];
+            time::timeout(Duration::from_secs(10), async {
+                while states.last() != Some(&last) {
+                    states.extend(socket.signalling_state_changes());
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("signalling state didn't change");
+            states
+        }
+
+        assert_eq!(
+            changes_until(&mut socket, SignallingState::Connected).await,
+            [SignallingState::Connecting, SignallingState::Connected]
+        );
+
+        socket.leave_room().expect("message loop stopped");
+        assert_eq!(
+            changes_until(&mut socket, SignallingState::Disconnected).await,
+            [SignallingState::Disconnected]
+        );
+
+        socket.join_room(server.room_url("game")).unwrap();
+        assert_eq!(
+            changes_until(&mut socket, SignallingState::Connected).await,
+            [SignallingState::Connecting, SignallingState::Connected]
+        );
+        assert_eq!(socket.signalling_state(), SignallingState::Connected);
+    }
+
+    #[tokio::test]
+    async fn stopped_message_loop_is_reported() {
+        let server = TestServer::start();
+        let (mut socket, message_loop) = WebRtcSocket::new(server.room_url("lonely"));
+        let message_loop = tokio::spawn(message_loop);
+
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(socket.try_accept_new_connections().unwrap().is_empty());
+        assert!(!socket.is_closed());
+
+        message_loop.abort();
+        let _ = message_loop.await;
+        assert!(matches!(
+            socket.try_accept_new_connections(),
+            Err(Error::MessageLoopStopped)
+        ));
+        assert!(socket.is_closed());
+
+        // nothing panics on a socket without a message loop
+        assert!(socket.receive().is_empty());
+        assert!(matches!(
+            socket.wait_for_peers(1).await,
+            Err(Error::MessageLoopStopped)
+        ));
+        assert!(matches!(
+            socket.send_room_message("anyone?"),
+            Err(Error::MessageLoopStopped)
+        ));
+        assert!(matches!(
+            socket.leave_room(),
+            Err(Error::MessageLoopStopped)
+        ));
+    }
+
+    #[test]
+    fn error_codes_mean_the_same_on_both_ends() {
+        use matchbox_server::SignallingErrorCode as ServerCode;
+        use matchbox_socket::protocol::SignallingErrorCode as SocketCode;
+
+        // a code added to the server has to be added here too
+        let codes = [
+            ServerCode::RoomFull,
+            ServerCode::Unauthorized,
+            ServerCode::ProtocolMismatch,
+            ServerCode::RateLimited,
+            ServerCode::ServerShutdown,
+            ServerCode::Kicked,
+            ServerCode::Banned,
+            ServerCode::VersionMismatch,
+            ServerCode::IdTaken,
+            ServerCode::InvalidId,
+            ServerCode::ServerBusy {
+                retry_after_secs: 30,
+            },
+        ];
+        for code in codes {
+            let expected = match code {
+                ServerCode::RoomFull => SocketCode::RoomFull,
+                ServerCode::Unauthorized => SocketCode::Unauthorized,
+                ServerCode::ProtocolMismatch => SocketCode::ProtocolMismatch,
+                ServerCode::RateLimited => SocketCode::RateLimited,
+                ServerCode::ServerShutdown => SocketCode::ServerShutdown,
+                ServerCode::Kicked => SocketCode::Kicked,
+                ServerCode::Banned => SocketCode::Banned,
+                ServerCode::VersionMismatch => SocketCode::VersionMismatch,
+                ServerCode::IdTaken => SocketCode::IdTaken,
+                ServerCode::InvalidId => SocketCode::InvalidId,
+                ServerCode::ServerBusy { retry_after_secs } => {
+                    SocketCode::ServerBusy { retry_after_secs }
+                }
+            };
+            let sent = serde_json::to_string(&code).unwrap();
+            assert_eq!(
+                serde_json::from_str::<SocketCode>(&sent).unwrap(),
+                expected,
+                "{sent}"
+            );
+            let echoed = serde_json::to_string(&expected).unwrap();
+            assert_eq!(serde_json::from_str::<ServerCode>(&echoed).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn partial_config_falls_back_to_defaults() {
+        let config: WebRtcSocketConfig = serde_json::from_str(
+            r#"{
+                "room_url": "ws://localhost:3536/settings",
+                "channels": [{ "ordered": false, "max_retransmits": 0 }],
+                "reconnect_backoff": { "initial_delay_ms": 50 }
+            }"#,
+        )
+        .unwrap();
+        let defaults = WebRtcSocketConfig::default();
+
+        assert_eq!(config.room_url, "ws://localhost:3536/settings");
+        assert_eq!(config.channels.len(), 1);
+        let channel = &config.channels[0];
+        assert_eq!((channel.ordered, channel.max_retransmits), (false, Some(0)));
+        assert_eq!(channel.priority, Default::default());
+        assert!(!channel.coalesce && !channel.requests && !channel.text);
+        assert_eq!(config.reconnect_backoff.initial_delay_ms, 50);
+        assert_eq!(
+            config.reconnect_backoff.max_delay_ms,
+            defaults.reconnect_backoff.max_delay_ms
+        );
+        assert_eq!(config.ice_server.urls, defaults.ice_server.urls);
+        assert_eq!(config.reconnect_attempts, defaults.reconnect_attempts);
+        assert_eq!(
+            config.max_concurrent_handshakes,
+            defaults.max_concurrent_handshakes
+        );
+        assert_eq!(config.strict, defaults.strict);
+
+        let empty: WebRtcSocketConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(empty.channels.len(), defaults.channels.len());
+        assert_eq!(empty.room_url, defaults.room_url);
+    }
+
+    #[test]
+    fn lenient_socket_logs_misuse_instead_of_panicking() {
+        let (mut socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            strict: false,
+            ..Default::default()
+        });
+        drop(message_loop);
+
+        // sends fail, the message loop is gone
+        socket.send(Box::new(*b"lost"), "peer");
+        socket.channel_sender(0).send(Box::new(*b"lost"), "peer");
+        // channels that don't exist
+        assert!(socket.receive_on_channel(7).is_empty());
+        assert!(socket.receive_requests_on_channel(7).is_empty());
+        assert_eq!(socket.channel_stats(7), ChannelStats::default());
+        assert!(socket
+            .channel_sender(7)
+            .try_send(Box::new(*b"lost"), "peer")
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "No data channel with index 7")]
+    fn strict_socket_panics_on_misuse() {
+        let (mut socket, _message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            strict: true,
+            ..Default::default()
+        });
+        socket.receive_on_channel(7);
+    }
+
+    #[tokio::test]
+    async fn invalid_room_urls_are_rejected_up_front() {
+        for url in ["not a url", "http://localhost:3536/room", "ws://[::1/room"] {
+            let (_socket, message_loop) = WebRtcSocket::new(url);
+            match time::timeout(Duration::from_secs(1), message_loop).await {
+                Ok(Err(Error::InvalidUrl { url: invalid, .. })) => assert_eq!(invalid, url),
+                res => panic!("unexpected result for {:?}: {:?}", url, res),
+            }
+        }
+
+        let server = TestServer::start();
+        let socket = server.socket("lobby", vec![ChannelConfig::reliable()]);
+        assert!(matches!(
+            socket.join_room("wss://"),
+            Err(Error::InvalidUrl { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn ipv6_room_url_connects() {
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let (addr, server) = warp::serve(matchbox_server::routes(Args::default()))
+            .bind_with_graceful_shutdown((std::net::Ipv6Addr::LOCALHOST, 0), async {
+                let _ = shutdown_rx.await;
+            });
+        tokio::spawn(server);
+        let room_url = format!("ws://[::1]:{}/v6?next=2", addr.port());
+
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| {
+                let (socket, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+                    room_url: room_url.clone(),
+                    ice_server: RtcIceServerConfig {
+                        urls: vec![],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                });
+                tokio::spawn(message_loop);
+                socket
+            })
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        drop(shutdown);
+    }
+
+    #[tokio::test]
+    async fn full_room_is_not_retried() {
+        let server = TestServer::start_with_args(Args {
+            rooms: vec![RoomRule {
+                pattern: "solo".to_string(),
+                next: None,
+                max_peers: Some(1),
+                channels: None,
+                event_log: None,
+                strict_version: false,
+                client_versions: None,
+                listed: false,
+            }],
+            ..Default::default()
+        });
+        let mut first = server.socket("solo", vec![ChannelConfig::reliable()]);
+        // the room's rules arrive once the socket has joined
+        time::timeout(Duration::from_secs(10), async {
+            while first.room_info().is_none() {
+                first.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("first socket didn't join");
+
+        let (_second, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: server.room_url("solo"),
+            signalling_reconnect_attempts: 3,
+            signalling_backoff: BackoffPolicy {
+                initial_delay_ms: 60_000,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let result = time::timeout(Duration::from_secs(10), message_loop)
+            .await
+            .expect("full room was retried");
+        assert!(matches!(
+            result,
+            Err(Error::Signalling(SignallingError::RoomFull))
+        ));
+    }
+
+    #[tokio::test]
+    async fn host_closes_room_for_everyone() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(3, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        // only the peer that created the room may close it
+        sockets[1].close_room().expect("message loop stopped");
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(sockets[1].room_closed_by(), None);
+
+        sockets[0].close_room().expect("message loop stopped");
+        let closed = time::timeout(
+            Duration::from_secs(10),
+            join_all(sockets.iter_mut().map(|socket| socket.room_closed())),
+        )
+        .await
+        .expect("room wasn't closed");
+        for closed_by in closed {
+            assert_eq!(
+                closed_by.expect("message loop stopped"),
+                RoomClosedBy::Host("peer-0".to_string())
+            );
+        }
+
+        // everyone disconnects from everyone else
+        time::timeout(Duration::from_secs(10), async {
+            while sockets.iter_mut().any(|socket| {
+                socket.accept_new_connections();
+                !socket.connected_peers().is_empty()
+            }) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peers stayed connected");
+    }
+
+    #[tokio::test]
+    async fn idle_room_is_closed_after_a_warning() {
+        let server = TestServer::start_with_args(Args {
+            limits: Limits {
+                room_idle_secs: 2,
+                room_idle_warning_secs: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut socket = server.socket("idle_room", vec![ChannelConfig::reliable()]);
+
+        let warnings = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let warnings = socket.idle_warnings();
+                if !warnings.is_empty() {
+                    return warnings;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("socket wasn't warned");
+        assert!(warnings[0] <= Duration::from_secs(1));
+
+        let closed_by = time::timeout(Duration::from_secs(10), socket.room_closed())
+            .await
+            .expect("room wasn't closed")
+            .expect("message loop stopped");
+        assert_eq!(closed_by, RoomClosedBy::Server);
+    }
+
+    #[tokio::test]
+    async fn paced_introductions_connect_everyone() {
+        let server = TestServer::start_with_args(Args {
+            limits: Limits {
+                introductions_per_sec: 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut sockets: Vec<_> = (0..5)
+            .map(|_| server.socket("paced?next=5", vec![ChannelConfig::reliable()]))
+            .collect();
+
+        time::timeout(
+            Duration::from_secs(20),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(4))),
+        )
+        .await
+        .expect("peers didn't connect");
+        for socket in &mut sockets {
+            assert_eq!(socket.pending_introductions(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn room_messages_reach_late_joiners() {
+        let server = TestServer::start_with_args(Args {
+            rooms: vec![RoomRule {
+                pattern: "lobby".to_string(),
+                next: None,
+                max_peers: None,
+                channels: None,
+                event_log: Some(8),
+                strict_version: false,
+                client_versions: None,
+                listed: false,
+            }],
+            ..Default::default()
+        });
+        let mut host = server.socket("lobby", vec![ChannelConfig::reliable()]);
+        // the room's rules arrive once the socket has joined
+        time::timeout(Duration::from_secs(10), async {
+            while host.room_info().is_none() {
+                host.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host didn't join");
+        host.send_room_message("map=desert")
+            .expect("message loop stopped");
+
+        let mut guest = server.socket("lobby", vec![ChannelConfig::reliable()]);
+        let messages = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let messages = guest.receive_room_messages();
+                if !messages.is_empty() {
+                    return messages;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("room message didn't arrive");
+        assert_eq!(
+            messages,
+            vec![(host.id().clone(), "map=desert".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_of_different_matches_chat_in_an_extra_room() {
+        let server = TestServer::start();
+        let mut a = server.socket("match_a", vec![ChannelConfig::reliable()]);
+        let mut b = server.socket("match_b", vec![ChannelConfig::reliable()]);
+        let (a_id, b_id) = (a.id().clone(), b.id().clone());
+        // requests are only sent once the message loops run
+        time::timeout(Duration::from_secs(10), async {
+            while a.signalling_state() != SignallingState::Connected
+                || b.signalling_state() != SignallingState::Connected
+            {
+                a.accept_new_connections();
+                b.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sockets didn't connect to the server");
+        a.join_extra_room("chat").expect("message loop stopped");
+        b.join_extra_room("chat").expect("message loop stopped");
+
+        async fn next_events(socket: &mut WebRtcSocket, count: usize) -> Vec<ExtraRoomEvent> {
+            let mut events = vec![];
+            time::timeout(Duration::from_secs(10), async {
+                while events.len() < count {
+                    events.extend(socket.extra_room_events());
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("extra room events didn't arrive");
+            events
+        }
+        let joined = |peer: &String| ExtraRoomEvent::PeerJoined {
+            room: "chat".to_string(),
+            peer: peer.clone(),
+        };
+        let events = next_events(&mut b, 2).await;
+        assert!(events.contains(&joined(&a_id)) && events.contains(&joined(&b_id)));
+        let mut peers = vec![a_id.clone(), b_id.clone()];
+        peers.sort();
+        assert_eq!(b.extra_room_peers("chat"), peers);
+        assert_eq!(b.extra_rooms(), ["chat".to_string()]);
+
+        a.send_extra_room_message("chat", "gl hf")
+            .expect("message loop stopped");
+        let events = next_events(&mut b, 1).await;
+        assert_eq!(
+            events,
+            vec![ExtraRoomEvent::Message {
+                room: "chat".to_string(),
+                sender: a_id.clone(),
+                data: "gl hf".to_string(),
+            }]
+        );
+        // the extra room has nothing to do with the match rooms
+        assert!(b.receive_room_messages().is_empty());
+
+        a.leave_extra_room("chat").expect("message loop stopped");
+        let events = next_events(&mut b, 1).await;
+        assert_eq!(
+            events,
+            vec![ExtraRoomEvent::PeerLeft {
+                room: "chat".to_string(),
+                peer: a_id,
+            }]
+        );
+        time::timeout(Duration::from_secs(10), async {
+            while !a.extra_rooms().is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("a didn't leave the extra room");
+    }
+
+    #[tokio::test]
+    async fn room_metadata_and_version_matching() {
+        let server = TestServer::start_with_args(Args {
+            rooms: vec![RoomRule {
+                pattern: "arena".to_string(),
+                next: None,
+                max_peers: None,
+                channels: None,
+                event_log: None,
+                strict_version: true,
+                client_versions: None,
+                listed: false,
+            }],
+            ..Default::default()
+        });
+        let versioned = |version: &str| WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            client_version: Some(version.to_string()),
+            ..Default::default()
+        };
+        async fn wait_for_metadata(socket: &mut WebRtcSocket) -> Option<RoomMetadata> {
+            time::timeout(Duration::from_secs(10), async {
+                while socket.room_metadata().is_none() {
+                    socket.accept_new_connections();
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("room metadata didn't arrive");
+            socket.room_metadata().cloned()
+        }
+
+        let mut host = server.socket_with_config("arena", versioned("1.0"));
+        // the room's rules arrive once the socket has joined
+        time::timeout(Duration::from_secs(10), async {
+            while host.room_info().is_none() {
+                host.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host didn't join");
+        host.set_room_metadata(RoomMetadata {
+            game_mode: Some("ctf".to_string()),
+            ..Default::default()
+        })
+        .expect("message loop stopped");
+        let metadata = RoomMetadata {
+            game_mode: Some("ctf".to_string()),
+            map: None,
+            version: Some("1.0".to_string()),
+        };
+        assert_eq!(wait_for_metadata(&mut host).await, Some(metadata.clone()));
+
+        let (_outdated, message_loop) = WebRtcSocket::new_with_config(WebRtcSocketConfig {
+            room_url: server.room_url("arena"),
+            ..versioned("0.9")
+        });
+        let result = time::timeout(Duration::from_secs(10), message_loop)
+            .await
+            .expect("outdated client wasn't rejected");
+        assert!(matches!(
+            result,
+            Err(Error::Signalling(SignallingError::VersionMismatch))
+        ));
+
+        let mut guest = server.socket_with_config("arena", versioned("1.0"));
+        assert_eq!(wait_for_metadata(&mut guest).await, Some(metadata));
+    }
+
+    #[tokio::test]
+    async fn peers_know_each_others_capabilities() {
+        let server = TestServer::start();
+        let config = |capabilities: &[&str]| WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("caps?next=2", config(&["voice", "zstd"])),
+            server.socket_with_config("caps?next=2", config(&["zstd"])),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let (first, second) = (&sockets[0], &sockets[1]);
+        assert_eq!(first.peer_capabilities(second.id()), ["zstd"]);
+        assert_eq!(second.peer_capabilities(first.id()), ["voice", "zstd"]);
+        assert!(first.peer_capabilities(&"unknown".to_string()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn channels_are_only_opened_with_capable_peers() {
+        let server = TestServer::start();
+        let config = |capabilities: &[&str]| WebRtcSocketConfig {
+            channels: vec![
+                ChannelConfig::reliable(),
+                ChannelConfig {
+                    required_capability: Some("voice".to_string()),
+                    ..ChannelConfig::unreliable()
+                },
+            ],
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("subset?next=3", config(&["voice"])),
+            server.socket_with_config("subset?next=3", config(&["voice"])),
+            server.socket_with_config("subset?next=3", config(&[])),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(2))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let (talker, listener, spectator) = (
+            sockets[0].id().clone(),
+            sockets[1].id().clone(),
+            sockets[2].id().clone(),
+        );
+
+        let channels = |socket: &WebRtcSocket, peer: &String| {
+            let info = socket.connection_info(peer).expect("no connection info");
+            info.channels
+                .iter()
+                .map(Option::is_some)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(channels(&sockets[0], &listener), [true, true]);
+        assert_eq!(channels(&sockets[0], &spectator), [true, false]);
+        assert_eq!(channels(&sockets[2], &talker), [true, false]);
+
+        let voice = sockets[0].channel_sender(1);
+        match voice.try_send(Box::new(*b"hi"), spectator.clone()) {
+            Err(Error::ChannelNotOpen { peer, channel }) => {
+                assert_eq!((peer, channel), (spectator.clone(), 1));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        voice.send(Box::new(*b"hi"), listener);
+        sockets[0].send(Box::new(*b"hello"), spectator);
+
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = sockets[1].receive_on_channel(1);
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("voice packet didn't arrive");
+        assert_eq!(packets, vec![(talker.clone(), Box::from(*b"hi"))]);
+        let packets = receive_some(&mut sockets[2]).await;
+        assert_eq!(packets, vec![(talker, Box::from(*b"hello"))]);
+    }
+
+    #[tokio::test]
+    async fn peers_may_open_only_some_channels() {
+        let server = TestServer::start();
+        let config = |capabilities: &[&str]| WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable(), ChannelConfig::reliable()],
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        // stands in for a client without matchbox, that only opens channel 0
+        let capability = format!("{CHANNELS_CAPABILITY_PREFIX}0");
+        let mut sockets = [
+            server.socket_with_config("interop?next=2", config(&[])),
+            server.socket_with_config("interop?next=2", config(&[&capability])),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let client = sockets[1].id().clone();
+
+        let info = sockets[0]
+            .connection_info(&client)
+            .expect("no connection info");
+        assert_eq!(
+            info.channels
+                .iter()
+                .map(Option::is_some)
+                .collect::<Vec<_>>(),
+            [true, false]
+        );
+        assert!(matches!(
+            sockets[0].try_send_on_channel(Box::new(*b"hi"), client.clone(), 1),
+            Err(Error::ChannelNotOpen { channel: 1, .. })
+        ));
+        sockets[0].send(Box::new(*b"hello"), client);
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(
+            packets,
+            vec![(sockets[0].id().clone(), Box::from(*b"hello"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn packets_are_sent_on_all_channels_or_none() {
+        let server = TestServer::start();
+        let config = |capabilities: &[&str]| WebRtcSocketConfig {
+            channels: vec![
+                ChannelConfig::reliable(),
+                ChannelConfig {
+                    required_capability: Some("voice".to_string()),
+                    ..ChannelConfig::reliable()
+                },
+            ],
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("all?next=3", config(&["voice"])),
+            server.socket_with_config("all?next=3", config(&["voice"])),
+            server.socket_with_config("all?next=3", config(&[])),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(2))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let (talker, listener, spectator) = (
+            sockets[0].id().clone(),
+            sockets[1].id().clone(),
+            sockets[2].id().clone(),
+        );
+
+        let all = sockets[0].all_channels();
+        assert_eq!(all, [0, 1]);
+        match sockets[0].try_send_on(&all, Box::new(*b"both"), spectator.clone()) {
+            Err(Error::ChannelNotOpen { peer, channel }) => {
+                assert_eq!((peer, channel), (spectator.clone(), 1));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(
+            sockets[0].try_send_on(&[0, 2], Box::new(*b"both"), listener.clone()),
+            Err(Error::ChannelNotOpen { channel: 2, .. })
+        ));
+        sockets[0].send_on(&all, Box::new(*b"both"), listener);
+        sockets[0].send(Box::new(*b"only"), spectator);
+
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets, vec![(talker.clone(), Box::from(*b"both"))]);
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = sockets[1].receive_on_channel(1);
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet didn't arrive on the second channel");
+        assert_eq!(packets, vec![(talker.clone(), Box::from(*b"both"))]);
+        // nothing was sent on the default channel when the other one failed
+        let packets = receive_some(&mut sockets[2]).await;
+        assert_eq!(packets, vec![(talker, Box::from(*b"only"))]);
+    }
+
+    #[tokio::test]
+    async fn channels_are_borrowed_together() {
+        let channels = vec![ChannelConfig::reliable(), ChannelConfig::reliable()];
+        let (_server, mut sockets) = connected_sockets(2, channels).await;
+        let acker = sockets[1].id().clone();
+        sockets[0].send(Box::new(*b"input"), acker.clone());
+
+        let mut channels = sockets[1].channels_mut();
+        assert_eq!(channels.len(), 2);
+        assert!(channels.get_many_mut([0, 0]).is_none());
+        assert!(channels.get_many_mut([0, 2]).is_none());
+        let [inputs, acks] = channels.get_many_mut([0, 1]).unwrap();
+        assert_eq!((inputs.index(), acks.index()), (0, 1));
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = inputs.receive();
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet didn't arrive");
+        for (peer, packet) in packets {
+            assert_eq!(&packet[..], b"input");
+            acks.send(Box::new(*b"ack"), peer);
+        }
+
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = sockets[0].receive_on_channel(1);
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("ack didn't arrive");
+        assert_eq!(packets, vec![(acker, Box::from(*b"ack"))]);
+        assert!(sockets[0].receive().is_empty());
+    }
+
+    #[tokio::test]
+    async fn packet_hooks_change_and_drop_packets() {
+        let server = TestServer::start();
+        let checksum = |packet: &[u8]| packet.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let config = WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            on_outgoing: Some(PacketHook::new(move |_, packet| {
+                if packet.starts_with(b"secret") {
+                    return PacketAction::Drop;
+                }
+                let mut checked = packet.to_vec();
+                checked.push(checksum(packet));
+                *packet = checked.into();
+                PacketAction::Forward
+            })),
+            on_incoming: Some(PacketHook::new(move |_, packet| {
+                match packet.split_last() {
+                    Some((sum, data)) if *sum == checksum(data) => {
+                        *packet = data.into();
+                        PacketAction::Forward
+                    }
+                    _ => PacketAction::Drop,
+                }
+            })),
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("hooks?next=2", config.clone()),
+            server.socket_with_config("hooks?next=2", config),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let (sender, receiver) = (sockets[0].id().clone(), sockets[1].id().clone());
+        sockets[0].send(Box::new(*b"secret"), receiver.clone());
+        sockets[0].send(Box::new(*b"hello"), receiver);
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets, vec![(sender, Box::from(*b"hello"))]);
+    }
+
+    #[tokio::test]
+    async fn idle_link_is_not_congested() {
+        let (_server, mut sockets) = connected_sockets(2, vec![ChannelConfig::reliable()]).await;
+        let peer = sockets[1].id().clone();
+        sockets[0].send(Box::new(*b"hello"), peer.clone());
+        receive_some(&mut sockets[1]).await;
+        time::sleep(Duration::from_millis(600)).await;
+
+        sockets[0].accept_new_connections();
+        assert_eq!(
+            sockets[0].congestion(&peer),
+            Some(Congestion {
+                level: CongestionLevel::Light,
+                buffered_bytes: 0,
+                buffered_growth: 0,
+            })
+        );
+        assert_eq!(sockets[0].congestion(&"unknown".to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn cross_play_peers_echo_every_channel() {
+        let server = TestServer::start();
+        let check = async {
+            for (name, channels) in cross_play::channel_configs() {
+                let room = format!("cross_play_{name}?next=2");
+                let mut socket = server.socket(&room, channels.clone());
+                let peers = socket.wait_for_peers(1).await.expect("no echo peer");
+                for channel in 0..channels.len() {
+                    socket.send_on_channel(Box::new(*b"echo"), peers[0].clone(), channel);
+                    loop {
+                        let packets = socket.receive_on_channel(channel);
+                        if !packets.is_empty() {
+                            assert_eq!(packets, vec![(peers[0].clone(), Box::from(*b"echo"))]);
+                            break;
+                        }
+                        time::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = cross_play::serve_echo_peers(&server) => unreachable!(),
+            checked = time::timeout(Duration::from_secs(30), check) => {
+                checked.expect("packets didn't come back");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn native_tuning_is_applied() {
+        let server = TestServer::start();
+        let tuned = WebRtcSocketConfig {
+            native: NativeSocketConfig {
+                sctp_max_message_size: Some(16 * 1024),
+                dtls_handshake_timeout_ms: 10_000,
+                ice_disconnected_timeout_ms: Some(2_000),
+                ice_failed_timeout_ms: Some(10_000),
+                ice_keepalive_interval_ms: Some(500),
+                interfaces: vec![],
+            },
+            ..Default::default()
+        };
+        let mut sockets = [
+            server.socket_with_config("tuned?next=2", tuned),
+            server.socket("tuned?next=2", vec![ChannelConfig::unreliable()]),
+        ];
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let max_message_size = |socket: &WebRtcSocket, peer: &WebRtcSocket| {
+            let info = socket
+                .connection_info(peer.id())
+                .expect("no connection info");
+            info.max_message_size
+        };
+        assert_eq!(max_message_size(&sockets[1], &sockets[0]), Some(16 * 1024));
+        assert_eq!(max_message_size(&sockets[0], &sockets[1]), Some(64 * 1024));
+    }
+
+    #[tokio::test]
+    async fn reserved_slot_is_kept_for_a_friend() {
+        let server = TestServer::start_with_args(Args {
+            rooms: vec![RoomRule {
+                pattern: "party".to_string(),
+                next: Some(3),
+                max_peers: None,
+                channels: None,
+                event_log: None,
+                strict_version: false,
+                client_versions: None,
+                listed: false,
+            }],
+            ..Default::default()
+        });
+        let channels = vec![ChannelConfig::reliable()];
+
+        let mut host = server.socket_with_id("party", "host", channels.clone());
+        // the room's rules arrive once the socket has joined
+        time::timeout(Duration::from_secs(10), async {
+            while host.room_info().is_none() {
+                host.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host didn't join");
+        host.reserve_slots(["friend-token"])
+            .expect("message loop stopped");
+        time::sleep(Duration::from_millis(100)).await;
+
+        let _stranger = server.socket_with_id("party", "stranger", channels.clone());
+        let joined = time::timeout(Duration::from_secs(30), host.wait_for_peers(1))
+            .await
+            .expect("stranger didn't connect");
+        assert_eq!(joined.unwrap(), ["stranger"]);
+
+        // the last slot is reserved, so this one has to wait
+        let mut late = server.socket_with_id("party", "late", channels.clone());
+        time::sleep(Duration::from_millis(100)).await;
+
+        let _friend = server.socket_with_config(
+            "party",
+            WebRtcSocketConfig {
+                channels,
+                peer_id: Some("friend".to_string()),
+                slot_token: Some("friend-token".to_string()),
+                ..Default::default()
+            },
+        );
+        let joined = time::timeout(Duration::from_secs(30), host.wait_for_peers(1))
+            .await
+            .expect("friend didn't connect");
+        assert_eq!(joined.unwrap(), ["friend"]);
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(late.accept_new_connections().is_empty());
+    }
+
+    /// Answers every STUN request with a bare binding success, returns the
+    /// url to reach it at
+    fn fake_stun_server() -> String {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if len < 20 {
+                    continue;
+                }
+                // binding success, no attributes, same cookie and transaction id
+                let mut response = vec![0x01, 0x01, 0, 0];
+                response.extend_from_slice(&buf[4..20]);
+                let _ = socket.send_to(&response, from);
+            }
+        });
+        format!("stun:{addr}")
+    }
+
+    #[tokio::test]
+    async fn matchmaking_queue_puts_peers_into_a_room() {
+        let server = TestServer::start_with_args(Args {
+            matchmaking: Some(Matchmaking {
+                regions: vec![MatchmakingRegion {
+                    name: "local".to_string(),
+                    stun_url: fake_stun_server(),
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let config = WebRtcSocketConfig {
+            channels: vec![ChannelConfig::reliable()],
+            matchmaking: true,
+            ..Default::default()
+        };
+
+        let mut first = server.socket_with_config("queue", config.clone());
+        let mut second = server.socket_with_config("queue", config);
+        let (joined_first, joined_second) = time::timeout(
+            Duration::from_secs(30),
+            futures::future::join(first.wait_for_peers(1), second.wait_for_peers(1)),
+        )
+        .await
+        .expect("sockets weren't matched");
+        assert_eq!(joined_first.unwrap(), [second.id().clone()]);
+        assert_eq!(joined_second.unwrap(), [first.id().clone()]);
+        assert!(first.matchmaking_regions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn best_endpoint_skips_unreachable_servers() {
+        let server = TestServer::start();
+        let ice_server = RtcIceServerConfig {
+            urls: vec![fake_stun_server()],
+            ..Default::default()
+        };
+        // nothing listens on a port once its listener is dropped
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = format!("ws://{}/probe", closed.local_addr().unwrap());
+        drop(closed);
+        let endpoints = vec![
+            Endpoint {
+                room_url: unreachable,
+                ice_server: ice_server.clone(),
+            },
+            Endpoint {
+                room_url: server.room_url("probe"),
+                ice_server,
+            },
+        ];
+
+        let latency = probe_endpoint(&endpoints[1]).await.expect("probing failed");
+        assert!(latency.ice.is_some());
+        assert!(probe_endpoint(&endpoints[0]).await.is_err());
+
+        let best = time::timeout(Duration::from_secs(10), select_best_endpoint(endpoints))
+            .await
+            .expect("probing took too long")
+            .expect("no endpoint was reachable");
+        assert_eq!(best.room_url, server.room_url("probe"));
+    }
+
+    #[tokio::test]
+    async fn room_elects_lowest_id_as_host() {
+        let (_server, sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(3, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let mut rooms: Vec<Room<String>> = sockets.into_iter().map(Room::new).collect();
+
+        for room in &rooms {
+            assert_eq!(room.host(), "peer-0");
+        }
+        assert!(rooms[0].is_host());
+        assert!(!rooms[1].is_host());
+
+        rooms[2].send_to_host(&"ready".to_string());
+        rooms[0].send_to_all(&"start".to_string());
+
+        let received = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let messages = rooms[0].receive();
+                if !messages.is_empty() {
+                    return messages;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("message didn't arrive");
+        assert_eq!(received, vec![("peer-2".to_string(), "ready".to_string())]);
+
+        let packets = receive_some(rooms[1].socket_mut()).await;
+        assert_eq!(
+            packets,
+            vec![("peer-0".to_string(), Box::from(*b"\"start\""))]
+        );
+    }
+
+    #[tokio::test]
+    async fn late_joiner_gets_state_from_host() {
+        let channel = ChannelConfig {
+            requests: true,
+            ..ChannelConfig::reliable()
+        };
+        let (_server, sockets) =
+            time::timeout(Duration::from_secs(30), connected_sockets(3, vec![channel]))
+                .await
+                .expect("sockets didn't connect");
+        let mut rooms: Vec<Room<Vec<String>>> = sockets.into_iter().map(Room::new).collect();
+        let (host, guests) = rooms.split_at_mut(1);
+        let host = &mut host[0];
+        let state = vec!["peer-0".to_string(), "peer-1".to_string()];
+
+        let answer = async {
+            let mut answered = 0;
+            while answered < 1 {
+                answered += host.answer_state_requests(&state);
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let (received, ()) = time::timeout(
+            Duration::from_secs(10),
+            join(guests[1].await_initial_state(), answer),
+        )
+        .await
+        .expect("state didn't arrive");
+        assert_eq!(received.expect("request failed"), Some(state));
+
+        let own = rooms[0].await_initial_state().await;
+        assert_eq!(own.expect("host can't fail"), None);
+    }
+
+    #[tokio::test]
+    async fn socket_set_tags_packets() {
+        let server = TestServer::start();
+        let mut host = SocketSet::new();
+        let mut clients = vec![];
+        for room in ["match-a", "match-b"] {
+            let url = format!("{room}?next=2");
+            host.insert(room, server.socket(&url, vec![ChannelConfig::reliable()]));
+            clients.push(server.socket(&url, vec![ChannelConfig::reliable()]));
+        }
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(clients.iter_mut().map(|client| client.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        for client in &mut clients {
+            let host_id = client.connected_peers()[0].clone();
+            client.send(Box::new(*b"hi"), host_id);
+        }
+
+        let mut received = time::timeout(Duration::from_secs(10), async {
+            let mut received = vec![];
+            while received.len() < 2 {
+                host.accept_new_connections();
+                received.extend(host.receive());
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            received
+        })
+        .await
+        .expect("packets didn't arrive");
+        received.sort();
+        let sender = |client: &WebRtcSocket| client.id().clone();
+        assert_eq!(
+            received,
+            vec![
+                ("match-a", sender(&clients[0]), Box::from(*b"hi")),
+                ("match-b", sender(&clients[1]), Box::from(*b"hi")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn observer_sees_room_peers() {
+        let server = TestServer::start();
+        let mut observer = server.socket_with_config(
+            "lobby",
+            WebRtcSocketConfig {
+                channels: vec![],
+                signalling_only: true,
+                ..Default::default()
+            },
+        );
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| server.socket("lobby?next=2", vec![ChannelConfig::reliable()]))
+            .collect();
+        let mut ids: Vec<_> = sockets.iter().map(|socket| socket.id().clone()).collect();
+        ids.sort();
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                assert!(observer.accept_new_connections().is_empty());
+                if observer.room_peers() == ids.as_slice() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("observer didn't see the peers");
+
+        // the peers never see the observer
+        for socket in &mut sockets {
+            socket.accept_new_connections();
+            assert_eq!(socket.connected_peers().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn observer_assembles_paged_peer_lists() {
+        let server = TestServer::start_with_args(Args {
+            limits: Limits {
+                peer_list_page_size: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut observer = server.socket_with_config(
+            "crowd",
+            WebRtcSocketConfig {
+                channels: vec![],
+                signalling_only: true,
+                ..Default::default()
+            },
+        );
+        let sockets: Vec<_> = (0..5)
+            .map(|_| server.socket("crowd", vec![ChannelConfig::reliable()]))
+            .collect();
+        let mut ids: Vec<_> = sockets.iter().map(|socket| socket.id().clone()).collect();
+        ids.sort();
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                observer.accept_new_connections();
+                if observer.peer_list_complete() && observer.room_peers() == ids.as_slice() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("observer didn't see all peers");
+    }
+
+    #[tokio::test]
+    async fn pooled_packets_reuse_buffers() {
+        let server = TestServer::start();
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| {
+                server.socket_with_config(
+                    "pool?next=2",
+                    WebRtcSocketConfig {
+                        channels: vec![ChannelConfig::reliable()],
+                        packet_pool_size: 4,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let packet = sockets[0].packet_pool().packet_from(b"hello");
+        let receiver = sockets[1].id().clone();
+        sockets[0].channel_sender(0).send_pooled(packet, receiver);
+
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = sockets[1].receive_pooled_on_channel(0);
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet didn't arrive");
+        assert_eq!(&*packets[0].1, b"hello");
+        assert_eq!(sockets[1].packet_pool().available(), 0);
+        drop(packets);
+        assert_eq!(sockets[1].packet_pool().available(), 1);
+
+        // the sent packet goes back to its pool once it's handed off
+        time::timeout(Duration::from_secs(10), async {
+            while sockets[0].packet_pool().available() == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sent packet wasn't returned to the pool");
+    }
+
+    #[tokio::test]
+    async fn room_info_is_advertised() {
+        let server = TestServer::start_with_args(Args {
+            rooms: vec![RoomRule {
+                pattern: "duel-*".to_string(),
+                next: Some(2),
+                max_peers: None,
+                channels: Some(1),
+                event_log: None,
+                strict_version: false,
+                client_versions: None,
+                listed: false,
+            }],
+            ..Default::default()
+        });
+        // no next parameter, the room rule pairs the sockets anyway
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| server.socket("duel-1", vec![ChannelConfig::reliable()]))
+            .collect();
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let info = RoomInfo {
+            next: Some(2),
+            max_peers: None,
+            channels: Some(1),
+        };
+        for socket in &mut sockets {
+            socket.accept_new_connections();
+            assert_eq!(socket.room_info(), Some(info));
+        }
+    }
+
+    #[tokio::test]
+    async fn lobby_state_follows_the_group() {
+        let server = TestServer::start();
+        let channels = vec![ChannelConfig::reliable()];
+        let mut host = server.socket("lobby?next=2", channels.clone());
+        let waiting = LobbyState::WaitingForPlayers {
+            current: 1,
+            needed: Some(2),
+        };
+        time::timeout(Duration::from_secs(10), async {
+            while host.lobby_state() != waiting {
+                host.accept_new_connections();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("host didn't get into the room");
+
+        let mut guest = server.socket("lobby?next=2", channels);
+        time::timeout(
+            Duration::from_secs(30),
+            join_all([host.wait_for_peers(1), guest.wait_for_peers(1)]),
+        )
+        .await
+        .expect("sockets didn't connect");
+        host.accept_new_connections();
+        assert_eq!(
+            host.lobby_state_changes(),
+            vec![
+                LobbyState::Searching,
+                waiting,
+                LobbyState::AllPeersConnected
+            ]
+        );
+
+        host.close_room().expect("message loop stopped");
+        time::timeout(Duration::from_secs(10), guest.room_closed())
+            .await
+            .expect("room wasn't closed")
+            .expect("message loop stopped");
+        guest.accept_new_connections();
+        assert_eq!(guest.lobby_state(), LobbyState::Failed);
+    }
+
+    #[tokio::test]
+    async fn recorded_session_replays() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(2, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let buffer = SharedBuffer::default();
+        let mut receiver = sockets
+            .pop()
+            .unwrap()
+            .with_recorder(Recorder::new(buffer.clone()));
+        let mut sender = sockets.pop().unwrap();
+
+        sender.send(Box::new(*b"ping"), "peer-1".to_string());
+        let packets = receive_some(&mut receiver).await;
+        receiver.send(Box::new(*b"pong"), "peer-0".to_string());
+
+        let recording = buffer.0.lock().unwrap().clone();
+        let mut replay = Replay::read(recording.as_slice()).unwrap();
+        let directions: Vec<_> = replay.packets().iter().map(|p| p.direction).collect();
+        assert_eq!(
+            directions,
+            vec![PacketDirection::Received, PacketDirection::Sent]
+        );
+
+        replay.advance_to_end();
+        assert_eq!(replay.receive(), packets);
+        assert!(replay.is_finished());
+    }
+
+    /// Who gets the packets sent from one peer to another
+    type Switchboard = Arc<Mutex<HashMap<(String, String), IncomingPackets>>>;
+
+    /// Connects peers in the same process, without WebRTC
+    struct MemoryMessenger {
+        id: String,
+        switchboard: Switchboard,
+    }
+
+    impl Messenger for MemoryMessenger {
+        fn connect(&self, mut peer: MessengerPeer) -> ConnectFuture {
+            let (me, switchboard) = (self.id.clone(), self.switchboard.clone());
+            Box::pin(async move {
+                let route = (peer.id.clone(), me.clone());
+                let incoming = peer.incoming.clone();
+                let register = || switchboard.lock().unwrap().insert(route, incoming);
+                if peer.initiator {
+                    register();
+                    peer.signaller.send("hello".to_string());
+                    // the socket stops listening once it gives up on the peer
+                    let ack = peer.signaller.receive().await.ok_or("gave up")?;
+                    assert_eq!(ack, "ack");
+                } else {
+                    let hello = peer.signaller.receive().await;
+                    assert_eq!(hello.as_deref(), Some("hello"));
+                    register();
+                    peer.signaller.send("ack".to_string());
+                }
+                let connection: Box<dyn MessengerConnection> = Box::new(MemoryConnection {
+                    route: (me, peer.id),
+                    switchboard,
+                });
+                Ok(connection)
+            })
+        }
+    }
+
+    struct MemoryConnection {
+        route: (String, String),
+        switchboard: Switchboard,
+    }
+
+    impl MessengerConnection for MemoryConnection {
+        fn send(&mut self, channel: usize, packet: &[u8]) -> Result<(), MessengerError> {
+            let switchboard = self.switchboard.lock().unwrap();
+            let incoming = switchboard.get(&self.route).ok_or("peer hung up")?;
+            incoming.deliver(channel, packet);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_messenger_carries_packets() {
+        let server = TestServer::start();
+        let switchboard = Switchboard::default();
+        let mut sockets: Vec<_> = ["alice", "bob"]
+            .iter()
+            .map(|id| {
+                let (socket, message_loop) = WebRtcSocket::new_with_messenger(
+                    WebRtcSocketConfig {
+                        room_url: server.room_url("memory?next=2"),
+                        peer_id: Some(id.to_string()),
+                        ..Default::default()
+                    },
+                    MemoryMessenger {
+                        id: id.to_string(),
+                        switchboard: switchboard.clone(),
+                    },
+                );
+                tokio::spawn(message_loop);
+                socket
+            })
+            .collect();
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        sockets[0].send(Box::new(*b"hi bob"), "bob".to_string());
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(packets, vec![("alice".to_string(), Box::from(*b"hi bob"))]);
+
+        sockets[1].send(Box::new(*b"hi alice"), "alice".to_string());
+        let packets = receive_some(&mut sockets[0]).await;
+        assert_eq!(packets, vec![("bob".to_string(), Box::from(*b"hi alice"))]);
+    }
+
+    /// Never finishes connecting to anyone
+    struct StalledMessenger;
+
+    impl Messenger for StalledMessenger {
+        fn connect(&self, _peer: MessengerPeer) -> ConnectFuture {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    /// Panics as soon as it's asked to connect to someone
+    struct PanickingMessenger;
+
+    impl Messenger for PanickingMessenger {
+        fn connect(&self, _peer: MessengerPeer) -> ConnectFuture {
+            panic!("messenger exploded")
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_message_loop_returns_an_error() {
+        let server = TestServer::start();
+        let config = |id: &str| WebRtcSocketConfig {
+            room_url: server.room_url("explosive"),
+            peer_id: Some(id.to_string()),
+            ..Default::default()
+        };
+        let (mut sockets, message_loops): (Vec<_>, Vec<_>) = ["alice", "bob"]
+            .iter()
+            .map(|id| {
+                let (socket, message_loop) =
+                    WebRtcSocket::new_with_messenger(config(id), PanickingMessenger);
+                (socket, tokio::spawn(message_loop))
+            })
+            .unzip();
+
+        // whoever starts connecting panics, the panic doesn't unwind out of
+        // the message loop
+        let (result, index, _) = time::timeout(
+            Duration::from_secs(10),
+            futures::future::select_all(message_loops),
+        )
+        .await
+        .expect("message loop didn't stop");
+        match result.expect("message loop unwound") {
+            Err(Error::MessageLoopPanicked(message)) => {
+                assert_eq!(message, "messenger exploded")
+            }
+            result => panic!("expected a panic, got {:?}", result),
+        }
+        let socket = &mut sockets[index];
+        assert!(socket.try_accept_new_connections().is_err());
+        assert!(socket.is_closed());
+    }
+
+    #[tokio::test]
+    async fn late_joiner_timing_out_only_disconnects_itself() {
+        let server = TestServer::start();
+        let switchboard = Switchboard::default();
+        let config = |id: &str| WebRtcSocketConfig {
+            room_url: server.room_url("timeouts"),
+            peer_id: Some(id.to_string()),
+            peer_connect_timeout_ms: 500,
+            ..Default::default()
+        };
+        let mut sockets: Vec<_> = ["alice", "bob"]
+            .iter()
+            .map(|id| {
+                let messenger = MemoryMessenger {
+                    id: id.to_string(),
+                    switchboard: switchboard.clone(),
+                };
+                let (socket, message_loop) =
+                    WebRtcSocket::new_with_messenger(config(id), messenger);
+                tokio::spawn(message_loop);
+                socket
+            })
+            .collect();
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+
+        let (_late, message_loop) =
+            WebRtcSocket::new_with_messenger(config("late"), StalledMessenger);
+        tokio::spawn(message_loop);
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                sockets[0].accept_new_connections();
+                if sockets[0].peer_state(&"late".to_string()) == Some(PeerState::Disconnected) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("late joiner didn't time out");
+
+        // the running session goes on
+        assert!(!sockets[0].is_closed());
+        assert_eq!(sockets[0].connected_peers(), ["bob"]);
+        sockets[0].send(Box::new(*b"still here"), "bob".to_string());
+        let packets = receive_some(&mut sockets[1]).await;
+        assert_eq!(
+            packets,
+            vec![("alice".to_string(), Box::from(*b"still here"))]
+        );
+    }
+
+    /// Mailboxes of a pretend platform relay, by relay address
+    type RelayNetwork = Arc<Mutex<HashMap<String, Vec<RelayPacket>>>>;
+
+    struct FakeRelay {
+        address: String,
+        network: RelayNetwork,
+    }
+
+    impl PlatformRelay for FakeRelay {
+        fn local_address(&self) -> String {
+            self.address.clone()
+        }
+
+        fn send_to(
+            &self,
+            address: &str,
+            channel: usize,
+            packet: &[u8],
+        ) -> Result<(), MessengerError> {
+            let mut network = self.network.lock().unwrap();
+            network
+                .entry(address.to_string())
+                .or_default()
+                .push(RelayPacket {
+                    from: self.address.clone(),
+                    channel,
+                    data: packet.into(),
+                });
+            Ok(())
+        }
+
+        fn receive(&self) -> Vec<RelayPacket> {
+            let mut network = self.network.lock().unwrap();
+            network.remove(&self.address).unwrap_or_default()
+        }
+    }
+
+    #[tokio::test]
+    async fn platform_relay_carries_packets() {
+        let server = TestServer::start();
+        let network = RelayNetwork::default();
+        let mut sockets: Vec<_> = ["steam-1", "steam-2"]
+            .iter()
+            .map(|address| {
+                let messenger = RelayMessenger::new(FakeRelay {
+                    address: address.to_string(),
+                    network: network.clone(),
+                });
+                let (socket, message_loop) = WebRtcSocket::new_with_messenger(
+                    WebRtcSocketConfig {
+                        room_url: server.room_url("relay?next=2"),
+                        channels: vec![ChannelConfig::reliable(), ChannelConfig::unreliable()],
+                        ..Default::default()
+                    },
+                    messenger.clone(),
+                );
+                tokio::spawn(message_loop);
+                tokio::spawn(async move {
+                    loop {
+                        messenger.pump();
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                });
+                socket
+            })
+            .collect();
+
+        time::timeout(
+            Duration::from_secs(30),
+            join_all(sockets.iter_mut().map(|socket| socket.wait_for_peers(1))),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let (first, second) = (sockets[0].id().clone(), sockets[1].id().clone());
+
+        sockets[0]
+            .channel_sender(1)
+            .send(Box::new(*b"fast"), second.clone());
+        let packets = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let packets = sockets[1].receive_on_channel(1);
+                if !packets.is_empty() {
+                    return packets;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet didn't arrive");
+        assert_eq!(packets, vec![(first.clone(), Box::from(*b"fast"))]);
+
+        sockets[1].send(Box::new(*b"safe"), first);
+        let packets = receive_some(&mut sockets[0]).await;
+        assert_eq!(packets, vec![(second, Box::from(*b"safe"))]);
+    }
+
+    #[tokio::test]
+    async fn peer_changes_are_followed_by_generation() {
+        let (_server, mut sockets) = time::timeout(
+            Duration::from_secs(30),
+            connected_sockets(3, vec![ChannelConfig::reliable()]),
+        )
+        .await
+        .expect("sockets didn't connect");
+        let socket = &mut sockets[0];
+        socket.accept_new_connections();
+
+        let mut changes = socket.peers_changed_since(0);
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            changes,
+            [
+                ("peer-1".to_string(), PeerState::Connected),
+                ("peer-2".to_string(), PeerState::Connected)
+            ]
+        );
+        let mut connected = socket.connected_peer_ids().to_vec();
+        connected.sort();
+        assert_eq!(connected, ["peer-1".to_string(), "peer-2".to_string()]);
+        assert!(socket.is_connected(&"peer-2".to_string()));
+        assert!(socket.disconnected_peers().is_empty());
+
+        let seen = socket.peer_generation();
+        socket.accept_new_connections();
+        assert!(socket.peers_changed_since(seen).is_empty());
+    }
+}